@@ -0,0 +1,76 @@
+use std::{
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use compress_io::{compress::CompressIo, compress_type::CompressType};
+
+/// Auxiliary TSV output file.  If bgzip is set, the file is written bgzip compressed and a
+/// .gzi index (allowing random access from htslib based tools) is generated after the file is
+/// closed, using bgzip from the PATH
+pub(crate) struct AuxFile {
+    path: PathBuf,
+    bgzip: bool,
+}
+
+impl AuxFile {
+    /// Auxiliary file for input file p with the extension ext (and a .gz suffix if compressed)
+    pub(crate) fn new(p: &Path, ext: &str, bgzip: bool) -> Self {
+        let mut path = p.to_path_buf();
+        if bgzip {
+            path.set_extension(format!("{ext}.gz"));
+        } else {
+            path.set_extension(ext);
+        }
+        Self { path, bgzip }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn writer(&self) -> io::Result<BufWriter<Box<dyn Write>>> {
+        let mut c = CompressIo::new();
+        c.path(&self.path);
+        if self.bgzip {
+            c.ctype(CompressType::Bgzip);
+        }
+        c.bufwriter()
+    }
+
+    /// Close the output file and write the index if required.  Returns the paths of the
+    /// output file and the index
+    pub(crate) fn finish(self, mut wrt: BufWriter<Box<dyn Write>>) -> anyhow::Result<Vec<PathBuf>> {
+        wrt.flush()
+            .with_context(|| format!("Error writing to {}", self.path.display()))?;
+        drop(wrt);
+        let mut v = vec![self.path];
+        if self.bgzip {
+            if let Some(ix) = bgzip_index(&v[0])? {
+                v.push(ix)
+            }
+        }
+        Ok(v)
+    }
+}
+
+// Generate .gzi index for bgzip compressed file p.  If bgzip can not be run a warning is
+// given and no index is written
+fn bgzip_index(p: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let status = match Command::new("bgzip").arg("-r").arg(p).status() {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Could not run bgzip to index {}: {e}", p.display());
+            return Ok(None);
+        }
+    };
+    if status.success() {
+        let mut ix = p.as_os_str().to_owned();
+        ix.push(".gzi");
+        Ok(Some(PathBuf::from(ix)))
+    } else {
+        Err(anyhow!("bgzip failed to index {} ({status})", p.display()))
+    }
+}
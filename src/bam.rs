@@ -0,0 +1,461 @@
+//! BAM and CRAM input (--input-format bam, or BAM/CRAM file names with automatic format
+//! selection), so that aligned archives can be checked without regenerating FASTQs.
+//!
+//! BAM records are decoded directly from the (decompressed) BAM stream.  CRAM files are
+//! converted to uncompressed BAM by samtools, which must be on the PATH and able to find the
+//! reference sequences (e.g. using REF_PATH), as decoding CRAM requires the reference.  Reads
+//! are counted as for FASTQ input (see [`crate::fastq`]), with reads aligned to the reverse
+//! strand being reverse complemented back to sequencing order, and bases without stored
+//! qualities counted as passing the quality threshold.  Secondary and supplementary
+//! alignments are skipped so that each read is counted once, and --aligned-reads restricts
+//! the counts to unmapped or to properly paired reads.
+//!
+//! One dataset is produced per read group.  Sample, library and index are taken from the SM,
+//! LB and BC fields of the @RG header line and flowcell and lane from the first read name of
+//! the group (if in Illumina format).  Both ends of paired reads are counted together.  Reads
+//! with no (or an unknown) RG tag form a separate dataset.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::Context;
+use clap::{builder::PossibleValue, ValueEnum};
+use compress_io::compress::CompressIo;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fastq::{fli_from_header, ReadCounter},
+    read::{read_group_path, DataSet, Fli, TempDataSet},
+    source::is_stdin,
+};
+
+const FLAG_PAIRED: u16 = 0x1;
+const FLAG_PROPER_PAIR: u16 = 0x2;
+const FLAG_UNMAPPED: u16 = 0x4;
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+// Bases for the 4 bit sequence codes of BAM records
+const SEQ_CODES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+// Size of the fixed part of a BAM record (after the block size)
+const FIXED_LEN: usize = 32;
+
+/// Reads used from aligned (BAM/CRAM) inputs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlignedReads {
+    /// All primary alignments
+    #[default]
+    All,
+    /// Only unmapped reads
+    Unmapped,
+    /// Only mapped reads from proper pairs
+    ProperPairs,
+}
+
+impl ValueEnum for AlignedReads {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::All, Self::Unmapped, Self::ProperPairs]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::All => Some(PossibleValue::new("all")),
+            Self::Unmapped => Some(PossibleValue::new("unmapped")),
+            Self::ProperPairs => Some(PossibleValue::new("proper-pairs")),
+        }
+    }
+}
+
+impl AlignedReads {
+    fn accepts(&self, flag: u16) -> bool {
+        if flag & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) != 0 {
+            return false;
+        }
+        match self {
+            Self::All => true,
+            Self::Unmapped => flag & FLAG_UNMAPPED != 0,
+            Self::ProperPairs => {
+                flag & (FLAG_PAIRED | FLAG_PROPER_PAIR | FLAG_UNMAPPED)
+                    == FLAG_PAIRED | FLAG_PROPER_PAIR
+            }
+        }
+    }
+}
+
+/// Read group from an @RG header line
+struct ReadGroup {
+    id: String,
+    sample: Option<String>,
+    library: Option<String>,
+    barcode: Option<String>,
+}
+
+impl ReadGroup {
+    fn from_header_line(l: &str) -> Option<Self> {
+        let mut fields: HashMap<_, _> = l
+            .strip_prefix("@RG\t")?
+            .split('\t')
+            .filter_map(|f| f.split_once(':'))
+            .map(|(k, v)| (k, v.to_owned()))
+            .collect();
+        Some(Self {
+            id: fields.remove("ID")?,
+            sample: fields.remove("SM"),
+            library: fields.remove("LB"),
+            barcode: fields.remove("BC"),
+        })
+    }
+
+    // Fli for the read group, with flowcell and lane taken from read name
+    fn fli(&self, name: &str) -> Fli {
+        let mut fli = fli_from_header(name);
+        if let Some(s) = self.sample.as_ref() {
+            fli.set_sample(s.to_owned())
+        }
+        if let Some(s) = self.library.as_ref() {
+            fli.set_library(s.to_owned())
+        }
+        if let Some(s) = self.barcode.as_ref() {
+            fli.set_index(s.to_owned())
+        }
+        fli
+    }
+}
+
+/// Fields of a BAM record used for counting
+struct BamRecord<'a> {
+    flag: u16,
+    name: &'a [u8],
+    seq: &'a [u8],
+    qual: &'a [u8],
+    aux: &'a [u8],
+}
+
+impl<'a> BamRecord<'a> {
+    /// Parse record from buf (the record after the block size).  Returns None if the
+    /// record is truncated
+    fn parse(buf: &'a [u8]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        if buf.len() < FIXED_LEN {
+            return None;
+        }
+        let l_name = buf[8] as usize;
+        let n_cigar = u16_at(12) as usize;
+        let flag = u16_at(14);
+        let l_seq = i32::from_le_bytes(buf[16..20].try_into().ok()?);
+        let l_seq = usize::try_from(l_seq).ok()?;
+        let name_end = FIXED_LEN + l_name;
+        let seq_start = name_end + 4 * n_cigar;
+        let qual_start = seq_start + l_seq.div_ceil(2);
+        let aux_start = qual_start + l_seq;
+        Some(Self {
+            flag,
+            // Read name is NUL terminated
+            name: buf.get(FIXED_LEN..name_end.saturating_sub(1))?,
+            seq: buf.get(seq_start..qual_start)?,
+            qual: buf.get(qual_start..aux_start)?,
+            aux: buf.get(aux_start..)?,
+        })
+    }
+
+    /// (base, quality) pairs in sequencing order
+    fn bases(&self) -> impl ExactSizeIterator<Item = (u8, u8)> + '_ {
+        let l = self.qual.len();
+        let rev = self.flag & FLAG_REVERSE != 0;
+        (0..l).map(move |i| {
+            let j = if rev { l - 1 - i } else { i };
+            let code = if j & 1 == 0 {
+                self.seq[j >> 1] >> 4
+            } else {
+                self.seq[j >> 1] & 0xf
+            };
+            let b = SEQ_CODES[code as usize];
+            (if rev { complement(b) } else { b }, self.qual[j])
+        })
+    }
+
+    /// Value of the RG tag (if present)
+    fn read_group(&self) -> Option<&'a str> {
+        let mut a = self.aux;
+        while a.len() >= 3 {
+            let (tag, tp) = (&a[..2], a[2]);
+            a = &a[3..];
+            let len = match tp {
+                b'A' | b'c' | b'C' => 1,
+                b's' | b'S' => 2,
+                b'i' | b'I' | b'f' => 4,
+                b'Z' | b'H' => {
+                    let n = a.iter().position(|c| *c == 0)?;
+                    if tag == b"RG" && tp == b'Z' {
+                        return std::str::from_utf8(&a[..n]).ok();
+                    }
+                    n + 1
+                }
+                b'B' => {
+                    let sz = match a.first()? {
+                        b'c' | b'C' => 1,
+                        b's' | b'S' => 2,
+                        b'i' | b'I' | b'f' => 4,
+                        _ => return None,
+                    };
+                    let n = u32::from_le_bytes(a.get(1..5)?.try_into().ok()?);
+                    5 + sz * n as usize
+                }
+                _ => return None,
+            };
+            a = a.get(len..)?;
+        }
+        None
+    }
+}
+
+fn complement(b: u8) -> u8 {
+    match b {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        _ => b'N',
+    }
+}
+
+fn read_len<R: Read>(rdr: &mut R) -> anyhow::Result<usize> {
+    let mut b = [0; 4];
+    rdr.read_exact(&mut b)?;
+    usize::try_from(i32::from_le_bytes(b)).map_err(|_| anyhow!("Negative length in BAM header"))
+}
+
+// Block size of the next record, or None at the end of the input
+fn next_block_size<R: Read>(rdr: &mut R) -> io::Result<Option<usize>> {
+    let mut b = [0; 4];
+    let mut n = 0;
+    while n < 4 {
+        match rdr.read(&mut b[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    match n {
+        0 => Ok(None),
+        4 => Ok(Some(u32::from_le_bytes(b) as usize)),
+        _ => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// Count the reads from uncompressed BAM data read from rdr, returning one dataset per read
+/// group with reads (name is used in error messages)
+pub fn count_bam<R: Read>(
+    mut rdr: R,
+    name: &str,
+    reads: AlignedReads,
+) -> anyhow::Result<Vec<TempDataSet>> {
+    let mut magic = [0; 4];
+    rdr.read_exact(&mut magic)
+        .with_context(|| format!("Error reading from {name}"))?;
+    if &magic != b"BAM\x01" {
+        return Err(anyhow!("{name} is not in BAM format"));
+    }
+    let mut text = vec![0; read_len(&mut rdr)?];
+    rdr.read_exact(&mut text)
+        .with_context(|| format!("{name}: truncated header"))?;
+    let groups: Vec<_> = String::from_utf8_lossy(&text)
+        .lines()
+        .filter_map(ReadGroup::from_header_line)
+        .collect();
+    // Reference sequence names and lengths are not needed
+    for _ in 0..read_len(&mut rdr)? {
+        let l = read_len(&mut rdr)?;
+        io::copy(&mut (&mut rdr).take(l as u64 + 4), &mut io::sink())?;
+    }
+
+    let ix: HashMap<_, _> = groups
+        .iter()
+        .enumerate()
+        .map(|(i, g)| (g.id.as_str(), i))
+        .collect();
+    // The extra counter is for reads without a (known) read group.  The name of the first
+    // read of each group is kept for the flowcell and lane
+    let mut counters: Vec<(ReadCounter, Option<String>)> =
+        (0..=groups.len()).map(|_| Default::default()).collect();
+    let mut buf = Vec::new();
+    let mut n_rec = 0;
+    while let Some(bs) = next_block_size(&mut rdr)
+        .with_context(|| format!("{name}: truncated record {}", n_rec + 1))?
+    {
+        n_rec += 1;
+        buf.resize(bs, 0);
+        rdr.read_exact(&mut buf)
+            .with_context(|| format!("{name}: truncated record {n_rec}"))?;
+        let rec =
+            BamRecord::parse(&buf).ok_or_else(|| anyhow!("{name}: record {n_rec} is malformed"))?;
+        if !reads.accepts(rec.flag) {
+            continue;
+        }
+        let g = rec
+            .read_group()
+            .and_then(|id| ix.get(id))
+            .copied()
+            .unwrap_or(groups.len());
+        let (ctr, first) = &mut counters[g];
+        if first.is_none() {
+            *first = Some(String::from_utf8_lossy(rec.name).into_owned())
+        }
+        ctr.add_read(rec.bases())
+    }
+    debug!("Read {n_rec} records from {name}");
+
+    let v: Vec<_> = counters
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (c, _))| c.n_reads() > 0)
+        .map(|(i, (c, first))| {
+            let first = first.unwrap_or_default();
+            let fli = match groups.get(i) {
+                Some(g) => g.fli(&first),
+                None => fli_from_header(&first),
+            };
+            c.into_temp_dataset(fli)
+        })
+        .collect();
+    if v.is_empty() {
+        Err(anyhow!("No reads selected from {name}"))
+    } else {
+        Ok(v)
+    }
+}
+
+// Convert the read groups counted from input p to datasets, giving each read group its own
+// path if there are several
+fn datasets(v: Vec<TempDataSet>, p: &Path) -> anyhow::Result<Vec<DataSet>> {
+    let multi = v.len() > 1;
+    v.into_iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let mut d = DataSet::from_temp_dataset(t, p)?;
+            if multi {
+                d.set_path(read_group_path(d.path(), i))
+            }
+            Ok(d)
+        })
+        .collect()
+}
+
+/// Read datasets (one per read group) from BAM file p.  If p is '-', reads from stdin and
+/// the datasets are given the path 'stdin'
+pub fn read_bam(p: &Path, reads: AlignedReads) -> anyhow::Result<Vec<DataSet>> {
+    let (path, name) = if is_stdin(p) {
+        (None, Path::new("stdin"))
+    } else {
+        (Some(p), p)
+    };
+    let rdr = CompressIo::new()
+        .opt_path(path)
+        .bufreader()
+        .with_context(|| format!("Could not open {} for input", name.display()))?;
+    let v = count_bam(rdr, &name.display().to_string(), reads)?;
+    datasets(v, name)
+}
+
+/// Read datasets (one per read group) from CRAM file p, using samtools to decode the file
+pub fn read_cram(p: &Path, reads: AlignedReads) -> anyhow::Result<Vec<DataSet>> {
+    let mut child = Command::new("samtools")
+        .args(["view", "-u"])
+        .arg(p)
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| "Could not run samtools (required for CRAM input)")?;
+    let rdr = io::BufReader::new(child.stdout.take().expect("Missing samtools output"));
+    let res = count_bam(rdr, &p.display().to_string(), reads);
+    let status = child.wait().with_context(|| "Error waiting for samtools")?;
+    if !status.success() {
+        return Err(anyhow!(
+            "samtools failed to read {} ({status})",
+            p.display()
+        ));
+    }
+    datasets(res?, p)
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    // Uncompressed BAM record with read name, flag, sequence (as bases), qualities and aux
+    #[allow(dead_code)]
+    fn bam_record(name: &str, flag: u16, seq: &[u8], qual: &[u8], aux: &[u8]) -> Vec<u8> {
+        let code = |b: u8| SEQ_CODES.iter().position(|c| *c == b).unwrap() as u8;
+        let mut r = Vec::new();
+        r.extend((-1i32).to_le_bytes());
+        r.extend((-1i32).to_le_bytes());
+        r.push(name.len() as u8 + 1);
+        r.extend([0, 0, 0, 0, 0]);
+        r.extend(flag.to_le_bytes());
+        r.extend((seq.len() as i32).to_le_bytes());
+        r.extend([0xff; 12]);
+        r.extend(name.as_bytes());
+        r.push(0);
+        for c in seq.chunks(2) {
+            r.push((code(c[0]) << 4) | c.get(1).map(|b| code(*b)).unwrap_or(0))
+        }
+        r.extend(qual);
+        r.extend(aux);
+        let mut v = (r.len() as u32).to_le_bytes().to_vec();
+        v.extend(r);
+        v
+    }
+
+    #[test]
+    fn count_bam_test() {
+        let text = b"@HD\tVN:1.6\n@RG\tID:rg1\tSM:S1\tLB:L1\n";
+        let mut bam = b"BAM\x01".to_vec();
+        bam.extend((text.len() as i32).to_le_bytes());
+        bam.extend(text);
+        bam.extend(0i32.to_le_bytes());
+        let rg = b"RGZrg1\0";
+        bam.extend(bam_record(
+            "M1:7:FC12:2:1101:100:200",
+            0,
+            b"ACGTN",
+            &[30, 30, 30, 10, 30],
+            rg,
+        ));
+        // Reverse strand read: counted as AACC
+        bam.extend(bam_record(
+            "r2",
+            FLAG_REVERSE,
+            b"GGTT",
+            &[30; 4],
+            b"NMi\x01\0\0\0RGZrg1\0",
+        ));
+        // Secondary alignments are skipped
+        bam.extend(bam_record("r2", FLAG_SECONDARY, b"GGTT", &[30; 4], rg));
+        bam.extend(bam_record("r3", FLAG_UNMAPPED, b"GG", &[30; 2], b""));
+
+        let v = count_bam(bam.as_slice(), "test", AlignedReads::All).unwrap();
+        assert_eq!(v.len(), 2);
+        let mut d = v
+            .into_iter()
+            .map(|t| DataSet::from_temp_dataset(t, Path::new("t.bam")).unwrap());
+        let mut d1 = d.next().unwrap();
+        assert_eq!(d1.n_reads(), 2);
+        assert_eq!(d1.sample(), Some("S1"));
+        assert_eq!(d1.fli_mut().fli(), None);
+        // Counts in order A, C, T, G, N
+        assert_eq!(d1.per_pos_cts()[0].cts(), &[2, 0, 0, 0, 0]);
+        assert_eq!(d1.per_pos_cts()[3].cts(), &[0, 1, 0, 0, 1]);
+        assert_eq!(d.next().unwrap().n_reads(), 1);
+
+        let v = count_bam(bam.as_slice(), "test", AlignedReads::Unmapped).unwrap();
+        assert_eq!(v.len(), 1);
+        assert!(count_bam(bam.as_slice(), "test", AlignedReads::ProperPairs).is_err());
+    }
+}
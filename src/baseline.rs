@@ -0,0 +1,308 @@
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    process::DataResults, read::DataSummary, results_table::ResultsTable, schema::FIXED_COLUMNS,
+};
+
+/// Metrics ranked against the baseline, as (TSV column, JSON key)
+pub const BASELINE_METRICS: [(&str, &str); 3] = [
+    ("KL-pctl", "kl_distance"),
+    ("Fold80-pctl", "fold_80_base_penalty"),
+    ("Mapped-frac-pctl", "mapped_fraction"),
+];
+
+// Decimal places of the metrics in the main output.  Dataset values are rounded to the same
+// precision as the baseline values before ranking so that ties are recognized (the mapped
+// fraction is calculated from counts, so is not rounded)
+const METRIC_DECIMALS: [Option<i32>; 3] = [Some(5), Some(6), None];
+
+/// Column giving the number of baseline datasets in the cohort of a dataset
+pub const COHORT_SIZE_COLUMN: &str = "Baseline-cohort-size";
+
+// Columns that can be matched between a dataset and the baseline in addition to the dataset
+// description columns of the main output
+const LIBRARY_TYPE_COLUMN: &str = "Library-type";
+const READ_LENGTH_COLUMN: &str = "Read-length";
+
+// Value of a numeric column, with NA and unparseable values treated as missing
+fn get_value(row: &[String], ix: Option<usize>) -> Option<f64> {
+    ix.and_then(|i| row[i].parse::<f64>().ok())
+        .filter(|x| x.is_finite())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FilterOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Filter on the rows of the baseline files, given as COLUMN OP VALUE where OP is one of
+/// =, !=, <, <=, > or >=.  The equality operators compare the values as strings, and the
+/// others compare them as numbers (rows with non numeric values do not pass)
+#[derive(Debug, Clone)]
+pub struct BaselineFilter {
+    column: String,
+    op: FilterOp,
+    value: String,
+}
+
+impl FromStr for BaselineFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        const OPS: [(&str, FilterOp); 6] = [
+            ("!=", FilterOp::Ne),
+            ("<=", FilterOp::Le),
+            (">=", FilterOp::Ge),
+            ("=", FilterOp::Eq),
+            ("<", FilterOp::Lt),
+            (">", FilterOp::Gt),
+        ];
+        // The first operator in the string, preferring two character operators so that <=
+        // is not taken as <
+        let (ix, tok, op) = OPS
+            .iter()
+            .filter_map(|(tok, op)| s.find(tok).map(|i| (i, *tok, *op)))
+            .min_by_key(|(i, tok, _)| (*i, usize::MAX - tok.len()))
+            .ok_or_else(|| anyhow!("No comparison operator found in baseline filter {s}"))?;
+        let column = s[..ix].trim();
+        let value = s[ix + tok.len()..].trim();
+        if column.is_empty() {
+            return Err(anyhow!("Missing column name in baseline filter {s}"));
+        }
+        if !matches!(op, FilterOp::Eq | FilterOp::Ne) && value.parse::<f64>().is_err() {
+            return Err(anyhow!("Non numeric value in baseline filter {s}"));
+        }
+        Ok(Self {
+            column: column.to_owned(),
+            op,
+            value: value.to_owned(),
+        })
+    }
+}
+
+impl BaselineFilter {
+    fn accepts(&self, x: &str) -> bool {
+        let num = || x.parse::<f64>().ok().zip(self.value.parse::<f64>().ok());
+        match self.op {
+            FilterOp::Eq => x == self.value,
+            FilterOp::Ne => x != self.value,
+            FilterOp::Lt => num().map(|(a, b)| a < b).unwrap_or(false),
+            FilterOp::Le => num().map(|(a, b)| a <= b).unwrap_or(false),
+            FilterOp::Gt => num().map(|(a, b)| a > b).unwrap_or(false),
+            FilterOp::Ge => num().map(|(a, b)| a >= b).unwrap_or(false),
+        }
+    }
+}
+
+/// Historical cohort of results read from TSV results files of previous runs.  Each dataset
+/// of the current run is given the percentile rank of its metrics within the cohort.
+///
+/// The baseline rows can be restricted with filters, and split into cohorts by the values of
+/// match columns, in which case each dataset is only compared with the baseline rows having
+/// the same values as the dataset for all match columns
+pub struct Baseline {
+    match_columns: Vec<String>,
+    // Sorted values for each metric (in the order of BASELINE_METRICS) by cohort, where the
+    // cohorts are identified by the values of the match columns
+    cohorts: HashMap<Vec<String>, [Vec<f64>; 3]>,
+}
+
+impl Baseline {
+    /// Read the baseline cohorts from one or more results files.  Metrics whose columns are
+    /// absent from a file are skipped for that file, and files without one of the filter or
+    /// match columns are not used
+    pub fn from_files(
+        files: &[PathBuf],
+        filters: &[BaselineFilter],
+        match_columns: &[String],
+    ) -> anyhow::Result<Self> {
+        let mut cohorts: HashMap<Vec<String>, [Vec<f64>; 3]> = HashMap::new();
+        let mut n_used = 0;
+        for p in files {
+            let t = ResultsTable::read(p)?;
+            let filter_ix: Option<Vec<_>> = filters.iter().map(|f| t.column(&f.column)).collect();
+            let match_ix: Option<Vec<_>> = match_columns.iter().map(|c| t.column(c)).collect();
+            let (Some(filter_ix), Some(match_ix)) = (filter_ix, match_ix) else {
+                warn!(
+                    "Baseline file {} skipped as it lacks a filter or match column",
+                    p.display()
+                );
+                continue;
+            };
+            let kl = t.column("KL-distance");
+            let f80 = t.column("Fold_80_base_penalty");
+            let (mapped, total) = (t.column("Mapped-reads"), t.column("Total-reads"));
+            for r in t.rows() {
+                if !filters
+                    .iter()
+                    .zip(filter_ix.iter())
+                    .all(|(f, i)| f.accepts(&r[*i]))
+                {
+                    continue;
+                }
+                n_used += 1;
+                let key: Vec<_> = match_ix.iter().map(|i| r[*i].clone()).collect();
+                let values = cohorts.entry(key).or_default();
+                let mapped_frac = match (get_value(r, mapped), get_value(r, total)) {
+                    (Some(m), Some(n)) if n > 0.0 => Some(m / n),
+                    _ => None,
+                };
+                for (v, x) in
+                    values
+                        .iter_mut()
+                        .zip([get_value(r, kl), get_value(r, f80), mapped_frac])
+                {
+                    if let Some(x) = x {
+                        v.push(x)
+                    }
+                }
+            }
+            debug!(
+                "Read {} baseline datasets from {}",
+                t.rows().len(),
+                p.display()
+            );
+        }
+        info!(
+            "Baseline of {n_used} datasets in {} cohort(s)",
+            cohorts.len()
+        );
+        for (ix, (name, _)) in BASELINE_METRICS.iter().enumerate() {
+            if cohorts.values().all(|v| v[ix].is_empty()) {
+                warn!("No baseline values found for {name}")
+            }
+        }
+        for v in cohorts.values_mut().flat_map(|v| v.iter_mut()) {
+            v.sort_unstable_by(|a, b| a.total_cmp(b))
+        }
+        Ok(Self {
+            match_columns: match_columns.to_vec(),
+            cohorts,
+        })
+    }
+
+    // Percentile rank of x in v (sorted), counting ties as half below
+    fn rank(v: &[f64], x: f64) -> Option<f64> {
+        if v.is_empty() {
+            return None;
+        }
+        let below = v.partition_point(|y| *y < x);
+        let not_above = v.partition_point(|y| *y <= x);
+        Some(100.0 * (below + not_above) as f64 / (2 * v.len()) as f64)
+    }
+
+    // Values of the match columns for dataset d, as they would appear in the main output
+    fn cohort_key(&self, d: &DataSummary) -> Vec<String> {
+        if self.match_columns.is_empty() {
+            return Vec::new();
+        }
+        let desc = d.to_string();
+        let fixed: HashMap<_, _> = FIXED_COLUMNS
+            .iter()
+            .copied()
+            .zip(desc.split('\t'))
+            .collect();
+        self.match_columns
+            .iter()
+            .map(|c| match c.as_str() {
+                LIBRARY_TYPE_COLUMN => d.library_type().unwrap_or("NA").to_owned(),
+                READ_LENGTH_COLUMN => d.max_read_len().to_string(),
+                c => fixed.get(c).copied().unwrap_or("NA").to_owned(),
+            })
+            .collect()
+    }
+
+    // Cohort of dataset d
+    fn cohort(&self, d: &DataSummary) -> Option<&[Vec<f64>; 3]> {
+        self.cohorts.get(&self.cohort_key(d))
+    }
+
+    /// Percentile ranks of the metrics of res for dataset d (in the order of
+    /// BASELINE_METRICS)
+    pub fn percentiles(&self, d: &DataSummary, res: &DataResults) -> [Option<f64>; 3] {
+        let mut p = [None; 3];
+        let Some(cohort) = self.cohort(d) else {
+            return p;
+        };
+        let kc = res.kmer_coverage();
+        let x = [
+            res.kl_distance(),
+            kc.map(|k| k.fold_80_base_penalty()),
+            kc.and_then(|k| k.mapped_fraction()),
+        ];
+        for (((p, v), x), dec) in p.iter_mut().zip(cohort.iter()).zip(x).zip(METRIC_DECIMALS) {
+            let x = match dec {
+                Some(n) => x.map(|x| (x * 10f64.powi(n)).round() / 10f64.powi(n)),
+                None => x,
+            };
+            *p = x.and_then(|x| Self::rank(v, x))
+        }
+        p
+    }
+
+    /// Number of baseline datasets in the cohort of dataset d
+    pub fn cohort_size(&self, d: &DataSummary) -> usize {
+        self.cohort(d)
+            .and_then(|v| v.iter().map(|x| x.len()).max())
+            .unwrap_or(0)
+    }
+
+    /// Percentile ranks and cohort size as TSV columns
+    pub fn columns(&self, d: &DataSummary, res: &DataResults) -> String {
+        let mut s: String = self
+            .percentiles(d, res)
+            .iter()
+            .map(|p| match p {
+                Some(p) => format!("\t{p:.1}"),
+                None => "\tNA".to_owned(),
+            })
+            .collect();
+        s.push_str(&format!("\t{}", self.cohort_size(d)));
+        s
+    }
+
+    /// Percentile ranks and cohort size as a JSON object
+    pub fn json(&self, d: &DataSummary, res: &DataResults) -> Value {
+        let p = self.percentiles(d, res);
+        let mut m: Map<_, _> = BASELINE_METRICS
+            .iter()
+            .zip(p)
+            .map(|((_, k), x)| (k.to_string(), json!(x)))
+            .collect();
+        m.insert("cohort_size".to_owned(), json!(self.cohort_size(d)));
+        Value::Object(m)
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn rank_test() {
+        let v = [0.01, 0.02, 0.02, 0.05];
+        assert_eq!(Baseline::rank(&v, 0.0), Some(0.0));
+        assert_eq!(Baseline::rank(&v, 0.02), Some(50.0));
+        assert_eq!(Baseline::rank(&v, 0.03), Some(75.0));
+        assert_eq!(Baseline::rank(&v, 0.1), Some(100.0));
+        assert_eq!(Baseline::rank(&[], 0.1), None);
+    }
+
+    #[test]
+    fn filter_test() {
+        let f: BaselineFilter = "Read-length>=100".parse().unwrap();
+        assert_eq!((f.column.as_str(), f.op), ("Read-length", FilterOp::Ge));
+        assert!(f.accepts("150") && !f.accepts("75") && !f.accepts("NA"));
+        let f: BaselineFilter = "Library-type != wgs".parse().unwrap();
+        assert!(f.accepts("exome") && !f.accepts("wgs"));
+        assert!("Reads".parse::<BaselineFilter>().is_err());
+        assert!("Reads<many".parse::<BaselineFilter>().is_err());
+    }
+}
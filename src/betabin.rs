@@ -1,18 +1,50 @@
 use anyhow::Context;
-use std::{io::Write, path::Path};
+use std::{
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
-use compress_io::compress::CompressIo;
+use clap::{builder::PossibleValue, ValueEnum};
 use libm::lgamma;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Binomial, Distribution};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    gauss_legendre::gauss_legendre_64,
+    aux_file::AuxFile,
+    gauss_legendre::{gauss_legendre_64, gauss_legendre_64_rule},
+    gc_density::{GcDensity, GC_HIST_BINS},
     reference::{GcHistKey, GcHistVal},
+    stats::Z_95,
 };
 
 pub fn lbeta(a: f64, b: f64) -> f64 {
     lgamma(a) + lgamma(b) - lgamma(a + b)
 }
 
+/// Log likelihood (without the binomial coefficient) of a read with a AT and b GC bases under
+/// the beta mixture of the reference distribution ref_dist, where ln_total is the log of the
+/// total reference count.  The likelihood is the count weighted mean over the reference bins
+/// of B(a + a_r + 1, b + b_r + 1) / B(a_r + 1, b_r + 1)
+pub(crate) fn ref_log_likelihood(
+    a: f64,
+    b: f64,
+    ref_dist: &[(GcHistKey, GcHistVal)],
+    ln_total: f64,
+) -> f64 {
+    let v: Vec<_> = ref_dist
+        .iter()
+        .filter(|(_, rv)| rv.count() > 0.0)
+        .map(|(rk, rv)| {
+            let (ra, rb) = rk.counts();
+            rv.count().ln() + lbeta(a + ra + 1.0, b + rb + 1.0) - rv.beta_a_b()
+        })
+        .collect();
+    let m = v.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    m + v.iter().map(|x| (x - m).exp()).sum::<f64>().ln() - ln_total
+}
+
 pub fn mean_gc(cts: &[(GcHistKey, GcHistVal)]) -> f64 {
     let mut ct = [0.0; 2];
     for (a, b) in cts.iter().map(|(k, v)| {
@@ -27,102 +59,501 @@ pub fn mean_gc(cts: &[(GcHistKey, GcHistVal)]) -> f64 {
     ct[1] / (ct[0] + ct[1])
 }
 
-fn prob_func(x: f64, cts: &[(GcHistKey, GcHistVal)]) -> f64 {
-    let lnx = x.ln();
-    let lnx1 = (1.0 - x).ln();
-    let (l, tot) = cts.iter().fold((0.0, 0.0), |(l, t), (c, v)| {
-        let (a, b) = c.counts();
-        let z = v.count();
-        (l + (lnx * b + lnx1 * a - v.beta_a_b()).exp() * z, t + z)
-    });
-    l / tot
+pub fn kl_distance(cts: &[(GcHistKey, GcHistVal)], ref_dist: &[(GcHistKey, GcHistVal)]) -> f64 {
+    smoothed_kl_distance(cts, ref_dist, 0.0)
 }
-fn kl_distance_func(
-    x: f64,
+
+/// KL distance after smoothing both distributions with a prior of `prior` pseudo-reads
+/// spread uniformly over [0, 1].  As the weight of the prior is relative to the read count,
+/// it mainly affects shallow datasets where the raw KL distance is dominated by sampling noise
+pub fn smoothed_kl_distance(
     cts: &[(GcHistKey, GcHistVal)],
     ref_dist: &[(GcHistKey, GcHistVal)],
+    prior: f64,
 ) -> f64 {
-    assert!(x > 0.0 && x < 1.0);
-    let p = prob_func(x, cts);
-    let q = prob_func(x, ref_dist);
-    p * (p / q).ln()
+    GcDensity::with_prior(cts, prior).kl_distance(&GcDensity::with_prior(ref_dist, prior))
 }
 
-pub fn kl_distance(cts: &[(GcHistKey, GcHistVal)], ref_dist: &[(GcHistKey, GcHistVal)]) -> f64 {
-    gauss_legendre_64(|x| kl_distance_func(x, cts, ref_dist), 0.0, 1.0)
+// Delta method standard error of the mean gc, taking reads as the sampling units so that
+// variation in gc content between reads is included.  Returns None with fewer than 2 reads
+fn mean_gc_se(cts: &[(GcHistKey, GcHistVal)]) -> Option<f64> {
+    let (mut n, mut x, mut y) = (0.0, 0.0, 0.0);
+    for (k, v) in cts.iter() {
+        let (a, b) = k.counts();
+        let w = v.count();
+        n += w;
+        x += w * (a + b);
+        y += w * b;
+    }
+    if n < 2.0 || x <= 0.0 {
+        return None;
+    }
+    let r = y / x;
+    let ss = cts.iter().fold(0.0, |s, (k, v)| {
+        let (a, b) = k.counts();
+        let e = b - r * (a + b);
+        s + v.count() * e * e
+    });
+    Some((ss / ((n - 1.0) * n)).sqrt() * n / x)
 }
 
-const GC_HIST_BINS: usize = 1000;
+// Resample the read counts of cts (multinomial with the same total)
+fn resample<R: Rng>(cts: &[(GcHistKey, GcHistVal)], rng: &mut R) -> Vec<(GcHistKey, GcHistVal)> {
+    let total = cts.iter().map(|(_, v)| v.count()).sum::<f64>();
+    let (mut n, mut p) = (total.round() as u64, 1.0);
+    let mut v = Vec::with_capacity(cts.len());
+    for (k, x) in cts.iter() {
+        if n == 0 {
+            break;
+        }
+        let q = if p > 0.0 {
+            (x.count() / total / p).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        let c = if q > 0.0 {
+            Binomial::new(n, q)
+                .expect("Invalid probability")
+                .sample(rng)
+        } else {
+            0
+        };
+        if c > 0 {
+            v.push((*k, GcHistVal::make(k, c)));
+            n -= c
+        }
+        p -= x.count() / total;
+    }
+    v
+}
 
-pub fn output_gc_hist(
-    path: &Path,
+// Percentile bootstrap interval for the (smoothed) KL distance of cts from ref_dist
+fn kl_distance_ci(
     cts: &[(GcHistKey, GcHistVal)],
-    ref_cts: Option<&[(GcHistKey, GcHistVal)]>,
-) -> anyhow::Result<()> {
-    let mut path1 = path.to_path_buf();
-    path1.set_extension("gc_hist.tsv");
+    ref_dist: &[(GcHistKey, GcHistVal)],
+    prior: f64,
+    reps: usize,
+    seed: u64,
+) -> Option<(f64, f64)> {
+    if reps < 2 {
+        return None;
+    }
+    // The order of the counts is arbitrary (they come from a hash), so sort them to make the
+    // resampling reproducible for a given seed
+    let mut cts = cts.to_vec();
+    cts.sort_unstable_by_key(|(k, _)| *k);
 
-    let mut wrt = CompressIo::new()
-        .path(&path1)
-        .bufwriter()
-        .with_context(|| "Could not open output gc distribution file")?;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let q = GcDensity::with_prior(ref_dist, prior);
+    let mut kl: Vec<_> = (0..reps)
+        .map(|_| GcDensity::with_prior(&resample(&cts, &mut rng), prior).kl_distance(&q))
+        .collect();
+    kl.sort_unstable_by(|a, b| a.total_cmp(b));
+    let m = (reps - 1) as f64;
+    Some((
+        kl[(0.025 * m).floor() as usize],
+        kl[(0.975 * m).ceil() as usize],
+    ))
+}
+
+/// Approximate 95% confidence intervals for the mean gc and the KL distance from the
+/// reference.  The interval for the mean gc is from the delta method standard error, and
+/// that for the KL distance is a percentile bootstrap interval from resampling the reads
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub struct ConfidenceIntervals {
+    mean_gc: Option<(f64, f64)>,
+    kl_distance: Option<(f64, f64)>,
+}
+
+impl ConfidenceIntervals {
+    /// Intervals for dataset with gc counts cts, using reps bootstrap replicates (with random
+    /// seed seed) for the KL distance.  The KL distance interval is None if there is no
+    /// reference
+    pub fn make(
+        cts: &[(GcHistKey, GcHistVal)],
+        ref_dist: Option<&[(GcHistKey, GcHistVal)]>,
+        prior: f64,
+        reps: usize,
+        seed: u64,
+    ) -> Self {
+        let mean_gc = mean_gc_se(cts).map(|se| {
+            let m = mean_gc(cts);
+            ((m - Z_95 * se).max(0.0), (m + Z_95 * se).min(1.0))
+        });
+        let kl_distance = ref_dist.and_then(|r| kl_distance_ci(cts, r, prior, reps, seed));
+        Self {
+            mean_gc,
+            kl_distance,
+        }
+    }
 
-    let mut lnp = Vec::with_capacity(GC_HIST_BINS);
-    let mut tmp = Vec::with_capacity(GC_HIST_BINS);
+    pub fn mean_gc(&self) -> Option<(f64, f64)> {
+        self.mean_gc
+    }
 
-    let bin_width = 1.0 / (GC_HIST_BINS as f64);
+    pub fn kl_distance(&self) -> Option<(f64, f64)> {
+        self.kl_distance
+    }
+}
 
-    for i in 0..GC_HIST_BINS {
-        let x = bin_width * (0.5 + (i as f64));
-        lnp.push((x, x.ln(), (1.0 - x).ln()))
-    }
-
-    let contrib = |c: &[(GcHistKey, GcHistVal)], tmp: &mut Vec<f64>, h: &mut [f64]| {
-        tmp.clear();
-        let mut t = 0.0;
-        for (b, a, v) in c.iter().map(|(key, v)| {
-            let (r, s) = key.counts();
-            (r, s, v)
-        }) {
-            let x = v.count();
-            t += x;
-            let konst = v.beta_a_b();
-            tmp.clear();
-            let mut z = 0.0;
-            for (_, lnp, lnp1) in lnp.iter() {
-                let p = (lnp * a + lnp1 * b - konst).exp();
-                z += p;
-                tmp.push(p);
+impl fmt::Display for ConfidenceIntervals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, x) in [self.mean_gc, self.kl_distance].iter().enumerate() {
+            if i > 0 {
+                write!(f, "\t")?
             }
-            for (p, q) in tmp.iter().zip(h.iter_mut()) {
-                *q += x * p / z
+            match x {
+                Some((lo, hi)) => write!(f, "{lo:.5}\t{hi:.5}")?,
+                None => write!(f, "NA\tNA")?,
             }
         }
-        t
-    };
+        Ok(())
+    }
+}
 
-    let mut hist = vec![0.0; GC_HIST_BINS];
-    let t = contrib(cts, &mut tmp, &mut hist);
+/// Divergence measures between a sample and reference gc distribution
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Divergence {
+    #[default]
+    Kl,
+    Js,
+    Wasserstein,
+    Hellinger,
+    Emd,
+}
 
-    let rhist = ref_cts.map(|r| {
-        let mut h = vec![0.0; GC_HIST_BINS];
-        let t = contrib(r, &mut tmp, &mut h);
-        (h, t)
-    });
+impl Divergence {
+    /// Column name in TSV output
+    pub fn column(&self) -> &'static str {
+        match self {
+            Self::Kl => "KL-distance",
+            Self::Js => "JS-divergence",
+            Self::Wasserstein => "Wasserstein-distance",
+            Self::Hellinger => "Hellinger-distance",
+            Self::Emd => "EMD-gc-pct",
+        }
+    }
+
+    /// Key in JSON output
+    pub fn key(&self) -> &'static str {
+        match self {
+            Self::Kl => "kl_distance",
+            Self::Js => "js_divergence",
+            Self::Wasserstein => "wasserstein_distance",
+            Self::Hellinger => "hellinger_distance",
+            Self::Emd => "emd_gc_pct",
+        }
+    }
+}
+
+impl ValueEnum for Divergence {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Self::Kl,
+            Self::Js,
+            Self::Wasserstein,
+            Self::Hellinger,
+            Self::Emd,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Kl => Some(PossibleValue::new("kl")),
+            Self::Js => Some(PossibleValue::new("js")),
+            Self::Wasserstein => Some(PossibleValue::new("wasserstein")),
+            Self::Hellinger => Some(PossibleValue::new("hellinger")),
+            Self::Emd => Some(PossibleValue::new("emd")),
+        }
+    }
+}
+
+// p * ln(p / q), taking the value for p = 0 as 0
+fn kl_term(p: f64, q: f64) -> f64 {
+    if p > 0.0 {
+        p * (p / q).ln()
+    } else {
+        0.0
+    }
+}
+
+// Jensen-Shannon divergence (natural log, so bounded above by ln 2).  Unlike the KL
+// distance this remains finite where either density is close to zero
+fn js_divergence(p: &GcDensity, q: &GcDensity) -> f64 {
+    gauss_legendre_64(
+        |x| {
+            let (a, b) = (p.density(x), q.density(x));
+            let m = 0.5 * (a + b);
+            0.5 * (kl_term(a, m) + kl_term(b, m))
+        },
+        0.0,
+        1.0,
+    )
+}
+
+// 1-Wasserstein (earth mover's) distance, the integral of the absolute difference of the cdfs
+fn wasserstein_distance(p: &GcDensity, q: &GcDensity) -> f64 {
+    gauss_legendre_64(|x| (p.cdf(x) - q.cdf(x)).abs(), 0.0, 1.0)
+}
+
+// Hellinger distance (in [0, 1])
+fn hellinger_distance(p: &GcDensity, q: &GcDensity) -> f64 {
+    let bc = gauss_legendre_64(|x| (p.density(x) * q.density(x)).sqrt(), 0.0, 1.0);
+    (1.0 - bc).max(0.0).sqrt()
+}
+
+/// Divergence of cts from ref_dist using measure div, after smoothing both distributions
+/// with a prior of `prior` pseudo-reads (see [`smoothed_kl_distance`])
+pub fn divergence(
+    div: Divergence,
+    cts: &[(GcHistKey, GcHistVal)],
+    ref_dist: &[(GcHistKey, GcHistVal)],
+    prior: f64,
+) -> f64 {
+    let p = GcDensity::with_prior(cts, prior);
+    let q = GcDensity::with_prior(ref_dist, prior);
+    match div {
+        Divergence::Kl => p.kl_distance(&q),
+        Divergence::Js => js_divergence(&p, &q),
+        Divergence::Wasserstein => wasserstein_distance(&p, &q),
+        Divergence::Hellinger => hellinger_distance(&p, &q),
+        // Earth mover's distance in gc percentage points: the mean shift in gc% needed to
+        // turn one distribution into the other
+        Divergence::Emd => 100.0 * wasserstein_distance(&p, &q),
+    }
+}
+
+/// gc density and cdf tabulated at the points of the 64 point Gauss-Legendre rule on [0, 1].
+/// Divergences between tabulated densities avoid re-evaluating the (mixture) densities, so are
+/// used where many pairs of distributions are compared
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TabulatedDensity {
+    // (weight, density, cdf) at each point
+    values: Vec<(f64, f64, f64)>,
+}
+
+impl TabulatedDensity {
+    /// Number of points at which densities are tabulated
+    pub const N_POINTS: usize = 64;
+
+    /// Tabulate the density of cts smoothed with a prior of `prior` pseudo-reads.  Returns
+    /// None if both the total count and the prior are zero
+    pub fn new(cts: &[(GcHistKey, GcHistVal)], prior: f64) -> Option<Self> {
+        if prior <= 0.0 && cts.iter().all(|(_, v)| v.count() <= 0.0) {
+            return None;
+        }
+        let p = GcDensity::with_prior(cts, prior);
+        let values = gauss_legendre_64_rule(0.0, 1.0)
+            .into_iter()
+            .map(|(x, w)| (w, p.density(x), p.cdf(x)))
+            .collect();
+        Some(Self { values })
+    }
+
+    pub fn n_points(&self) -> usize {
+        self.values.len()
+    }
+
+    fn integrate<F: Fn((f64, f64), (f64, f64)) -> f64>(&self, other: &Self, f: F) -> f64 {
+        self.values
+            .iter()
+            .zip(other.values.iter())
+            .map(|((w, a, fa), (_, b, fb))| w * f((*a, *fa), (*b, *fb)))
+            .sum()
+    }
+
+    /// Divergence of self from other using measure div.  As for [`divergence`], except that
+    /// the KL distance is symmetrized (the mean of the distances in both directions) so that
+    /// all measures are symmetric
+    pub fn divergence(&self, other: &Self, div: Divergence) -> f64 {
+        match div {
+            Divergence::Kl => {
+                0.5 * self.integrate(other, |(a, _), (b, _)| kl_term(a, b) + kl_term(b, a))
+            }
+            Divergence::Js => self.integrate(other, |(a, _), (b, _)| {
+                let m = 0.5 * (a + b);
+                0.5 * (kl_term(a, m) + kl_term(b, m))
+            }),
+            Divergence::Wasserstein => self.integrate(other, |(_, fa), (_, fb)| (fa - fb).abs()),
+            Divergence::Hellinger => {
+                let bc = self.integrate(other, |(a, _), (b, _)| (a * b).sqrt());
+                (1.0 - bc).max(0.0).sqrt()
+            }
+            Divergence::Emd => 100.0 * self.divergence(other, Divergence::Wasserstein),
+        }
+    }
+}
+
+/// Write the sample (and reference) gc densities to <p>.gc_hist.tsv (bgzip compressed if
+/// bgzip is set), returning the paths of the output files
+pub fn output_gc_hist(
+    path: &Path,
+    cts: &[(GcHistKey, GcHistVal)],
+    ref_cts: Option<&[(GcHistKey, GcHistVal)]>,
+    bgzip: bool,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let aux = AuxFile::new(path, "gc_hist.tsv", bgzip);
+    let mut wrt = aux
+        .writer()
+        .with_context(|| "Could not open output gc distribution file")?;
+
+    let hist = GcDensity::new(cts).histogram(GC_HIST_BINS);
+    let rhist = ref_cts.map(|r| GcDensity::new(r).histogram(GC_HIST_BINS));
 
     write!(wrt, "GC\tSample")?;
     if rhist.is_some() {
         write!(wrt, "\tReference")?
     }
     writeln!(wrt)?;
-    let z = GC_HIST_BINS as f64;
-    for i in 0..1000 {
-        write!(wrt, "{}\t{}", lnp[i].0, hist[i] * z / t)?;
-        if let Some((rh, t1)) = rhist.as_ref() {
-            write!(wrt, "\t{}", rh[i] * z / t1)?;
+    for i in 0..GC_HIST_BINS {
+        write!(wrt, "{}\t{}", hist[i].0, hist[i].1)?;
+        if let Some(rh) = rhist.as_ref() {
+            write!(wrt, "\t{}", rh[i].1)?;
         }
         writeln!(wrt)?
     }
 
-    Ok(())
+    aux.finish(wrt)
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn make_counts(v: &[(&str, u64)]) -> Vec<(GcHistKey, GcHistVal)> {
+        v.iter()
+            .map(|(s, c)| {
+                let k = s.parse::<GcHistKey>().expect("Bad key");
+                (k, GcHistVal::make(&k, *c))
+            })
+            .collect()
+    }
+
+    // Digamma function for positive integer arguments
+    #[allow(dead_code)]
+    fn digamma_int(n: u32) -> f64 {
+        const EULER_GAMMA: f64 = 0.577_215_664_901_532_9;
+        (1..n).map(|k| 1.0 / k as f64).sum::<f64>() - EULER_GAMMA
+    }
+
+    // Closed form KL distance between Beta(a1, b1) and Beta(a2, b2) for integer parameters
+    #[allow(dead_code)]
+    fn beta_kl(a1: u32, b1: u32, a2: u32, b2: u32) -> f64 {
+        let f = |x: u32| x as f64;
+        lbeta(f(a2), f(b2)) - lbeta(f(a1), f(b1))
+            + (f(a1) - f(a2)) * digamma_int(a1)
+            + (f(b1) - f(b2)) * digamma_int(b1)
+            + (f(a2) - f(a1) + f(b2) - f(b1)) * digamma_int(a1 + b1)
+    }
+
+    #[test]
+    fn lbeta_test() {
+        assert!(lbeta(1.0, 1.0).abs() < 1.0e-14);
+        assert!((lbeta(2.0, 3.0) - (1.0f64 / 12.0).ln()).abs() < 1.0e-14);
+        assert!((lbeta(0.5, 0.5) - std::f64::consts::PI.ln()).abs() < 1.0e-14);
+        assert!((lbeta(3.5, 7.25) - lbeta(7.25, 3.5)).abs() < 1.0e-14);
+        // B(a, b) = B(a + 1, b) + B(a, b + 1)
+        let (a, b) = (4.5f64, 12.0f64);
+        let s = lbeta(a + 1.0, b).exp() + lbeta(a, b + 1.0).exp();
+        assert!((lbeta(a, b) - s.ln()).abs() < 1.0e-12);
+    }
+
+    #[test]
+    fn mean_gc_test() {
+        // Keys are AT:GC counts
+        let cts = make_counts(&[("1:3", 2), ("3:1", 1)]);
+        assert!((mean_gc(&cts) - 7.0 / 12.0).abs() < 1.0e-14);
+        let cts = make_counts(&[("10:0", 5)]);
+        assert_eq!(mean_gc(&cts), 0.0);
+    }
+
+    #[test]
+    fn kl_distance_test() {
+        // A single key (a, b) gives a Beta(b + 1, a + 1) density
+        let p = make_counts(&[("1:4", 3)]);
+        let q = make_counts(&[("3:2", 7)]);
+        assert!(kl_distance(&p, &p).abs() < 1.0e-12);
+        // Accuracy of the quadrature is limited by the log singularities at the boundaries
+        assert!((kl_distance(&p, &q) - beta_kl(5, 2, 3, 4)).abs() < 1.0e-5);
+        assert!((kl_distance(&q, &p) - beta_kl(3, 4, 5, 2)).abs() < 1.0e-5);
+
+        // Mixture with itself scaled is unchanged
+        let p = make_counts(&[("1:4", 3), ("6:2", 1)]);
+        let p2 = make_counts(&[("1:4", 30), ("6:2", 10)]);
+        assert!(kl_distance(&p, &p2).abs() < 1.0e-12);
+        assert!(kl_distance(&p, &q) > 0.0);
+
+        // Smoothing with a large prior pulls both distributions towards uniform
+        let kl = kl_distance(&p, &q);
+        let kl_s = smoothed_kl_distance(&p, &q, 1.0e6);
+        assert!(kl_s < 1.0e-6 * kl);
+    }
+
+    #[test]
+    fn confidence_interval_test() {
+        // No variation between reads gives a zero width interval for the mean gc
+        let cts = make_counts(&[("6:4", 50)]);
+        let ci = ConfidenceIntervals::make(&cts, None, 0.0, 100, 0);
+        let (lo, hi) = ci.mean_gc().unwrap();
+        assert!((lo - 0.4).abs() < 1.0e-12 && (hi - 0.4).abs() < 1.0e-12);
+        assert!(ci.kl_distance().is_none());
+
+        let p = make_counts(&[("6:4", 50), ("3:7", 20), ("8:2", 30)]);
+        let q = make_counts(&[("5:5", 100)]);
+        let ci = ConfidenceIntervals::make(&p, Some(&q), 1.0, 200, 1);
+        let (lo, hi) = ci.mean_gc().unwrap();
+        let m = mean_gc(&p);
+        assert!(lo < m && m < hi);
+        let (lo, hi) = ci.kl_distance().unwrap();
+        assert!(lo > 0.0 && lo < hi);
+        // Resampling is reproducible for a given seed
+        let ci1 = ConfidenceIntervals::make(&p, Some(&q), 1.0, 200, 1);
+        assert_eq!(ci1.kl_distance(), ci.kl_distance());
+    }
+
+    #[test]
+    fn divergence_test() {
+        let p = make_counts(&[("0:1", 2)]);
+        let q = make_counts(&[("1:0", 3)]);
+        let u = make_counts(&[("0:0", 1)]);
+        for div in Divergence::value_variants() {
+            assert!(divergence(*div, &p, &p, 0.0).abs() < 1.0e-6);
+        }
+        // Hellinger distance between Beta(2, 1) and Beta(1, 2) is sqrt(1 - pi/4)
+        let h = (1.0 - std::f64::consts::FRAC_PI_4).sqrt();
+        assert!((divergence(Divergence::Hellinger, &p, &q, 0.0) - h).abs() < 1.0e-4);
+        // Wasserstein distance between Beta(2, 1) and U(0, 1) is 1/6
+        let w = divergence(Divergence::Wasserstein, &p, &u, 0.0);
+        assert!((w - 1.0 / 6.0).abs() < 1.0e-10);
+        let emd = divergence(Divergence::Emd, &p, &u, 0.0);
+        assert!((emd - 100.0 / 6.0).abs() < 1.0e-8);
+        // JS divergence is symmetric and bounded by ln 2
+        let js = divergence(Divergence::Js, &p, &q, 0.0);
+        assert!((js - divergence(Divergence::Js, &q, &p, 0.0)).abs() < 1.0e-12);
+        assert!(js > 0.0 && js < 2.0f64.ln());
+    }
+
+    #[test]
+    fn tabulated_divergence_test() {
+        let p = make_counts(&[("6:4", 50), ("3:7", 20), ("8:2", 30)]);
+        let q = make_counts(&[("5:5", 100)]);
+        let (tp, tq) = (
+            TabulatedDensity::new(&p, 1.0).unwrap(),
+            TabulatedDensity::new(&q, 1.0).unwrap(),
+        );
+        for div in Divergence::value_variants()
+            .iter()
+            .filter(|d| **d != Divergence::Kl)
+        {
+            let x = divergence(*div, &p, &q, 1.0);
+            assert!((tp.divergence(&tq, *div) - x).abs() < 1.0e-10);
+            assert!((tq.divergence(&tp, *div) - x).abs() < 1.0e-10);
+        }
+        let kl = 0.5
+            * (divergence(Divergence::Kl, &p, &q, 1.0) + divergence(Divergence::Kl, &q, &p, 1.0));
+        assert!((tp.divergence(&tq, Divergence::Kl) - kl).abs() < 1.0e-10);
+        assert!(TabulatedDensity::new(&[], 0.0).is_none());
+    }
 }
@@ -0,0 +1,164 @@
+use std::io::{BufRead, Write};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+use serde_json::{json, Map, Value};
+
+use crate::cli::BuildRefConfig;
+
+/// Counts of windows by GC count (index) for one read length
+struct WindowCounts {
+    read_length: usize,
+    regular: Vec<u64>,
+    bisulfite: Vec<u64>,
+}
+
+impl WindowCounts {
+    fn new(read_length: usize) -> Self {
+        Self {
+            read_length,
+            regular: vec![0; read_length + 1],
+            bisulfite: vec![0; read_length + 1],
+        }
+    }
+
+    // Add all windows of length read_length from seq that do not contain non ACGT bases.
+    // For bisulfite counts, both converted strands are counted
+    fn add_contig(&mut self, seq: &[u8], bisulfite: bool) {
+        let rl = self.read_length;
+        if seq.len() < rl {
+            return;
+        }
+        // GC status of each base for the regular and the two bisulfite converted strands.
+        // After conversion, C is only GC on the top strand in a CpG context, and similarly
+        // for G on the bottom strand
+        let class = |i: usize| -> Option<(u32, u32, u32)> {
+            match seq[i] {
+                b'A' | b'T' => Some((0, 0, 0)),
+                b'C' => Some((1, (seq.get(i + 1) == Some(&b'G')) as u32, 1)),
+                b'G' => Some((1, 1, (i > 0 && seq[i - 1] == b'C') as u32)),
+                _ => None,
+            }
+        };
+        let classes: Vec<_> = (0..seq.len()).map(class).collect();
+
+        let (mut n, mut gc, mut top, mut bot) = (0, 0, 0, 0);
+        for (i, c) in classes.iter().enumerate() {
+            match c {
+                Some((x, y, z)) => {
+                    gc += x;
+                    top += y;
+                    bot += z
+                }
+                None => n += 1,
+            }
+            if i >= rl {
+                match classes[i - rl] {
+                    Some((x, y, z)) => {
+                        gc -= x;
+                        top -= y;
+                        bot -= z
+                    }
+                    None => n -= 1,
+                }
+            }
+            if i + 1 >= rl && n == 0 {
+                self.regular[gc as usize] += 1;
+                if bisulfite {
+                    self.bisulfite[top as usize] += 1;
+                    self.bisulfite[bot as usize] += 1;
+                }
+            }
+        }
+    }
+
+    // Counts in reference JSON format, with keys AT:GC
+    fn json_counts(v: &[u64], rl: usize) -> Value {
+        let m: Map<String, Value> = v
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c > 0)
+            .map(|(gc, c)| (format!("{}:{gc}", rl - gc), json!(c)))
+            .collect();
+        Value::Object(m)
+    }
+
+    fn to_json(&self, bisulfite: bool) -> Value {
+        let mut m = Map::new();
+        m.insert(
+            "counts".to_owned(),
+            Self::json_counts(&self.regular, self.read_length),
+        );
+        if bisulfite {
+            m.insert(
+                "bisulfite_counts".to_owned(),
+                Self::json_counts(&self.bisulfite, self.read_length),
+            );
+        }
+        Value::Object(m)
+    }
+}
+
+/// Build reference gc distributions for the requested read lengths from a FASTA file
+pub fn build_ref(cfg: &BuildRefConfig) -> anyhow::Result<()> {
+    let p = cfg.fasta();
+    let rdr = CompressIo::new()
+        .path(p)
+        .bufreader()
+        .with_context(|| format!("Could not open FASTA file {} for input", p.display()))?;
+
+    let mut counts: Vec<_> = cfg
+        .read_lengths()
+        .iter()
+        .map(|l| WindowCounts::new(*l as usize))
+        .collect();
+
+    let mut process_contig = |name: Option<&str>, seq: &mut Vec<u8>| {
+        if let Some(name) = name {
+            debug!("Processing contig {name} ({} bases)", seq.len());
+            for c in counts.iter_mut() {
+                c.add_contig(seq, cfg.bisulfite())
+            }
+        }
+        seq.clear()
+    };
+
+    let mut name: Option<String> = None;
+    let mut seq = Vec::new();
+    for (ix, line) in rdr.lines().enumerate() {
+        let line = line.with_context(|| format!("Error reading from {}", p.display()))?;
+        if let Some(s) = line.strip_prefix('>') {
+            process_contig(name.as_deref(), &mut seq);
+            name = Some(s.split_ascii_whitespace().next().unwrap_or("").to_owned());
+        } else if name.is_some() {
+            seq.extend(line.trim_end().bytes().map(|c| c.to_ascii_uppercase()))
+        } else if !line.trim().is_empty() {
+            return Err(anyhow!(
+                "{}: sequence before first header at line {}",
+                p.display(),
+                ix + 1
+            ));
+        }
+    }
+    process_contig(name.as_deref(), &mut seq);
+
+    let mut rlsc = Map::new();
+    for c in counts.iter() {
+        if c.regular.iter().all(|x| *x == 0) {
+            warn!("No windows found for read length {}", c.read_length)
+        }
+        rlsc.insert(c.read_length.to_string(), c.to_json(cfg.bisulfite()));
+    }
+    let v = json!({
+        "read_lengths": cfg.read_lengths(),
+        "read_length_specific_counts": rlsc,
+    });
+
+    let mut wrt = CompressIo::new()
+        .opt_path(cfg.output_file())
+        .bufwriter()
+        .with_context(|| "Could not open output file")?;
+    serde_json::to_writer(&mut wrt, &v).with_context(|| "Error writing reference JSON")?;
+    writeln!(wrt)?;
+    Ok(())
+}
@@ -0,0 +1,716 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+use clap::{builder::PossibleValue, ValueEnum};
+use compress_io::{compress::CompressIo, compress_type::CompressType};
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+use crate::{
+    bam::AlignedReads,
+    betabin::Divergence,
+    cli::Config,
+    gof::GofTest,
+    process::DataResults,
+    read::{read_group_path, DataSet, TempDataSet},
+    simple_regression::DriftModel,
+    temp_dir::{atomic_write, remove_stale_files, TempDir},
+};
+
+/// Version of the analysis metrics.  Increment whenever a change is made that
+/// alters the results of the analysis so that previously cached results are invalidated.
+pub const METRICS_VERSION: u32 = 20;
+
+/// Hash of the contents of a file (as stored on disk, so compressed files are not decompressed)
+pub fn hash_file<P: AsRef<Path>>(p: P) -> anyhow::Result<u128> {
+    let p = p.as_ref();
+    let mut f =
+        fs::File::open(p).with_context(|| format!("Could not open {} for hashing", p.display()))?;
+    let mut hasher = Xxh3::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let n = f
+            .read(&mut buf)
+            .with_context(|| format!("Error reading from {}", p.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n])
+    }
+    Ok(hasher.digest128())
+}
+
+/// Key identifying the analysis of one input file.  All components must match
+/// for a cached result to be used.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheKey {
+    crate_version: String,
+    metrics_version: u32,
+    content_hash: u128,
+    reference_id: Option<u128>,
+    ref_panel: Vec<(String, u128)>,
+    kmcv_id: Option<u128>,
+    library_type: Option<String>,
+    regression: bool,
+    robust_regression: bool,
+    drift_model: Option<DriftModel>,
+    skew: bool,
+    multiqc: bool,
+    html_report: bool,
+    // Stored as bits as f64 does not implement Eq
+    kl_prior: u64,
+    divergences: Vec<Divergence>,
+    confidence_intervals: Option<(usize, Option<u64>)>,
+    #[serde(default)]
+    fingerprint: bool,
+    #[serde(default)]
+    contamination_id: Option<u128>,
+    #[serde(default)]
+    gof_test: Option<GofTest>,
+    #[serde(default)]
+    qual_bands: bool,
+    #[serde(default)]
+    aligned_reads: AlignedReads,
+    #[serde(default)]
+    read_ends: bool,
+    #[serde(default)]
+    cfdna: bool,
+    #[serde(default)]
+    target_gc_id: Option<u128>,
+    // Stored as bits as f64 does not implement Eq
+    #[serde(default)]
+    expected_mapped_fraction: Option<u64>,
+    #[serde(default)]
+    pairwise: bool,
+    // Options changing which auxiliary files are written (all unset with --no-aux).  The
+    // location of the files is checked when an entry is used
+    #[serde(default)]
+    aux_output: bool,
+    #[serde(default)]
+    target_coverage: bool,
+    #[serde(default)]
+    base_dist_ci: bool,
+    #[serde(default)]
+    base_dist_max_rows: Option<usize>,
+    #[serde(default)]
+    bgzip_aux: bool,
+}
+
+// Hash of a cache key, used in entry names so that entries for the same input file with
+// different keys are kept separately
+fn key_hash<K: Serialize>(key: &K) -> u64 {
+    xxh3_64(&serde_json::to_vec(key).expect("Error serializing cache key"))
+}
+
+// Hash of the contents of input file p, using the file index of the cache if there is one
+fn content_hash(cfg: &Config, p: &Path) -> anyhow::Result<u128> {
+    match cfg.cache() {
+        Some(c) => c.content_hash(p),
+        None => hash_file(p),
+    }
+}
+
+impl CacheKey {
+    pub fn make(cfg: &Config, p: &Path) -> anyhow::Result<Self> {
+        let aux = cfg.aux_output();
+        Ok(Self {
+            crate_version: env!("CARGO_PKG_VERSION").to_owned(),
+            metrics_version: METRICS_VERSION,
+            content_hash: content_hash(cfg, p)?,
+            reference_id: cfg.reference_id_for(p),
+            ref_panel: cfg.ref_panel_ids(),
+            kmcv_id: cfg.kmcv_id_for(p),
+            library_type: cfg
+                .manifest()
+                .and_then(|m| m.library_type(p))
+                .map(|s| s.to_owned()),
+            regression: cfg.regression(),
+            robust_regression: cfg.robust_regression(),
+            drift_model: cfg.drift_model(),
+            skew: cfg.skew(),
+            multiqc: cfg.multiqc(),
+            html_report: cfg.html_report().is_some(),
+            kl_prior: cfg.kl_prior().to_bits(),
+            divergences: cfg.divergences().to_vec(),
+            confidence_intervals: cfg.confidence_intervals().map(|n| (n, cfg.seed())),
+            fingerprint: cfg.fingerprinting(),
+            contamination_id: cfg.contamination_id(),
+            gof_test: cfg.gof_test(),
+            qual_bands: cfg.qual_bands(),
+            aligned_reads: cfg.aligned_reads(),
+            read_ends: cfg.read_ends(),
+            cfdna: cfg.cfdna(),
+            target_gc_id: cfg.target_gc_id(),
+            expected_mapped_fraction: cfg.expected_mapped_fraction().map(f64::to_bits),
+            pairwise: cfg.pairwise_kl().is_some(),
+            aux_output: aux,
+            target_coverage: aux && cfg.target_coverage(),
+            base_dist_ci: aux && cfg.base_dist_ci(),
+            base_dist_max_rows: cfg.base_dist_max_rows().filter(|_| aux),
+            bgzip_aux: aux && cfg.bgzip_aux(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct CacheEntry {
+    key: CacheKey,
+    // Dataset and results for each read group of the input file
+    datasets: Vec<(DataSet, DataResults)>,
+}
+
+#[derive(Serialize)]
+struct CacheEntryRef<'a> {
+    key: &'a CacheKey,
+    datasets: &'a [(DataSet, DataResults)],
+}
+
+/// Key identifying the datasets counted from an input file (FASTQ, BAM or CRAM).  Counts are
+/// cached so that merged datasets can be re-merged without counting unchanged inputs again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CountsKey {
+    crate_version: String,
+    content_hash: u128,
+    aligned_reads: AlignedReads,
+}
+
+impl CountsKey {
+    pub fn make(cfg: &Config, p: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            crate_version: env!("CARGO_PKG_VERSION").to_owned(),
+            content_hash: content_hash(cfg, p)?,
+            aligned_reads: cfg.aligned_reads(),
+        })
+    }
+}
+
+// Counted datasets are stored in the fastq_gc JSON layout, one per read group
+#[derive(Deserialize)]
+struct CountsEntry {
+    key: CountsKey,
+    datasets: Vec<TempDataSet>,
+}
+
+#[derive(Serialize)]
+struct CountsEntryRef<'a> {
+    key: &'a CountsKey,
+    datasets: Vec<TempDataSet>,
+}
+
+/// Name of the index of input files in the cache directory
+const INDEX_NAME: &str = "file_index.json";
+
+// Size and modification time (in ns from the epoch) of an input file when its contents were
+// hashed.  The hash is reused while the size and modification time are unchanged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    size: u64,
+    mtime: u128,
+    content_hash: u128,
+}
+
+// Size and modification time of file p
+fn file_stamp(p: &Path) -> Option<(u64, u128)> {
+    let m = fs::metadata(p).ok()?;
+    let t = m.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((m.len(), t.as_nanos()))
+}
+
+// Input files (by canonical path) hashed in this or earlier runs
+#[derive(Default)]
+struct FileIndex {
+    files: BTreeMap<PathBuf, FileStamp>,
+    changed: bool,
+}
+
+impl FileIndex {
+    fn read(p: &Path) -> Self {
+        let files = fs::read(p)
+            .ok()
+            .and_then(|v| match serde_json::from_slice(&v) {
+                Ok(f) => Some(f),
+                Err(e) => {
+                    warn!("Ignoring invalid cache file index {}: {e}", p.display());
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            files,
+            changed: false,
+        }
+    }
+}
+
+/// Compression of cache entries.  Entries are read whatever the compression used to write
+/// them, so the compression can be changed for an existing cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl ValueEnum for CacheCompression {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::None, Self::Gzip, Self::Zstd]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::None => Some(PossibleValue::new("none")),
+            Self::Gzip => Some(PossibleValue::new("gzip")),
+            Self::Zstd => Some(PossibleValue::new("zstd")),
+        }
+    }
+}
+
+/// Name of the zstd dictionary in the cache directory
+const DICT_NAME: &str = "cache.dict";
+
+// Dictionary training state.  Until a dictionary is available, the uncompressed entries
+// written are kept (in the temporary directory) as training samples
+#[derive(Default)]
+struct DictState {
+    dict: Option<PathBuf>,
+    samples: Vec<PathBuf>,
+    failed: bool,
+}
+
+/// On disk cache of analysis results, with one JSON file per input file and set of options
+/// affecting the results, named from the content hash of the input file and a hash of the
+/// cache key (so runs with different options do not overwrite each other's entries).
+///
+/// Input files are identified by their content hash, which is recorded in a file index (with
+/// the size and modification time of the file) so that files unchanged since an earlier run are
+/// not read to find it.  On a re-run only new or changed inputs are analyzed.  When merging,
+/// the results of individual inputs can not be used, so instead the datasets counted from
+/// FASTQ, BAM and CRAM inputs are cached to be merged again without being recounted
+///
+/// With zstd compression, entries can be compressed with a dictionary trained (using the
+/// external zstd program) on the first entries written, which greatly reduces the size of
+/// the entries when the input files are similar (e.g., from lanes of the same run).  The
+/// dictionary is kept in the cache directory and used for later runs.  Entries compressed
+/// with the dictionary are stored with a .dict.zst extension as they can not be read without
+/// it
+pub struct ResultsCache {
+    dir: PathBuf,
+    compression: CacheCompression,
+    // Number of entries to train the dictionary on (if a dictionary should be trained)
+    dict_samples: Option<usize>,
+    dict: Mutex<DictState>,
+    index: Mutex<FileIndex>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    temp: TempDir,
+}
+
+// Check that the external zstd program (used for zstd compression of entries and for
+// dictionaries) can be run, so that a missing program is reported when the cache is opened
+// rather than when entries are written
+fn check_zstd() -> anyhow::Result<()> {
+    let status = Command::new("zstd")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .with_context(|| "Could not run zstd, which is required for --cache-compression zstd")?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("zstd --version failed ({status})"))
+    }
+}
+
+// Compress data with the zstd dictionary dict, writing to p
+fn zstd_dict_compress(data: &[u8], dict: &Path, p: &Path) -> anyhow::Result<()> {
+    let mut child = Command::new("zstd")
+        .args(["-q", "-f", "-D"])
+        .arg(dict)
+        .arg("-o")
+        .arg(p)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| "Could not run zstd")?;
+    child
+        .stdin
+        .take()
+        .expect("Missing zstd stdin")
+        .write_all(data)
+        .with_context(|| "Error sending data to zstd")?;
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "zstd failed to compress {} ({status})",
+            p.display()
+        ))
+    }
+}
+
+// Decompress file p compressed with the zstd dictionary dict
+fn zstd_dict_decompress(p: &Path, dict: &Path) -> anyhow::Result<Vec<u8>> {
+    let out = Command::new("zstd")
+        .args(["-q", "-d", "-c", "-D"])
+        .arg(dict)
+        .arg(p)
+        .output()
+        .with_context(|| "Could not run zstd")?;
+    if out.status.success() {
+        Ok(out.stdout)
+    } else {
+        Err(anyhow!(
+            "zstd failed to decompress {} ({})",
+            p.display(),
+            out.status
+        ))
+    }
+}
+
+// Train a zstd dictionary on the sample files, writing it to p
+fn zstd_train(samples: &[PathBuf], p: &Path) -> anyhow::Result<()> {
+    atomic_write(p, |tmp| {
+        let status = Command::new("zstd")
+            .args(["-q", "-f", "--train"])
+            .args(samples)
+            .arg("-o")
+            .arg(tmp)
+            .status()
+            .with_context(|| "Could not run zstd")?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(anyhow!("zstd dictionary training failed ({status})"))
+        }
+    })
+}
+
+impl ResultsCache {
+    /// Cache in directory dir, with entries compressed using compression.  If dict_samples
+    /// is set, compression is zstd and the cache directory has no dictionary, a dictionary is
+    /// trained on the first dict_samples entries written.  Fails if compression is zstd and
+    /// the zstd program can not be run
+    pub fn new<P: AsRef<Path>>(
+        dir: P,
+        compression: CacheCompression,
+        dict_samples: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create cache directory {}", dir.display()))?;
+        // Partial entries left by interrupted runs
+        remove_stale_files(&dir);
+        let dict_path = dir.join(DICT_NAME);
+        let dict = DictState {
+            dict: (compression == CacheCompression::Zstd && dict_path.exists()).then(|| {
+                debug!("Using cache dictionary {}", dict_path.display());
+                dict_path
+            }),
+            ..Default::default()
+        };
+        let dict_samples = dict_samples.filter(|_| compression == CacheCompression::Zstd);
+        if compression == CacheCompression::Zstd {
+            check_zstd()?
+        }
+        let index = FileIndex::read(&dir.join(INDEX_NAME));
+        let temp = TempDir::new(Some(&dir));
+        Ok(Self {
+            dir,
+            compression,
+            dict_samples,
+            dict: Mutex::new(dict),
+            index: Mutex::new(index),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            temp,
+        })
+    }
+
+    /// Hash of the contents of input file p, taken from the file index if the size and
+    /// modification time of p are unchanged since it was last hashed
+    pub fn content_hash(&self, p: &Path) -> anyhow::Result<u128> {
+        let path = fs::canonicalize(p)
+            .with_context(|| format!("Could not find input file {}", p.display()))?;
+        // Taken before hashing, so a file changed while being hashed is hashed again next time
+        let stamp = file_stamp(&path);
+        if let Some((size, mtime)) = stamp {
+            if let Some(s) = self.index.lock().unwrap().files.get(&path) {
+                if s.size == size && s.mtime == mtime {
+                    trace!("Using indexed content hash for {}", p.display());
+                    return Ok(s.content_hash);
+                }
+            }
+        }
+        let content_hash = hash_file(&path)?;
+        if let Some((size, mtime)) = stamp {
+            let mut index = self.index.lock().unwrap();
+            index.files.insert(
+                path,
+                FileStamp {
+                    size,
+                    mtime,
+                    content_hash,
+                },
+            );
+            index.changed = true
+        }
+        Ok(content_hash)
+    }
+
+    /// Write the file index if it has changed since it was read or last written
+    pub fn write_index(&self) -> anyhow::Result<()> {
+        let mut index = self.index.lock().unwrap();
+        if index.changed {
+            let p = self.dir.join(INDEX_NAME);
+            debug!("Writing cache file index {}", p.display());
+            atomic_write(&p, |tmp| {
+                let s = serde_json::to_vec(&index.files)?;
+                fs::write(tmp, s)
+                    .with_context(|| format!("Error writing cache file index {}", tmp.display()))
+            })?;
+            index.changed = false
+        }
+        Ok(())
+    }
+
+    /// Write the file index (if changed) and log the use of the cache during the run
+    pub fn finish(&self) -> anyhow::Result<()> {
+        self.write_index()?;
+        let (hits, misses) = (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        );
+        if hits + misses > 0 {
+            info!("Results cache: {hits} input(s) found, {misses} input(s) processed")
+        }
+        Ok(())
+    }
+
+    // Record whether a lookup found a usable entry
+    fn count<T>(&self, x: Option<T>) -> Option<T> {
+        match x {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        x
+    }
+
+    fn entry_path(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(format!(
+            "{:032x}.{:016x}.json",
+            key.content_hash,
+            key_hash(key)
+        ))
+    }
+
+    fn dict_entry_path(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(format!(
+            "{:032x}.{:016x}.dict.zst",
+            key.content_hash,
+            key_hash(key)
+        ))
+    }
+
+    fn counts_path(&self, key: &CountsKey) -> PathBuf {
+        self.dir.join(format!(
+            "{:032x}.{:016x}.counts.json",
+            key.content_hash,
+            key_hash(key)
+        ))
+    }
+
+    /// Cached results for key, passed through f which adapts the results to the current
+    /// input (returning None if they can not be used)
+    pub fn get<F>(&self, key: &CacheKey, f: F) -> Option<Vec<(DataSet, DataResults)>>
+    where
+        F: FnOnce(Vec<(DataSet, DataResults)>) -> Option<Vec<(DataSet, DataResults)>>,
+    {
+        let res = self.lookup(key).and_then(f);
+        self.count(res)
+    }
+
+    fn lookup(&self, key: &CacheKey) -> Option<Vec<(DataSet, DataResults)>> {
+        let (p, dp) = (self.entry_path(key), self.dict_entry_path(key));
+        let entry = if dp.exists() {
+            let res = zstd_dict_decompress(&dp, &self.dir.join(DICT_NAME)).and_then(|v| {
+                serde_json::from_slice::<CacheEntry>(&v).map_err(anyhow::Error::from)
+            });
+            match res {
+                Ok(e) => Some(e),
+                Err(e) => {
+                    warn!("Could not read cache entry {}: {e:#}", dp.display());
+                    return None;
+                }
+            }
+        } else if p.exists() {
+            CompressIo::new()
+                .path(&p)
+                .bufreader()
+                .ok()
+                .and_then(|rdr| serde_json::from_reader::<_, CacheEntry>(rdr).ok())
+        } else {
+            return None;
+        };
+
+        match entry {
+            Some(e) if &e.key == key => Some(e.datasets),
+            Some(_) => {
+                debug!("Cache entry {} is stale", p.display());
+                None
+            }
+            None => {
+                warn!("Could not read cache entry {}", p.display());
+                None
+            }
+        }
+    }
+
+    // Dictionary to compress an entry with, collecting the entry as a training sample if a
+    // dictionary is to be trained.  The dictionary is trained once enough samples are collected
+    fn dictionary(&self, data: &[u8]) -> anyhow::Result<Option<PathBuf>> {
+        let Some(n) = self.dict_samples else {
+            return Ok(self.dict.lock().unwrap().dict.clone());
+        };
+        let mut state = self.dict.lock().unwrap();
+        if state.dict.is_none() && !state.failed {
+            let f = self.temp.file(".json")?;
+            fs::write(&f, data)
+                .with_context(|| format!("Error writing dictionary sample {}", f.display()))?;
+            state.samples.push(f);
+            if state.samples.len() >= n {
+                let p = self.dir.join(DICT_NAME);
+                info!(
+                    "Training cache dictionary on {} entries",
+                    state.samples.len()
+                );
+                match zstd_train(&state.samples, &p) {
+                    Ok(_) => state.dict = Some(p),
+                    Err(e) => {
+                        warn!("Could not train cache dictionary: {e:#}");
+                        state.failed = true
+                    }
+                }
+                for f in state.samples.drain(..) {
+                    let _ = fs::remove_file(f);
+                }
+            }
+        }
+        Ok(state.dict.clone())
+    }
+
+    pub fn put(&self, key: &CacheKey, v: &[(DataSet, DataResults)]) -> anyhow::Result<()> {
+        let (p, dp) = (self.entry_path(key), self.dict_entry_path(key));
+        let entry = CacheEntryRef { key, datasets: v };
+        let dict = match self.compression {
+            CacheCompression::Zstd => {
+                let data = serde_json::to_vec(&entry)?;
+                self.dictionary(&data)?.map(|d| (d, data))
+            }
+            _ => None,
+        };
+        // Written atomically so that concurrent readers never see partial entries.  Any entry
+        // for the file in the other format is removed so that it does not shadow the new entry
+        if let Some((dict, data)) = dict {
+            atomic_write(&dp, |tmp| zstd_dict_compress(&data, &dict, tmp))?;
+            let _ = fs::remove_file(&p);
+            return Ok(());
+        }
+        self.write_entry(&p, &entry)?;
+        let _ = fs::remove_file(&dp);
+        Ok(())
+    }
+
+    // Write entry to p (atomically) with the cache compression
+    fn write_entry<T: Serialize>(&self, p: &Path, entry: &T) -> anyhow::Result<()> {
+        atomic_write(p, |tmp| {
+            let mut c = CompressIo::new();
+            c.path(tmp);
+            match self.compression {
+                CacheCompression::None => (),
+                CacheCompression::Gzip => {
+                    c.ctype(CompressType::Gzip);
+                }
+                CacheCompression::Zstd => {
+                    c.ctype(CompressType::Zstd);
+                }
+            }
+            let wrt = c.bufwriter().with_context(|| {
+                format!("Could not open cache file {} for output", tmp.display())
+            })?;
+            serde_json::to_writer(wrt, entry)
+                .with_context(|| format!("Error writing cache file {}", tmp.display()))
+        })
+    }
+
+    /// Look up the datasets counted from input file p.  Returns None if there is no entry for
+    /// the file or if the entry was generated with different settings or software versions
+    pub fn get_counts(&self, key: &CountsKey, p: &Path) -> Option<Vec<DataSet>> {
+        let cp = self.counts_path(key);
+        let entry = cp.exists().then(|| {
+            CompressIo::new()
+                .path(&cp)
+                .bufreader()
+                .ok()
+                .and_then(|rdr| serde_json::from_reader::<_, CountsEntry>(rdr).ok())
+        })?;
+        let res = match entry {
+            Some(e) if &e.key == key => {
+                // Datasets are named as when counted from p
+                let multi = e.datasets.len() > 1;
+                e.datasets
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, t)| {
+                        DataSet::from_temp_dataset(t, p).map(|mut d| {
+                            if multi {
+                                d.set_path(read_group_path(d.path(), i))
+                            }
+                            d
+                        })
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .map_err(|e| warn!("Invalid cached counts {}: {e:#}", cp.display()))
+                    .ok()
+            }
+            Some(_) => {
+                debug!("Cached counts {} are stale", cp.display());
+                None
+            }
+            None => {
+                warn!("Could not read cached counts {}", cp.display());
+                None
+            }
+        };
+        self.count(res)
+    }
+
+    pub fn put_counts(&self, key: &CountsKey, v: &[DataSet]) -> anyhow::Result<()> {
+        // Zero N counts are dropped to keep entries small (they are read back as zero)
+        let entry = CountsEntryRef {
+            key,
+            datasets: v
+                .iter()
+                .map(|d| {
+                    let mut t = d.to_temp_dataset();
+                    t.drop_zero_n();
+                    t
+                })
+                .collect(),
+        };
+        self.write_entry(&self.counts_path(key), &entry)
+    }
+}
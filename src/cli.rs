@@ -1,106 +1,1913 @@
 use std::path::{Path, PathBuf};
+#[cfg(feature = "watch")]
+use std::time::Duration;
 
 use anyhow::Context;
+use clap::{parser::ValueSource, ArgMatches};
 use compress_io::compress::CompressIo;
+use regex::Regex;
+use xxhash_rust::xxh3::xxh3_128;
 
 mod cli_model;
+mod print_config;
 
-use crate::{kmcv::Kmcv, reference::RefDist};
+use crate::{
+    bam::AlignedReads,
+    baseline::{Baseline, BaselineFilter},
+    betabin::Divergence,
+    cache::{hash_file, CacheCompression, ResultsCache},
+    fingerprint::FingerprintDb,
+    gof::GofTest,
+    kmcv::Kmcv,
+    manifest::Manifest,
+    mapped_fraction::TargetGc,
+    merge::GroupOrder,
+    output::{OutputFormat, SortKey},
+    reference::{RefDist, ReferenceProvider, ReferenceSource},
+    report::ReportFormat,
+    schema::Schema,
+    self_reference::ReferencePool,
+    simple_regression::DriftModel,
+    source::{is_stdin, InputFormat},
+    temp_dir::TempDir,
+    thresholds::Thresholds,
+};
 pub use cli_model::MergeKey;
 
+/// Task selected from the command line
+pub enum Task {
+    Collect(Box<Config>),
+    Report(ReportConfig),
+    SelfTest(SelfTestConfig),
+    Simulate(SimulateConfig),
+    PanelCompare(PanelCompareConfig),
+    BuildRef(BuildRefConfig),
+    Concat(ConcatConfig),
+    Validate(ValidateConfig),
+    Rescore(RescoreConfig),
+    #[cfg(feature = "server")]
+    Serve(Box<Config>, ServeConfig),
+    #[cfg(feature = "watch")]
+    Watch(Box<Config>, WatchConfig),
+    /// Effective configuration to be printed (--print-config)
+    PrintConfig(String),
+}
+
+pub struct ConcatConfig {
+    input_files: Vec<PathBuf>,
+    output_file: Option<PathBuf>,
+}
+
+impl ConcatConfig {
+    pub fn input_files(&self) -> &[PathBuf] {
+        &self.input_files
+    }
+    pub fn output_file(&self) -> Option<&Path> {
+        self.output_file.as_deref()
+    }
+}
+
+pub struct ValidateConfig {
+    input_files: Vec<PathBuf>,
+    output_file: Option<PathBuf>,
+}
+
+impl ValidateConfig {
+    pub fn input_files(&self) -> &[PathBuf] {
+        &self.input_files
+    }
+    pub fn output_file(&self) -> Option<&Path> {
+        self.output_file.as_deref()
+    }
+}
+
+#[cfg(feature = "server")]
+pub struct ServeConfig {
+    listen: String,
+}
+
+#[cfg(feature = "server")]
+impl ServeConfig {
+    /// Address and port to listen on
+    pub fn listen(&self) -> &str {
+        &self.listen
+    }
+}
+
+#[cfg(feature = "watch")]
+pub struct WatchConfig {
+    dir: PathBuf,
+    existing: bool,
+    merge_interval: Duration,
+    merged: Option<Box<Config>>,
+}
+
+#[cfg(feature = "watch")]
+impl WatchConfig {
+    /// Directory watched for new input files
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    /// Analyze the input files already present when watching starts
+    pub fn existing(&self) -> bool {
+        self.existing
+    }
+    /// Interval between rewrites of the merged output
+    pub fn merge_interval(&self) -> Duration {
+        self.merge_interval
+    }
+    /// Configuration for the merged output (if merging)
+    pub fn merged(&self) -> Option<&Config> {
+        self.merged.as_deref()
+    }
+}
+
+pub struct RescoreConfig {
+    input_files: Vec<PathBuf>,
+    output_file: Option<PathBuf>,
+    reference: Option<RefDist>,
+    read_length: Option<u32>,
+    bisulfite: bool,
+    divergences: Vec<Divergence>,
+}
+
+impl RescoreConfig {
+    /// Rescoring of gc_hist files against the reference densities in the files, with no
+    /// additional divergences
+    pub(crate) fn for_aux_files(input_files: Vec<PathBuf>) -> Self {
+        Self {
+            input_files,
+            output_file: None,
+            reference: None,
+            read_length: None,
+            bisulfite: false,
+            divergences: Vec::new(),
+        }
+    }
+    pub fn input_files(&self) -> &[PathBuf] {
+        &self.input_files
+    }
+    pub fn output_file(&self) -> Option<&Path> {
+        self.output_file.as_deref()
+    }
+    /// New reference (if not set, the reference densities in the input files are used)
+    pub fn reference(&self) -> Option<&RefDist> {
+        self.reference.as_ref()
+    }
+    pub fn read_length(&self) -> Option<u32> {
+        self.read_length
+    }
+    pub fn bisulfite(&self) -> bool {
+        self.bisulfite
+    }
+    pub fn divergences(&self) -> &[Divergence] {
+        &self.divergences
+    }
+}
+
+pub struct BuildRefConfig {
+    fasta: PathBuf,
+    read_lengths: Vec<u32>,
+    bisulfite: bool,
+    output_file: Option<PathBuf>,
+}
+
+impl BuildRefConfig {
+    pub fn fasta(&self) -> &Path {
+        &self.fasta
+    }
+    pub fn read_lengths(&self) -> &[u32] {
+        &self.read_lengths
+    }
+    pub fn bisulfite(&self) -> bool {
+        self.bisulfite
+    }
+    pub fn output_file(&self) -> Option<&Path> {
+        self.output_file.as_deref()
+    }
+}
+
+pub struct PanelCompareConfig {
+    input_files: Vec<PathBuf>,
+    output_file: Option<PathBuf>,
+    old_kmcv: Kmcv,
+    new_kmcv: Kmcv,
+}
+
+impl PanelCompareConfig {
+    pub fn input_files(&self) -> &[PathBuf] {
+        &self.input_files
+    }
+    pub fn output_file(&self) -> Option<&Path> {
+        self.output_file.as_deref()
+    }
+    pub fn old_kmcv(&self) -> &Kmcv {
+        &self.old_kmcv
+    }
+    pub fn new_kmcv(&self) -> &Kmcv {
+        &self.new_kmcv
+    }
+}
+
+pub struct SimulateConfig {
+    outdir: PathBuf,
+    prefix: String,
+    n_datasets: usize,
+    read_length: u32,
+    depth: u64,
+    gc_mean: f64,
+    gc_overdispersion: f64,
+    kmcv: Option<Kmcv>,
+    mapped_fraction: f64,
+    seed: Option<u64>,
+}
+
+impl SimulateConfig {
+    pub fn outdir(&self) -> &Path {
+        &self.outdir
+    }
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+    pub fn n_datasets(&self) -> usize {
+        self.n_datasets
+    }
+    pub fn read_length(&self) -> u32 {
+        self.read_length
+    }
+    pub fn depth(&self) -> u64 {
+        self.depth
+    }
+    pub fn gc_mean(&self) -> f64 {
+        self.gc_mean
+    }
+    pub fn gc_overdispersion(&self) -> f64 {
+        self.gc_overdispersion
+    }
+    pub fn kmcv(&self) -> Option<&Kmcv> {
+        self.kmcv.as_ref()
+    }
+    pub fn mapped_fraction(&self) -> f64 {
+        self.mapped_fraction
+    }
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+}
+
+pub struct SelfTestConfig {
+    dir: Option<PathBuf>,
+    tmpdir: Option<PathBuf>,
+}
+
+impl SelfTestConfig {
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+    pub fn tmpdir(&self) -> Option<&Path> {
+        self.tmpdir.as_deref()
+    }
+}
+
+pub struct ReportConfig {
+    input_files: Vec<PathBuf>,
+    output_file: Option<PathBuf>,
+    format: ReportFormat,
+    template_dir: Option<PathBuf>,
+    fli_gc_tolerance: f64,
+}
+
+impl ReportConfig {
+    pub fn input_files(&self) -> &[PathBuf] {
+        &self.input_files
+    }
+    pub fn output_file(&self) -> Option<&Path> {
+        self.output_file.as_deref()
+    }
+    pub fn format(&self) -> ReportFormat {
+        self.format
+    }
+    pub fn template_dir(&self) -> Option<&Path> {
+        self.template_dir.as_deref()
+    }
+    /// Largest difference in gc profile allowed between datasets with the same
+    /// flowcell/lane/index
+    pub fn fli_gc_tolerance(&self) -> f64 {
+        self.fli_gc_tolerance
+    }
+}
+
 pub struct Config {
     input_files: Vec<PathBuf>,
+    input_format: InputFormat,
+    aligned_reads: AlignedReads,
     output_file: Option<PathBuf>,
-    ref_dist: Option<RefDist>,
+    output_format: OutputFormat,
+    references: Vec<(String, Box<dyn ReferenceProvider>)>,
     threads: usize,
     regression: bool,
+    skew: bool,
     kmcv: Option<Kmcv>,
+    manifest: Option<Manifest>,
     merge_key: Option<MergeKey>,
+    then_by: Option<MergeKey>,
+    keep_individual: bool,
+    status_file: Option<PathBuf>,
+    aux_output: bool,
+    quiet_stdout: bool,
+    multiqc: bool,
+    member_kl: Option<PathBuf>,
+    influence: bool,
+    thresholds: Option<Thresholds>,
+    exclude_failing: bool,
+    html_report: Option<PathBuf>,
+    kl_prior: f64,
+    skip_errors: bool,
+    target_coverage: bool,
+    divergences: Vec<Divergence>,
+    base_dist_ci: bool,
+    base_dist_max_rows: Option<usize>,
+    bgzip_aux: bool,
+    two_pass_merge: bool,
+    merge_bisulfite_strands: bool,
+    consistency_report: Option<PathBuf>,
+    split_read_ends: bool,
+    read_ends: bool,
+    cfdna: bool,
+    write_merged: Option<PathBuf>,
+    confidence_intervals: Option<usize>,
+    provenance: bool,
+    robust_regression: bool,
+    drift_model: Option<DriftModel>,
+    baseline: Option<Baseline>,
+    aux_dir: Option<PathBuf>,
+    aux_prefix: Option<String>,
+    self_reference: bool,
+    reference_pool: Option<ReferencePool>,
+    temp_dir: TempDir,
+    group_order: GroupOrder,
+    compact_merged: bool,
+    summary_file: Option<PathBuf>,
+    flag_outliers: Option<f64>,
+    pairwise_kl: Option<PathBuf>,
+    pairwise_divergence: Divergence,
+    fingerprints: Option<FingerprintDb>,
+    write_fingerprints: Option<PathBuf>,
+    contamination_ref: Option<Box<dyn ReferenceProvider>>,
+    contamination_id: Option<u128>,
+    gof_test: Option<GofTest>,
+    qual_bands: bool,
+    target_gc: Option<TargetGc>,
+    target_gc_id: Option<u128>,
+    expected_mapped_fraction: Option<f64>,
+    sort_output: Option<SortKey>,
+    schema: Schema,
+    cache: Option<ResultsCache>,
+    reference_ids: Vec<u128>,
+    kmcv_id: Option<u128>,
+    contrast: Option<(PathBuf, PathBuf)>,
+    contrast_reps: usize,
+    seed: Option<u64>,
+    service: bool,
+    stream_socket: Option<PathBuf>,
+    append_output: bool,
 }
 
 impl Config {
     pub fn input_files(&self) -> &[PathBuf] {
         &self.input_files
     }
+    pub fn input_format(&self) -> InputFormat {
+        self.input_format
+    }
+    /// Reads used from aligned (BAM/CRAM) inputs
+    pub fn aligned_reads(&self) -> AlignedReads {
+        self.aligned_reads
+    }
     pub fn output_file(&self) -> Option<&Path> {
         self.output_file.as_deref()
     }
+    pub fn output_format(&self) -> OutputFormat {
+        self.output_format
+    }
     pub fn threads(&self) -> usize {
         self.threads
     }
-    pub fn ref_dist(&self) -> Option<&RefDist> {
-        self.ref_dist.as_ref()
+    /// Primary reference (the first reference given)
+    pub fn ref_dist(&self) -> Option<&dyn ReferenceProvider> {
+        self.references.first().map(|(_, r)| r.as_ref())
+    }
+    /// Named references for best match selection.  Empty unless more than one reference
+    /// has been given
+    pub fn ref_panel(&self) -> &[(String, Box<dyn ReferenceProvider>)] {
+        if self.references.len() > 1 {
+            &self.references
+        } else {
+            &[]
+        }
     }
     pub fn regression(&self) -> bool {
         self.regression
     }
+    pub fn skew(&self) -> bool {
+        self.skew
+    }
     pub fn kmcv(&self) -> Option<&Kmcv> {
         self.kmcv.as_ref()
     }
-    pub fn merge_key(&self) -> Option<MergeKey> {
-        self.merge_key
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.manifest.as_ref()
+    }
+    /// Reference for input file p, taking into account any manifest override
+    pub fn ref_dist_for(&self, p: &Path) -> Option<&dyn ReferenceProvider> {
+        match self.manifest.as_ref().and_then(|m| m.reference(p)) {
+            Some((_, r)) => Some(r),
+            None => self.ref_dist(),
+        }
+    }
+    /// Kmer file for input file p, taking into account any manifest override
+    pub fn kmcv_for(&self, p: &Path) -> Option<&Kmcv> {
+        match self.manifest.as_ref().and_then(|m| m.kmcv(p)) {
+            Some((_, k)) => Some(k),
+            None => self.kmcv(),
+        }
+    }
+    /// Kmer coverage is calculated for at least some input files
+    pub fn has_kmcv(&self) -> bool {
+        self.kmcv.is_some()
+            || self
+                .manifest
+                .as_ref()
+                .map(|m| m.has_kmcv())
+                .unwrap_or(false)
+    }
+    pub fn merge_key(&self) -> Option<&MergeKey> {
+        self.merge_key.as_ref()
+    }
+    /// Key for the second level merge of merged datasets
+    pub fn then_by(&self) -> Option<&MergeKey> {
+        self.then_by.as_ref()
+    }
+    /// Output the members of merged datasets as well as the merged datasets
+    pub fn keep_individual(&self) -> bool {
+        self.keep_individual
+    }
+    pub fn status_file(&self) -> Option<&Path> {
+        self.status_file.as_deref()
+    }
+    pub fn aux_output(&self) -> bool {
+        self.aux_output
+    }
+    pub fn quiet_stdout(&self) -> bool {
+        self.quiet_stdout
+    }
+    pub fn multiqc(&self) -> bool {
+        self.multiqc
+    }
+    /// Output file for member KL distances of merged datasets
+    pub fn member_kl(&self) -> Option<&Path> {
+        self.member_kl.as_deref()
+    }
+    /// Leave-one-out influence analysis of merged datasets
+    pub fn influence(&self) -> bool {
+        self.influence
+    }
+    pub fn thresholds(&self) -> Option<&Thresholds> {
+        self.thresholds.as_ref()
+    }
+    /// Exclude members of merged datasets that fail QC thresholds
+    pub fn exclude_failing(&self) -> bool {
+        self.exclude_failing
+    }
+    /// Output directory for HTML report
+    pub fn html_report(&self) -> Option<&Path> {
+        self.html_report.as_deref()
+    }
+    /// Pseudo-count of smoothing prior used for KL distances
+    pub fn kl_prior(&self) -> f64 {
+        self.kl_prior
+    }
+    /// Skip input files that can not be read or analyzed instead of aborting
+    pub fn skip_errors(&self) -> bool {
+        self.skip_errors
+    }
+    /// Write per target coverage files
+    pub fn target_coverage(&self) -> bool {
+        self.target_coverage
+    }
+    /// Divergence measures reported in addition to the KL distance
+    pub fn divergences(&self) -> &[Divergence] {
+        &self.divergences
+    }
+    /// Add per cycle confidence intervals to base distribution files
+    pub fn base_dist_ci(&self) -> bool {
+        self.base_dist_ci
+    }
+    /// Maximum number of rows in base distribution files (None for one row per cycle)
+    pub fn base_dist_max_rows(&self) -> Option<usize> {
+        self.base_dist_max_rows
+    }
+    /// Write auxiliary files bgzip compressed with a .gzi index
+    pub fn bgzip_aux(&self) -> bool {
+        self.bgzip_aux
+    }
+    /// Merge datasets one group at a time to limit memory use
+    pub fn two_pass_merge(&self) -> bool {
+        self.two_pass_merge
+    }
+    /// Merge datasets with different bisulfite strands into NonStranded composites
+    pub fn merge_bisulfite_strands(&self) -> bool {
+        self.merge_bisulfite_strands
+    }
+    /// Report of the trim and min_qual values in each merge group (written in any case if
+    /// the values in a group differ)
+    pub fn consistency_report(&self) -> Option<&Path> {
+        self.consistency_report.as_deref()
+    }
+    /// Merge datasets from different read ends separately
+    pub fn split_read_ends(&self) -> bool {
+        self.split_read_ends
+    }
+    /// Output per read end gc metrics
+    pub fn read_ends(&self) -> bool {
+        self.read_ends
+    }
+    /// Output fragment metrics for cfDNA datasets
+    pub fn cfdna(&self) -> bool {
+        self.cfdna
+    }
+    /// Directory for merged datasets in fastq_gc JSON format
+    pub fn write_merged(&self) -> Option<&Path> {
+        self.write_merged.as_deref()
+    }
+    /// Number of bootstrap replicates if confidence intervals for the mean gc and KL distance
+    /// are requested
+    pub fn confidence_intervals(&self) -> Option<usize> {
+        self.confidence_intervals
+    }
+    /// Add upstream program version and command line columns to the TSV output
+    pub fn provenance(&self) -> bool {
+        self.provenance
+    }
+    /// Use Theil-Sen rather than least squares regression for drift along the read
+    pub fn robust_regression(&self) -> bool {
+        self.robust_regression
+    }
+    /// Non-linear model fitted to base composition drift along the read
+    pub fn drift_model(&self) -> Option<DriftModel> {
+        self.drift_model
+    }
+    /// Historical cohort for percentile ranks of metrics
+    pub fn baseline(&self) -> Option<&Baseline> {
+        self.baseline.as_ref()
+    }
+    /// Compare datasets against a reference pooled from the run
+    pub fn self_reference(&self) -> bool {
+        self.self_reference
+    }
+    /// Pooled reference for --self-reference (set after the first pass over the inputs)
+    pub fn reference_pool(&self) -> Option<&ReferencePool> {
+        self.reference_pool.as_ref()
+    }
+    pub fn set_reference_pool(&mut self, pool: ReferencePool) {
+        self.reference_pool = Some(pool)
+    }
+    /// Scratch directory for the run (removed when the Config is dropped)
+    pub fn temp_dir(&self) -> &TempDir {
+        &self.temp_dir
+    }
+    /// Order in which merged groups are emitted
+    pub fn group_order(&self) -> GroupOrder {
+        self.group_order
+    }
+    /// Omit all zero N counts from datasets written with --write-merged
+    pub fn compact_merged(&self) -> bool {
+        self.compact_merged
+    }
+    /// JSON file for the end of run summary
+    pub fn summary_file(&self) -> Option<&Path> {
+        self.summary_file.as_deref()
+    }
+    /// Limit on the robust z-score of metrics for datasets to be flagged as outliers (None
+    /// if outliers are not flagged)
+    pub fn flag_outliers(&self) -> Option<f64> {
+        self.flag_outliers
+    }
+    /// File for the matrix of divergences between all pairs of datasets
+    pub fn pairwise_kl(&self) -> Option<&Path> {
+        self.pairwise_kl.as_deref()
+    }
+    /// Divergence measure used for the pairwise matrix
+    pub fn pairwise_divergence(&self) -> Divergence {
+        self.pairwise_divergence
+    }
+    /// Historical fingerprints for the sample swap screen
+    pub fn fingerprints(&self) -> Option<&FingerprintDb> {
+        self.fingerprints.as_ref()
+    }
+    /// File for the fingerprints of this run
+    pub fn write_fingerprints(&self) -> Option<&Path> {
+        self.write_fingerprints.as_deref()
+    }
+    /// Fingerprints are calculated for the datasets
+    pub fn fingerprinting(&self) -> bool {
+        self.fingerprints.is_some() || self.write_fingerprints.is_some()
+    }
+    /// Contaminant reference distributions for the contamination estimate
+    pub fn contamination_ref(&self) -> Option<&dyn ReferenceProvider> {
+        self.contamination_ref.as_deref()
+    }
+    /// Id of the contaminant reference (for the results cache)
+    pub fn contamination_id(&self) -> Option<u128> {
+        self.contamination_id
+    }
+    /// Goodness of fit test of the sample gc distribution against the reference
+    pub fn gof_test(&self) -> Option<GofTest> {
+        self.gof_test
+    }
+    /// Output quality band composition and high quality fraction regression
+    pub fn qual_bands(&self) -> bool {
+        self.qual_bands
+    }
+    /// gc content of the panel targets for the gc bias factor and expected mapped fraction
+    pub fn target_gc(&self) -> Option<&TargetGc> {
+        self.target_gc.as_ref()
+    }
+    /// Id of the target gc file (for the results cache)
+    pub fn target_gc_id(&self) -> Option<u128> {
+        self.target_gc_id
+    }
+    /// Mapped fraction expected for the panel without gc bias
+    pub fn expected_mapped_fraction(&self) -> Option<f64> {
+        self.expected_mapped_fraction
+    }
+    /// Sort order of main output (None to output datasets as they are completed)
+    pub fn sort_output(&self) -> Option<SortKey> {
+        self.sort_output
+    }
+    /// Layout version of the main output
+    pub fn schema(&self) -> Schema {
+        self.schema
+    }
+    /// Directory for additional output files (the directory of the main output file)
+    pub fn output_dir(&self) -> &Path {
+        self.output_file()
+            .and_then(|p| p.parent())
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."))
+    }
+    /// Base path for the auxiliary output files of the dataset with path p.  By default the
+    /// files are written next to p; with --outdir they are written to the given directory,
+    /// named from the file name of p.  Any prefix is added to the file name
+    pub fn aux_path(&self, p: &Path) -> PathBuf {
+        if self.aux_dir.is_none() && self.aux_prefix.is_none() {
+            return p.to_owned();
+        }
+        let name = format!(
+            "{}{}",
+            self.aux_prefix.as_deref().unwrap_or(""),
+            p.file_name()
+                .map(|s| s.to_string_lossy())
+                .unwrap_or_default()
+        );
+        match self.aux_dir.as_deref() {
+            Some(dir) => dir.join(name),
+            None => p.with_file_name(name),
+        }
+    }
+    pub(crate) fn cache(&self) -> Option<&ResultsCache> {
+        self.cache.as_ref()
+    }
+    pub fn reference_id(&self) -> Option<u128> {
+        self.reference_ids.first().copied()
+    }
+    /// Names and ids of the references in the reference panel (for the results cache)
+    pub fn ref_panel_ids(&self) -> Vec<(String, u128)> {
+        self.ref_panel()
+            .iter()
+            .zip(self.reference_ids.iter())
+            .map(|((name, _), id)| (name.clone(), *id))
+            .collect()
+    }
+    pub fn kmcv_id(&self) -> Option<u128> {
+        self.kmcv_id
+    }
+    pub fn reference_id_for(&self, p: &Path) -> Option<u128> {
+        match self.manifest.as_ref().and_then(|m| m.reference(p)) {
+            Some((id, _)) => Some(id),
+            None => self.reference_id(),
+        }
+    }
+    pub fn kmcv_id_for(&self, p: &Path) -> Option<u128> {
+        match self.manifest.as_ref().and_then(|m| m.kmcv(p)) {
+            Some((id, _)) => Some(id),
+            None => self.kmcv_id,
+        }
+    }
+    pub fn contrast(&self) -> Option<&(PathBuf, PathBuf)> {
+        self.contrast.as_ref()
+    }
+    pub fn contrast_reps(&self) -> usize {
+        self.contrast_reps
+    }
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+    /// Running in service mode (input files are submitted as requests)
+    pub fn service(&self) -> bool {
+        self.service
+    }
+    /// Unix socket on which datasets are received in stream mode
+    pub fn stream_socket(&self) -> Option<&Path> {
+        self.stream_socket.as_deref()
+    }
+    /// Rows are appended to the output file and flushed as they are written
+    pub fn append_output(&self) -> bool {
+        self.append_output
+    }
+}
+
+/// Builder for programmatic construction of a [`Config`] without going through the command line
+#[derive(Default)]
+pub struct ConfigBuilder {
+    input_files: Vec<PathBuf>,
+    input_format: Option<InputFormat>,
+    aligned_reads: AlignedReads,
+    output_file: Option<PathBuf>,
+    output_format: Option<OutputFormat>,
+    references: Vec<(String, Box<dyn ReferenceProvider>)>,
+    threads: Option<usize>,
+    regression: bool,
+    skew: bool,
+    kmcv: Option<Kmcv>,
+    manifest: Option<Manifest>,
+    merge_key: Option<MergeKey>,
+    then_by: Option<MergeKey>,
+    keep_individual: bool,
+    status_file: Option<PathBuf>,
+    no_aux: bool,
+    quiet_stdout: bool,
+    multiqc: bool,
+    member_kl: Option<PathBuf>,
+    influence: bool,
+    thresholds: Option<Thresholds>,
+    exclude_failing: bool,
+    html_report: Option<PathBuf>,
+    kl_prior: f64,
+    skip_errors: bool,
+    target_coverage: bool,
+    divergences: Vec<Divergence>,
+    base_dist_ci: bool,
+    base_dist_max_rows: Option<usize>,
+    bgzip_aux: bool,
+    two_pass_merge: bool,
+    merge_bisulfite_strands: bool,
+    consistency_report: Option<PathBuf>,
+    split_read_ends: bool,
+    read_ends: bool,
+    cfdna: bool,
+    write_merged: Option<PathBuf>,
+    confidence_intervals: Option<usize>,
+    provenance: bool,
+    robust_regression: bool,
+    drift_model: Option<DriftModel>,
+    baseline: Option<Baseline>,
+    aux_dir: Option<PathBuf>,
+    aux_prefix: Option<String>,
+    self_reference: bool,
+    tmpdir: Option<PathBuf>,
+    group_order: GroupOrder,
+    compact_merged: bool,
+    summary_file: Option<PathBuf>,
+    flag_outliers: Option<f64>,
+    pairwise_kl: Option<PathBuf>,
+    pairwise_divergence: Divergence,
+    fingerprints: Option<FingerprintDb>,
+    write_fingerprints: Option<PathBuf>,
+    contamination_ref: Option<Box<dyn ReferenceProvider>>,
+    contamination_id: Option<u128>,
+    gof_test: Option<GofTest>,
+    qual_bands: bool,
+    target_gc: Option<TargetGc>,
+    target_gc_id: Option<u128>,
+    expected_mapped_fraction: Option<f64>,
+    sort_output: Option<SortKey>,
+    schema: Schema,
+    cache: Option<ResultsCache>,
+    reference_ids: Vec<u128>,
+    kmcv_id: Option<u128>,
+    contrast: Option<(PathBuf, PathBuf)>,
+    contrast_reps: Option<usize>,
+    seed: Option<u64>,
+    service: bool,
+    stream_socket: Option<PathBuf>,
+    append_output: bool,
+    no_input_check: bool,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn input_files<I: IntoIterator<Item = P>, P: AsRef<Path>>(mut self, it: I) -> Self {
+        self.input_files = it.into_iter().map(|p| p.as_ref().to_owned()).collect();
+        self
+    }
+    /// Format of input files [default: auto]
+    pub fn input_format(mut self, f: InputFormat) -> Self {
+        self.input_format = Some(f);
+        self
+    }
+    /// Reads used from aligned (BAM/CRAM) inputs [default: all]
+    pub fn aligned_reads(mut self, x: AlignedReads) -> Self {
+        self.aligned_reads = x;
+        self
+    }
+    /// Output file (None for stdout)
+    pub fn output_file<P: AsRef<Path>>(mut self, p: Option<P>) -> Self {
+        self.output_file = p.map(|p| p.as_ref().to_owned());
+        self
+    }
+    /// Format of main results output [default: tsv]
+    pub fn output_format(mut self, f: OutputFormat) -> Self {
+        self.output_format = Some(f);
+        self
+    }
+    /// Set a single reference, replacing any previously added references
+    pub fn ref_dist<R: ReferenceProvider + 'static>(mut self, r: Option<R>) -> Self {
+        self.references = r
+            .map(|r| {
+                (
+                    "reference".to_owned(),
+                    Box::new(r) as Box<dyn ReferenceProvider>,
+                )
+            })
+            .into_iter()
+            .collect();
+        self
+    }
+    /// Add a named reference.  The first reference added is the primary reference; if more
+    /// than one is added, datasets are compared against each to find the best match
+    pub fn add_reference<S: AsRef<str>, R: ReferenceProvider + 'static>(
+        mut self,
+        name: S,
+        r: R,
+    ) -> Self {
+        self.references.push((
+            name.as_ref().to_owned(),
+            Box::new(r) as Box<dyn ReferenceProvider>,
+        ));
+        self
+    }
+    /// Number of threads [default: number of cpus (up to the number of input files)]
+    pub fn threads(mut self, n: usize) -> Self {
+        self.threads = Some(n);
+        self
+    }
+    pub fn regression(mut self, x: bool) -> Self {
+        self.regression = x;
+        self
+    }
+    pub fn skew(mut self, x: bool) -> Self {
+        self.skew = x;
+        self
+    }
+    pub fn kmcv(mut self, k: Option<Kmcv>) -> Self {
+        self.kmcv = k;
+        self
+    }
+    /// Manifest with per input file overrides.  If no input files are given, the
+    /// input files are taken from the manifest
+    pub fn manifest(mut self, m: Option<Manifest>) -> Self {
+        self.manifest = m;
+        self
+    }
+    pub fn merge_key(mut self, m: Option<MergeKey>) -> Self {
+        self.merge_key = m;
+        self
+    }
+    /// Merge the merged datasets again by key m, emitting both levels
+    pub fn then_by(mut self, m: Option<MergeKey>) -> Self {
+        self.then_by = m;
+        self
+    }
+    /// When merging, also output the individual members of the merged datasets
+    pub fn keep_individual(mut self, x: bool) -> Self {
+        self.keep_individual = x;
+        self
+    }
+    pub fn status_file<P: AsRef<Path>>(mut self, p: Option<P>) -> Self {
+        self.status_file = p.map(|p| p.as_ref().to_owned());
+        self
+    }
+    /// Write auxiliary output files (per dataset gc and base distributions) [default: true]
+    pub fn aux_output(mut self, x: bool) -> Self {
+        self.no_aux = !x;
+        self
+    }
+    pub fn quiet_stdout(mut self, x: bool) -> Self {
+        self.quiet_stdout = x;
+        self
+    }
+    /// Write MultiQC custom content files
+    pub fn multiqc(mut self, x: bool) -> Self {
+        self.multiqc = x;
+        self
+    }
+    /// When merging, write KL distances of members from the merged datasets to file p
+    pub fn member_kl<P: AsRef<Path>>(mut self, p: Option<P>) -> Self {
+        self.member_kl = p.map(|p| p.as_ref().to_owned());
+        self
+    }
+    /// When merging, report leave-one-out influence of members
+    pub fn influence(mut self, x: bool) -> Self {
+        self.influence = x;
+        self
+    }
+    /// QC thresholds for pass/warn/fail status of datasets
+    pub fn thresholds(mut self, t: Option<Thresholds>) -> Self {
+        self.thresholds = t;
+        self
+    }
+    /// When merging, exclude members that fail the QC thresholds
+    pub fn exclude_failing(mut self, x: bool) -> Self {
+        self.exclude_failing = x;
+        self
+    }
+    /// Write HTML QC report to directory p
+    pub fn html_report<P: AsRef<Path>>(mut self, p: Option<P>) -> Self {
+        self.html_report = p.map(|p| p.as_ref().to_owned());
+        self
+    }
+    /// Smooth distributions with a prior of x pseudo-reads before calculating KL distances
+    pub fn kl_prior(mut self, x: f64) -> Self {
+        self.kl_prior = x;
+        self
+    }
+    /// Record and skip input files with errors rather than aborting the run
+    pub fn skip_errors(mut self, x: bool) -> Self {
+        self.skip_errors = x;
+        self
+    }
+    /// Write per target coverage file for each dataset with kmer counts
+    pub fn target_coverage(mut self, x: bool) -> Self {
+        self.target_coverage = x;
+        self
+    }
+    /// Divergence measures to report.  The KL distance is always reported
+    pub fn divergences(mut self, v: Vec<Divergence>) -> Self {
+        self.divergences = v;
+        self
+    }
+    /// Write 95% confidence intervals for the per cycle base proportions
+    pub fn base_dist_ci(mut self, x: bool) -> Self {
+        self.base_dist_ci = x;
+        self
+    }
+    /// Bin consecutive cycles in base distribution files to give at most n rows
+    pub fn base_dist_max_rows(mut self, n: Option<usize>) -> Self {
+        self.base_dist_max_rows = n;
+        self
+    }
+    /// Compress auxiliary files with bgzip and write .gzi indexes (requires bgzip in the PATH)
+    pub fn bgzip_aux(mut self, x: bool) -> Self {
+        self.bgzip_aux = x;
+        self
+    }
+    /// Group input files by merge key before merging so that only one merged dataset is held
+    /// in memory at a time.  Input files are read twice
+    pub fn two_pass_merge(mut self, x: bool) -> Self {
+        self.two_pass_merge = x;
+        self
+    }
+    /// Merge Forward and Reverse bisulfite datasets into NonStranded composites rather than
+    /// failing on the bisulfite type mismatch
+    pub fn merge_bisulfite_strands(mut self, x: bool) -> Self {
+        self.merge_bisulfite_strands = x;
+        self
+    }
+    /// Write the merge group consistency report to p
+    pub fn consistency_report<P: AsRef<Path>>(mut self, p: Option<P>) -> Self {
+        self.consistency_report = p.map(|p| p.as_ref().to_owned());
+        self
+    }
+    /// Keep datasets from different read ends in separate merge groups
+    pub fn split_read_ends(mut self, x: bool) -> Self {
+        self.split_read_ends = x;
+        self
+    }
+    /// Output gc content and gc regression for R1 and R2
+    pub fn read_ends(mut self, x: bool) -> Self {
+        self.read_ends = x;
+        self
+    }
+    /// Output fragment length proxy and fragment end gc metrics for cfDNA datasets
+    pub fn cfdna(mut self, x: bool) -> Self {
+        self.cfdna = x;
+        self
+    }
+    /// Write each merged dataset in fastq_gc JSON format to a file in dir, so that it can be
+    /// used as input to later runs
+    pub fn write_merged(mut self, dir: Option<PathBuf>) -> Self {
+        self.write_merged = dir;
+        self
+    }
+    /// Report 95% confidence intervals for the mean gc and KL distance, using reps bootstrap
+    /// replicates for the KL distance (None for no intervals)
+    pub fn confidence_intervals(mut self, reps: Option<usize>) -> Self {
+        self.confidence_intervals = reps;
+        self
+    }
+    /// Report the upstream program version and command line recorded in the input files in
+    /// the TSV output (they are always included in JSON output if present)
+    pub fn provenance(mut self, x: bool) -> Self {
+        self.provenance = x;
+        self
+    }
+    /// Use Theil-Sen regression for the base content, gc and skew regressions along the read,
+    /// so that a few anomalous cycles (typically at the end of the read) do not dominate
+    pub fn robust_regression(mut self, x: bool) -> Self {
+        self.robust_regression = x;
+        self
+    }
+    /// Fit a non-linear model (reporting curvature or breakpoint) to the base composition drift
+    /// along the read, in addition to any linear regressions
+    pub fn drift_model(mut self, m: Option<DriftModel>) -> Self {
+        self.drift_model = m;
+        self
+    }
+    /// Historical cohort used to report the percentile rank of key metrics for each dataset
+    pub fn baseline(mut self, b: Option<Baseline>) -> Self {
+        self.baseline = b;
+        self
+    }
+    /// Directory for auxiliary output files (by default they are written next to the input
+    /// files)
+    pub fn aux_dir<P: AsRef<Path>>(mut self, dir: Option<P>) -> Self {
+        self.aux_dir = dir.map(|p| p.as_ref().to_owned());
+        self
+    }
+    /// Prefix for the names of auxiliary output files
+    pub fn aux_prefix<S: AsRef<str>>(mut self, prefix: Option<S>) -> Self {
+        self.aux_prefix = prefix.map(|s| s.as_ref().to_owned());
+        self
+    }
+    /// Compare each dataset against the pooled distribution of the other datasets in the run
+    /// rather than against a reference file
+    pub fn self_reference(mut self, x: bool) -> Self {
+        self.self_reference = x;
+        self
+    }
+    /// Base directory for temporary files (by default TMPDIR or the system temporary
+    /// directory)
+    pub fn tmpdir<P: AsRef<Path>>(mut self, dir: Option<P>) -> Self {
+        self.tmpdir = dir.map(|p| p.as_ref().to_owned());
+        self
+    }
+    /// Order in which merged groups are emitted and numbered
+    pub fn group_order(mut self, o: GroupOrder) -> Self {
+        self.group_order = o;
+        self
+    }
+    /// Omit N counts from datasets written with --write-merged when they are all zero
+    pub fn compact_merged(mut self, x: bool) -> Self {
+        self.compact_merged = x;
+        self
+    }
+    /// Write the end of run summary as JSON to p
+    pub fn summary_file<P: AsRef<Path>>(mut self, p: Option<P>) -> Self {
+        self.summary_file = p.map(|p| p.as_ref().to_owned());
+        self
+    }
+    /// Flag datasets with metrics having robust z-scores above the limit
+    pub fn flag_outliers(mut self, x: Option<f64>) -> Self {
+        self.flag_outliers = x;
+        self
+    }
+    /// Write the matrix of divergences between all pairs of datasets to p
+    pub fn pairwise_kl<P: AsRef<Path>>(mut self, p: Option<P>) -> Self {
+        self.pairwise_kl = p.map(|p| p.as_ref().to_owned());
+        self
+    }
+    /// Divergence measure for the pairwise matrix (default KL distance)
+    pub fn pairwise_divergence(mut self, x: Divergence) -> Self {
+        self.pairwise_divergence = x;
+        self
+    }
+    /// Historical fingerprints to screen datasets for sample swaps
+    pub fn fingerprints(mut self, db: Option<FingerprintDb>) -> Self {
+        self.fingerprints = db;
+        self
+    }
+    /// Write the fingerprints of the datasets to p
+    pub fn write_fingerprints<P: AsRef<Path>>(mut self, p: Option<P>) -> Self {
+        self.write_fingerprints = p.map(|p| p.as_ref().to_owned());
+        self
+    }
+    /// Contaminant reference distributions (e.g., from a bacterial panel) for estimating the
+    /// fraction of contaminating reads, with the id of the reference file for the results cache
+    pub fn contamination_ref<R: ReferenceProvider + 'static>(
+        mut self,
+        r: Option<R>,
+        id: Option<u128>,
+    ) -> Self {
+        self.contamination_ref = r.map(|r| Box::new(r) as Box<dyn ReferenceProvider>);
+        self.contamination_id = id;
+        self
+    }
+    /// Test the fit of the sample gc distribution to the reference with test
+    pub fn gof_test(mut self, test: Option<GofTest>) -> Self {
+        self.gof_test = test;
+        self
+    }
+    /// Analyze quality stratified per position counts if present in the input
+    pub fn qual_bands(mut self, x: bool) -> Self {
+        self.qual_bands = x;
+        self
+    }
+    /// gc content of the panel targets, with the id of the target gc file for the results
+    /// cache
+    pub fn target_gc(mut self, t: Option<TargetGc>, id: Option<u128>) -> Self {
+        self.target_gc = t;
+        self.target_gc_id = id;
+        self
+    }
+    /// Mapped fraction expected for the panel without gc bias
+    pub fn expected_mapped_fraction(mut self, x: Option<f64>) -> Self {
+        self.expected_mapped_fraction = x;
+        self
+    }
+    /// Sort datasets in the main output (by default datasets are output as they are completed)
+    pub fn sort_output(mut self, key: Option<SortKey>) -> Self {
+        self.sort_output = key;
+        self
+    }
+    /// Layout version of the main output [default: current schema].  Earlier schemas are only
+    /// available for TSV output
+    pub fn schema(mut self, schema: Schema) -> Self {
+        self.schema = schema;
+        self
+    }
+    /// Results cache, with ids of the reference (in the order added) and kmer files used for
+    /// the cache key
+    pub(crate) fn cache(
+        mut self,
+        cache: Option<ResultsCache>,
+        reference_ids: Vec<u128>,
+        kmcv_id: Option<u128>,
+    ) -> Self {
+        self.cache = cache;
+        self.reference_ids = reference_ids;
+        self.kmcv_id = kmcv_id;
+        self
+    }
+    pub fn contrast(mut self, lists: Option<(PathBuf, PathBuf)>) -> Self {
+        self.contrast = lists;
+        self
+    }
+    /// Number of permutations for contrast mode [default: 1000]
+    pub fn contrast_reps(mut self, n: usize) -> Self {
+        self.contrast_reps = Some(n);
+        self
+    }
+    pub fn seed(mut self, s: Option<u64>) -> Self {
+        self.seed = s;
+        self
+    }
+    pub fn service(mut self, x: bool) -> Self {
+        self.service = x;
+        self
+    }
+    /// Build without requiring input files (for printing the configuration)
+    pub(crate) fn no_input_check(mut self, x: bool) -> Self {
+        self.no_input_check = x;
+        self
+    }
+    /// Receive datasets on Unix socket p instead of reading input files
+    pub fn stream_socket<P: AsRef<Path>>(mut self, p: Option<P>) -> Self {
+        self.stream_socket = p.map(|p| p.as_ref().to_owned());
+        self
+    }
+    /// Append rows to the output file (writing the header only if the file is new or empty)
+    pub fn append_output(mut self, x: bool) -> Self {
+        self.append_output = x;
+        self
+    }
+
+    pub fn build(mut self) -> anyhow::Result<Config> {
+        if self.input_files.is_empty() {
+            if let Some(m) = self.manifest.as_ref() {
+                self.input_files = m.input_files().to_vec()
+            }
+        }
+        let streaming = self.stream_socket.is_some();
+        if streaming {
+            if cfg!(not(unix)) {
+                return Err(anyhow!("Stream mode requires Unix domain sockets"));
+            }
+            if matches!(self.merge_key, Some(MergeKey::Path(_))) {
+                return Err(anyhow!(
+                    "Merge keys can not be taken from input file paths in stream mode"
+                ));
+            }
+            if !self.input_files.is_empty() {
+                warn!("Input files ignored in stream mode")
+            }
+            if self.two_pass_merge {
+                warn!("--two-pass-merge option ignored in stream mode")
+            }
+        } else if self.input_files.is_empty()
+            && self.contrast.is_none()
+            && !(self.service || self.no_input_check)
+        {
+            return Err(anyhow!("No input files specified"));
+        }
+        let threads = match self.threads {
+            Some(0) => return Err(anyhow!("Number of threads must be at least 1")),
+            Some(n) => n,
+            None if self.service || streaming => num_cpus::get(),
+            None => num_cpus::get().min(self.input_files.len()).max(1),
+        };
+        let output_format = self.output_format.unwrap_or(OutputFormat::Tsv);
+        if self.append_output {
+            if !matches!(output_format, OutputFormat::Tsv) {
+                return Err(anyhow!("Output can only be appended in TSV format"));
+            }
+            // These need the results of all datasets before anything is written
+            if self.sort_output.is_some() || self.flag_outliers.is_some() {
+                return Err(anyhow!(
+                    "--sort-output and --flag-outliers can not be used when appending output"
+                ));
+            }
+        }
+        if self.schema != Schema::CURRENT && !matches!(output_format, OutputFormat::Tsv) {
+            return Err(anyhow!(
+                "Output schema {} is only available for TSV output",
+                self.schema
+            ));
+        }
+        let mut names: Vec<_> = self.references.iter().map(|(s, _)| s.as_str()).collect();
+        names.sort_unstable();
+        if let Some(w) = names.windows(2).find(|w| w[0] == w[1]) {
+            return Err(anyhow!("Duplicate reference name {}", w[0]));
+        }
+        if self.self_reference {
+            if self.input_files.iter().any(|p| is_stdin(p)) {
+                return Err(anyhow!(
+                    "Input from stdin can not be used with a self reference"
+                ));
+            }
+            // Results depend on all datasets in the run, so can not be cached per file
+            if self.cache.take().is_some() {
+                warn!("Results cache not used with a self reference")
+            }
+        }
+        // The KL distance is always reported, so only the additional measures are kept
+        let mut divergences = self.divergences;
+        divergences.retain(|d| *d != Divergence::Kl);
+        divergences.sort_unstable();
+        divergences.dedup();
+        let merging = self.merge_key.is_some();
+        Ok(Config {
+            input_files: self.input_files,
+            input_format: self.input_format.unwrap_or(InputFormat::Auto),
+            aligned_reads: self.aligned_reads,
+            output_file: self.output_file,
+            output_format,
+            references: self.references,
+            threads,
+            regression: self.regression,
+            skew: self.skew,
+            kmcv: self.kmcv,
+            manifest: self.manifest,
+            merge_key: self.merge_key,
+            then_by: self.then_by.filter(|_| merging),
+            keep_individual: self.keep_individual && merging,
+            status_file: self.status_file,
+            aux_output: !self.no_aux,
+            quiet_stdout: self.quiet_stdout,
+            multiqc: self.multiqc,
+            member_kl: self.member_kl,
+            influence: self.influence,
+            thresholds: self.thresholds,
+            exclude_failing: self.exclude_failing && merging,
+            html_report: self.html_report,
+            kl_prior: self.kl_prior,
+            skip_errors: self.skip_errors,
+            target_coverage: self.target_coverage,
+            divergences,
+            base_dist_ci: self.base_dist_ci,
+            base_dist_max_rows: self.base_dist_max_rows,
+            bgzip_aux: self.bgzip_aux,
+            two_pass_merge: self.two_pass_merge,
+            merge_bisulfite_strands: self.merge_bisulfite_strands,
+            consistency_report: self.consistency_report,
+            split_read_ends: self.split_read_ends,
+            read_ends: self.read_ends,
+            cfdna: self.cfdna,
+            write_merged: self.write_merged,
+            confidence_intervals: self.confidence_intervals,
+            provenance: self.provenance,
+            robust_regression: self.robust_regression,
+            drift_model: self.drift_model,
+            baseline: self.baseline,
+            aux_dir: self.aux_dir,
+            aux_prefix: self.aux_prefix,
+            self_reference: self.self_reference,
+            reference_pool: None,
+            temp_dir: TempDir::new(self.tmpdir),
+            group_order: self.group_order,
+            compact_merged: self.compact_merged,
+            summary_file: self.summary_file,
+            flag_outliers: self.flag_outliers,
+            pairwise_kl: self.pairwise_kl,
+            pairwise_divergence: self.pairwise_divergence,
+            fingerprints: self.fingerprints,
+            write_fingerprints: self.write_fingerprints,
+            contamination_ref: self.contamination_ref,
+            contamination_id: self.contamination_id,
+            gof_test: self.gof_test,
+            qual_bands: self.qual_bands,
+            target_gc: self.target_gc,
+            target_gc_id: self.target_gc_id,
+            expected_mapped_fraction: self.expected_mapped_fraction,
+            // Merged datasets complete in an order depending on thread scheduling, so are output
+            // in group order unless another order is requested
+            sort_output: self
+                .sort_output
+                .or_else(|| merging.then_some(SortKey::Input)),
+            schema: self.schema,
+            cache: self.cache,
+            reference_ids: self.reference_ids,
+            kmcv_id: self.kmcv_id,
+            contrast: self.contrast,
+            contrast_reps: self.contrast_reps.unwrap_or(1000),
+            seed: self.seed,
+            service: self.service,
+            append_output: self.append_output,
+            stream_socket: self.stream_socket,
+        })
+    }
+}
+
+fn get_input_files(m: &ArgMatches) -> Vec<PathBuf> {
+    m.get_many("input")
+        .map(|v| v.map(|p: &PathBuf| p.to_owned()).collect())
+        .unwrap_or_default()
+}
+
+// Output file given as "-" is treated as stdout
+fn get_output_file(m: &ArgMatches) -> Option<PathBuf> {
+    m.get_one::<PathBuf>("output")
+        .filter(|p| p.as_os_str() != "-")
+        .map(|p| p.to_owned())
+}
+
+fn handle_report(m: &ArgMatches) -> ReportConfig {
+    ReportConfig {
+        input_files: get_input_files(m),
+        output_file: get_output_file(m),
+        format: m
+            .get_one::<ReportFormat>("format")
+            .copied()
+            .expect("Missing default report format"),
+        template_dir: m
+            .get_one::<PathBuf>("report_template")
+            .map(|p| p.to_owned()),
+        fli_gc_tolerance: *m
+            .get_one::<f64>("fli_gc_tolerance")
+            .expect("Missing default FLI gc tolerance"),
+    }
+}
+
+pub(crate) fn read_kmcv_file(p: &Path) -> anyhow::Result<Kmcv> {
+    let mut rdr = CompressIo::new()
+        .path(p)
+        .bufreader()
+        .with_context(|| "Could not open kmer file for input")?;
+
+    debug!("Opened kmer file for input");
+    Kmcv::read(&mut rdr).with_context(|| format!("Could not read kmer file {}", p.display()))
+}
+
+fn read_kmcv(m: &ArgMatches) -> anyhow::Result<Option<Kmcv>> {
+    m.get_one::<PathBuf>("kmers")
+        .map(|p| read_kmcv_file(p))
+        .transpose()
+}
+
+fn handle_panel_compare(m: &ArgMatches) -> anyhow::Result<PanelCompareConfig> {
+    let get_kmcv = |s| read_kmcv_file(m.get_one::<PathBuf>(s).expect("Missing required argument"));
+    Ok(PanelCompareConfig {
+        input_files: get_input_files(m),
+        output_file: get_output_file(m),
+        old_kmcv: get_kmcv("old")?,
+        new_kmcv: get_kmcv("new")?,
+    })
+}
+
+fn handle_rescore(m: &ArgMatches) -> anyhow::Result<RescoreConfig> {
+    let mut divergences: Vec<Divergence> = m
+        .get_many::<Divergence>("divergence")
+        .map(|v| v.copied().filter(|d| *d != Divergence::Kl).collect())
+        .unwrap_or_default();
+    divergences.sort_unstable();
+    divergences.dedup();
+    Ok(RescoreConfig {
+        input_files: get_input_files(m),
+        output_file: get_output_file(m),
+        reference: m
+            .get_one::<PathBuf>("reference")
+            .map(|p| read_reference(p))
+            .transpose()?,
+        read_length: m.get_one::<u32>("read_length").copied(),
+        bisulfite: m.get_flag("bisulfite"),
+        divergences,
+    })
+}
+
+fn handle_build_ref(m: &ArgMatches) -> BuildRefConfig {
+    let mut read_lengths: Vec<u32> = m
+        .get_many("read_lengths")
+        .expect("Missing required argument")
+        .copied()
+        .collect();
+    read_lengths.sort_unstable();
+    read_lengths.dedup();
+    BuildRefConfig {
+        fasta: m
+            .get_one::<PathBuf>("fasta")
+            .expect("Missing required argument")
+            .to_owned(),
+        read_lengths,
+        bisulfite: m.get_flag("bisulfite"),
+        output_file: get_output_file(m),
     }
 }
-pub fn handle_cli() -> anyhow::Result<Config> {
+
+fn handle_simulate(m: &ArgMatches) -> anyhow::Result<SimulateConfig> {
+    let get_f64 = |s| *m.get_one::<f64>(s).expect("Missing default value");
+    let gc_mean = get_f64("gc_mean");
+    let gc_overdispersion = get_f64("gc_overdispersion");
+    let mapped_fraction = get_f64("mapped_fraction");
+    if !(gc_mean > 0.0 && gc_mean < 1.0) {
+        return Err(anyhow!("GC mean must be between 0 and 1"));
+    }
+    if !(0.0..1.0).contains(&gc_overdispersion) {
+        return Err(anyhow!("GC overdispersion must be >= 0 and < 1"));
+    }
+    if !(0.0..=1.0).contains(&mapped_fraction) {
+        return Err(anyhow!("Mapped fraction must be between 0 and 1"));
+    }
+
+    Ok(SimulateConfig {
+        outdir: m
+            .get_one::<PathBuf>("outdir")
+            .expect("Missing default")
+            .to_owned(),
+        prefix: m
+            .get_one::<String>("prefix")
+            .expect("Missing default")
+            .to_owned(),
+        n_datasets: *m.get_one::<u64>("datasets").expect("Missing default") as usize,
+        read_length: *m.get_one::<u32>("read_length").expect("Missing default"),
+        depth: *m.get_one::<u64>("depth").expect("Missing default"),
+        gc_mean,
+        gc_overdispersion,
+        kmcv: read_kmcv(m)?,
+        mapped_fraction,
+        seed: m.get_one::<u64>("seed").copied(),
+    })
+}
+
+pub fn handle_cli() -> anyhow::Result<Task> {
     let c = cli_model::cli_model();
     let m = c.get_matches();
-    super::utils::init_log(&m);
 
-    let input_files: Vec<PathBuf> = m
-        .get_many("input")
-        .expect("Missing required input argument")
-        .map(|p: &PathBuf| p.to_owned())
-        .collect();
+    match m.subcommand() {
+        Some(("report", m_sub)) => {
+            super::utils::init_log(m_sub);
+            Ok(Task::Report(handle_report(m_sub)))
+        }
+        Some(("selftest", m_sub)) => {
+            super::utils::init_log(m_sub);
+            Ok(Task::SelfTest(SelfTestConfig {
+                dir: m_sub.get_one::<PathBuf>("dir").map(|p| p.to_owned()),
+                tmpdir: m_sub.get_one::<PathBuf>("tmpdir").map(|p| p.to_owned()),
+            }))
+        }
+        Some(("simulate", m_sub)) => {
+            super::utils::init_log(m_sub);
+            Ok(Task::Simulate(handle_simulate(m_sub)?))
+        }
+        Some(("panel-compare", m_sub)) => {
+            super::utils::init_log(m_sub);
+            Ok(Task::PanelCompare(handle_panel_compare(m_sub)?))
+        }
+        Some(("build-ref", m_sub)) => {
+            super::utils::init_log(m_sub);
+            Ok(Task::BuildRef(handle_build_ref(m_sub)))
+        }
+        Some(("concat", m_sub)) => {
+            super::utils::init_log(m_sub);
+            Ok(Task::Concat(ConcatConfig {
+                input_files: get_input_files(m_sub),
+                output_file: get_output_file(m_sub),
+            }))
+        }
+        Some(("validate", m_sub)) => {
+            super::utils::init_log(m_sub);
+            Ok(Task::Validate(ValidateConfig {
+                input_files: get_input_files(m_sub),
+                output_file: get_output_file(m_sub),
+            }))
+        }
+        Some(("rescore", m_sub)) => {
+            super::utils::init_log(m_sub);
+            Ok(Task::Rescore(handle_rescore(m_sub)?))
+        }
+        // Analysis options are taken from the main command line
+        #[cfg(feature = "server")]
+        Some(("serve", m_sub)) => {
+            super::utils::init_log(m_sub);
+            Ok(Task::Serve(
+                Box::new(collect_builder(&m)?.service(true).build()?),
+                ServeConfig {
+                    listen: m_sub
+                        .get_one::<String>("listen")
+                        .expect("Missing default listen address")
+                        .to_owned(),
+                },
+            ))
+        }
+        // Analysis options are taken from the main command line
+        #[cfg(feature = "watch")]
+        Some(("watch", m_sub)) => {
+            super::utils::init_log(m_sub);
+            Ok(handle_watch(&m, m_sub)?)
+        }
+        _ => {
+            super::utils::init_log(&m);
+            match m.get_one::<cli_model::ConfigFormat>("print_config") {
+                Some(f) => {
+                    let cfg = collect_builder(&m)?.no_input_check(true).build()?;
+                    Ok(Task::PrintConfig(print_config::print_config(
+                        &cfg,
+                        &cli_model::cli_model(),
+                        &m,
+                        *f,
+                    )))
+                }
+                None => Ok(Task::Collect(Box::new(handle_collect(&m)?))),
+            }
+        }
+    }
+}
 
-    let output_file = m.get_one::<PathBuf>("output").map(|p| p.to_owned());
-    let threads = m
-        .get_one::<u64>("threads")
-        .map(|x| *x as usize)
-        .unwrap_or_else(|| num_cpus::get().min(input_files.len()));
-
-    let ref_dist = match m.get_one::<PathBuf>("ref") {
-        Some(p) => Some(RefDist::from_json_file(&p).with_context(|| {
-            format!(
-                "Error reading reference distributions from JSON file {}",
-                p.display()
-            )
-        })?),
+/// Name of a reference (for output) from the file name without extensions
+fn reference_name(p: &Path) -> String {
+    p.file_name()
+        .map(|s| s.to_string_lossy())
+        .and_then(|s| s.split('.').next().map(|s| s.to_owned()))
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| p.display().to_string())
+}
+
+/// Select reference provider from the reference argument
+pub(crate) fn read_reference(p: &Path) -> anyhow::Result<RefDist> {
+    ReferenceSource::from_path(p).load()
+}
+
+// Datasets are analyzed individually as they arrive, so merge options are only used for the
+// merged output.  Invalid files are skipped so that watching continues
+#[cfg(feature = "watch")]
+fn handle_watch(m: &ArgMatches, m_sub: &ArgMatches) -> anyhow::Result<Task> {
+    let merge_interval = Duration::from_secs(
+        *m_sub
+            .get_one::<u64>("merge_interval")
+            .expect("Missing default merge interval"),
+    );
+    let merged = match m_sub.get_one::<PathBuf>("merged_output") {
+        Some(p) => {
+            // The individual datasets are already written to the main output as they arrive
+            let cfg = collect_builder(m)?
+                .service(true)
+                .skip_errors(true)
+                .output_file(Some(p))
+                .keep_individual(false)
+                .build()?;
+            if cfg.merge_key().is_none() {
+                return Err(anyhow!("--merged-output requires a merge key (--merge-by)"));
+            }
+            Some(Box::new(cfg))
+        }
         None => None,
     };
+    let cfg = collect_builder(m)?
+        .service(true)
+        .skip_errors(true)
+        .append_output(true)
+        .merge_key(None)
+        .build()?;
+    let dir = m_sub
+        .get_one::<PathBuf>("dir")
+        .expect("Missing watch directory")
+        .to_owned();
+    if !dir.is_dir() {
+        return Err(anyhow!("{} is not a directory", dir.display()));
+    }
+    Ok(Task::Watch(
+        Box::new(cfg),
+        WatchConfig {
+            dir,
+            existing: m_sub.get_flag("existing"),
+            merge_interval,
+            merged,
+        },
+    ))
+}
 
-    let regression = m.get_flag("regression");
-    
-    let merge_key = m.get_one::<MergeKey>("merge_by").copied().or_else(|| {
-        if m.get_flag("merge") {
-            Some(MergeKey::Default)
-        } else {
-            None
+fn handle_collect(m: &ArgMatches) -> anyhow::Result<Config> {
+    collect_builder(m)?.build()
+}
+
+fn collect_builder(m: &ArgMatches) -> anyhow::Result<ConfigBuilder> {
+    let input_files = get_input_files(m);
+
+    let contrast = match m.get_one::<String>("contrast") {
+        Some(s) => Some(
+            s.split_once(':')
+                .map(|(a, b)| (PathBuf::from(a), PathBuf::from(b)))
+                .ok_or_else(|| anyhow!("Contrast argument should be of form <list A>:<list B>"))?,
+        ),
+        None => None,
+    };
+
+    let ref_paths: Vec<_> = m
+        .get_many::<PathBuf>("ref")
+        .map(|v| v.collect())
+        .unwrap_or_default();
+
+    let merge_key = m
+        .get_one::<Regex>("merge_by_path")
+        .map(|r| MergeKey::Path(r.clone()))
+        .or_else(|| m.get_one::<MergeKey>("merge_by").cloned())
+        .or_else(|| {
+            if m.get_flag("merge") {
+                Some(MergeKey::Default)
+            } else {
+                None
+            }
+        });
+
+    let kmcv = read_kmcv(m)?;
+
+    if merge_key.is_none() {
+        for (id, opt) in [
+            ("member_kl", "--member-kl"),
+            ("influence", "--influence"),
+            ("exclude_failing", "--exclude-failing"),
+            ("two_pass_merge", "--two-pass-merge"),
+            ("merge_bisulfite_strands", "--merge-bisulfite-strands"),
+            ("consistency_report", "--consistency-report"),
+            ("split_read_ends", "--split-read-ends"),
+            ("write_merged", "--write-merged"),
+            ("then_by", "--then-by"),
+            ("keep_individual", "--keep-individual"),
+        ] {
+            if m.value_source(id) == Some(ValueSource::CommandLine) {
+                warn!("{opt} option ignored as datasets are not being merged")
+            }
         }
-    });
+    }
 
-    let kmcv = match m.get_one::<PathBuf>("kmers") {
-        Some(p) => {
-            let mut rdr = CompressIo::new()
-                .path(p)
-                .bufreader()
-                .with_context(|| "Could not open kmer file for input")?;
-
-            debug!("Opened kmer file for input");
-            Some(
-                Kmcv::read(&mut rdr)
-                    .with_context(|| format!("Could not read kmer file {}", p.display()))?,
+    let kl_prior = *m.get_one::<f64>("kl_prior").expect("Missing default value");
+    if !(kl_prior >= 0.0 && kl_prior.is_finite()) {
+        return Err(anyhow!("KL prior must be a non-negative number"));
+    }
+
+    let thresholds = m
+        .get_one::<PathBuf>("thresholds")
+        .map(Thresholds::from_file)
+        .transpose()?;
+
+    let baseline_filters = m
+        .get_many::<String>("baseline_filter")
+        .map(|v| {
+            v.map(|s| s.parse::<BaselineFilter>())
+                .collect::<anyhow::Result<Vec<_>>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let baseline_match: Vec<String> = m
+        .get_many::<String>("baseline_match")
+        .map(|v| v.cloned().collect())
+        .unwrap_or_default();
+    let baseline = m
+        .get_many::<PathBuf>("baseline")
+        .map(|v| {
+            Baseline::from_files(
+                &v.cloned().collect::<Vec<_>>(),
+                &baseline_filters,
+                &baseline_match,
             )
+        })
+        .transpose()?;
+
+    let fingerprints = m
+        .get_many::<PathBuf>("fingerprints")
+        .map(|v| FingerprintDb::from_files(&v.collect::<Vec<_>>()))
+        .transpose()?;
+
+    let expected_gc = match m.get_one::<f64>("expected_gc") {
+        Some(gc) => {
+            let conc = *m
+                .get_one::<f64>("expected_gc_concentration")
+                .expect("Missing default value");
+            if !(*gc > 0.0 && *gc < 1.0) {
+                return Err(anyhow!("Expected gc must be between 0 and 1"));
+            }
+            if !(conc > 0.0 && conc.is_finite()) {
+                return Err(anyhow!(
+                    "Expected gc concentration must be a positive number"
+                ));
+            }
+            Some((*gc, conc))
+        }
+        None => {
+            if m.value_source("expected_gc_concentration") == Some(ValueSource::CommandLine) {
+                warn!("--expected-gc-concentration option ignored without --expected-gc")
+            }
+            None
         }
-        None => None,
     };
 
-    Ok(Config {
-        input_files,
-        output_file,
-        merge_key,
-        threads,
-        ref_dist,
-        regression,
-        kmcv,
-    })
+    let contamination_path = m.get_one::<PathBuf>("contamination_ref");
+    let contamination_ref = contamination_path.map(|p| read_reference(p)).transpose()?;
+
+    let aux_dir = m.get_one::<PathBuf>("outdir");
+    if let Some(dir) = aux_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create output directory {}", dir.display()))?
+    }
+
+    let manifest = m
+        .get_one::<PathBuf>("manifest")
+        .map(Manifest::read)
+        .transpose()?;
+
+    let cache_compression = *m
+        .get_one::<CacheCompression>("cache_compression")
+        .expect("Missing default cache compression");
+    if cache_compression != CacheCompression::Zstd
+        && m.value_source("cache_dict") == Some(ValueSource::CommandLine)
+    {
+        warn!("--cache-dict option ignored as cache compression is not zstd")
+    }
+
+    // Reference and kmer file ids are only needed for the results cache
+    let (cache, reference_ids, kmcv_id) =
+        match m.get_one::<PathBuf>("cache_dir") {
+            Some(dir) => (
+                Some(ResultsCache::new(
+                    dir,
+                    cache_compression,
+                    m.get_one::<u64>("cache_dict").map(|n| *n as usize),
+                )?),
+                ref_paths
+                    .iter()
+                    .map(hash_file)
+                    .chain(expected_gc.map(|(gc, conc)| {
+                        Ok(xxh3_128(format!("expected-gc:{gc}:{conc}").as_bytes()))
+                    }))
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+                m.get_one::<PathBuf>("kmers").map(hash_file).transpose()?,
+            ),
+            None => (None, Vec::new(), None),
+        };
+    let contamination_id = match contamination_path {
+        Some(p) if cache.is_some() => Some(hash_file(p)?),
+        _ => None,
+    };
+
+    let target_gc_path = m.get_one::<PathBuf>("target_gc");
+    let target_gc = target_gc_path.map(TargetGc::read).transpose()?;
+    if target_gc.is_some() && m.get_one::<PathBuf>("kmers").is_none() {
+        warn!("--target-gc option ignored without an input kmer file (use -k option)")
+    }
+    let target_gc_id = match target_gc_path {
+        Some(p) if cache.is_some() => Some(hash_file(p)?),
+        _ => None,
+    };
+    let expected_mapped_fraction = m.get_one::<f64>("expected_mapped_fraction").copied();
+    if let Some(x) = expected_mapped_fraction {
+        if !(x > 0.0 && x <= 1.0) {
+            return Err(anyhow!("Expected mapped fraction must be in (0, 1]"));
+        }
+    }
+
+    let mut builder = ConfigBuilder::new()
+        .input_files(input_files)
+        .input_format(
+            *m.get_one::<InputFormat>("input_format")
+                .expect("Missing default input format"),
+        )
+        .aligned_reads(
+            *m.get_one::<AlignedReads>("aligned_reads")
+                .expect("Missing default aligned reads"),
+        )
+        .output_file(get_output_file(m))
+        .output_format(
+            *m.get_one::<OutputFormat>("format")
+                .expect("Missing default output format"),
+        )
+        .merge_key(merge_key)
+        .then_by(m.get_one::<MergeKey>("then_by").cloned())
+        .stream_socket(m.get_one::<PathBuf>("stream_socket"))
+        .keep_individual(m.get_flag("keep_individual"))
+        .regression(m.get_flag("regression"))
+        .skew(m.get_flag("skew"))
+        .kmcv(kmcv)
+        .manifest(manifest)
+        .status_file(m.get_one::<PathBuf>("status_file"))
+        .aux_output(!m.get_flag("no_aux"))
+        .quiet_stdout(m.get_flag("quiet_stdout"))
+        .multiqc(m.get_flag("multiqc"))
+        .member_kl(m.get_one::<PathBuf>("member_kl"))
+        .influence(m.get_flag("influence"))
+        .thresholds(thresholds)
+        .exclude_failing(m.get_flag("exclude_failing"))
+        .html_report(m.get_one::<PathBuf>("html_report"))
+        .kl_prior(kl_prior)
+        .skip_errors(m.get_flag("skip_errors"))
+        .target_coverage(m.get_flag("target_coverage"))
+        .base_dist_ci(m.get_flag("base_dist_ci"))
+        .base_dist_max_rows(m.get_one::<u64>("base_dist_max_rows").map(|n| *n as usize))
+        .bgzip_aux(m.get_flag("bgzip_aux"))
+        .two_pass_merge(m.get_flag("two_pass_merge"))
+        .merge_bisulfite_strands(m.get_flag("merge_bisulfite_strands"))
+        .consistency_report(m.get_one::<PathBuf>("consistency_report"))
+        .split_read_ends(m.get_flag("split_read_ends"))
+        .read_ends(m.get_flag("read_ends"))
+        .cfdna(m.get_flag("cfdna"))
+        .write_merged(m.get_one::<PathBuf>("write_merged").cloned())
+        .confidence_intervals(
+            m.get_flag("confidence_intervals")
+                .then(|| *m.get_one::<u64>("ci_reps").expect("Missing default") as usize),
+        )
+        .provenance(m.get_flag("provenance"))
+        .robust_regression(m.get_flag("robust_regression"))
+        .drift_model(m.get_one::<DriftModel>("drift_model").copied())
+        .baseline(baseline)
+        .aux_dir(aux_dir)
+        .aux_prefix(m.get_one::<String>("prefix"))
+        .self_reference(m.get_flag("self_reference"))
+        .tmpdir(m.get_one::<PathBuf>("tmpdir"))
+        .group_order(
+            *m.get_one::<GroupOrder>("group_order")
+                .expect("Missing default group order"),
+        )
+        .compact_merged(m.get_flag("compact_merged"))
+        .summary_file(m.get_one::<PathBuf>("summary_file"))
+        .flag_outliers(m.get_one::<f64>("flag_outliers").copied())
+        .pairwise_kl(m.get_one::<PathBuf>("pairwise_kl"))
+        .pairwise_divergence(
+            *m.get_one::<Divergence>("pairwise_divergence")
+                .expect("Missing default pairwise divergence"),
+        )
+        .fingerprints(fingerprints)
+        .write_fingerprints(m.get_one::<PathBuf>("write_fingerprints"))
+        .contamination_ref(contamination_ref, contamination_id)
+        .gof_test(m.get_one::<GofTest>("gof_test").copied())
+        .qual_bands(m.get_flag("qual_bands"))
+        .target_gc(target_gc, target_gc_id)
+        .expected_mapped_fraction(expected_mapped_fraction)
+        .sort_output(m.get_one::<SortKey>("sort_output").copied())
+        .schema(
+            *m.get_one::<Schema>("schema")
+                .expect("Missing default schema"),
+        )
+        .divergences(
+            m.get_many::<Divergence>("divergence")
+                .map(|v| v.copied().collect())
+                .unwrap_or_default(),
+        )
+        .cache(cache, reference_ids, kmcv_id)
+        .contrast(contrast)
+        .contrast_reps(*m.get_one::<u64>("contrast_reps").expect("Missing default") as usize)
+        .seed(m.get_one::<u64>("seed").copied());
+
+    for p in ref_paths {
+        builder = builder.add_reference(reference_name(p), read_reference(p)?)
+    }
+    if let Some((gc, conc)) = expected_gc {
+        info!("Using synthetic reference with expected gc {gc} (concentration {conc})");
+        builder = builder.add_reference("expected-gc", RefDist::expected_gc(gc, conc))
+    }
+
+    if let Some(n) = m.get_one::<u64>("threads") {
+        builder = builder.threads(*n as usize)
+    }
+
+    Ok(builder)
 }
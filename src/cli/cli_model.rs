@@ -1,18 +1,46 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, str::FromStr};
 
 use clap::{builder::PossibleValue, command, value_parser, Arg, ArgAction, Command, ValueEnum};
+use regex::Regex;
 
-use crate::utils::LogLevel;
+use crate::{
+    bam::AlignedReads,
+    betabin::Divergence,
+    cache::CacheCompression,
+    gof::GofTest,
+    merge::GroupOrder,
+    merge_expr::MergeExpr,
+    output::{OutputFormat, SortKey},
+    report::ReportFormat,
+    schema::Schema,
+    simple_regression::DriftModel,
+    source::InputFormat,
+    utils::LogLevel,
+};
 
 pub(super) fn cli_model() -> Command {
-    command!()
-        .arg(
+    let c = command!()
+        .subcommand_negates_reqs(true)
+        .subcommand(report_command())
+        .subcommand(selftest_command())
+        .subcommand(simulate_command())
+        .subcommand(panel_compare_command())
+        .subcommand(build_ref_command())
+        .subcommand(concat_command())
+        .subcommand(validate_command())
+        .subcommand(rescore_command());
+    #[cfg(feature = "server")]
+    let c = c.subcommand(serve_command());
+    #[cfg(feature = "watch")]
+    let c = c.subcommand(watch_command());
+    c.arg(
             Arg::new("timestamp")
                 .short('X')
                 .long("timestamp")
                 .value_parser(value_parser!(stderrlog::Timestamp))
                 .value_name("GRANULARITY")
                 .default_value("none")
+                .global(true)
                 .help("Prepend log entries with a timestamp"),
         )
         .arg(
@@ -23,6 +51,7 @@ pub(super) fn cli_model() -> Command {
                 .value_parser(value_parser!(LogLevel))
                 .ignore_case(true)
                 .default_value("info")
+                .global(true)
                 .help("Set log level"),
         )
         .arg(
@@ -30,6 +59,7 @@ pub(super) fn cli_model() -> Command {
                 .action(ArgAction::SetTrue)
                 .long("quiet")
                 .conflicts_with("loglevel")
+                .global(true)
                 .help("Silence all output"),
         )
         .arg(
@@ -39,6 +69,12 @@ pub(super) fn cli_model() -> Command {
                 .long("regression")
                 .help("Perform regression of base composition along reads"),
         )
+        .arg(
+            Arg::new("skew")
+                .long("skew")
+                .action(ArgAction::SetTrue)
+                .help("Output per cycle AT/GC skew and regression of skew along reads"),
+        )
         .arg(
             Arg::new("merge")
                 .short('m')
@@ -51,9 +87,309 @@ pub(super) fn cli_model() -> Command {
                 .short('M')
                 .long("merge-by")
                 .value_name("MERGE KEY")
-                .value_parser(value_parser!(MergeKey))
+                .value_parser(|s: &str| s.parse::<MergeKey>().map_err(|e| format!("{e:#}")))
+                .help("Set merge key [possible values: default, sample, barcode, library, fli, expr:TEMPLATE]")
+                .long_help("Set merge key: one of default, sample, barcode, library or fli, or expr:TEMPLATE \
+where TEMPLATE gives the key using the FLI fields sample, barcode, library, flowcell, index, lane \
+and read_end in braces (e.g., expr:{sample}_{library} or expr:{flowcell}:{lane})"),
+        )
+        .arg(
+            Arg::new("merge_by_path")
+                .long("merge-by-path")
+                .value_name("REGEX")
+                .value_parser(|s: &str| Regex::new(s).map_err(|e| e.to_string()))
+                .conflicts_with("merge_by")
+                .help("Merge using key extracted from input file paths by REGEX (first capture group, or whole match)"),
+        )
+        .arg(
+            Arg::new("then_by")
+                .long("then-by")
+                .value_name("MERGE KEY")
+                .value_parser(|s: &str| s.parse::<MergeKey>().map_err(|e| format!("{e:#}")))
+                .help("Also merge the merged datasets by a second key, reporting both levels (e.g., --merge-by fli --then-by sample)"),
+        )
+        .arg(
+            Arg::new("keep_individual")
+                .long("keep-individual")
+                .action(ArgAction::SetTrue)
+                .help("When merging, also output a row for each merged input dataset (adds Merge-group column giving its group)"),
+        )
+        .arg(
+            Arg::new("two_pass_merge")
+                .long("two-pass-merge")
+                .action(ArgAction::SetTrue)
+                .help("Group input files by merge key before merging to limit memory use (input files are read twice)"),
+        )
+        .arg(
+            Arg::new("write_merged")
+                .long("write-merged")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("DIR")
+                .help("Write merged datasets in fastq_gc JSON format to DIR"),
+        )
+        .arg(
+            Arg::new("confidence_intervals")
+                .long("confidence-intervals")
+                .action(ArgAction::SetTrue)
+                .help("Report 95% confidence intervals for mean gc and KL distance"),
+        )
+        .arg(
+            Arg::new("provenance")
+                .long("provenance")
+                .action(ArgAction::SetTrue)
+                .help("Add upstream program version and command line columns to TSV output"),
+        )
+        .arg(
+            Arg::new("robust_regression")
+                .long("robust-regression")
+                .action(ArgAction::SetTrue)
+                .help("Use Theil-Sen instead of least squares regression for drift along reads"),
+        )
+        .arg(
+            Arg::new("drift_model")
+                .long("drift-model")
+                .value_parser(value_parser!(DriftModel))
+                .ignore_case(true)
+                .value_name("MODEL")
+                .help("Fit a quadratic or segmented (two segment) model to base composition drift along reads"),
+        )
+        .arg(
+            Arg::new("baseline")
+                .long("baseline")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .action(ArgAction::Append)
+                .help("TSV results file from previous runs (may be given multiple times); adds percentile ranks of KL distance, fold 80 penalty and mapped fraction"),
+        )
+        .arg(
+            Arg::new("outdir")
+                .long("outdir")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("DIR")
+                .help("Write auxiliary files (gc_hist, base_dist, target_cov) to DIR instead of next to the input files"),
+        )
+        .arg(
+            Arg::new("prefix")
+                .long("prefix")
+                .value_name("STRING")
+                .help("Prefix added to the names of auxiliary files"),
+        )
+        .arg(
+            Arg::new("tmpdir")
+                .long("tmpdir")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("DIR")
+                .help("Directory for temporary files [default: TMPDIR or the system temporary directory]"),
+        )
+        .arg(
+            Arg::new("baseline_filter")
+                .long("baseline-filter")
+                .value_name("EXPR")
+                .action(ArgAction::Append)
+                .requires("baseline")
+                .help("Only use baseline rows matching EXPR (COLUMN OP VALUE with OP one of =, !=, <, <=, >, >=)"),
+        )
+        .arg(
+            Arg::new("baseline_match")
+                .long("baseline-match")
+                .value_name("COLUMN")
+                .action(ArgAction::Append)
+                .requires("baseline")
+                .help("Compare each dataset only with baseline rows having the same value of COLUMN (a dataset description column, Library-type or Read-length)"),
+        )
+        .arg(
+            Arg::new("self_reference")
+                .long("self-reference")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("ref")
+                .help("Compare each dataset against the pooled distribution of the other datasets in the run"),
+        )
+        .arg(
+            Arg::new("group_order")
+                .long("group-order")
+                .value_parser(value_parser!(GroupOrder))
+                .ignore_case(true)
+                .value_name("ORDER")
+                .default_value("key")
+                .help("Order in which merged groups are emitted and numbered: by merge key, input order of first member or decreasing number of input files"),
+        )
+        .arg(
+            Arg::new("compact_merged")
+                .long("compact-merged")
+                .action(ArgAction::SetTrue)
+                .requires("write_merged")
+                .help("Omit N counts from datasets written with --write-merged when they are all zero"),
+        )
+        .arg(
+            Arg::new("summary_file")
+                .long("summary-file")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .help("Write JSON file summarizing the run (mean gc distribution, largest KL distance, coverage dispersion and bisulfite types)"),
+        )
+        .arg(
+            Arg::new("flag_outliers")
+                .long("flag-outliers")
+                .value_parser(value_parser!(f64))
+                .value_name("N")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("3.5")
+                .help("Add Outlier-flags column listing metrics (gc, KL-distance, Median-coverage) more than N scaled MADs from the median of the run [default N: 3.5]"),
+        )
+        .arg(
+            Arg::new("pairwise_kl")
+                .long("pairwise-kl")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .help("Write TSV matrix of divergences between the gc distributions of all pairs of datasets (or merged groups)"),
+        )
+        .arg(
+            Arg::new("pairwise_divergence")
+                .long("pairwise-divergence")
+                .value_parser(value_parser!(Divergence))
+                .value_name("MEASURE")
+                .ignore_case(true)
+                .default_value("kl")
+                .requires("pairwise_kl")
+                .help("Divergence measure for --pairwise-kl (the KL distance is symmetrized by averaging both directions)"),
+        )
+        .arg(
+            Arg::new("fingerprints")
+                .long("fingerprints")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .action(ArgAction::Append)
+                .help("Fingerprint file from previous runs (may be given multiple times); adds Swap-check column flagging datasets closer to the gc and coverage fingerprint of another sample than to their own"),
+        )
+        .arg(
+            Arg::new("write_fingerprints")
+                .long("write-fingerprints")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .help("Write gc and coverage fingerprints of datasets with a sample name, for use with --fingerprints in later runs"),
+        )
+        .arg(
+            Arg::new("contamination_ref")
+                .long("contamination-ref")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("JSON")
+                .help("Contaminant reference distributions (e.g. bacterial panel); adds the contaminant fraction from a two-component mixture fit with the primary reference and its likelihood ratio p-value"),
+        )
+        .arg(
+            Arg::new("gof_test")
+                .long("gof-test")
+                .value_parser(value_parser!(GofTest))
+                .value_name("TEST")
+                .ignore_case(true)
+                .num_args(0..=1)
+                .default_missing_value("g")
+                .help("Test the fit of the binned sample gc distribution to the reference with a chi-square or G (likelihood ratio) test [default TEST: g]"),
+        )
+        .arg(
+            Arg::new("qual_bands")
+                .long("qual-bands")
+                .action(ArgAction::SetTrue)
+                .help("Output per cycle base composition by quality band and regression of the high quality fraction along reads (for inputs with quality stratified counts)"),
+        )
+        .arg(
+            Arg::new("cache_compression")
+                .long("cache-compression")
+                .value_parser(value_parser!(CacheCompression))
+                .value_name("METHOD")
+                .ignore_case(true)
+                .default_value("none")
+                .requires("cache_dir")
+                .help("Compression of results cache entries (zstd requires the zstd program to be installed)"),
+        )
+        .arg(
+            Arg::new("cache_dict")
+                .long("cache-dict")
+                .value_parser(value_parser!(u64).range(1..))
+                .value_name("N")
+                .requires("cache_dir")
+                .help("Train a zstd dictionary (kept in the cache directory for later runs) on the first N cache entries and use it to compress the remaining entries (requires --cache-compression zstd and the zstd program)"),
+        )
+        .arg(
+            Arg::new("merge_bisulfite_strands")
+                .long("merge-bisulfite-strands")
+                .action(ArgAction::SetTrue)
+                .help("Merge Forward and Reverse bisulfite datasets of a group into a NonStranded composite instead of failing on the bisulfite type mismatch"),
+        )
+        .arg(
+            Arg::new("consistency_report")
+                .long("consistency-report")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .help("Write table of the trim and min_qual values in each merge group to FILE (written to merge_consistency.tsv in the output directory if any group is inconsistent)"),
+        )
+        .arg(
+            Arg::new("read_ends")
+                .long("read-ends")
+                .action(ArgAction::SetTrue)
+                .help("Output gc content and gc regression along reads for R1 and R2 separately (kept when read ends are merged)"),
+        )
+        .arg(
+            Arg::new("cfdna")
+                .long("cfdna")
+                .action(ArgAction::SetTrue)
+                .help("Output fragment length proxy (from the short reads of R1 and R2, so read ends must be merged) and fragment end gc for datasets with library type cfDNA (NA for other datasets)"),
+        )
+        .arg(
+            Arg::new("split_read_ends")
+                .long("split-read-ends")
+                .action(ArgAction::SetTrue)
+                .help("Merge datasets from different read ends (R1/R2) separately, adding the read end to the merge key"),
+        )
+        .arg(
+            Arg::new("target_gc")
+                .long("target-gc")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .help("Target gc content (contig, start, end, gc) for estimating the gc bias factor of the panel targets and the expected mapped fraction"),
+        )
+        .arg(
+            Arg::new("expected_mapped_fraction")
+                .long("expected-mapped-fraction")
+                .value_parser(value_parser!(f64))
+                .value_name("FLOAT")
+                .requires("target_gc")
+                .help("Mapped fraction expected for the panel without gc bias; adds the expected mapped fraction given the gc bias and the observed/expected ratio"),
+        )
+        .arg(
+            Arg::new("print_config")
+                .long("print-config")
+                .value_parser(value_parser!(ConfigFormat))
                 .ignore_case(true)
-                .help("Set merge key"),
+                .value_name("FORMAT")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("toml")
+                .help("Print effective configuration (--print-config=json for JSON, default TOML) and exit"),
+        )
+        .arg(
+            Arg::new("ci_reps")
+                .long("ci-reps")
+                .value_parser(value_parser!(u64).range(2..))
+                .value_name("INT")
+                .default_value("200")
+                .help("Number of bootstrap replicates for KL distance confidence intervals"),
+        )
+        .arg(
+            Arg::new("schema")
+                .long("schema")
+                .value_parser(value_parser!(Schema))
+                .ignore_case(true)
+                .default_value("v2")
+                .help("Layout of main output (v1 for the layout of gc_collect 0.3, TSV only)"),
+        )
+        .arg(
+            Arg::new("sort_output")
+                .long("sort-output")
+                .value_parser(value_parser!(SortKey))
+                .ignore_case(true)
+                .value_name("KEY")
+                .help("Sort datasets in main output by input order (group order when merging), merge key (or file), gc, KL distance or median coverage [default: group order when merging, otherwise order of completion]"),
         )
         .arg(
             Arg::new("threads")
@@ -69,7 +405,24 @@ pub(super) fn cli_model() -> Command {
                 .long("reference-json")
                 .value_parser(value_parser!(PathBuf))
                 .value_name("FILE")
-                .help("Reference JSON file produced by analyze_ref_gc"),
+                .action(ArgAction::Append)
+                .help("Reference JSON file produced by analyze_ref_gc (a binary copy, FILE.gcref, is cached next to the file and can also be given directly).  If given multiple times, KL distances from each reference and the best matching reference are also reported"),
+        )
+        .arg(
+            Arg::new("expected_gc")
+                .long("expected-gc")
+                .value_parser(value_parser!(f64))
+                .value_name("FLOAT")
+                .conflicts_with_all(["ref", "self_reference"])
+                .help("Compare datasets against a synthetic reference for an organism with the given gc proportion (when no reference JSON is available)"),
+        )
+        .arg(
+            Arg::new("expected_gc_concentration")
+                .long("expected-gc-concentration")
+                .value_parser(value_parser!(f64))
+                .value_name("FLOAT")
+                .default_value("50")
+                .help("Concentration (alpha + beta) of the beta distribution of local gc for the synthetic reference (larger values give a narrower distribution)"),
         )
         .arg(
             Arg::new("kmers")
@@ -85,7 +438,472 @@ pub(super) fn cli_model() -> Command {
                 .long("output")
                 .value_parser(value_parser!(PathBuf))
                 .value_name("OUTPUT")
-                .help("Main output file [default: <stdout>]"),
+                .help("Main output file (- for stdout) [default: <stdout>]"),
+        )
+        .arg(
+            Arg::new("input_format")
+                .long("input-format")
+                .value_parser(value_parser!(InputFormat))
+                .ignore_case(true)
+                .default_value("auto")
+                .help("Format of input files (auto selects from file name; - reads from stdin)"),
+        )
+        .arg(
+            Arg::new("aligned_reads")
+                .long("aligned-reads")
+                .value_parser(value_parser!(AlignedReads))
+                .value_name("READS")
+                .ignore_case(true)
+                .default_value("all")
+                .help("Reads used from BAM/CRAM inputs (secondary and supplementary alignments are always skipped)"),
+        )
+        .arg(
+            Arg::new("format")
+                .short('F')
+                .long("format")
+                .value_parser(value_parser!(OutputFormat))
+                .ignore_case(true)
+                .default_value("tsv")
+                .help("Format of main output"),
+        )
+        .arg(
+            Arg::new("quiet_stdout")
+                .action(ArgAction::SetTrue)
+                .long("quiet-stdout")
+                .help("Only write the header and result rows (no metadata line) when main output is to stdout"),
+        )
+        .arg(
+            Arg::new("multiqc")
+                .long("multiqc")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Write MultiQC custom content files (gc_collect_*mqc.json) to output directory",
+                ),
+        )
+        .arg(
+            Arg::new("html_report")
+                .long("html-report")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("DIR")
+                .help("Write HTML QC report (index page and one page per dataset) to DIR"),
+        )
+        .arg(
+            Arg::new("thresholds")
+                .long("thresholds")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .help("QC thresholds file (JSON or TOML); adds QC-status column and exits with error if any dataset fails"),
+        )
+        .arg(
+            Arg::new("exclude_failing")
+                .long("exclude-failing")
+                .action(ArgAction::SetTrue)
+                .requires("thresholds")
+                .help("When merging, exclude members that individually fail the QC thresholds"),
+        )
+        .arg(
+            Arg::new("influence")
+                .long("influence")
+                .action(ArgAction::SetTrue)
+                .help("When merging, report the member with the largest leave-one-out effect on gc and KL distance"),
+        )
+        .arg(
+            Arg::new("member_kl")
+                .long("member-kl")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .help("When merging, write KL distances of members from their merged group to FILE and add max/mean columns"),
+        )
+        .arg(
+            Arg::new("kl_prior")
+                .long("kl-prior")
+                .value_parser(value_parser!(f64))
+                .value_name("FLOAT")
+                .default_value("0")
+                .help("Pseudo-count of uniform smoothing prior added to gc distributions before calculating KL distances"),
+        )
+        .arg(
+            Arg::new("skip_errors")
+                .long("skip-errors")
+                .action(ArgAction::SetTrue)
+                .help("Skip input files that can not be processed, listing them in failed_inputs.tsv, and exit with status 2"),
+        )
+        .arg(
+            Arg::new("divergence")
+                .long("divergence")
+                .value_parser(value_parser!(Divergence))
+                .value_name("MEASURE")
+                .ignore_case(true)
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .help("Divergence measure(s) from reference reported in addition to the KL distance (emd gives the earth mover's distance in gc percentage points)"),
+        )
+        .arg(
+            Arg::new("base_dist_ci")
+                .long("base-dist-ci")
+                .action(ArgAction::SetTrue)
+                .help("Add 95% (Wilson) confidence intervals for each base to the per cycle base_dist files"),
+        )
+        .arg(
+            Arg::new("base_dist_max_rows")
+                .long("base-dist-max-rows")
+                .value_parser(value_parser!(u64).range(1..))
+                .value_name("INT")
+                .help("Bin consecutive cycles in base_dist files to give at most INT rows"),
+        )
+        .arg(
+            Arg::new("bgzip_aux")
+                .long("bgzip-aux")
+                .action(ArgAction::SetTrue)
+                .help("Write auxiliary TSV files bgzip compressed with .gzi indexes (requires bgzip in the PATH)"),
+        )
+        .arg(
+            Arg::new("target_coverage")
+                .long("target-coverage")
+                .action(ArgAction::SetTrue)
+                .help("Write per target coverage (target_cov.tsv) auxiliary file for datasets with kmer counts"),
+        )
+        .arg(
+            Arg::new("no_aux")
+                .action(ArgAction::SetTrue)
+                .long("no-aux")
+                .help("Do not write auxiliary output files (gc_hist, base_dist)"),
+        )
+        .arg(
+            Arg::new("cache_dir")
+                .long("cache-dir")
+                .visible_alias("cache")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("DIR")
+                .help("Directory for cache of per input file analysis results")
+                .long_help("Directory for cache of per input file analysis results.  Input files are \
+identified by their contents, and files unchanged since an earlier run (same size and modification time) \
+are not read again to check this.  On a re-run only new or changed inputs are analyzed, and the cached \
+results are output for the others.  When merging, the counts from FASTQ, BAM and CRAM inputs are cached \
+so that unchanged inputs are re-merged without being counted again"),
+        )
+        .arg(
+            Arg::new("stream_socket")
+                .long("stream-socket")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("PATH")
+                .conflicts_with_all(["contrast", "self_reference"])
+                .help("Receive datasets from fastq_gc on Unix socket PATH instead of reading input files")
+                .long_help("Receive datasets from fastq_gc on Unix socket PATH instead of reading input files.  \
+Each dataset is sent as a frame of a 4 byte big-endian length followed by fastq_gc JSON, and each frame \
+is answered with a JSON frame giving the record number or an error.  A zero length frame ends the run \
+once all open connections have closed"),
+        )
+        .arg(
+            Arg::new("contrast")
+                .long("contrast")
+                .value_name("LIST A:LIST B")
+                .help("Compare pooled GC distributions of the inputs in two list files")
+                .long_help("Compare pooled GC distributions of the inputs in two list files.  The p-value \
+of the symmetric KL distance between the groups is estimated by a permutation test, randomly reassigning \
+the datasets to groups of the original sizes"),
+        )
+        .arg(
+            Arg::new("contrast_reps")
+                .long("contrast-reps")
+                .value_parser(value_parser!(u64).range(1..))
+                .value_name("INT")
+                .default_value("1000")
+                .help("Number of permutations for contrast p-value"),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_parser(value_parser!(u64))
+                .value_name("INT")
+                .help("Seed for random number generator"),
+        )
+        .arg(
+            Arg::new("status_file")
+                .long("status-file")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .help("Write JSON file with exit status, error counts and output files"),
+        )
+        .arg(
+            Arg::new("manifest")
+                .long("manifest")
+                .visible_alias("input-list")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .conflicts_with_all(["input", "contrast"])
+                .help("TSV file with input files and optional per file reference, kmcv, library_type, sample, barcode and library overrides"),
+        )
+        .arg(
+            Arg::new("input")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("INPUT")
+                .num_args(1..)
+                .required_unless_present_any(["contrast", "manifest", "stream_socket", "print_config"])
+                .help("Input JSON file(s) from fastq_gc (or FASTQ, BAM or CRAM files, which are counted directly)"),
+        )
+}
+
+fn report_command() -> Command {
+    Command::new("report")
+        .about("Regenerate report from existing results and auxiliary files without re-running analysis")
+        .arg(
+            Arg::new("format")
+                .short('f')
+                .long("format")
+                .value_parser(value_parser!(ReportFormat))
+                .ignore_case(true)
+                .default_value("markdown")
+                .help("Report format"),
+        )
+        .arg(
+            Arg::new("report_template")
+                .long("report-template")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("DIR")
+                .help(
+                    "Directory with Tera templates (report.html / report.md) to customize report",
+                ),
+        )
+        .arg(
+            Arg::new("fli_gc_tolerance")
+                .long("fli-gc-tolerance")
+                .value_parser(value_parser!(f64))
+                .value_name("FLOAT")
+                .default_value("0.01")
+                .help("Report flowcell/lane/index keys seen more than once with gc profiles (gc, frac-C, frac-G) differing by more than this"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("OUTPUT")
+                .help("Report output file (- for stdout) [default: <stdout>]"),
+        )
+        .arg(
+            Arg::new("input")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("INPUT")
+                .num_args(1..)
+                .required(true)
+                .help("Results tables (TSV or JSON, including per dataset JSON records) or gc_hist/base_dist auxiliary files from previous gc_collect runs"),
+        )
+}
+
+fn concat_command() -> Command {
+    Command::new("concat")
+        .about(
+            "Concatenate results tables (TSV or JSON) from previous runs, removing duplicate rows",
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("OUTPUT")
+                .help("Output file (- for stdout) [default: <stdout>]"),
+        )
+        .arg(
+            Arg::new("input")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("INPUT")
+                .num_args(1..)
+                .required(true)
+                .help("Results files from previous gc_collect runs (all TSV or all JSON)"),
+        )
+}
+
+fn validate_command() -> Command {
+    Command::new("validate")
+        .about("Check fastq_gc JSON files for problems without running the analysis")
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("OUTPUT")
+                .help("Output file for the report (- for stdout) [default: <stdout>]"),
+        )
+        .arg(
+            Arg::new("input")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("INPUT")
+                .num_args(1..)
+                .required(true)
+                .help("Input JSON file(s) from fastq_gc (- for stdin)"),
+        )
+}
+
+#[cfg(feature = "server")]
+fn serve_command() -> Command {
+    Command::new("serve")
+        .about("Run as a service, analyzing datasets submitted over HTTP with the analysis options given before the subcommand")
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .default_value("127.0.0.1:8080")
+                .help("Address and port to listen on"),
+        )
+}
+
+#[cfg(feature = "watch")]
+fn watch_command() -> Command {
+    Command::new("watch")
+        .about("Watch a directory for new fastq_gc JSON files, analyzing them as they appear with the analysis options given before the subcommand")
+        .arg(
+            Arg::new("existing")
+                .long("existing")
+                .action(ArgAction::SetTrue)
+                .help("Also analyze the JSON files already in the directory"),
+        )
+        .arg(
+            Arg::new("merged_output")
+                .long("merged-output")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FILE")
+                .help("Periodically rewrite FILE with the merged results of all datasets seen so far (requires --merge-by)"),
+        )
+        .arg(
+            Arg::new("merge_interval")
+                .long("merge-interval")
+                .value_parser(value_parser!(u64).range(1..))
+                .value_name("SECS")
+                .default_value("60")
+                .requires("merged_output")
+                .help("Interval between rewrites of the merged output, which is only rewritten if new datasets have arrived"),
+        )
+        .arg(
+            Arg::new("dir")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("DIR")
+                .required(true)
+                .help("Directory to watch"),
+        )
+}
+
+fn rescore_command() -> Command {
+    Command::new("rescore")
+        .about("Recalculate summary metrics from the gc_hist (and base_dist) files of a previous run")
+        .arg(
+            Arg::new("reference")
+                .short('r')
+                .long("reference")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("JSON")
+                .help("New reference distributions [default: reference densities in the gc_hist files]"),
+        )
+        .arg(
+            Arg::new("read_length")
+                .long("read-length")
+                .value_parser(value_parser!(u32).range(1..))
+                .value_name("INT")
+                .requires("reference")
+                .help("Read length used to select the reference distribution [default: number of cycles in the base_dist file]"),
+        )
+        .arg(
+            Arg::new("bisulfite")
+                .short('b')
+                .long("bisulfite")
+                .action(ArgAction::SetTrue)
+                .requires("reference")
+                .help("Compare against the bisulfite converted reference distributions"),
+        )
+        .arg(
+            Arg::new("divergence")
+                .long("divergence")
+                .value_parser(value_parser!(Divergence))
+                .value_name("MEASURE")
+                .ignore_case(true)
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .help("Divergence measure(s) from reference reported in addition to the KL distance"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("OUTPUT")
+                .help("Output file (- for stdout) [default: <stdout>]"),
+        )
+        .arg(
+            Arg::new("input")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("INPUT")
+                .num_args(1..)
+                .required(true)
+                .help("Input gc_hist.tsv file(s) from a previous run"),
+        )
+}
+
+fn build_ref_command() -> Command {
+    Command::new("build-ref")
+        .about("Build reference gc distributions from a FASTA file")
+        .arg(
+            Arg::new("read_lengths")
+                .short('L')
+                .long("read-lengths")
+                .value_parser(value_parser!(u32).range(1..))
+                .value_name("INT")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .required(true)
+                .help("Read length(s) for which to generate distributions"),
+        )
+        .arg(
+            Arg::new("bisulfite")
+                .short('b')
+                .long("bisulfite")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Also generate distributions for bisulfite converted reads (methylated CpGs)",
+                ),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("OUTPUT")
+                .help("Output reference JSON file (- for stdout) [default: <stdout>]"),
+        )
+        .arg(
+            Arg::new("fasta")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("FASTA")
+                .required(true)
+                .help("Input reference FASTA file (may be compressed)"),
+        )
+}
+
+fn panel_compare_command() -> Command {
+    Command::new("panel-compare")
+        .about("Compare target coverage of a dataset between old and new versions of a target panel")
+        .arg(
+            Arg::new("old")
+                .long("old")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("KM FILE")
+                .required(true)
+                .help("KM file for old panel"),
+        )
+        .arg(
+            Arg::new("new")
+                .long("new")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("KM FILE")
+                .required(true)
+                .help("KM file for new panel"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("OUTPUT")
+                .help("Output file (- for stdout) [default: <stdout>]"),
         )
         .arg(
             Arg::new("input")
@@ -93,17 +911,167 @@ pub(super) fn cli_model() -> Command {
                 .value_name("INPUT")
                 .num_args(1..)
                 .required(true)
-                .help("Input JSON file(s) from fastq_gc"),
+                .help("Input JSON file(s) from fastq_gc for the dataset with kmer counts for each panel"),
         )
 }
 
+fn selftest_command() -> Command {
+    Command::new("selftest")
+        .about("Run pipeline on synthetic data and check results against expected values")
+        .arg(
+            Arg::new("dir")
+                .short('d')
+                .long("dir")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("DIR")
+                .help(
+                    "Write (and keep) test data and outputs in DIR [default: temporary directory]",
+                ),
+        )
+        .arg(
+            Arg::new("tmpdir")
+                .long("tmpdir")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("DIR")
+                .help("Directory for temporary files [default: TMPDIR or the system temporary directory]"),
+        )
+}
+
+fn simulate_command() -> Command {
+    Command::new("simulate")
+        .about("Generate synthetic fastq_gc datasets for benchmarking and testing")
+        .arg(
+            Arg::new("outdir")
+                .short('o')
+                .long("outdir")
+                .value_parser(value_parser!(PathBuf))
+                .value_name("DIR")
+                .default_value(".")
+                .help("Output directory"),
+        )
+        .arg(
+            Arg::new("prefix")
+                .short('P')
+                .long("prefix")
+                .value_name("STRING")
+                .default_value("sim")
+                .help("Prefix for dataset names"),
+        )
+        .arg(
+            Arg::new("datasets")
+                .short('n')
+                .long("datasets")
+                .value_parser(value_parser!(u64).range(1..))
+                .value_name("INT")
+                .default_value("1")
+                .help("Number of datasets to generate"),
+        )
+        .arg(
+            Arg::new("read_length")
+                .short('L')
+                .long("read-length")
+                .value_parser(value_parser!(u32).range(1..))
+                .value_name("INT")
+                .default_value("150")
+                .help("Read length"),
+        )
+        .arg(
+            Arg::new("depth")
+                .short('d')
+                .long("depth")
+                .value_parser(value_parser!(u64).range(1..))
+                .value_name("INT")
+                .default_value("100000")
+                .help("Number of reads per dataset"),
+        )
+        .arg(
+            Arg::new("gc_mean")
+                .short('g')
+                .long("gc-mean")
+                .value_parser(value_parser!(f64))
+                .value_name("FLOAT")
+                .default_value("0.41")
+                .help("Mean GC proportion"),
+        )
+        .arg(
+            Arg::new("gc_overdispersion")
+                .short('r')
+                .long("gc-overdispersion")
+                .value_parser(value_parser!(f64))
+                .value_name("FLOAT")
+                .default_value("0.0")
+                .help("Overdispersion (intra-class correlation) of per read GC proportion"),
+        )
+        .arg(
+            Arg::new("kmers")
+                .long("kmers")
+                .short('k')
+                .value_parser(value_parser!(PathBuf))
+                .value_name("KM FILE")
+                .help("Input KM file used to generate per target kmer counts"),
+        )
+        .arg(
+            Arg::new("mapped_fraction")
+                .short('m')
+                .long("mapped-fraction")
+                .value_parser(value_parser!(f64))
+                .value_name("FLOAT")
+                .default_value("0.5")
+                .help("Fraction of reads mapping to targets"),
+        )
+        .arg(
+            Arg::new("seed")
+                .short('s')
+                .long("seed")
+                .value_parser(value_parser!(u64))
+                .value_name("INT")
+                .help("Seed for random number generator"),
+        )
+}
+
+/// Output format for --print-config
 #[derive(Debug, Clone, Copy)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+}
+
+impl ValueEnum for ConfigFormat {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Json, Self::Toml]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Json => Some(PossibleValue::new("json")),
+            Self::Toml => Some(PossibleValue::new("toml")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum MergeKey {
     Default,
     Sample,
     Barcode,
     Library,
     Fli,
+    Expr(MergeExpr),
+    Path(Regex),
+}
+
+impl FromStr for MergeKey {
+    type Err = anyhow::Error;
+
+    /// Parse one of the fixed keys (ignoring case) or expr:TEMPLATE
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.strip_prefix("expr:") {
+            Some(t) => Ok(Self::Expr(t.parse()?)),
+            None => {
+                <Self as ValueEnum>::from_str(s, true).map_err(|_| anyhow!("Invalid merge key {s}"))
+            }
+        }
+    }
 }
 
 impl ValueEnum for MergeKey {
@@ -124,6 +1092,7 @@ impl ValueEnum for MergeKey {
             Self::Barcode => Some(PossibleValue::new("barcode")),
             Self::Library => Some(PossibleValue::new("library")),
             Self::Fli => Some(PossibleValue::new("fli")),
+            Self::Expr(_) | Self::Path(_) => None,
         }
     }
 }
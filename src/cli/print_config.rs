@@ -0,0 +1,177 @@
+use serde_json::{Map, Value};
+
+use clap::{parser::ValueSource, ArgMatches, Command, ValueEnum};
+
+use super::{cli_model::ConfigFormat, Config, MergeKey};
+
+// Options that do not affect a run
+const SKIP_IDS: [&str; 3] = ["help", "version", "print_config"];
+
+// Values of option id, typed where the value parser gives a number or a bool.  Other
+// values (strings, paths and enums) are given as they appeared on the command line
+fn values(m: &ArgMatches, id: &str) -> Option<Vec<Value>> {
+    fn typed<T: Clone + Into<Value> + Send + Sync + 'static>(
+        m: &ArgMatches,
+        id: &str,
+    ) -> Option<Vec<Value>> {
+        m.try_get_many::<T>(id)
+            .ok()
+            .flatten()
+            .map(|v| v.cloned().map(Into::into).collect())
+    }
+
+    typed::<bool>(m, id)
+        .or_else(|| typed::<u64>(m, id))
+        .or_else(|| typed::<usize>(m, id))
+        .or_else(|| typed::<u32>(m, id))
+        .or_else(|| typed::<u8>(m, id))
+        .or_else(|| typed::<f64>(m, id))
+        .or_else(|| {
+            m.get_raw(id).map(|v| {
+                v.map(|s| Value::String(s.to_string_lossy().into_owned()))
+                    .collect()
+            })
+        })
+}
+
+fn source_name(s: ValueSource) -> &'static str {
+    match s {
+        ValueSource::DefaultValue => "default",
+        ValueSource::EnvVariable => "environment",
+        ValueSource::CommandLine => "command line",
+        _ => "unknown",
+    }
+}
+
+fn enum_value<T: ValueEnum>(x: &T) -> Value {
+    x.to_possible_value()
+        .map(|v| Value::String(v.get_name().to_owned()))
+        .unwrap_or(Value::Null)
+}
+
+fn path_value<P: AsRef<std::path::Path>>(p: P) -> Value {
+    Value::String(p.as_ref().display().to_string())
+}
+
+// Values of options as resolved by the configuration (i.e., after the thread count is
+// capped, input files are taken from the manifest etc.), by option id
+fn resolved_values(cfg: &Config) -> Vec<(&'static str, Vec<Value>)> {
+    let mut v = vec![
+        ("input", cfg.input_files().iter().map(path_value).collect()),
+        ("threads", vec![cfg.threads().into()]),
+        ("format", vec![enum_value(&cfg.output_format())]),
+        ("input_format", vec![enum_value(&cfg.input_format())]),
+        ("aligned_reads", vec![enum_value(&cfg.aligned_reads())]),
+        (
+            "sort_output",
+            cfg.sort_output().iter().map(enum_value).collect(),
+        ),
+    ];
+    // Merge keys from paths are given by the merge_by_path option
+    match cfg.merge_key() {
+        Some(MergeKey::Path(_)) => (),
+        Some(MergeKey::Expr(e)) => v.push(("merge_by", vec![format!("expr:{e}").into()])),
+        k => v.push(("merge_by", k.iter().map(|k| enum_value(*k)).collect())),
+    }
+    v
+}
+
+// Enum values are parsed ignoring case, so the resolved value may differ in case
+fn same_values(a: &[Value], b: &[Value]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(x, y)| match (x, y) {
+            (Value::String(x), Value::String(y)) => x.eq_ignore_ascii_case(y),
+            _ => x == y,
+        })
+}
+
+// Effective options as (name, value, source), where name is the long option name if present.
+// Where the configuration resolves the value of an option differently from the command line
+// (or the option is not set), the resolved value is given with source "resolved"
+fn effective_options(
+    cfg: &Config,
+    cmd: &Command,
+    m: &ArgMatches,
+) -> Vec<(String, Value, &'static str)> {
+    let resolved = resolved_values(cfg);
+    let mut v = Vec::new();
+    for arg in cmd.get_arguments() {
+        let id = arg.get_id().as_str();
+        if SKIP_IDS.contains(&id) {
+            continue;
+        }
+        let res = resolved.iter().find(|(k, _)| *k == id).map(|(_, x)| x);
+        let src = m.value_source(id).map(source_name);
+        let (vals, src) = match (values(m, id), res, src) {
+            (Some(x), Some(y), Some(src)) if same_values(&x, y) => (y.clone(), src),
+            (_, Some(y), _) if !y.is_empty() => (y.clone(), "resolved"),
+            (Some(x), None, Some(src)) => (x, src),
+            _ => continue,
+        };
+        let multiple = matches!(arg.get_action(), clap::ArgAction::Append)
+            || arg
+                .get_num_args()
+                .map(|r| r.max_values() > 1)
+                .unwrap_or(false);
+        let val = if multiple || vals.len() != 1 {
+            Value::Array(vals)
+        } else {
+            vals.into_iter().next().unwrap()
+        };
+        let name = arg.get_long().unwrap_or(id).to_owned();
+        v.push((name, val, src))
+    }
+    v
+}
+
+fn toml_value(v: &Value) -> String {
+    match v {
+        Value::Array(a) => {
+            let s: Vec<_> = a.iter().map(toml_value).collect();
+            format!("[{}]", s.join(", "))
+        }
+        // JSON string escapes are valid in TOML basic strings
+        _ => v.to_string(),
+    }
+}
+
+/// Effective configuration of a collect run (after applying defaults) in format fmt, giving
+/// the value and source of each option that is set
+pub(super) fn print_config(
+    cfg: &Config,
+    cmd: &Command,
+    m: &ArgMatches,
+    fmt: ConfigFormat,
+) -> String {
+    let opts = effective_options(cfg, cmd, m);
+    match fmt {
+        ConfigFormat::Json => {
+            let options: Map<_, _> = opts
+                .into_iter()
+                .map(|(k, v, s)| {
+                    let mut e = Map::new();
+                    e.insert("value".to_owned(), v);
+                    e.insert("source".to_owned(), Value::String(s.to_owned()));
+                    (k, Value::Object(e))
+                })
+                .collect();
+            let mut top = Map::new();
+            top.insert(
+                "gc_collect_version".to_owned(),
+                Value::String(env!("CARGO_PKG_VERSION").to_owned()),
+            );
+            top.insert("options".to_owned(), Value::Object(options));
+            format!("{:#}\n", Value::Object(top))
+        }
+        ConfigFormat::Toml => {
+            let mut s = format!(
+                "# Effective configuration for gc_collect {}\n",
+                env!("CARGO_PKG_VERSION")
+            );
+            for (k, v, src) in opts {
+                s.push_str(&format!("{k} = {} # {src}\n", toml_value(&v)))
+            }
+            s
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+// Bases are indexed A=0, C=1, G=2, T=3 for the k-mer tables
+fn base_index(c: u8) -> Option<usize> {
+    match c {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+// Index of k-mer s in a table of size 4^k.  K-mers with non ACGT bases return None
+fn kmer_index(s: &str, k: usize) -> anyhow::Result<Option<usize>> {
+    if s.len() != k {
+        return Err(anyhow!(
+            "Unexpected k-mer {s} in composition counts (expected k = {k})"
+        ));
+    }
+    Ok(s.bytes()
+        .try_fold(0, |ix, c| base_index(c).map(|b| (ix << 2) | b)))
+}
+
+fn kmer_table(h: &HashMap<String, u64>, k: usize) -> anyhow::Result<Vec<u64>> {
+    let mut v = vec![0; 1 << (2 * k)];
+    for (s, n) in h.iter() {
+        if let Some(ix) = kmer_index(s, k)? {
+            v[ix] += *n
+        }
+    }
+    Ok(v)
+}
+
+// Inverse of kmer_table
+fn kmer_hash(v: &[u64], k: usize) -> HashMap<String, u64> {
+    v.iter()
+        .enumerate()
+        .map(|(ix, n)| {
+            let s: String = (0..k)
+                .rev()
+                .map(|i| b"ACGT"[(ix >> (2 * i)) & 3] as char)
+                .collect();
+            (s, *n)
+        })
+        .collect()
+}
+
+/// Dinucleotide and (optional) trinucleotide composition counts from the input JSON
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Composition {
+    dinuc: Vec<u64>,
+    trinuc: Option<Vec<u64>>,
+}
+
+impl Composition {
+    /// Make from the optional dinucleotide and trinucleotide count hashes in the JSON file.
+    /// If only trinucleotide counts are present, the dinucleotide counts are derived from them
+    pub fn from_counts(
+        dinuc: Option<&HashMap<String, u64>>,
+        trinuc: Option<&HashMap<String, u64>>,
+    ) -> anyhow::Result<Option<Self>> {
+        let trinuc = trinuc.map(|h| kmer_table(h, 3)).transpose()?;
+        let dinuc = match (dinuc, trinuc.as_ref()) {
+            (Some(h), _) => kmer_table(h, 2)?,
+            (None, Some(t)) => t.chunks(4).map(|c| c.iter().sum()).collect(),
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Self { dinuc, trinuc }))
+    }
+
+    /// Merge counts from other.  Trinucleotide counts are only kept if present in both
+    pub fn add(&mut self, other: &Self) {
+        for (x, y) in self.dinuc.iter_mut().zip(other.dinuc.iter()) {
+            *x += *y
+        }
+        match (self.trinuc.as_mut(), other.trinuc.as_ref()) {
+            (Some(t1), Some(t2)) => {
+                for (x, y) in t1.iter_mut().zip(t2.iter()) {
+                    *x += *y
+                }
+            }
+            _ => self.trinuc = None,
+        }
+    }
+
+    /// Dinucleotide and (if present) trinucleotide counts as hashes keyed by k-mer, as in
+    /// the JSON file
+    pub fn to_counts(&self) -> (HashMap<String, u64>, Option<HashMap<String, u64>>) {
+        (
+            kmer_hash(&self.dinuc, 2),
+            self.trinuc.as_ref().map(|t| kmer_hash(t, 3)),
+        )
+    }
+
+    /// Dinucleotide relative abundances rho(XY) = f(XY) / (f(X) f(Y)), where the
+    /// mononucleotide frequencies are taken from the marginals of the dinucleotide counts
+    pub fn rho(&self) -> Option<[f64; 16]> {
+        let tot = self.dinuc.iter().sum::<u64>();
+        if tot == 0 {
+            return None;
+        }
+        let tot = tot as f64;
+        let mut f1 = [0.0; 4];
+        for (ix, n) in self.dinuc.iter().enumerate() {
+            let n = *n as f64 / (2.0 * tot);
+            f1[ix >> 2] += n;
+            f1[ix & 3] += n;
+        }
+        let mut rho = [f64::NAN; 16];
+        for (ix, (r, n)) in rho.iter_mut().zip(self.dinuc.iter()).enumerate() {
+            let e = f1[ix >> 2] * f1[ix & 3];
+            if e > 0.0 {
+                *r = *n as f64 / (tot * e)
+            }
+        }
+        Some(rho)
+    }
+}
+
+/// Summary metrics from dinucleotide composition
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CompositionMetrics {
+    cpg_obs_exp: Option<f64>,
+    dinuc_bias: Option<f64>,
+}
+
+impl CompositionMetrics {
+    /// CpG observed/expected ratio and dinucleotide bias (mean absolute deviation of rho from 1
+    /// over all dinucleotides, Karlin's delta)
+    pub fn from_composition(c: &Composition) -> Self {
+        match c.rho() {
+            Some(rho) => {
+                let cpg = rho[(1 << 2) | 2];
+                let v: Vec<_> = rho.iter().filter(|x| x.is_finite()).collect();
+                Self {
+                    cpg_obs_exp: Some(cpg).filter(|x| x.is_finite()),
+                    dinuc_bias: if v.is_empty() {
+                        None
+                    } else {
+                        Some(v.iter().map(|x| (*x - 1.0).abs()).sum::<f64>() / v.len() as f64)
+                    },
+                }
+            }
+            None => Self::default(),
+        }
+    }
+
+    pub fn cpg_obs_exp(&self) -> Option<f64> {
+        self.cpg_obs_exp
+    }
+
+    pub fn dinuc_bias(&self) -> Option<f64> {
+        self.dinuc_bias
+    }
+}
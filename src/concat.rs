@@ -0,0 +1,139 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::Path,
+};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+use serde_json::{Map, Value};
+
+use crate::{
+    cli::ConcatConfig,
+    results_table::{first_char, ResultsTable},
+};
+
+// Results files are taken as JSON if the first non-whitespace character is '['
+fn is_json(p: &Path) -> anyhow::Result<bool> {
+    Ok(first_char(p)? == Some(b'['))
+}
+
+// Concatenate TSV results tables.  Columns are taken in order of first appearance
+fn concat_tsv<W: Write>(files: &[&Path], wrt: &mut W) -> anyhow::Result<()> {
+    let tables = files
+        .iter()
+        .map(ResultsTable::read)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut columns: Vec<&str> = Vec::new();
+    let mut col_ix: HashMap<&str, usize> = HashMap::new();
+    for t in tables.iter() {
+        for c in t.header() {
+            col_ix.entry(c.as_str()).or_insert_with(|| {
+                columns.push(c.as_str());
+                columns.len() - 1
+            });
+        }
+    }
+
+    writeln!(wrt, "{}", columns.join("\t"))?;
+    let mut seen = HashSet::new();
+    let (mut n, mut dups) = (0, 0);
+    for t in tables.iter() {
+        let ix: Vec<_> = columns.iter().map(|c| t.column(c)).collect();
+        for r in t.rows() {
+            let row: Vec<_> = ix
+                .iter()
+                .map(|i| i.map(|i| r[i].as_str()).unwrap_or("NA"))
+                .collect();
+            let row = row.join("\t");
+            if seen.insert(row.clone()) {
+                writeln!(wrt, "{row}")?;
+                n += 1
+            } else {
+                dups += 1
+            }
+        }
+    }
+    info!(
+        "Wrote {n} rows with {} columns ({dups} duplicates removed)",
+        columns.len()
+    );
+    Ok(())
+}
+
+fn read_json_results(p: &Path) -> anyhow::Result<Vec<Map<String, Value>>> {
+    let rdr = CompressIo::new()
+        .path(p)
+        .bufreader()
+        .with_context(|| format!("Could not open {} for input", p.display()))?;
+    serde_json::from_reader(rdr).with_context(|| format!("Error parsing JSON from {}", p.display()))
+}
+
+// Concatenate JSON results.  Keys missing from a record are added with null values
+fn concat_json<W: Write>(files: &[&Path], wrt: &mut W) -> anyhow::Result<()> {
+    let results = files
+        .iter()
+        .map(|p| read_json_results(p))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let keys: HashSet<String> = results
+        .iter()
+        .flatten()
+        .flat_map(|m| m.keys().cloned())
+        .collect();
+
+    write!(wrt, "[")?;
+    let mut seen = HashSet::new();
+    let (mut n, mut dups) = (0, 0);
+    for mut m in results.into_iter().flatten() {
+        for k in keys.iter() {
+            if !m.contains_key(k) {
+                m.insert(k.clone(), Value::Null);
+            }
+        }
+        let s = serde_json::to_string(&m)?;
+        if seen.insert(s.clone()) {
+            write!(wrt, "{}\n  {s}", if n == 0 { "" } else { "," })?;
+            n += 1
+        } else {
+            dups += 1
+        }
+    }
+    writeln!(wrt, "\n]")?;
+    info!(
+        "Wrote {n} records with {} keys ({dups} duplicates removed)",
+        keys.len()
+    );
+    Ok(())
+}
+
+/// Concatenate results tables from previous runs, reconciling the column sets (missing
+/// columns are filled with NA) and removing duplicate rows.  All inputs must be either TSV or
+/// JSON results files, and the output has the same format as the inputs
+pub fn concat(cfg: &ConcatConfig) -> anyhow::Result<()> {
+    let files: Vec<_> = cfg.input_files().iter().map(|p| p.as_path()).collect();
+    let json = files
+        .iter()
+        .map(|p| is_json(p))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let use_json = json[0];
+    if let Some(i) = json.iter().position(|j| *j != use_json) {
+        return Err(anyhow!(
+            "Can not concatenate TSV and JSON results ({} and {})",
+            files[0].display(),
+            files[i].display()
+        ));
+    }
+
+    let mut wrt = CompressIo::new()
+        .opt_path(cfg.output_file())
+        .bufwriter()
+        .with_context(|| "Could not open output file")?;
+
+    if use_json {
+        concat_json(&files, &mut wrt)
+    } else {
+        concat_tsv(&files, &mut wrt)
+    }
+}
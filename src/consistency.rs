@@ -0,0 +1,118 @@
+//! Consistency of the processing parameters (trim and min_qual) of the members of each merge
+//! group.  Datasets processed with different parameters can not be merged, so rather than
+//! failing on the first mismatch the values for every group are collected and, if any group
+//! is inconsistent, a report is written before the run fails.  The report has one row per
+//! value of each inconsistent parameter of a group, giving the number of files with the value
+//! and the first such file, so the odd lane or file out is easy to find.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{cli::Config, read::DataSet, temp_dir::atomic_write};
+
+/// Parameters checked (in report order)
+const PARAMETERS: [&str; 2] = ["trim", "min_qual"];
+
+/// Default name of the report (placed as for the auxiliary files)
+const REPORT_NAME: &str = "merge_consistency.tsv";
+
+// Number of files and first file for each value of a parameter
+type ValueCounts = BTreeMap<usize, (usize, PathBuf)>;
+
+#[derive(Default)]
+pub struct Consistency {
+    groups: BTreeMap<String, [ValueCounts; 2]>,
+}
+
+impl Consistency {
+    /// Record the parameters of dataset d from input file p in merge group key.  Returns
+    /// true if the group is consistent so far
+    pub fn add(&mut self, key: &str, d: &DataSet, p: &Path) -> bool {
+        let g = self.groups.entry(key.to_owned()).or_default();
+        for (v, x) in g.iter_mut().zip([d.trim(), d.min_qual() as usize]) {
+            v.entry(x).or_insert_with(|| (0, p.to_owned())).0 += 1
+        }
+        g.iter().all(|v| v.len() == 1)
+    }
+
+    /// Number of groups with members processed with different parameters
+    pub fn n_inconsistent(&self) -> usize {
+        self.groups
+            .values()
+            .filter(|g| g.iter().any(|v| v.len() > 1))
+            .count()
+    }
+
+    fn report(&self) -> String {
+        let mut s = "Group\tParameter\tValue\tFiles\tFirst-file\n".to_owned();
+        for (key, g) in self.groups.iter() {
+            for (par, v) in PARAMETERS.iter().zip(g.iter()).filter(|(_, v)| v.len() > 1) {
+                for (x, (n, p)) in v.iter() {
+                    writeln!(s, "{key}\t{par}\t{x}\t{n}\t{}", p.display())
+                        .expect("Error formatting report")
+                }
+            }
+        }
+        s
+    }
+
+    /// Write the report if requested, or if any group is inconsistent, in which case an error
+    /// is returned
+    pub fn check(&self, cfg: &Config) -> anyhow::Result<()> {
+        let n = self.n_inconsistent();
+        let p = match cfg.consistency_report() {
+            Some(p) => p.to_owned(),
+            None if n > 0 => cfg.aux_path(Path::new(REPORT_NAME)),
+            None => return Ok(()),
+        };
+        debug!("Writing merge consistency report to {}", p.display());
+        let s = self.report();
+        atomic_write(&p, |tmp| {
+            std::fs::write(tmp, s)
+                .map_err(|e| anyhow!("Error writing consistency report {}: {e}", tmp.display()))
+        })?;
+        if n > 0 {
+            Err(anyhow!(
+                "Members of {n} merge group(s) were processed with different trim or min_qual settings (see {})",
+                p.display()
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn consistency_test() {
+        use crate::read::{Fli, TempDataSet};
+        use std::collections::HashMap;
+
+        let d = |q| {
+            let t = TempDataSet::new(
+                q,
+                Fli::default(),
+                vec![[1, 1, 1, 1, 0]],
+                HashMap::new(),
+                None,
+            );
+            DataSet::from_temp_dataset(t, Path::new("x.json")).unwrap()
+        };
+        let mut c = Consistency::default();
+        assert!(c.add("S1", &d(20), Path::new("a.json")));
+        assert!(c.add("S1", &d(20), Path::new("b.json")));
+        assert!(c.add("S2", &d(30), Path::new("c.json")));
+        assert!(!c.add("S1", &d(30), Path::new("d.json")));
+        assert_eq!(c.n_inconsistent(), 1);
+        assert_eq!(
+            c.report(),
+            "Group\tParameter\tValue\tFiles\tFirst-file\nS1\tmin_qual\t20\t2\ta.json\nS1\tmin_qual\t30\t1\td.json\n"
+        );
+    }
+}
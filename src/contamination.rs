@@ -0,0 +1,143 @@
+//! Estimation of contamination by fitting the gc distribution of a dataset as a two-component
+//! mixture of the primary reference and a contaminant reference (--contamination-ref).
+//!
+//! The gc counts of each read are modelled as beta-binomial with the beta mixture of the
+//! reference, so the likelihood of a read under a reference is the count weighted mean of
+//! B(a + a_r + 1, b + b_r + 1) / B(a_r + 1, b_r + 1) over the reference bins (the binomial
+//! coefficient is common to both components and cancels).  The log likelihood of the mixture
+//! is concave in the contaminant fraction, so the maximum is found by bisection on the
+//! derivative.  The fraction is tested against zero with a likelihood ratio test, taking the
+//! null distribution as the 50:50 mixture of a point mass at 0 and chi-square with 1 df as the
+//! null value is on the boundary of the parameter space.  With the read counts of typical
+//! datasets even small departures from the primary reference are highly significant, so the
+//! estimated fraction is usually the more useful measure.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    betabin::ref_log_likelihood,
+    reference::{GcHistKey, GcHistVal},
+    stats::chi_squared_cdf,
+};
+
+// Iterations of bisection for the contaminant fraction
+const BISECTION_ITER: usize = 60;
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Contamination {
+    fraction: f64,
+    p: f64,
+}
+
+// Log likelihood ratio of the mixture with contaminant fraction f against the primary
+// reference, and its derivative.  Each element of v is the read count and the difference in
+// log likelihood between the contaminant and primary references for a gc key
+fn mixture_llr(v: &[(f64, f64)], f: f64) -> (f64, f64) {
+    v.iter().fold((0.0, 0.0), |(l, dl), (n, d)| {
+        // Terms are scaled by the larger likelihood to avoid overflow
+        let (x, y) = if *d > 0.0 {
+            let s = (-d).exp();
+            (
+                d + ((1.0 - f) * s + f).ln(),
+                (1.0 - s) / ((1.0 - f) * s + f),
+            )
+        } else {
+            let r = d.exp();
+            ((1.0 - f + f * r).ln(), (r - 1.0) / (1.0 - f + f * r))
+        };
+        (l + n * x, dl + n * y)
+    })
+}
+
+// Maximum likelihood estimate of the contaminant fraction, with the log likelihood ratio
+// against no contamination
+fn fit_fraction(v: &[(f64, f64)]) -> (f64, f64) {
+    let f = if mixture_llr(v, 0.0).1 <= 0.0 {
+        0.0
+    } else if mixture_llr(v, 1.0).1 >= 0.0 {
+        1.0
+    } else {
+        let (mut lo, mut hi) = (0.0, 1.0);
+        for _ in 0..BISECTION_ITER {
+            let mid = 0.5 * (lo + hi);
+            if mixture_llr(v, mid).1 > 0.0 {
+                lo = mid
+            } else {
+                hi = mid
+            }
+        }
+        0.5 * (lo + hi)
+    };
+    (f, mixture_llr(v, f).0.max(0.0))
+}
+
+impl Contamination {
+    /// Fit the gc counts cts as a mixture of the primary and contaminant reference
+    /// distributions.  Returns None if there are no counts or either reference is empty
+    pub fn estimate(
+        cts: &[(GcHistKey, GcHistVal)],
+        primary: &[(GcHistKey, GcHistVal)],
+        contaminant: &[(GcHistKey, GcHistVal)],
+    ) -> Option<Self> {
+        let ln_total = |r: &[(GcHistKey, GcHistVal)]| {
+            let t: f64 = r.iter().map(|(_, v)| v.count()).sum();
+            (t > 0.0).then(|| t.ln())
+        };
+        let (tp, tc) = (ln_total(primary)?, ln_total(contaminant)?);
+        let v: Vec<_> = cts
+            .iter()
+            .filter(|(_, c)| c.count() > 0.0)
+            .map(|(k, c)| {
+                let (a, b) = k.counts();
+                (
+                    c.count(),
+                    ref_log_likelihood(a, b, contaminant, tc)
+                        - ref_log_likelihood(a, b, primary, tp),
+                )
+            })
+            .collect();
+        if v.is_empty() {
+            return None;
+        }
+        let (fraction, llr) = fit_fraction(&v);
+        let p = if llr > 0.0 {
+            0.5 * (1.0 - chi_squared_cdf(2.0 * llr, 1.0))
+        } else {
+            1.0
+        };
+        Some(Self { fraction, p })
+    }
+
+    /// Estimated contaminant fraction
+    pub fn fraction(&self) -> f64 {
+        self.fraction
+    }
+
+    /// Likelihood ratio test p-value for a non-zero contaminant fraction
+    pub fn p(&self) -> f64 {
+        self.p
+    }
+}
+
+/// Columns of the main TSV output for contamination estimate c (NA if not estimated)
+pub fn contamination_columns(c: Option<&Contamination>) -> String {
+    match c {
+        Some(c) => format!("\t{:.5}\t{:.5e}", c.fraction, c.p),
+        None => "\tNA\tNA".to_owned(),
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn fit_fraction_test() {
+        // Equal numbers of reads strongly favouring each component
+        let v = [(100.0, 20.0), (100.0, -20.0)];
+        assert!((fit_fraction(&v).0 - 0.5).abs() < 1e-6);
+        let (f, llr) = fit_fraction(&[(100.0, -1.0), (10.0, 0.0)]);
+        assert_eq!((f, llr), (0.0, 0.0));
+        assert_eq!(fit_fraction(&[(5.0, 3.0)]).0, 1.0);
+    }
+}
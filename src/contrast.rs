@@ -0,0 +1,183 @@
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use crate::{
+    bam::AlignedReads,
+    betabin::{mean_gc, smoothed_kl_distance},
+    cli::Config,
+    reference::{GcHistKey, GcHistVal},
+    source::{read_dataset, InputFormat},
+};
+
+type GcCounts = Vec<(GcHistKey, GcHistVal)>;
+
+fn read_list(p: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let rdr = CompressIo::new()
+        .path(p)
+        .bufreader()
+        .with_context(|| format!("Could not open list file {}", p.display()))?;
+    let mut v = Vec::new();
+    for l in rdr.lines() {
+        let l = l.with_context(|| format!("Error reading from {}", p.display()))?;
+        let l = l.trim();
+        if !(l.is_empty() || l.starts_with('#')) {
+            v.push(PathBuf::from(l))
+        }
+    }
+    if v.is_empty() {
+        Err(anyhow!("No input files found in list {}", p.display()))
+    } else {
+        Ok(v)
+    }
+}
+
+fn read_group(p: &Path, fmt: InputFormat, reads: AlignedReads) -> anyhow::Result<Vec<GcCounts>> {
+    let files = read_list(p)?;
+    let mut v = Vec::with_capacity(files.len());
+    for f in files {
+        trace!("Reading from {}", f.display());
+        let data = read_dataset(&f, fmt, reads)
+            .with_context(|| format!("Error reading from {}", f.display()))?;
+        for mut d in data {
+            d.mk_gc_counts()?;
+            v.push(d.gc_counts().unwrap().to_vec())
+        }
+    }
+    Ok(v)
+}
+
+// As the densities are count weighted mixtures, pooling the histograms of a set of
+// datasets is simply a matter of concatenating them
+fn pool<'a, I: Iterator<Item = &'a GcCounts>>(it: I) -> GcCounts {
+    it.flat_map(|c| c.iter().copied()).collect()
+}
+
+// Symmetric KL distance (Jeffreys divergence)
+fn divergence(a: &GcCounts, b: &GcCounts, prior: f64) -> (f64, f64) {
+    (
+        smoothed_kl_distance(a, b, prior),
+        smoothed_kl_distance(b, a, prior),
+    )
+}
+
+// Permutation test p-value for the symmetric divergence `obs` between the pooled groups,
+// found by randomly reassigning the datasets to groups of the original sizes
+fn permutation_p_value(
+    ga: &[GcCounts],
+    gb: &[GcCounts],
+    obs: f64,
+    prior: f64,
+    reps: usize,
+    rng: &mut StdRng,
+) -> f64 {
+    let na = ga.len();
+    let all: Vec<_> = ga.iter().chain(gb.iter()).collect();
+    let mut ix: Vec<usize> = (0..all.len()).collect();
+    let mut n_ge = 0;
+    for _ in 0..reps {
+        ix.shuffle(rng);
+        let a = pool(ix[..na].iter().map(|i| all[*i]));
+        let b = pool(ix[na..].iter().map(|i| all[*i]));
+        let (x, y) = divergence(&a, &b, prior);
+        if x + y >= obs {
+            n_ge += 1
+        }
+    }
+    (n_ge + 1) as f64 / (reps + 1) as f64
+}
+
+/// Compare the pooled GC distributions of two groups of datasets, estimating the
+/// significance of the divergence with a permutation test on the group labels
+pub fn contrast(cfg: &Config, lists: &(PathBuf, PathBuf)) -> anyhow::Result<()> {
+    let ga = read_group(&lists.0, cfg.input_format(), cfg.aligned_reads())?;
+    let gb = read_group(&lists.1, cfg.input_format(), cfg.aligned_reads())?;
+    let (na, nb) = (ga.len(), gb.len());
+    info!("Contrasting {na} datasets against {nb} datasets");
+
+    let pa = pool(ga.iter());
+    let pb = pool(gb.iter());
+    let (kl_ab, kl_ba) = divergence(&pa, &pb, cfg.kl_prior());
+    let obs = kl_ab + kl_ba;
+
+    let mut rng = match cfg.seed() {
+        Some(s) => StdRng::seed_from_u64(s),
+        None => StdRng::from_entropy(),
+    };
+    let reps = cfg.contrast_reps();
+    let p = permutation_p_value(&ga, &gb, obs, cfg.kl_prior(), reps, &mut rng);
+
+    let mut wrt = CompressIo::new()
+        .opt_path(cfg.output_file())
+        .bufwriter()
+        .with_context(|| "Could not open output file")?;
+
+    writeln!(
+        wrt,
+        "Group-A\tGroup-B\tN-A\tN-B\tgc-A\tgc-B\tKL(A||B)\tKL(B||A)\tSymmetric-KL\tPermutations\tPermutation-p-value"
+    )?;
+    writeln!(
+        wrt,
+        "{}\t{}\t{na}\t{nb}\t{:.5}\t{:.5}\t{:.5}\t{:.5}\t{:.5}\t{reps}\t{:.5}",
+        lists.0.display(),
+        lists.1.display(),
+        mean_gc(&pa),
+        mean_gc(&pb),
+        kl_ab,
+        kl_ba,
+        obs,
+        p
+    )?;
+    Ok(())
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn counts(at: u32, gc: u32, n: u64) -> GcCounts {
+        let k = GcHistKey::new(at, gc);
+        vec![(k, GcHistVal::make(&k, n))]
+    }
+
+    #[test]
+    fn read_list_test() {
+        let p = std::env::temp_dir().join(format!("gc_collect_test_{}.list", std::process::id()));
+        std::fs::write(&p, "# Group A\na.json\n\n  b.json  \n").unwrap();
+        let v = read_list(&p);
+        std::fs::write(&p, "# Empty\n").unwrap();
+        let e = read_list(&p);
+        let _ = std::fs::remove_file(&p);
+        assert_eq!(
+            v.unwrap(),
+            vec![PathBuf::from("a.json"), PathBuf::from("b.json")]
+        );
+        assert!(e.is_err());
+    }
+
+    #[test]
+    fn pool_test() {
+        let v = [counts(50, 50, 10), counts(40, 60, 5)];
+        let p = pool(v.iter());
+        assert_eq!(p.len(), 2);
+        assert_eq!(p[1].0, GcHistKey::new(40, 60));
+    }
+
+    #[test]
+    fn permutation_p_value_test() {
+        let p_value = |ga: &[GcCounts], gb: &[GcCounts]| {
+            let (x, y) = divergence(&pool(ga.iter()), &pool(gb.iter()), 1.0);
+            permutation_p_value(ga, gb, x + y, 1.0, 200, &mut StdRng::seed_from_u64(42))
+        };
+        let ga: Vec<_> = (0..5).map(|i| counts(50 + i, 50 - i, 1000)).collect();
+        let gb: Vec<_> = (0..5).map(|i| counts(70 + i, 30 - i, 1000)).collect();
+        assert!(p_value(&ga, &ga) > 0.5);
+        assert!(p_value(&ga, &gb) < 0.05);
+    }
+}
@@ -0,0 +1,184 @@
+//! Direct FASTQ input (--input-format fastq, or FASTQ file names with automatic format
+//! selection), so that small datasets can be checked without running fastq_gc first.
+//!
+//! Reads are counted as fastq_gc does with no trimming or bisulfite conversion: bases with a
+//! quality below FASTQ_MIN_QUAL (or that are not A, C, G or T) are counted as N and left out
+//! of the per read gc counts.  Qualities are assumed to be Phred+33.  Flowcell, lane, read end
+//! and index are taken from the first read header if it is in the Illumina (CASAVA 1.8+)
+//! format.  Kmer counts are not generated as the kmer index is not part of the KM file
+//! information used by gc_collect, so target coverage is not available for FASTQ inputs.
+
+use std::{collections::HashMap, io::BufRead, path::Path};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+
+use crate::{
+    read::{DataSet, Fli, TempDataSet},
+    source::is_stdin,
+};
+
+/// Bases with quality below this value are counted as N (also used for BAM/CRAM input)
+pub const FASTQ_MIN_QUAL: u8 = 20;
+
+// Offset of Phred quality scores in FASTQ quality strings
+const QUAL_OFFSET: u8 = 33;
+
+// Flowcell, lane, read end and index from an Illumina read header of the form
+// @instrument:run:flowcell:lane:tile:x:y read_end:filtered:control:index
+pub(crate) fn fli_from_header(h: &str) -> Fli {
+    let mut fli = Fli::default();
+    let mut it = h.trim_start_matches('@').split_whitespace();
+    let fields: Vec<_> = it.next().unwrap_or("").split(':').collect();
+    if fields.len() == 7 {
+        if let Ok(lane) = fields[3].parse::<u8>() {
+            fli = Fli::new(None, None, Some(fields[2].to_owned()), None, Some(lane))
+        }
+    }
+    if let Some(c) = it.next() {
+        let comment: Vec<_> = c.split(':').collect();
+        if comment.len() == 4 {
+            if let Ok(r) = comment[0].parse::<u8>() {
+                fli.set_read_end(r)
+            }
+            if !comment[3].is_empty() {
+                fli.set_index(comment[3].to_owned())
+            }
+        }
+    }
+    fli
+}
+
+// Index in [A, C, G, T, N] counts for a base call
+fn base_ix(b: u8) -> usize {
+    match b {
+        b'A' | b'a' => 0,
+        b'C' | b'c' => 1,
+        b'G' | b'g' => 2,
+        b'T' | b't' => 3,
+        _ => 4,
+    }
+}
+
+/// Per position and per read gc counts accumulated from reads as fastq_gc counts them
+#[derive(Default)]
+pub(crate) struct ReadCounter {
+    per_pos: Vec<[u64; 5]>,
+    gc_hash: HashMap<String, u64>,
+    n_reads: u64,
+}
+
+impl ReadCounter {
+    /// Add a read given as (base, Phred quality) pairs in sequencing order
+    pub(crate) fn add_read<I>(&mut self, bases: I)
+    where
+        I: ExactSizeIterator<Item = (u8, u8)>,
+    {
+        if self.per_pos.len() < bases.len() {
+            self.per_pos.resize(bases.len(), [0; 5])
+        }
+        let (mut at, mut gc) = (0, 0);
+        for ((c, q), ct) in bases.zip(self.per_pos.iter_mut()) {
+            let ix = if q < FASTQ_MIN_QUAL { 4 } else { base_ix(c) };
+            ct[ix] += 1;
+            match ix {
+                0 | 3 => at += 1,
+                1 | 2 => gc += 1,
+                _ => (),
+            }
+        }
+        if at + gc > 0 {
+            *self.gc_hash.entry(format!("{at}:{gc}")).or_insert(0) += 1
+        }
+        self.n_reads += 1
+    }
+
+    pub(crate) fn n_reads(&self) -> u64 {
+        self.n_reads
+    }
+
+    pub(crate) fn into_temp_dataset(self, fli: Fli) -> TempDataSet {
+        let mut t = TempDataSet::new(FASTQ_MIN_QUAL, fli, self.per_pos, self.gc_hash, None);
+        t.drop_zero_n();
+        t
+    }
+}
+
+/// Count the reads from FASTQ records read from rdr (name is used in error messages)
+pub fn count_fastq<R: BufRead>(mut rdr: R, name: &str) -> anyhow::Result<TempDataSet> {
+    let mut counter = ReadCounter::default();
+    let mut fli: Option<Fli> = None;
+    let mut lines: [String; 4] = Default::default();
+    let mut n_rec = 0;
+    'records: loop {
+        for (i, l) in lines.iter_mut().enumerate() {
+            l.clear();
+            let n = rdr
+                .read_line(l)
+                .with_context(|| format!("Error reading from {name}"))?;
+            if n == 0 {
+                if i == 0 {
+                    break 'records;
+                }
+                return Err(anyhow!("{name}: truncated record {}", n_rec + 1));
+            }
+            l.truncate(l.trim_end().len())
+        }
+        n_rec += 1;
+        let [hdr, seq, plus, qual] = &lines;
+        if !hdr.starts_with('@') || !plus.starts_with('+') {
+            return Err(anyhow!("{name}: record {n_rec} is not in FASTQ format"));
+        }
+        if seq.len() != qual.len() {
+            return Err(anyhow!(
+                "{name}: record {n_rec} has sequence and quality strings of different lengths"
+            ));
+        }
+        if fli.is_none() {
+            fli = Some(fli_from_header(hdr))
+        }
+        counter.add_read(
+            seq.bytes()
+                .zip(qual.bytes().map(|q| q.saturating_sub(QUAL_OFFSET))),
+        )
+    }
+    if n_rec == 0 {
+        return Err(anyhow!("No reads found in {name}"));
+    }
+    debug!("Counted {n_rec} reads from {name}");
+    Ok(counter.into_temp_dataset(fli.unwrap_or_default()))
+}
+
+/// Read dataset from FASTQ file p (optionally compressed).  If p is '-', reads from stdin
+/// and the dataset is given the path 'stdin'
+pub fn read_fastq(p: &Path) -> anyhow::Result<Vec<DataSet>> {
+    let (path, name) = if is_stdin(p) {
+        (None, Path::new("stdin"))
+    } else {
+        (Some(p), p)
+    };
+    let rdr = CompressIo::new()
+        .opt_path(path)
+        .bufreader()
+        .with_context(|| format!("Could not open {} for input", name.display()))?;
+    let t = count_fastq(rdr, &name.display().to_string())?;
+    Ok(vec![DataSet::from_temp_dataset(t, name)?])
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn count_fastq_test() {
+        let s = "@M1:7:FC12:2:1101:100:200 1:N:0:ACGTAC\nACGTN\n+\nIIII#\n\
+                 @M1:7:FC12:2:1101:100:300 1:N:0:ACGTAC\nGGC\n+\nI#I\n";
+        let t = count_fastq(s.as_bytes(), "test").unwrap();
+        let mut d = DataSet::from_temp_dataset(t, Path::new("test.fastq")).unwrap();
+        assert_eq!(d.n_reads(), 2);
+        assert_eq!(d.max_read_len(), 5);
+        assert_eq!(d.per_pos_cts()[1].cts(), &[0, 1, 0, 0, 1]);
+        assert_eq!(d.fli_mut().fli().as_deref(), Some("FC12_2_ACGTAC"));
+        assert!(count_fastq("@r\nAC\n+\nI\n".as_bytes(), "test").is_err());
+    }
+}
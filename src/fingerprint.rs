@@ -0,0 +1,233 @@
+//! Sample swap screen from gc and coverage fingerprints (--fingerprints).
+//!
+//! The fingerprint of a dataset combines its (smoothed) gc distribution with the coverage of
+//! each contig relative to the mean coverage of all targets.  The distance between two
+//! fingerprints is the sum of the Jensen-Shannon distance between the gc distributions (the
+//! square root of the JS divergence in bits, so in [0, 1]) and the root mean square difference
+//! in log2 relative coverage over the contigs present in both fingerprints.  So that the
+//! distances of a dataset from different historical fingerprints are comparable, the coverage
+//! term is only used if the dataset shares contigs with all historical fingerprints (i.e., if
+//! all were generated with kmer counts for the same panel); otherwise only the gc distributions
+//! are compared.
+//!
+//! A dataset is flagged as a possible swap if its fingerprint is closer to the historical
+//! fingerprint of another sample than to any historical fingerprint of its own sample.  No
+//! genotypes are needed, but the screen relies on samples differing in gc profile or copy
+//! number, so a swap between similar samples can not be detected.
+
+use std::{collections::BTreeMap, io::Write, path::Path};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    betabin::{Divergence, TabulatedDensity},
+    kmcv::Kmcv,
+    kmers::Coverage,
+    read::{DataSet, DataSummary},
+    status::RunStatus,
+    temp_dir::atomic_write,
+    utils::tsv_escape,
+};
+
+/// Column of the main output with the result of the swap screen
+pub const SWAP_CHECK_COLUMN: &str = "Swap-check";
+
+// Relative coverages are floored at this value before taking logs so that contigs without
+// coverage give a finite distance
+const MIN_RELATIVE_COVERAGE: f64 = 0.01;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Fingerprint {
+    sample: String,
+    gc_density: TabulatedDensity,
+    // Coverage of each contig relative to the mean coverage over all targets
+    contig_coverage: BTreeMap<String, f64>,
+}
+
+impl Fingerprint {
+    /// Fingerprint of dataset d, with the gc distribution smoothed with a prior of `prior`
+    /// pseudo-reads.  Returns None if d has no sample name or no gc counts
+    pub fn make(d: &DataSet, kmcv: Option<&Kmcv>, prior: f64) -> Option<Self> {
+        let sample = d.sample()?.to_owned();
+        let gc_density = TabulatedDensity::new(d.gc_counts()?, prior)?;
+        let contig_coverage = match (d.kmer_counts(), kmcv) {
+            (Some(kc), Some(k)) => contig_coverage(&kc.target_coverage(k), k),
+            _ => BTreeMap::new(),
+        };
+        Some(Self {
+            sample,
+            gc_density,
+            contig_coverage,
+        })
+    }
+
+    pub fn sample(&self) -> &str {
+        &self.sample
+    }
+
+    // Contigs with coverage are shared with other
+    fn shares_contigs(&self, other: &Self) -> bool {
+        self.contig_coverage
+            .keys()
+            .any(|c| other.contig_coverage.contains_key(c))
+    }
+
+    /// Distance from other, including the coverage term if use_coverage is set
+    pub fn distance(&self, other: &Self, use_coverage: bool) -> f64 {
+        let js = self
+            .gc_density
+            .divergence(&other.gc_density, Divergence::Js);
+        let gc_dist = (js / std::f64::consts::LN_2).max(0.0).sqrt();
+        if !use_coverage {
+            return gc_dist;
+        }
+        let (ss, n) = self
+            .contig_coverage
+            .iter()
+            .filter_map(|(c, x)| other.contig_coverage.get(c).map(|y| (*x, *y)))
+            .fold((0.0, 0), |(ss, n), (x, y)| {
+                let f = |z: f64| z.max(MIN_RELATIVE_COVERAGE).log2();
+                let d = f(x) - f(y);
+                (ss + d * d, n + 1)
+            });
+        let cov_dist = if n > 0 { (ss / n as f64).sqrt() } else { 0.0 };
+        gc_dist + cov_dist
+    }
+}
+
+// Coverage of each contig (over its targets) relative to the mean coverage of all targets,
+// from the mean coverage per target.  Contigs without targets are omitted
+fn contig_coverage(cov: &[Coverage], kmcv: &Kmcv) -> BTreeMap<String, f64> {
+    let mut bases: BTreeMap<&str, (f64, f64)> = BTreeMap::new();
+    let (mut tot_bases, mut tot_span) = (0.0, 0.0);
+    for (ix, c) in cov.iter().enumerate() {
+        let (ctg, t) = kmcv.get_target_location(ix).expect("Bad target ix");
+        let (b, s) = (c.get() * t.size() as f64, t.size() as f64);
+        let e = bases.entry(ctg).or_insert((0.0, 0.0));
+        e.0 += b;
+        e.1 += s;
+        tot_bases += b;
+        tot_span += s;
+    }
+    if tot_bases <= 0.0 {
+        return BTreeMap::new();
+    }
+    let mean = tot_bases / tot_span;
+    bases
+        .into_iter()
+        .filter(|(_, (_, s))| *s > 0.0)
+        .map(|(c, (b, s))| (c.to_owned(), b / s / mean))
+        .collect()
+}
+
+/// Result of the swap screen for one dataset
+pub enum SwapCheck {
+    /// No sample name or no historical fingerprint for the sample
+    Unknown,
+    Ok,
+    /// Closer to the historical fingerprint of the given other sample
+    Swap(String),
+}
+
+impl SwapCheck {
+    /// Value for the TSV column
+    pub fn column(&self) -> String {
+        match self {
+            Self::Unknown => "\tNA".to_owned(),
+            Self::Ok => "\tOK".to_owned(),
+            Self::Swap(s) => format!("\tCloser to {}", tsv_escape(s)),
+        }
+    }
+
+    /// Value for JSON output
+    pub fn json(&self) -> serde_json::Value {
+        match self {
+            Self::Unknown => serde_json::Value::Null,
+            Self::Ok => "OK".into(),
+            Self::Swap(s) => format!("Closer to {s}").into(),
+        }
+    }
+}
+
+/// Historical fingerprints read from the fingerprint files of previous runs
+pub struct FingerprintDb {
+    fingerprints: Vec<Fingerprint>,
+}
+
+impl FingerprintDb {
+    pub fn from_files<P: AsRef<Path>>(files: &[P]) -> anyhow::Result<Self> {
+        let mut fingerprints = Vec::new();
+        for p in files {
+            let p = p.as_ref();
+            let rdr = CompressIo::new().path(p).bufreader().with_context(|| {
+                format!("Could not open fingerprint file {} for input", p.display())
+            })?;
+            let v: Vec<Fingerprint> = serde_json::from_reader(rdr)
+                .with_context(|| format!("Error reading fingerprints from {}", p.display()))?;
+            if v.iter()
+                .any(|f| f.gc_density.n_points() != TabulatedDensity::N_POINTS)
+            {
+                return Err(anyhow!(
+                    "Fingerprint file {} has gc densities with the wrong number of points",
+                    p.display()
+                ));
+            }
+            debug!("Read {} fingerprints from {}", v.len(), p.display());
+            fingerprints.extend(v)
+        }
+        info!("Read {} historical fingerprints", fingerprints.len());
+        Ok(Self { fingerprints })
+    }
+
+    /// Check the fingerprint fp of dataset d against the historical fingerprints
+    pub fn check(&self, d: &DataSummary, fp: Option<&Fingerprint>) -> SwapCheck {
+        let Some(fp) = fp else {
+            return SwapCheck::Unknown;
+        };
+        let use_coverage = self.fingerprints.iter().all(|h| fp.shares_contigs(h));
+        let (mut own, mut other): (Option<f64>, Option<(f64, &str)>) = (None, None);
+        for h in self.fingerprints.iter() {
+            let x = fp.distance(h, use_coverage);
+            if h.sample == fp.sample {
+                own = Some(own.map(|y| y.min(x)).unwrap_or(x))
+            } else if other.map(|(y, _)| x < y).unwrap_or(true) {
+                other = Some((x, h.sample.as_str()))
+            }
+        }
+        match (own, other) {
+            (None, _) => SwapCheck::Unknown,
+            (Some(x), Some((y, s))) if y < x => {
+                warn!(
+                    "{} (sample {}) is closer to the fingerprint of sample {s} ({y:.4}) than to its own ({x:.4})",
+                    d.path().display(),
+                    fp.sample
+                );
+                SwapCheck::Swap(s.to_owned())
+            }
+            _ => SwapCheck::Ok,
+        }
+    }
+}
+
+/// Write fingerprints as a JSON array to p (to be used as historical fingerprints for
+/// later runs)
+pub fn write_fingerprints(
+    fingerprints: &[Fingerprint],
+    p: &Path,
+    status: &RunStatus,
+) -> anyhow::Result<()> {
+    atomic_write(p, |tmp| {
+        let mut wrt = CompressIo::new()
+            .path(tmp)
+            .bufwriter()
+            .with_context(|| format!("Could not open fingerprint file {}", p.display()))?;
+        serde_json::to_writer(&mut wrt, fingerprints)
+            .with_context(|| format!("Error writing fingerprints to {}", p.display()))?;
+        writeln!(wrt)?;
+        Ok(wrt.flush()?)
+    })?;
+    status.add_output(p);
+    Ok(())
+}
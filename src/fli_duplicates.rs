@@ -0,0 +1,140 @@
+//! Detection of flowcell/lane/index (FLI) keys that appear more than once in historical
+//! results tables with materially different gc profiles.
+//!
+//! The same FLI key should always describe the same data, so a key seen with different
+//! profiles points to a relabelled rerun or a sample mix-up in the LIMS that can not be seen
+//! from the QC of a single run.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::results_table::ResultsTable;
+
+/// Columns identifying a dataset.  The read end is included so that the two ends of a read
+/// pair are not compared with each other
+const FLI_COLUMNS: [&str; 4] = ["Flowcell", "Lane", "Index", "Read-end"];
+
+/// Columns making up the gc profile of a dataset (those present in a table are used)
+const PROFILE_COLUMNS: [&str; 3] = ["gc", "frac-C", "frac-G"];
+
+// Values of the profile columns for a dataset, with the file it came from
+type Profile = (String, Vec<Option<f64>>);
+
+/// FLI key seen with profiles differing by more than the tolerance
+#[derive(Serialize)]
+pub struct FliDuplicate {
+    flowcell: String,
+    lane: String,
+    index: String,
+    read_end: String,
+    n: usize,
+    max_difference: f64,
+    files: Vec<String>,
+}
+
+impl FliDuplicate {
+    pub fn key(&self) -> String {
+        format!(
+            "{}:{}:{}:{}",
+            self.flowcell, self.lane, self.index, self.read_end
+        )
+    }
+    pub fn n(&self) -> usize {
+        self.n
+    }
+    pub fn max_difference(&self) -> f64 {
+        self.max_difference
+    }
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+}
+
+// Largest absolute difference between two profiles over the columns present in both
+fn profile_difference(a: &[Option<f64>], b: &[Option<f64>]) -> Option<f64> {
+    a.iter()
+        .zip(b)
+        .filter_map(|(x, y)| x.zip(*y).map(|(x, y)| (x - y).abs()))
+        .reduce(f64::max)
+}
+
+/// Find FLI keys in the tables whose profiles differ by more than tol.  Tables without the
+/// FLI columns are skipped, as are rows with any FLI column missing (NA)
+pub fn find_fli_duplicates(tables: &[ResultsTable], tol: f64) -> Vec<FliDuplicate> {
+    let mut groups: BTreeMap<Vec<String>, Vec<Profile>> = BTreeMap::new();
+    for t in tables {
+        let Some(key_ix) = FLI_COLUMNS
+            .iter()
+            .map(|c| t.column(c))
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+        let file_ix = t.column("File");
+        let prof_ix: Vec<_> = PROFILE_COLUMNS.iter().map(|c| t.column(c)).collect();
+        for r in t.rows() {
+            let key: Vec<_> = key_ix.iter().map(|i| r[*i].clone()).collect();
+            if key.iter().any(|s| s.is_empty() || s == "NA") {
+                continue;
+            }
+            let file = file_ix.map(|i| r[i].clone()).unwrap_or_else(|| "NA".into());
+            let prof = prof_ix
+                .iter()
+                .map(|i| {
+                    i.and_then(|i| r[i].parse::<f64>().ok())
+                        .filter(|x| x.is_finite())
+                })
+                .collect();
+            groups.entry(key).or_default().push((file, prof))
+        }
+    }
+
+    let mut dups = Vec::new();
+    for (key, v) in groups.into_iter().filter(|(_, v)| v.len() > 1) {
+        let max_difference = v
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (_, a))| v[i + 1..].iter().map(move |(_, b)| (a, b)))
+            .filter_map(|(a, b)| profile_difference(a, b))
+            .fold(0.0, f64::max);
+        if max_difference > tol {
+            let mut files: Vec<_> = v.iter().map(|(f, _)| f.clone()).collect();
+            files.sort_unstable();
+            files.dedup();
+            let [flowcell, lane, index, read_end] = <[String; 4]>::try_from(key).unwrap();
+            dups.push(FliDuplicate {
+                flowcell,
+                lane,
+                index,
+                read_end,
+                n: v.len(),
+                max_difference,
+                files,
+            })
+        }
+    }
+    for d in dups.iter() {
+        warn!(
+            "FLI {} seen {} times with gc profiles differing by up to {:.4} ({})",
+            d.key(),
+            d.n,
+            d.max_difference,
+            d.files.join(", ")
+        )
+    }
+    dups
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn profile_difference_test() {
+        let a = [Some(0.41), Some(0.2), None];
+        let b = [Some(0.45), Some(0.21), Some(0.2)];
+        assert!((profile_difference(&a, &b).unwrap() - 0.04).abs() < 1e-12);
+        assert_eq!(profile_difference(&a, &[None, None, Some(0.1)]), None);
+    }
+}
@@ -0,0 +1,132 @@
+//! Fragment length proxy and fragment end composition metrics for cfDNA libraries (--cfdna).
+//!
+//! Reads shorter than the maximum read length are taken as coming from fragments shorter
+//! than the read length (adapter read through), in which case both reads of a pair are
+//! trimmed to the fragment length.  The fragment length proxy is the mean length of these
+//! short reads pooled over R1 and R2, and is only calculated for datasets with per cycle counts
+//! for both read ends (i.e., where R1 and R2 have been merged).  The fragment end gc is the gc
+//! content of the first cycles of the reads (the 5' ends of the fragment).
+
+use serde::{Deserialize, Serialize};
+
+use crate::read::{Counts, DataSet};
+
+/// Number of cycles at the 5' end of the reads used to estimate fragment end composition
+pub const END_CYCLES: usize = 10;
+
+/// Column names in TSV output
+pub const CFDNA_COLUMNS: [&str; 4] = [
+    "cfDNA-frag-length",
+    "cfDNA-short-frac",
+    "cfDNA-end-gc",
+    "cfDNA-end-gc-ratio",
+];
+
+// GC fraction (excluding Ns) over a set of per cycle counts
+fn gc_frac<'a, I: Iterator<Item = &'a Counts>>(it: I) -> Option<f64> {
+    let mut ct = [0, 0];
+    for c in it {
+        let c = c.cts();
+        ct[0] += c[0] + c[2];
+        ct[1] += c[1] + c[3];
+    }
+    if ct[0] + ct[1] > 0 {
+        Some(ct[1] as f64 / (ct[0] + ct[1]) as f64)
+    } else {
+        None
+    }
+}
+
+// Number of reads, number of short reads (ending before the last cycle) and the summed length
+// of the short reads from the per cycle totals of a read end.  Reads start at cycle trim + 1
+fn short_reads(totals: &[u64], trim: usize) -> (u64, u64, u64) {
+    let n = totals.first().copied().unwrap_or(0);
+    let (mut short, mut length) = (0, 0);
+    for (i, w) in totals.windows(2).enumerate() {
+        let ending = w[0].saturating_sub(w[1]);
+        short += ending;
+        length += ending * (trim + i + 1) as u64;
+    }
+    (n, short, length)
+}
+
+/// Fragment length proxies and fragment end composition for cfDNA libraries
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FragmentMetrics {
+    frag_length: Option<f64>,
+    short_fraction: Option<f64>,
+    end_gc: Option<f64>,
+    end_gc_ratio: Option<f64>,
+}
+
+impl FragmentMetrics {
+    /// Returns None if the dataset is not tagged as cfDNA or has no reads
+    pub fn from_dataset(d: &DataSet) -> Option<Self> {
+        if !d.is_cfdna() {
+            return None;
+        }
+        let ct = d.per_pos_cts();
+        if d.n_reads() == 0 || ct.is_empty() {
+            return None;
+        }
+
+        // Fragment length proxy from the short reads of R1 and R2
+        let (n, short, length) = [1, 2]
+            .iter()
+            .map(|r| d.read_end_cts().get(r))
+            .try_fold((0, 0, 0), |(n, s, l), v| {
+                let t: Vec<_> = v?.iter().map(|c| c.total()).collect();
+                let (n1, s1, l1) = short_reads(&t, d.trim());
+                (n1 > 0).then_some((n + n1, s + s1, l + l1))
+            })
+            .unwrap_or_default();
+        let short_fraction = (n > 0).then(|| short as f64 / n as f64);
+        let frag_length = (short > 0).then(|| length as f64 / short as f64);
+
+        // GC at the fragment end compared to the whole read
+        let end_gc = gc_frac(ct.iter().take(END_CYCLES));
+        let end_gc_ratio = match (end_gc, gc_frac(ct.iter())) {
+            (Some(x), Some(y)) if y > 0.0 => Some(x / y),
+            _ => None,
+        };
+
+        Some(Self {
+            frag_length,
+            short_fraction,
+            end_gc,
+            end_gc_ratio,
+        })
+    }
+}
+
+/// Columns for TSV output (NA if the metrics are not available)
+pub fn fragment_columns(m: Option<&FragmentMetrics>) -> String {
+    let opt = |x: Option<f64>, prec: usize| {
+        x.map(|x| format!("\t{x:.prec$}"))
+            .unwrap_or_else(|| "\tNA".to_owned())
+    };
+    match m {
+        Some(m) => format!(
+            "{}{}{}{}",
+            opt(m.frag_length, 2),
+            opt(m.short_fraction, 5),
+            opt(m.end_gc, 5),
+            opt(m.end_gc_ratio, 5)
+        ),
+        None => "\tNA".repeat(CFDNA_COLUMNS.len()),
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn short_reads_test() {
+        // 10 reads: 2 of length 3, 1 of length 4 and 7 full length (5) reads, with 2 bases
+        // trimmed
+        assert_eq!(short_reads(&[10, 8, 7], 2), (10, 3, 2 * 3 + 4));
+        assert_eq!(short_reads(&[], 0), (0, 0, 0));
+        assert_eq!(fragment_columns(None), "\tNA\tNA\tNA\tNA");
+    }
+}
@@ -51,3 +51,53 @@ where
         .sum::<f64>()
         * xrange
 }
+
+/// Points and weights, as (x, w), of the 64 point Gauss-Legendre rule on [lower, upper], so
+/// that integrals of functions of several distributions can be calculated from values
+/// tabulated once at the same points
+pub fn gauss_legendre_64_rule(lower: f64, upper: f64) -> Vec<(f64, f64)> {
+    assert!(lower < upper);
+    let xmean = 0.5 * (lower + upper);
+    let xrange = 0.5 * (upper - lower);
+    GAUSS_LEG_64
+        .iter()
+        .flat_map(|(w, x)| {
+            let delta_x = xrange * *x;
+            [
+                (xmean + delta_x, *w * xrange),
+                (xmean - delta_x, *w * xrange),
+            ]
+        })
+        .collect()
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn polynomial_test() {
+        // 64 point rule is exact for polynomials of degree up to 127
+        let f = |x: f64| 3.0 * x.powi(2) - 2.0 * x + 1.0;
+        assert!((gauss_legendre_64(f, 0.0, 2.0) - 6.0).abs() < 1.0e-12);
+        let f = |x: f64| 101.0 * x.powi(100);
+        assert!((gauss_legendre_64(f, 0.0, 1.0) - 1.0).abs() < 1.0e-12);
+        assert!(gauss_legendre_64(|x| x.powi(5), -1.0, 1.0).abs() < 1.0e-14);
+    }
+
+    #[test]
+    fn weights_test() {
+        // Weights over [-1, 1] sum to 2
+        assert!((gauss_legendre_64(|_| 1.0, -1.0, 1.0) - 2.0).abs() < 1.0e-12);
+        let s: f64 = GAUSS_LEG_64.iter().map(|(w, _)| *w).sum();
+        assert!((s - 1.0).abs() < 1.0e-12);
+    }
+
+    #[test]
+    fn transcendental_test() {
+        use std::f64::consts::{E, PI};
+        assert!((gauss_legendre_64(f64::sin, 0.0, PI) - 2.0).abs() < 1.0e-12);
+        assert!((gauss_legendre_64(f64::exp, 0.0, 1.0) - (E - 1.0)).abs() < 1.0e-12);
+        assert!((gauss_legendre_64(|x| 1.0 / (1.0 + x * x), 0.0, 1.0) - PI / 4.0).abs() < 1.0e-12);
+    }
+}
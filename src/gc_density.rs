@@ -0,0 +1,236 @@
+use crate::{
+    gauss_legendre::gauss_legendre_64,
+    reference::{GcHistKey, GcHistVal},
+    stats::{reg_inc_beta, xlogy},
+};
+
+/// Number of bins used internally for histogram export
+pub const GC_HIST_BINS: usize = 1000;
+
+/// Convergence tolerance for quantile bisection
+const QUANTILE_TOL: f64 = 1.0e-12;
+
+/// Density of the per read gc proportion.
+///
+/// The density is a count weighted mixture of beta distributions, one for each (AT, GC) count
+/// in the gc histogram, where the component for (a, b) is Beta(b + 1, a + 1) (the posterior
+/// for the gc proportion of a read with b GC and a AT bases under a uniform prior).  An
+/// optional prior of `prior` pseudo-reads spread uniformly over [0, 1] can be added to smooth
+/// the density for shallow datasets.
+#[derive(Debug, Clone, Copy)]
+pub struct GcDensity<'a> {
+    cts: &'a [(GcHistKey, GcHistVal)],
+    total: f64,
+    prior: f64,
+}
+
+impl<'a> GcDensity<'a> {
+    /// Density from gc histogram counts.  Panics if the total count is zero
+    pub fn new(cts: &'a [(GcHistKey, GcHistVal)]) -> Self {
+        Self::with_prior(cts, 0.0)
+    }
+
+    /// Density from gc histogram counts smoothed with a uniform prior of weight prior
+    /// pseudo-reads.  Panics if the prior is negative or if both the total count and the
+    /// prior are zero
+    pub fn with_prior(cts: &'a [(GcHistKey, GcHistVal)], prior: f64) -> Self {
+        assert!(prior >= 0.0, "Prior must be non-negative");
+        let total = cts.iter().map(|(_, v)| v.count()).sum::<f64>();
+        assert!(total + prior > 0.0, "Empty gc distribution");
+        Self { cts, total, prior }
+    }
+
+    /// Total read count (excluding the prior)
+    pub fn total(&self) -> f64 {
+        self.total
+    }
+
+    // Combine the mixture components evaluated using f with the uniform prior
+    // contribution u
+    fn mix<F>(&self, f: F, u: f64) -> f64
+    where
+        F: Fn(f64, f64, &GcHistVal) -> f64,
+    {
+        let l = self.cts.iter().fold(0.0, |l, (k, v)| {
+            let (a, b) = k.counts();
+            l + f(a, b, v) * v.count()
+        });
+        (l + self.prior * u) / (self.total + self.prior)
+    }
+
+    /// Probability density at x.  Returns 0 for x outside of [0, 1]
+    pub fn density(&self, x: f64) -> f64 {
+        if !(0.0..=1.0).contains(&x) {
+            return 0.0;
+        }
+        let (lnx, lnx1) = (x.ln(), (1.0 - x).ln());
+        self.mix(
+            |a, b, v| (xlogy(b, lnx) + xlogy(a, lnx1) - v.beta_a_b()).exp(),
+            1.0,
+        )
+    }
+
+    /// Cumulative distribution function at x
+    pub fn cdf(&self, x: f64) -> f64 {
+        let x = x.clamp(0.0, 1.0);
+        self.mix(|a, b, v| reg_inc_beta(x, b + 1.0, a + 1.0, v.beta_a_b()), x)
+            .clamp(0.0, 1.0)
+    }
+
+    /// Quantile function (inverse of the cdf) for p in [0, 1], found by bisection
+    pub fn quantile(&self, p: f64) -> f64 {
+        assert!((0.0..=1.0).contains(&p), "Probability must be in [0, 1]");
+        let (mut lo, mut hi) = (0.0, 1.0);
+        while hi - lo > QUANTILE_TOL {
+            let mid = 0.5 * (lo + hi);
+            if self.cdf(mid) < p {
+                lo = mid
+            } else {
+                hi = mid
+            }
+        }
+        0.5 * (lo + hi)
+    }
+
+    /// KL distance of self from other
+    pub fn kl_distance(&self, other: &GcDensity) -> f64 {
+        gauss_legendre_64(
+            |x| {
+                let p = self.density(x);
+                p * (p / other.density(x)).ln()
+            },
+            0.0,
+            1.0,
+        )
+    }
+
+    /// Density averaged over n_bins equal width bins on [0, 1], as (bin centre, density)
+    /// pairs.  The components are evaluated on a fine grid and normalized so that each
+    /// component has the correct total mass.  n_bins should divide 1000
+    pub fn histogram(&self, n_bins: usize) -> Vec<(f64, f64)> {
+        assert!(n_bins > 0 && GC_HIST_BINS.is_multiple_of(n_bins));
+        let bin_width = 1.0 / (GC_HIST_BINS as f64);
+        let lnp: Vec<_> = (0..GC_HIST_BINS)
+            .map(|i| {
+                let x = bin_width * (0.5 + (i as f64));
+                (x.ln(), (1.0 - x).ln())
+            })
+            .collect();
+
+        let mut tmp = Vec::with_capacity(GC_HIST_BINS);
+        let mut h = vec![0.0; GC_HIST_BINS];
+        for (k, v) in self.cts.iter() {
+            let (a, b) = k.counts();
+            tmp.clear();
+            let mut z = 0.0;
+            for (lnx, lnx1) in lnp.iter() {
+                let p = (lnx * b + lnx1 * a - v.beta_a_b()).exp();
+                z += p;
+                tmp.push(p);
+            }
+            let x = v.count();
+            for (p, q) in tmp.iter().zip(h.iter_mut()) {
+                *q += x * p / z
+            }
+        }
+        let z = GC_HIST_BINS as f64;
+        let t = self.total + self.prior;
+        for x in h.iter_mut() {
+            *x = (*x * z + self.prior) / t
+        }
+
+        let k = GC_HIST_BINS / n_bins;
+        h.chunks(k)
+            .enumerate()
+            .map(|(i, c)| {
+                (
+                    (0.5 + i as f64) / n_bins as f64,
+                    c.iter().sum::<f64>() / k as f64,
+                )
+            })
+            .collect()
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn make_counts(v: &[(&str, u64)]) -> Vec<(GcHistKey, GcHistVal)> {
+        v.iter()
+            .map(|(s, c)| {
+                let k = s.parse::<GcHistKey>().expect("Bad key");
+                (k, GcHistVal::make(&k, *c))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn uniform_test() {
+        // A read with no bases gives a uniform density
+        let cts = make_counts(&[("0:0", 10)]);
+        let d = GcDensity::new(&cts);
+        for x in [0.0, 0.1, 0.5, 0.9, 1.0] {
+            assert!((d.density(x) - 1.0).abs() < 1.0e-12);
+            assert!((d.cdf(x) - x).abs() < 1.0e-12);
+            assert!((d.quantile(x) - x).abs() < 1.0e-9);
+        }
+        assert_eq!(d.density(1.5), 0.0);
+    }
+
+    #[test]
+    fn beta_test() {
+        // One GC base: Beta(2, 1) with density 2x, cdf x^2
+        let cts = make_counts(&[("0:1", 5)]);
+        let d = GcDensity::new(&cts);
+        for x in [0.05, 0.3, 0.5, 0.8] {
+            assert!((d.density(x) - 2.0 * x).abs() < 1.0e-12);
+            assert!((d.cdf(x) - x * x).abs() < 1.0e-12);
+            assert!((d.quantile(x) - x.sqrt()).abs() < 1.0e-9);
+        }
+        // Two AT and two GC bases: Beta(3, 3) with cdf 10x^3 - 15x^4 + 6x^5
+        let cts = make_counts(&[("2:2", 1)]);
+        let d = GcDensity::new(&cts);
+        for x in [0.1, 0.25, 0.6, 0.95] {
+            let cdf = x * x * x * (10.0 - 15.0 * x + 6.0 * x * x);
+            assert!((d.density(x) - 30.0 * (x * (1.0 - x)).powi(2)).abs() < 1.0e-10);
+            assert!((d.cdf(x) - cdf).abs() < 1.0e-12);
+        }
+        assert!((d.quantile(0.5) - 0.5).abs() < 1.0e-9);
+    }
+
+    #[test]
+    fn mixture_test() {
+        // Equal mixture of Beta(2, 1) and Beta(1, 2) is uniform
+        let cts = make_counts(&[("0:1", 3), ("1:0", 3)]);
+        let d = GcDensity::new(&cts);
+        for x in [0.1, 0.4, 0.7] {
+            assert!((d.density(x) - 1.0).abs() < 1.0e-12);
+            assert!((d.cdf(x) - x).abs() < 1.0e-12);
+        }
+        // Adding a prior to a uniform density leaves it unchanged
+        let d = GcDensity::with_prior(&cts, 100.0);
+        assert!((d.density(0.2) - 1.0).abs() < 1.0e-12);
+        // Prior of equal weight to Beta(2, 1) gives density x + 0.5
+        let cts = make_counts(&[("0:1", 4)]);
+        let d = GcDensity::with_prior(&cts, 4.0);
+        assert!((d.density(0.3) - 0.8).abs() < 1.0e-12);
+        assert!((d.cdf(0.3) - 0.195).abs() < 1.0e-12);
+    }
+
+    #[test]
+    fn kl_test() {
+        let u = make_counts(&[("0:0", 1)]);
+        let b = make_counts(&[("0:1", 1)]);
+        let (du, db) = (GcDensity::new(&u), GcDensity::new(&b));
+        assert!(du.kl_distance(&du).abs() < 1.0e-12);
+        // KL(Beta(2, 1) || U(0, 1)) = ln(2) - 1/2
+        assert!((db.kl_distance(&du) - (2.0f64.ln() - 0.5)).abs() < 1.0e-6);
+        // Histogram of a uniform density is flat
+        assert!(du
+            .histogram(10)
+            .iter()
+            .all(|(_, y)| (y - 1.0).abs() < 1.0e-9));
+    }
+}
@@ -0,0 +1,182 @@
+//! Goodness of fit test of the sample gc distribution against the reference (--gof-test).
+//!
+//! Reads are binned by their gc proportion using the same GC_HIST_BINS discretization as the
+//! gc histogram output.  The expected count for a bin is found from the beta-binomial
+//! distribution of the number of GC bases under the reference for each read length (number
+//! of non-N bases) in the sample, so the test is conditional on the read lengths.  Adjacent
+//! bins are pooled until each pooled bin has an expected count of at least MIN_EXPECTED, so
+//! that the sparse tails of the distribution do not dominate the statistic, and the degrees
+//! of freedom are one less than the number of pooled bins.  As the number of reads is large
+//! for most datasets, small departures from the reference will usually be significant.
+
+use std::collections::BTreeMap;
+
+use clap::{builder::PossibleValue, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    betabin::{lbeta, ref_log_likelihood},
+    gc_density::GC_HIST_BINS,
+    reference::{GcHistKey, GcHistVal},
+    stats::chi_squared_cdf,
+};
+
+/// Smallest expected count for a (pooled) bin
+const MIN_EXPECTED: f64 = 5.0;
+
+/// Test statistic for the goodness of fit test
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GofTest {
+    /// Pearson's chi-square
+    ChiSquare,
+    /// Likelihood ratio (G) test
+    G,
+}
+
+impl GofTest {
+    // Contribution of a bin with observed count o and expected count e to the statistic
+    fn term(&self, o: f64, e: f64) -> f64 {
+        match self {
+            Self::ChiSquare => (o - e).powi(2) / e,
+            Self::G if o > 0.0 => 2.0 * o * (o / e).ln(),
+            Self::G => 0.0,
+        }
+    }
+}
+
+impl ValueEnum for GofTest {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::ChiSquare, Self::G]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::ChiSquare => Some(PossibleValue::new("chisq")),
+            Self::G => Some(PossibleValue::new("g")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct GoodnessOfFit {
+    test: GofTest,
+    statistic: f64,
+    df: usize,
+    p: f64,
+}
+
+/// Column names in TSV output
+pub const GOF_COLUMNS: [&str; 3] = ["GOF-statistic", "GOF-df", "GOF-p"];
+
+// Bin for a read with b GC bases out of n
+fn gc_bin(b: u32, n: u32) -> usize {
+    ((b as usize * GC_HIST_BINS) / n as usize).min(GC_HIST_BINS - 1)
+}
+
+// Pool adjacent bins of (observed, expected) counts so that each pooled bin has an expected
+// count of at least MIN_EXPECTED.  Any remainder is added to the last pooled bin
+fn pool_bins(v: &[(f64, f64)]) -> Vec<(f64, f64)> {
+    let mut pooled: Vec<(f64, f64)> = Vec::new();
+    let (mut o, mut e) = (0.0, 0.0);
+    for (x, y) in v {
+        o += x;
+        e += y;
+        if e >= MIN_EXPECTED {
+            pooled.push((o, e));
+            (o, e) = (0.0, 0.0)
+        }
+    }
+    if e > 0.0 || o > 0.0 {
+        match pooled.last_mut() {
+            Some(l) => {
+                l.0 += o;
+                l.1 += e
+            }
+            None => pooled.push((o, e)),
+        }
+    }
+    pooled
+}
+
+impl GoodnessOfFit {
+    /// Test the fit of the gc counts cts to the reference distribution ref_cts.  Returns None
+    /// if there are no counts, the reference is empty or there are fewer than two pooled bins
+    pub fn test(
+        cts: &[(GcHistKey, GcHistVal)],
+        ref_cts: &[(GcHistKey, GcHistVal)],
+        test: GofTest,
+    ) -> Option<Self> {
+        let ln_total = ref_cts.iter().map(|(_, v)| v.count()).sum::<f64>().ln();
+        if !ln_total.is_finite() {
+            return None;
+        }
+        // Observed counts by gc bin, and the number of reads by length
+        let mut bins = vec![(0.0, 0.0); GC_HIST_BINS];
+        let mut lengths: BTreeMap<u32, f64> = BTreeMap::new();
+        for (k, v) in cts.iter().filter(|(_, v)| v.count() > 0.0) {
+            let (a, b) = k.counts();
+            let n = (a + b) as u32;
+            if n > 0 {
+                bins[gc_bin(b as u32, n)].0 += v.count();
+                *lengths.entry(n).or_insert(0.0) += v.count()
+            }
+        }
+        if lengths.is_empty() {
+            return None;
+        }
+        for (n, m) in lengths {
+            let ln_choose =
+                |b: u32| -lbeta((b + 1) as f64, (n - b + 1) as f64) - ((n + 1) as f64).ln();
+            for b in 0..=n {
+                let (x, y) = ((n - b) as f64, b as f64);
+                let p = (ln_choose(b) + ref_log_likelihood(x, y, ref_cts, ln_total)).exp();
+                bins[gc_bin(b, n)].1 += m * p
+            }
+        }
+        let pooled = pool_bins(&bins);
+        if pooled.len() < 2 {
+            return None;
+        }
+        let statistic: f64 = pooled.iter().map(|(o, e)| test.term(*o, *e)).sum();
+        let df = pooled.len() - 1;
+        Some(Self {
+            test,
+            statistic,
+            df,
+            p: 1.0 - chi_squared_cdf(statistic, df as f64),
+        })
+    }
+
+    pub fn statistic(&self) -> f64 {
+        self.statistic
+    }
+
+    pub fn df(&self) -> usize {
+        self.df
+    }
+
+    pub fn p(&self) -> f64 {
+        self.p
+    }
+}
+
+/// Columns of the main TSV output for goodness of fit result g (NA if not tested)
+pub fn gof_columns(g: Option<&GoodnessOfFit>) -> String {
+    match g {
+        Some(g) => format!("\t{:.4}\t{}\t{:.5e}", g.statistic, g.df, g.p),
+        None => "\tNA\tNA\tNA".to_owned(),
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn pool_bins_test() {
+        let v = [(1.0, 2.0), (2.0, 4.0), (6.0, 5.0), (0.0, 1.0), (1.0, 0.5)];
+        assert_eq!(pool_bins(&v), vec![(3.0, 6.0), (7.0, 6.5)]);
+        assert_eq!(pool_bins(&[(1.0, 1.0)]), vec![(1.0, 1.0)]);
+    }
+}
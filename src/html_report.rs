@@ -0,0 +1,402 @@
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+
+use crate::{
+    kmers::KmerCoverage, process::DataResults, read::DataSummary, report::html_escape,
+    status::RunStatus,
+};
+
+/// Plot dimensions (in pixels) and margins for axes labels
+const PLOT_WIDTH: f64 = 640.0;
+const PLOT_HEIGHT: f64 = 320.0;
+const MARGIN_LEFT: f64 = 60.0;
+const MARGIN_RIGHT: f64 = 110.0;
+const MARGIN_TOP: f64 = 30.0;
+const MARGIN_BOTTOM: f64 = 45.0;
+const N_TICKS: usize = 5;
+
+const STYLE: &str = "body { font-family: sans-serif; } table { border-collapse: collapse; } td, th { border: 1px solid #999; padding: 2px 6px; } svg { display: block; margin: 1em 0; }";
+
+/// Line series for a plot
+struct Series<'a> {
+    name: &'a str,
+    colour: &'a str,
+    data: Vec<(f64, f64)>,
+}
+
+// Format tick label with a precision appropriate to the axis range
+fn tick_label(x: f64, range: f64) -> String {
+    if range >= 10.0 {
+        format!("{x:.0}")
+    } else if range >= 1.0 {
+        format!("{x:.1}")
+    } else {
+        format!("{x:.3}")
+    }
+}
+
+// Axes with ticks, labels and title for a plot.  Returns the svg header and axes and
+// a function mapping data coordinates to plot coordinates
+fn plot_axes(
+    title: &str,
+    xlab: &str,
+    ylab: &str,
+    (xmin, xmax): (f64, f64),
+    (ymin, ymax): (f64, f64),
+) -> (String, impl Fn(f64, f64) -> (f64, f64)) {
+    let w = PLOT_WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+    let h = PLOT_HEIGHT - MARGIN_TOP - MARGIN_BOTTOM;
+    let (xr, yr) = (
+        (xmax - xmin).max(f64::EPSILON),
+        (ymax - ymin).max(f64::EPSILON),
+    );
+    let tr = move |x: f64, y: f64| {
+        (
+            MARGIN_LEFT + w * (x - xmin) / xr,
+            MARGIN_TOP + h * (1.0 - (y - ymin) / yr),
+        )
+    };
+
+    let mut s = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{PLOT_WIDTH}\" height=\"{PLOT_HEIGHT}\" font-size=\"11\">\n"
+    );
+    let _ = writeln!(
+        s,
+        "<text x=\"{}\" y=\"18\" text-anchor=\"middle\" font-size=\"14\">{}</text>",
+        MARGIN_LEFT + 0.5 * w,
+        html_escape(title)
+    );
+    let _ = writeln!(
+        s,
+        "<rect x=\"{MARGIN_LEFT}\" y=\"{MARGIN_TOP}\" width=\"{w}\" height=\"{h}\" fill=\"none\" stroke=\"#444\"/>"
+    );
+    for i in 0..=N_TICKS {
+        let f = i as f64 / N_TICKS as f64;
+        let (x, y0) = tr(xmin + f * xr, ymin);
+        let _ = writeln!(
+            s,
+            "<line x1=\"{x:.1}\" y1=\"{y0:.1}\" x2=\"{x:.1}\" y2=\"{:.1}\" stroke=\"#444\"/><text x=\"{x:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>",
+            y0 + 4.0,
+            y0 + 16.0,
+            tick_label(xmin + f * xr, xr)
+        );
+        let (x0, y) = tr(xmin, ymin + f * yr);
+        let _ = writeln!(
+            s,
+            "<line x1=\"{:.1}\" y1=\"{y:.1}\" x2=\"{x0:.1}\" y2=\"{y:.1}\" stroke=\"#444\"/><text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>",
+            x0 - 4.0,
+            x0 - 6.0,
+            y + 4.0,
+            tick_label(ymin + f * yr, yr)
+        );
+    }
+    let _ = writeln!(
+        s,
+        "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+        MARGIN_LEFT + 0.5 * w,
+        PLOT_HEIGHT - 8.0,
+        html_escape(xlab)
+    );
+    let _ = writeln!(
+        s,
+        "<text x=\"14\" y=\"{0}\" text-anchor=\"middle\" transform=\"rotate(-90 14 {0})\">{1}</text>",
+        MARGIN_TOP + 0.5 * h,
+        html_escape(ylab)
+    );
+    (s, tr)
+}
+
+/// Line plot of one or more series as an inline SVG element
+fn line_plot(title: &str, xlab: &str, ylab: &str, series: &[Series]) -> String {
+    let pts = || series.iter().flat_map(|s| s.data.iter());
+    let xmin = pts().map(|(x, _)| *x).fold(f64::INFINITY, f64::min);
+    let xmax = pts().map(|(x, _)| *x).fold(f64::NEG_INFINITY, f64::max);
+    let ymax = pts().map(|(_, y)| *y).fold(0.0, f64::max);
+    if xmin >= xmax {
+        return String::new();
+    }
+
+    let (mut s, tr) = plot_axes(title, xlab, ylab, (xmin, xmax), (0.0, 1.05 * ymax));
+    for (i, ser) in series.iter().enumerate() {
+        let path: Vec<_> = ser
+            .data
+            .iter()
+            .map(|(x, y)| {
+                let (x, y) = tr(*x, *y);
+                format!("{x:.1},{y:.1}")
+            })
+            .collect();
+        let _ = writeln!(
+            s,
+            "<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\" points=\"{}\"/>",
+            ser.colour,
+            path.join(" ")
+        );
+        // Legend
+        let ly = MARGIN_TOP + 10.0 + 16.0 * i as f64;
+        let lx = PLOT_WIDTH - MARGIN_RIGHT + 10.0;
+        let _ = writeln!(
+            s,
+            "<line x1=\"{lx}\" y1=\"{ly}\" x2=\"{}\" y2=\"{ly}\" stroke=\"{}\" stroke-width=\"2\"/><text x=\"{}\" y=\"{}\">{}</text>",
+            lx + 20.0,
+            ser.colour,
+            lx + 25.0,
+            ly + 4.0,
+            html_escape(ser.name)
+        );
+    }
+    s.push_str("</svg>");
+    s
+}
+
+/// Box plot of the quartiles of per target kmer coverage, with the mean marked
+fn coverage_plot(kc: &KmerCoverage) -> String {
+    let [q1, q2, q3] = kc.quartiles().map(|c| c.get());
+    let mean = kc.mean().get();
+    let xmax = 1.1 * q3.max(mean);
+    if xmax <= 0.0 {
+        return String::new();
+    }
+    let (mut s, tr) = plot_axes(
+        "Per target kmer coverage",
+        "Coverage",
+        "",
+        (0.0, xmax),
+        (0.0, 1.0),
+    );
+    let (x1, y1) = tr(q1, 0.7);
+    let (x3, y0) = tr(q3, 0.3);
+    let _ = writeln!(
+        s,
+        "<rect x=\"{x1:.1}\" y=\"{y1:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"#9ecae1\" stroke=\"#3182bd\"/>",
+        x3 - x1,
+        y0 - y1
+    );
+    let (x2, _) = tr(q2, 0.0);
+    let _ = writeln!(
+        s,
+        "<line x1=\"{x2:.1}\" y1=\"{y1:.1}\" x2=\"{x2:.1}\" y2=\"{y0:.1}\" stroke=\"#08519c\" stroke-width=\"2\"/>"
+    );
+    let (xm, ym) = tr(mean, 0.5);
+    let _ = writeln!(
+        s,
+        "<circle cx=\"{xm:.1}\" cy=\"{ym:.1}\" r=\"4\" fill=\"#e6550d\"/>"
+    );
+    let lx = PLOT_WIDTH - MARGIN_RIGHT + 10.0;
+    let _ = writeln!(
+        s,
+        "<text x=\"{lx}\" y=\"{}\">Q1 {q1:.1}</text><text x=\"{lx}\" y=\"{}\">Median {q2:.1}</text><text x=\"{lx}\" y=\"{}\">Q3 {q3:.1}</text><text x=\"{lx}\" y=\"{}\" fill=\"#e6550d\">Mean {mean:.1}</text>",
+        MARGIN_TOP + 14.0,
+        MARGIN_TOP + 30.0,
+        MARGIN_TOP + 46.0,
+        MARGIN_TOP + 62.0
+    );
+    s.push_str("</svg>");
+    s
+}
+
+fn opt_f64(x: Option<f64>) -> String {
+    x.map(|x| format!("{x:.5}"))
+        .unwrap_or_else(|| "NA".to_owned())
+}
+
+// Dataset summary for the index page
+struct IndexEntry {
+    name: String,
+    file: String,
+    reads: u64,
+    gc: f64,
+    ref_gc: Option<f64>,
+    kl_distance: Option<f64>,
+    median_coverage: Option<f64>,
+}
+
+/// HTML QC report with one page per dataset and an index page linking to them
+pub struct HtmlReport {
+    dir: PathBuf,
+    entries: Vec<IndexEntry>,
+    used: HashSet<String>,
+}
+
+impl HtmlReport {
+    pub fn new<P: AsRef<Path>>(dir: P) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create report directory {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            entries: Vec::new(),
+            used: HashSet::new(),
+        })
+    }
+
+    // File name for dataset page, made unique if necessary
+    fn page_name(&mut self, name: &str) -> String {
+        let stem: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let mut file = format!("{stem}.html");
+        let mut ix = 1;
+        while self.used.contains(&file) || file == "index.html" {
+            ix += 1;
+            file = format!("{stem}_{ix}.html")
+        }
+        self.used.insert(file.clone());
+        file
+    }
+
+    fn open(&self, file: &str, status: &RunStatus) -> anyhow::Result<impl Write> {
+        let p = self.dir.join(file);
+        let wrt = CompressIo::new()
+            .path(&p)
+            .bufwriter()
+            .with_context(|| format!("Could not open report file {} for output", p.display()))?;
+        status.add_output(&p);
+        Ok(wrt)
+    }
+
+    /// Write page for dataset
+    pub fn add(
+        &mut self,
+        d: &DataSummary,
+        res: &DataResults,
+        status: &RunStatus,
+    ) -> anyhow::Result<()> {
+        let name = d.path().display().to_string();
+        let file = self.page_name(&name);
+        let mut wrt = self.open(&file, status)?;
+        let title = html_escape(&name);
+
+        writeln!(
+            wrt,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>{STYLE}</style>\n</head>\n<body>"
+        )?;
+        writeln!(
+            wrt,
+            "<p><a href=\"index.html\">Index</a></p>\n<h1>{title}</h1>\n<table>"
+        )?;
+        let kc = res.kmer_coverage();
+        for (k, v) in [
+            ("Reads", d.n_reads().to_string()),
+            ("Bases", d.n_bases().to_string()),
+            ("Bisulfite type", d.bisulfite().to_string()),
+            ("GC", format!("{:.5}", res.mean_gc())),
+            ("Reference GC", opt_f64(res.ref_mean_gc())),
+            ("KL distance", opt_f64(res.kl_distance())),
+            ("CpG o/e", opt_f64(res.composition().cpg_obs_exp())),
+            ("Mean coverage", opt_f64(kc.map(|k| k.mean().get()))),
+            ("Median coverage", opt_f64(kc.map(|k| k.median().get()))),
+            (
+                "Fold 80 base penalty",
+                opt_f64(kc.map(|k| k.fold_80_base_penalty())),
+            ),
+        ] {
+            writeln!(wrt, "<tr><th>{k}</th><td>{}</td></tr>", html_escape(&v))?
+        }
+        writeln!(wrt, "</table>")?;
+
+        if let Some(m) = res.multiqc() {
+            let mut series = vec![Series {
+                name: "Dataset",
+                colour: "#3182bd",
+                data: m.gc_hist().to_vec(),
+            }];
+            if let Some(h) = res.ref_gc_hist() {
+                series.push(Series {
+                    name: "Reference",
+                    colour: "#e6550d",
+                    data: h.to_vec(),
+                })
+            }
+            writeln!(
+                wrt,
+                "{}",
+                line_plot("GC distribution", "% GC", "Density", &series)
+            )?;
+
+            let series: Vec<_> = ["A", "C", "G", "T"]
+                .iter()
+                .zip(["#2ca02c", "#1f77b4", "#ff7f0e", "#d62728"])
+                .enumerate()
+                .map(|(ix, (name, colour))| Series {
+                    name,
+                    colour,
+                    data: m
+                        .base_comp()
+                        .iter()
+                        .map(|(c, v)| (*c as f64, v[ix]))
+                        .collect(),
+                })
+                .collect();
+            writeln!(
+                wrt,
+                "{}",
+                line_plot("Per cycle base composition", "Cycle", "%", &series)
+            )?;
+        }
+
+        if let Some(k) = kc {
+            writeln!(wrt, "{}", coverage_plot(k))?
+        }
+        writeln!(wrt, "</body>\n</html>")?;
+
+        self.entries.push(IndexEntry {
+            name,
+            file,
+            reads: d.n_reads(),
+            gc: res.mean_gc(),
+            ref_gc: res.ref_mean_gc(),
+            kl_distance: res.kl_distance(),
+            median_coverage: kc.map(|k| k.median().get()),
+        });
+        Ok(())
+    }
+
+    /// Write index page
+    pub fn write_index(&self, status: &RunStatus) -> anyhow::Result<()> {
+        let mut wrt = self.open("index.html", status)?;
+        writeln!(
+            wrt,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>gc_collect QC report</title>\n<style>{STYLE}</style>\n</head>\n<body>"
+        )?;
+        writeln!(
+            wrt,
+            "<h1>gc_collect QC report</h1>\n<p>Number of datasets: {}</p>\n<table>",
+            self.entries.len()
+        )?;
+        writeln!(
+            wrt,
+            "<tr><th>Dataset</th><th>Reads</th><th>GC</th><th>Reference GC</th><th>KL distance</th><th>Median coverage</th></tr>"
+        )?;
+        for e in self.entries.iter() {
+            writeln!(
+                wrt,
+                "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{:.5}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                html_escape(&e.file),
+                html_escape(&e.name),
+                e.reads,
+                e.gc,
+                opt_f64(e.ref_gc),
+                opt_f64(e.kl_distance),
+                opt_f64(e.median_coverage)
+            )?
+        }
+        writeln!(wrt, "</table>\n</body>\n</html>")?;
+        Ok(())
+    }
+}
@@ -1,9 +1,9 @@
-use std::io::BufRead;
+use std::{borrow::Cow, io::BufRead};
 
 use crate::kmers::KmerType;
 use anyhow::Context;
 use log::{log_enabled, Level::Trace};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 fn get_u16_from_slice(p: &[u8]) -> u16 {
     u16::from_le_bytes(p.try_into().expect("Slice has wrong size"))
@@ -13,7 +13,7 @@ fn get_u32_from_slice(p: &[u8]) -> u32 {
     u32::from_le_bytes(p.try_into().expect("Slice has wrong size"))
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct KmcvHeaderCore {
     version: [u8; 2],
     kmer_length: u8,
@@ -23,6 +23,12 @@ pub struct KmcvHeaderCore {
     rnd_id: u32,
 }
 
+impl KmcvHeaderCore {
+    pub fn n_targets(&self) -> u32 {
+        self.n_targets
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct KmcvHeader {
     core: KmcvHeaderCore,
@@ -75,6 +81,28 @@ impl Target {
     pub fn size(&self) -> u32 {
         self.end + 1 - self.start
     }
+
+    #[inline]
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    #[inline]
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    /// Number of bases shared with other target (assumed to be on the same contig)
+    #[inline]
+    pub fn overlap(&self, other: &Self) -> u32 {
+        let s = self.start.max(other.start);
+        let e = self.end.min(other.end);
+        if e >= s {
+            e + 1 - s
+        } else {
+            0
+        }
+    }
 }
 impl Target {
     fn read<R: BufRead>(rdr: &mut R, n_contigs: u32) -> anyhow::Result<(Self, u32)> {
@@ -128,22 +156,27 @@ impl KContig {
             return Err(anyhow!("Contig name length is zero"));
         }
 
-        let mut s = String::with_capacity(l);
-        while s.len() < l {
-            let p = rdr
-                .fill_buf()
-                .with_context(|| "Error while reading contig name")?;
-            let m = l - s.len();
-            let n = m.min(p.len());
-            let s1 = std::str::from_utf8(&p[..n]).with_context(|| "Contig name not utf8")?;
-            s.push_str(s1);
-            rdr.consume(n);
-            if n == m {
-                break;
-            }
+        let mut buf = vec![0u8; l];
+        rdr.read_exact(&mut buf)
+            .with_context(|| "Error while reading contig name")?;
+        let s = String::from_utf8_lossy(&buf);
+        if let Cow::Owned(_) = s {
+            warn!("Contig name {s} is not valid UTF-8; invalid sequences replaced")
         }
         trace!("Read contig {s}");
-        let name = s.into_boxed_str();
+
+        // As for FASTA headers, the contig name is the part before the first whitespace (the
+        // remainder being a description)
+        let name: Box<str> = match s.split_once(char::is_whitespace) {
+            Some((n, desc)) if !n.is_empty() => {
+                debug!("Contig name {n} (description '{desc}' dropped)");
+                n.into()
+            }
+            _ => s.trim().into(),
+        };
+        if name.is_empty() {
+            return Err(anyhow!("Contig name is blank"));
+        }
         let targets = Vec::new();
         Ok(Self { name, targets })
     }
@@ -153,6 +186,7 @@ pub struct Kmcv {
     header: KmcvHeader,
     contigs: Vec<KContig>,
     targets: Vec<Target>,
+    target_contig: Vec<u32>,
 }
 
 impl Kmcv {
@@ -168,6 +202,7 @@ impl Kmcv {
             header,
             contigs: Vec::with_capacity(n_ctgs),
             targets: Vec::with_capacity(n_targets),
+            target_contig: Vec::with_capacity(n_targets),
         };
 
         debug!("Reading contig blocks from kmer file");
@@ -181,10 +216,77 @@ impl Kmcv {
         Ok(kmcv)
     }
 
+    pub fn header_core(&self) -> &KmcvHeaderCore {
+        &self.header.core
+    }
+
+    pub fn n_targets(&self) -> usize {
+        self.targets.len()
+    }
+
     pub fn get_target_size(&self, ix: usize) -> Option<u32> {
         self.targets.get(ix).map(|t| t.size())
     }
 
+    pub fn n_contigs(&self) -> usize {
+        self.contigs.len()
+    }
+
+    /// Iterator over contig names (in contig id order)
+    pub fn contig_names(&self) -> impl Iterator<Item = &str> {
+        self.contigs.iter().map(|c| c.name.as_ref())
+    }
+
+    /// Iterator over (target ix, target) for the targets on contig contig_ix
+    pub fn contig_targets(
+        &self,
+        contig_ix: usize,
+    ) -> Option<impl Iterator<Item = (usize, &Target)>> {
+        self.contigs.get(contig_ix).map(|c| {
+            c.targets
+                .iter()
+                .map(|ix| (*ix as usize, &self.targets[*ix as usize]))
+        })
+    }
+
+    /// Iterator over (contig name, target) for all targets (in target id order)
+    pub fn targets(&self) -> impl Iterator<Item = (&str, &Target)> {
+        self.targets
+            .iter()
+            .zip(self.target_contig.iter())
+            .map(|(t, c)| (self.contigs[*c as usize].name.as_ref(), t))
+    }
+
+    /// Total number of bases covered by targets
+    pub fn total_target_span(&self) -> u64 {
+        self.targets.iter().map(|t| t.size() as u64).sum()
+    }
+
+    /// Contig name and target coordinates for target ix
+    pub fn get_target_location(&self, ix: usize) -> Option<(&str, &Target)> {
+        self.targets.get(ix).map(|t| {
+            (
+                self.contigs[self.target_contig[ix] as usize].name.as_ref(),
+                t,
+            )
+        })
+    }
+
+    /// Match targets between two target sets by coordinates.  Targets on contigs with the same
+    /// name are paired if each is the other's best (largest overlap) match.  Returns pairs of
+    /// (self target index, other target index) sorted on self target index
+    pub fn match_targets(&self, other: &Self) -> Vec<(usize, usize)> {
+        let best1 = self.best_overlaps(other);
+        let best2 = other.best_overlaps(self);
+        let mut v: Vec<_> = best1
+            .iter()
+            .enumerate()
+            .filter_map(|(i, j)| j.filter(|j| best2[*j] == Some(i)).map(|j| (i, j)))
+            .collect();
+        v.sort_unstable();
+        v
+    }
+
     /// Private functions
     fn read_contig_blocks<R: BufRead>(&mut self, rdr: &mut R) -> anyhow::Result<()> {
         self.contigs.clear();
@@ -201,6 +303,7 @@ impl Kmcv {
             let (target, contig) = Target::read(rdr, n_contigs)?;
             self.contigs[contig as usize].targets.push(ix);
             self.targets.push(target);
+            self.target_contig.push(contig);
         }
 
         if log_enabled!(Trace) {
@@ -215,4 +318,47 @@ impl Kmcv {
 
         Ok(())
     }
+
+    // For each target in self, find target in other with the largest overlap (if any)
+    fn best_overlaps(&self, other: &Self) -> Vec<Option<usize>> {
+        let mut best = vec![None; self.targets.len()];
+        for (cix, name) in self.contig_names().enumerate() {
+            let Some(ocix) = other.contig_names().position(|s| s == name) else {
+                continue;
+            };
+            // Targets from other contig sorted on start position
+            let mut ot: Vec<_> = other
+                .contig_targets(ocix)
+                .expect("Bad contig ix")
+                .map(|(i, _)| i)
+                .collect();
+            ot.sort_unstable_by_key(|i| other.targets[*i].start);
+            // Running maximum of end positions so that we know when to stop searching
+            let max_end: Vec<_> = ot
+                .iter()
+                .scan(0, |m, i| {
+                    *m = other.targets[*i].end.max(*m);
+                    Some(*m)
+                })
+                .collect();
+            for (ix, t) in self.contig_targets(cix).expect("Bad contig ix") {
+                // Candidates all have start <= t.end
+                let k = ot.partition_point(|i| other.targets[*i].start <= t.end);
+                let mut b: Option<(u32, usize)> = None;
+                for (j, _) in ot[..k]
+                    .iter()
+                    .zip(max_end.iter())
+                    .rev()
+                    .take_while(|(_, e)| **e >= t.start)
+                {
+                    let ov = t.overlap(&other.targets[*j]);
+                    if ov > 0 && b.map(|(x, _)| ov > x).unwrap_or(true) {
+                        b = Some((ov, *j))
+                    }
+                }
+                best[ix] = b.map(|(_, j)| j)
+            }
+        }
+        best
+    }
 }
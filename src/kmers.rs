@@ -1,32 +1,156 @@
-use std::fmt;
+use std::{
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
-use serde::Deserialize;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
 use crate::{
-    cli::Config,
+    aux_file::AuxFile,
     kmcv::{Kmcv, KmcvHeaderCore},
+    stats::chi_squared_sf,
+    utils::tsv_escape,
 };
 
 pub type KmerType = u32;
 
-#[derive(Clone, Deserialize)]
+/// Number of reads.  Stored as u64 as merged datasets from large flowcells
+/// can exceed the range of a u32
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct ReadCount(u64);
+
+impl ReadCount {
+    pub fn new(x: u64) -> Self {
+        Self(x)
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Self) -> anyhow::Result<Self> {
+        self.0
+            .checked_add(other.0)
+            .map(Self)
+            .ok_or_else(|| anyhow!("Overflow when adding read counts"))
+    }
+}
+
+impl fmt::Display for ReadCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Number of bases
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct BaseCount(u64);
+
+impl BaseCount {
+    pub fn new(x: u64) -> Self {
+        Self(x)
+    }
+
+    pub fn checked_add(self, other: Self) -> anyhow::Result<Self> {
+        self.0
+            .checked_add(other.0)
+            .map(Self)
+            .ok_or_else(|| anyhow!("Overflow when adding base counts"))
+    }
+
+    /// Mean coverage when these bases are spread over span bases
+    pub fn coverage(&self, span: u32) -> Coverage {
+        Coverage(self.0 as f64 / span as f64)
+    }
+}
+
+impl fmt::Display for BaseCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Mean per base coverage
+#[derive(Copy, Clone, Debug, Default, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Coverage(f64);
+
+impl Coverage {
+    pub fn get(&self) -> f64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct KmerCounts {
     kmcv: KmcvHeaderCore,
-    total_reads: u32,
-    mapped_reads: u32,
-    total_bases: u64,
-    mapped_bases: u64,
-    counts: Vec<(u32, u64)>,
+    total_reads: ReadCount,
+    mapped_reads: ReadCount,
+    total_bases: BaseCount,
+    mapped_bases: BaseCount,
+    counts: Vec<(ReadCount, BaseCount)>,
 }
 
 impl KmerCounts {
-    pub fn kmer_coverage(&self, cfg: &Config) -> Option<KmerCoverage> {
-        if let Some(kmcv) = cfg.kmcv() {
-            Some(self.get_coverage(kmcv))
-        } else {
-            warn!("Cannot process kmer coverage without an input kmer file (use -k option)");
-            None
+    pub fn new(
+        kmcv: KmcvHeaderCore,
+        total_reads: ReadCount,
+        mapped_reads: ReadCount,
+        total_bases: BaseCount,
+        mapped_bases: BaseCount,
+        counts: Vec<(ReadCount, BaseCount)>,
+    ) -> Self {
+        Self {
+            kmcv,
+            total_reads,
+            mapped_reads,
+            total_bases,
+            mapped_bases,
+            counts,
+        }
+    }
+
+    /// Inconsistencies between the counts and the kmer file header
+    pub fn check(&self) -> Vec<String> {
+        let mut v = Vec::new();
+        let n = self.kmcv.n_targets() as usize;
+        if n == 0 {
+            v.push("kmer header has no targets".to_owned())
+        }
+        if self.counts.len() != n {
+            v.push(format!(
+                "kmer counts given for {} targets but kmer header has {n}",
+                self.counts.len()
+            ))
+        }
+        if self.mapped_reads > self.total_reads {
+            v.push(format!(
+                "kmer mapped reads ({}) exceeds total reads ({})",
+                self.mapped_reads, self.total_reads
+            ))
+        }
+        if self.mapped_bases > self.total_bases {
+            v.push(format!(
+                "kmer mapped bases ({}) exceeds total bases ({})",
+                self.mapped_bases, self.total_bases
+            ))
         }
+        v
+    }
+
+    pub fn kmer_coverage(&self, kmcv: &Kmcv) -> KmerCoverage {
+        self.get_coverage(kmcv)
     }
 
     pub fn add(&mut self, other: &Self) -> anyhow::Result<()> {
@@ -35,36 +159,95 @@ impl KmerCounts {
                 "Cannot merge datasets as kmer files are not compatible"
             ))
         } else {
-            self.total_reads += other.total_reads;
-            self.total_bases += other.total_bases;
-            self.mapped_reads += other.mapped_reads;
-            self.mapped_bases += other.mapped_bases;
-
-            assert_eq!(self.counts.len(), other.counts.len());
-            for (p, q) in self.counts.iter_mut().zip(other.counts.iter()) {
-                p.0 += q.0;
-                p.1 += q.1
+            if self.counts.len() != other.counts.len() {
+                return Err(anyhow!(
+                    "Cannot merge datasets with different numbers of targets"
+                ));
             }
+            // Calculate all sums before updating so that self is unchanged on overflow
+            let total_reads = self.total_reads.checked_add(other.total_reads)?;
+            let total_bases = self.total_bases.checked_add(other.total_bases)?;
+            let mapped_reads = self.mapped_reads.checked_add(other.mapped_reads)?;
+            let mapped_bases = self.mapped_bases.checked_add(other.mapped_bases)?;
+            let counts = self
+                .counts
+                .iter()
+                .zip(other.counts.iter())
+                .map(|((r1, b1), (r2, b2))| Ok((r1.checked_add(*r2)?, b1.checked_add(*b2)?)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            self.total_reads = total_reads;
+            self.total_bases = total_bases;
+            self.mapped_reads = mapped_reads;
+            self.mapped_bases = mapped_bases;
+            self.counts = counts;
 
             Ok(())
         }
     }
 
-    fn get_coverage(&self, kmcv: &Kmcv) -> KmerCoverage {
-        let mut v: Vec<_> = self
-            .counts
+    pub fn kmcv_header(&self) -> &KmcvHeaderCore {
+        &self.kmcv
+    }
+
+    /// Mean coverage per target
+    pub fn target_coverage(&self, kmcv: &Kmcv) -> Vec<Coverage> {
+        self.counts
             .iter()
             .enumerate()
             .map(|(target_ix, (_, bases))| {
-                let target_size = kmcv.get_target_size(target_ix).expect("Bad target ix") as f64;
+                let target_size = kmcv.get_target_size(target_ix).expect("Bad target ix");
                 // println!("ACK:\t{target_ix}\t{target_size}\t{reads}\t{bases}\t{:.2}\t{:.2}", if *reads > 0 { *bases as f64 / *reads as f64 } else { 0.0 }, *bases as f64 / target_size);
-                *bases as f64 / target_size
+                bases.coverage(target_size)
             })
-            .collect();
+            .collect()
+    }
+
+    /// Write per target read and base counts with coverage normalized by the mean target
+    /// coverage to <p>.target_cov.tsv (bgzip compressed if bgzip is set), returning the paths
+    /// of the output files
+    pub fn output_target_coverage(
+        &self,
+        kmcv: &Kmcv,
+        p: &Path,
+        bgzip: bool,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let aux = AuxFile::new(p, "target_cov.tsv", bgzip);
+        let mut wrt = aux
+            .writer()
+            .with_context(|| "Could not open output target coverage file")?;
+
+        let cov = self.target_coverage(kmcv);
+        let mean = cov.iter().map(|c| c.get()).sum::<f64>() / cov.len() as f64;
+        writeln!(
+            wrt,
+            "Contig\tStart\tEnd\tReads\tBases\tCoverage\tNormalized-coverage"
+        )?;
+        for (ix, ((reads, bases), c)) in self.counts.iter().zip(cov.iter()).enumerate() {
+            let (ctg, t) = kmcv.get_target_location(ix).expect("Bad target ix");
+            write!(
+                wrt,
+                "{}\t{}\t{}\t{reads}\t{bases}\t{:.3}",
+                tsv_escape(ctg),
+                t.start(),
+                t.end(),
+                c.get()
+            )?;
+            if mean > 0.0 {
+                writeln!(wrt, "\t{:.5}", c.get() / mean)?
+            } else {
+                writeln!(wrt, "\tNA")?
+            }
+        }
+        aux.finish(wrt)
+    }
+
+    fn get_coverage(&self, kmcv: &Kmcv) -> KmerCoverage {
+        let mut v: Vec<_> = self.target_coverage(kmcv).iter().map(|c| c.get()).collect();
         v.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
         let l = v.len();
-        let mean = v.iter().sum::<f64>() / (l as f64);
-        let quartiles = [v[l >> 2], v[l >> 1], v[(3 * l) >> 2]];
+        let mean = Coverage(v.iter().sum::<f64>() / (l as f64));
+        let quartiles = [v[l >> 2], v[l >> 1], v[(3 * l) >> 2]].map(Coverage);
  
         // For fold_80_base_penalty, we want to calculate the mean and 20th percentile of targets with non-zero coverage
         let mut i = l;
@@ -81,6 +264,8 @@ impl KmerCounts {
         } else {
             0.0
         };
+        let e_score = evenness_score(&v);
+        let poisson_fit = self.poisson_fit(kmcv);
         KmerCoverage {
             total_bases: self.total_bases,
             mapped_bases: self.mapped_bases,
@@ -89,37 +274,152 @@ impl KmerCounts {
             mean,
             quartiles,
             f80_penalty,
+            poisson_fit,
+            e_score,
+        }
+    }
+
+    // Test whether the per target read counts are consistent with a Poisson distribution
+    // where the expected count for each target is proportional to the target size.  Targets
+    // of zero size (with an expected count of zero) are excluded.  Returns None if there are
+    // too few targets or no reads on targets
+    fn poisson_fit(&self, kmcv: &Kmcv) -> Option<PoissonFit> {
+        let obs: Vec<_> = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(target_ix, (reads, _))| {
+                (
+                    reads.get() as f64,
+                    kmcv.get_target_size(target_ix).expect("Bad target ix") as f64,
+                )
+            })
+            .filter(|(_, size)| *size > 0.0)
+            .collect();
+        let n = obs.len();
+        if n < 2 {
+            return None;
+        }
+        let (tot_reads, tot_size) = obs
+            .iter()
+            .fold((0.0, 0.0), |(r, s), (reads, size)| (r + reads, s + size));
+        if tot_reads <= 0.0 {
+            return None;
         }
+        let rate = tot_reads / tot_size;
+        let chi2 = obs
+            .iter()
+            .map(|(reads, size)| {
+                let e = rate * size;
+                (reads - e).powi(2) / e
+            })
+            .sum::<f64>();
+        let df = n - 1;
+        Some(PoissonFit {
+            chi2,
+            df,
+            p: chi_squared_sf(chi2, df as f64),
+        })
+    }
+}
+
+/// Evenness score (Mokry et al., 2010) of per target coverages v.  Defined as 1 - the integral
+/// from 0 to 1 of the distribution function of coverage relative to the mean, which is the
+/// mean over targets of min(c / mean, 1).  Bounded [0, 1], with 1 for perfectly even coverage.
+/// None if there is no coverage
+fn evenness_score(v: &[f64]) -> Option<f64> {
+    let mean = v.iter().sum::<f64>() / (v.len() as f64);
+    (mean > 0.0).then(|| v.iter().map(|c| (c / mean).min(1.0)).sum::<f64>() / (v.len() as f64))
+}
+
+/// Result of Poisson goodness of fit test of per target read counts
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+pub struct PoissonFit {
+    chi2: f64,
+    df: usize,
+    p: f64,
+}
+
+impl PoissonFit {
+    /// Overdispersion index (Pearson chi-square / df). Expected to be close to 1
+    /// for a Poisson distribution; values >> 1 indicate clustering or duplication
+    pub fn overdispersion(&self) -> f64 {
+        self.chi2 / (self.df as f64)
+    }
+
+    pub fn p(&self) -> f64 {
+        self.p
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct KmerCoverage {
-    total_bases: u64,
-    total_reads: u32,
-    mapped_bases: u64,
-    mapped_reads: u32,
-    mean: f64,
-    quartiles: [f64; 3],
+    total_bases: BaseCount,
+    total_reads: ReadCount,
+    mapped_bases: BaseCount,
+    mapped_reads: ReadCount,
+    mean: Coverage,
+    quartiles: [Coverage; 3],
     f80_penalty: f64,
+    poisson_fit: Option<PoissonFit>,
+    #[serde(default)]
+    e_score: Option<f64>,
 }
 
 impl KmerCoverage {
-    pub fn median(&self) -> f64 {
+    pub fn mean(&self) -> Coverage {
+        self.mean
+    }
+
+    /// Lower quartile, median and upper quartile of per target coverage
+    pub fn quartiles(&self) -> &[Coverage; 3] {
+        &self.quartiles
+    }
+
+    pub fn median(&self) -> Coverage {
         self.quartiles[1]
     }
 
     pub fn iqr(&self) -> f64 {
-        self.quartiles[2] - self.quartiles[0]
+        self.quartiles[2].get() - self.quartiles[0].get()
     }
 
     pub fn dispersion(&self) -> f64 {
-        self.iqr() / (self.quartiles[0] + self.quartiles[1])
+        self.iqr() / (self.quartiles[0].get() + self.quartiles[1].get())
     }
 
     pub fn fold_80_base_penalty(&self) -> f64 {
         self.f80_penalty
     }
+
+    /// Evenness score of per target coverage (None if there is no coverage)
+    pub fn e_score(&self) -> Option<f64> {
+        self.e_score
+    }
+
+    /// Fraction of reads mapped to targets (None if there are no reads)
+    pub fn mapped_fraction(&self) -> Option<f64> {
+        let n = self.total_reads.get();
+        (n > 0).then(|| self.mapped_reads.get() as f64 / n as f64)
+    }
+
+    /// Coverage metrics as a JSON value (for JSON output)
+    pub fn json_summary(&self) -> Value {
+        json!({
+            "total_reads": self.total_reads,
+            "mapped_reads": self.mapped_reads,
+            "total_bases": self.total_bases,
+            "mapped_bases": self.mapped_bases,
+            "mean_coverage": self.mean,
+            "median_coverage": self.median(),
+            "median_mean_ratio": self.median().get() / self.mean.get(),
+            "dispersion": self.dispersion(),
+            "fold_80_base_penalty": self.fold_80_base_penalty(),
+            "overdispersion": self.poisson_fit.map(|pf| pf.overdispersion()),
+            "poisson_p": self.poisson_fit.map(|pf| pf.p()),
+            "e_score": self.e_score,
+        })
+    }
 }
 impl fmt::Display for KmerCoverage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -132,9 +432,27 @@ impl fmt::Display for KmerCoverage {
             self.mapped_bases,
             self.mean,
             self.median(),
-            self.median() / self.mean,
+            self.median().get() / self.mean.get(),
             self.dispersion(),
             self.fold_80_base_penalty()
-        )
+        )?;
+        if let Some(pf) = self.poisson_fit.as_ref() {
+            write!(f, "\t{:.6}\t{:.5e}", pf.overdispersion(), pf.p())
+        } else {
+            write!(f, "\tNA\tNA")
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn evenness_score_test() {
+        assert_eq!(evenness_score(&[5.0, 5.0, 5.0]), Some(1.0));
+        // mean = 4, so min(c / mean, 1) = 0, 0.5, 1, 1
+        assert_eq!(evenness_score(&[0.0, 2.0, 6.0, 8.0]), Some(0.625));
+        assert_eq!(evenness_score(&[0.0, 0.0]), None);
     }
 }
@@ -0,0 +1,96 @@
+//! Collect and analyze per cycle base composition and gc content statistics generated by
+//! fastq_gc.
+//!
+//! The main entry points for embedding the analysis are [`read_json`] to read a dataset,
+//! [`analyze_dataset`] to compute the metrics for a dataset using a [`Config`] (built using
+//! [`ConfigBuilder`]), and [`kl_distance`] or [`GcDensity`] to compare and
+//! summarize gc distributions directly.  The types taken by the [`ConfigBuilder`] options are
+//! also exported; these are enums, are parsed from strings as on the command line (e.g.,
+//! [`MergeKey`]) or are read from files (e.g., [`Kmcv::read`], [`Thresholds::from_file`]).  [`run`] runs gc_collect with the command
+//! line arguments of the process, as for the gc_collect binary.  The other modules are internal.
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate anyhow;
+
+mod aux_file;
+mod bam;
+mod baseline;
+mod betabin;
+mod build_ref;
+mod cache;
+mod cli;
+mod composition;
+mod concat;
+mod consistency;
+mod contamination;
+mod contrast;
+mod fastq;
+mod fingerprint;
+mod fli_duplicates;
+mod fragments;
+mod gauss_legendre;
+mod gc_density;
+mod gof;
+mod html_report;
+mod kmcv;
+mod kmers;
+mod manifest;
+mod mapped_fraction;
+mod merge;
+mod merge_expr;
+mod multiqc;
+mod outliers;
+mod output;
+mod pairwise;
+mod panel_compare;
+mod process;
+mod qual_bands;
+mod read;
+mod read_ends;
+mod reference;
+mod report;
+mod rescore;
+mod results_table;
+mod run;
+mod schema;
+mod self_reference;
+mod selftest;
+#[cfg(feature = "server")]
+mod server;
+mod simple_regression;
+mod simulate;
+mod source;
+mod stats;
+mod status;
+#[cfg(unix)]
+mod stream;
+mod summary;
+mod temp_dir;
+mod thresholds;
+mod utils;
+mod validate;
+#[cfg(feature = "watch")]
+mod watch;
+
+pub use bam::AlignedReads;
+pub use baseline::{Baseline, BaselineFilter};
+pub use betabin::{kl_distance, Divergence};
+pub use cli::{Config, ConfigBuilder, MergeKey};
+pub use fingerprint::FingerprintDb;
+pub use gc_density::GcDensity;
+pub use gof::GofTest;
+pub use kmcv::Kmcv;
+pub use manifest::Manifest;
+pub use mapped_fraction::TargetGc;
+pub use merge::GroupOrder;
+pub use output::{OutputFormat, SortKey};
+pub use process::{analyze_dataset, DataResults};
+pub use read::{read_json, DataSet};
+pub use reference::{Counts, GcHistKey, GcHistVal, RefDist, ReferenceProvider, ReferenceSource};
+pub use run::run;
+pub use schema::Schema;
+pub use simple_regression::DriftModel;
+pub use source::InputFormat;
+pub use thresholds::Thresholds;
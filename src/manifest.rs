@@ -0,0 +1,261 @@
+use std::{
+    borrow::Cow,
+    collections::{hash_map, HashMap},
+    io::BufRead,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+
+use crate::{
+    cache::hash_file,
+    cli::{read_kmcv_file, read_reference},
+    kmcv::Kmcv,
+    read::DataSet,
+    reference::ReferenceProvider,
+};
+
+/// Manifest columns.  Only path is required.  name is an alias for sample
+const MANIFEST_COLUMNS: [&str; 8] = [
+    "path",
+    "reference",
+    "kmcv",
+    "library_type",
+    "name",
+    "sample",
+    "barcode",
+    "library",
+];
+
+/// Columns (as indices into MANIFEST_COLUMNS) for files without a header line
+const HEADERLESS_COLUMNS: [usize; 4] = [0, 5, 6, 7];
+
+/// Per input file overrides from one row of the manifest.  References and kmer files are
+/// stored as indices into the (deduplicated) file lists of the manifest
+#[derive(Debug, Default)]
+struct ManifestEntry {
+    reference: Option<usize>,
+    kmcv: Option<usize>,
+    library_type: Option<String>,
+    sample: Option<String>,
+    barcode: Option<String>,
+    library: Option<String>,
+}
+
+/// Auxiliary file loaded from the manifest, with the hash of its contents (for the results cache)
+struct Loaded<T> {
+    id: u128,
+    data: T,
+}
+
+/// Input files with per file overrides read from a TSV manifest file.
+///
+/// The manifest has a header line naming the columns.  The path column is required;
+/// the optional reference, kmcv and library_type columns give overrides for the reference
+/// distributions, kmer file and library type of the dataset, and the sample (or name), barcode
+/// and library columns replace the corresponding dataset metadata.  If the first line does not
+/// have a path column, the file is taken as having no header, with the columns path, sample,
+/// barcode and library.  Empty, `-` or `NA` values leave the global setting (or the metadata
+/// from the input file) unchanged.  Lines starting with `#` are ignored.
+#[derive(Default)]
+pub struct Manifest {
+    input_files: Vec<PathBuf>,
+    entries: HashMap<PathBuf, ManifestEntry>,
+    references: Vec<Loaded<Box<dyn ReferenceProvider>>>,
+    kmcvs: Vec<Loaded<Kmcv>>,
+}
+
+// Add file to list if not already present (as judged by the path), returning the index
+fn add_file<T, F>(
+    v: &mut Vec<Loaded<T>>,
+    seen: &mut HashMap<String, usize>,
+    s: &str,
+    f: F,
+) -> anyhow::Result<usize>
+where
+    F: Fn(&Path) -> anyhow::Result<T>,
+{
+    Ok(match seen.entry(s.to_owned()) {
+        hash_map::Entry::Occupied(e) => *e.get(),
+        hash_map::Entry::Vacant(e) => {
+            let p = Path::new(s);
+            let data = f(p)?;
+            v.push(Loaded {
+                id: hash_file(p)?,
+                data,
+            });
+            *e.insert(v.len() - 1)
+        }
+    })
+}
+
+impl Manifest {
+    pub fn read<P: AsRef<Path>>(p: P) -> anyhow::Result<Self> {
+        let p = p.as_ref();
+        let rdr = CompressIo::new()
+            .path(p)
+            .bufreader()
+            .with_context(|| format!("Could not open manifest file {} for input", p.display()))?;
+        Self::read_manifest(rdr)
+            .with_context(|| format!("Error reading manifest file {}", p.display()))
+    }
+
+    fn read_manifest<R: BufRead>(rdr: R) -> anyhow::Result<Self> {
+        let mut manifest = Self::default();
+        let mut columns: Option<Vec<Option<usize>>> = None;
+        let mut seen_refs = HashMap::new();
+        let mut seen_kmcvs = HashMap::new();
+
+        for (ix, line) in rdr.split(b'\n').enumerate() {
+            // Names are not required to be UTF-8; invalid sequences are replaced
+            let line = line?;
+            let line = String::from_utf8_lossy(&line);
+            if let Cow::Owned(_) = line {
+                warn!("Invalid UTF-8 at manifest line {} replaced", ix + 1)
+            }
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<_> = line.split('\t').map(|s| s.trim()).collect();
+
+            // First non comment line is the header if it has a path column
+            if columns.is_none() {
+                let cols: Vec<_> = fields
+                    .iter()
+                    .map(|s| {
+                        MANIFEST_COLUMNS
+                            .iter()
+                            .position(|c| c.eq_ignore_ascii_case(s))
+                    })
+                    .collect();
+                if cols.contains(&Some(0)) {
+                    for (c, s) in cols.iter().zip(fields.iter()) {
+                        if c.is_none() {
+                            warn!("Unknown column {s} in manifest header ignored")
+                        }
+                    }
+                    columns = Some(cols);
+                    continue;
+                }
+                columns = Some(HEADERLESS_COLUMNS.iter().map(|c| Some(*c)).collect());
+            }
+            let cols = columns.as_ref().unwrap();
+
+            let mut path = None;
+            let mut entry = ManifestEntry::default();
+            for (c, s) in cols.iter().zip(fields.iter()) {
+                if s.is_empty() || *s == "-" || *s == "NA" {
+                    continue;
+                }
+                match c {
+                    Some(0) => path = Some(PathBuf::from(s)),
+                    Some(1) => {
+                        entry.reference = Some(add_file(
+                            &mut manifest.references,
+                            &mut seen_refs,
+                            s,
+                            |p| {
+                                read_reference(p).map(|r| Box::new(r) as Box<dyn ReferenceProvider>)
+                            },
+                        )?)
+                    }
+                    Some(2) => {
+                        entry.kmcv = Some(add_file(
+                            &mut manifest.kmcvs,
+                            &mut seen_kmcvs,
+                            s,
+                            read_kmcv_file,
+                        )?)
+                    }
+                    Some(3) => entry.library_type = Some(s.to_string()),
+                    Some(4) | Some(5) => entry.sample = Some(s.to_string()),
+                    Some(6) => entry.barcode = Some(s.to_string()),
+                    Some(7) => entry.library = Some(s.to_string()),
+                    _ => (),
+                }
+            }
+            let path = path.ok_or_else(|| anyhow!("Missing path at line {}", ix + 1))?;
+            match manifest.entries.entry(path.clone()) {
+                hash_map::Entry::Occupied(_) => {
+                    return Err(anyhow!(
+                        "Duplicate path {} at line {}",
+                        path.display(),
+                        ix + 1
+                    ))
+                }
+                hash_map::Entry::Vacant(e) => {
+                    e.insert(entry);
+                }
+            }
+            manifest.input_files.push(path);
+        }
+
+        if manifest.input_files.is_empty() {
+            Err(anyhow!("No input files in manifest"))
+        } else {
+            Ok(manifest)
+        }
+    }
+
+    /// Input files in manifest order
+    pub fn input_files(&self) -> &[PathBuf] {
+        &self.input_files
+    }
+
+    /// Reference override for input file p (with the id of the reference file)
+    pub fn reference(&self, p: &Path) -> Option<(u128, &dyn ReferenceProvider)> {
+        self.entries
+            .get(p)
+            .and_then(|e| e.reference)
+            .map(|ix| (self.references[ix].id, self.references[ix].data.as_ref()))
+    }
+
+    /// Kmer file override for input file p (with the id of the kmer file)
+    pub fn kmcv(&self, p: &Path) -> Option<(u128, &Kmcv)> {
+        self.entries
+            .get(p)
+            .and_then(|e| e.kmcv)
+            .map(|ix| (self.kmcvs[ix].id, &self.kmcvs[ix].data))
+    }
+
+    pub fn library_type(&self, p: &Path) -> Option<&str> {
+        self.entries.get(p).and_then(|e| e.library_type.as_deref())
+    }
+
+    pub fn has_kmcv(&self) -> bool {
+        !self.kmcvs.is_empty()
+    }
+
+    /// Input files p1 and p2 have the same reference and kmer file overrides, so can be
+    /// analyzed together when merged
+    pub fn same_analysis(&self, p1: &Path, p2: &Path) -> bool {
+        let get = |p| {
+            self.entries
+                .get(p)
+                .map(|e: &ManifestEntry| (e.reference, e.kmcv))
+                .unwrap_or_default()
+        };
+        get(p1) == get(p2)
+    }
+
+    /// Apply the library type and metadata overrides for input file p to dataset d
+    pub fn apply(&self, p: &Path, d: &mut DataSet) {
+        if let Some(e) = self.entries.get(p) {
+            if let Some(s) = e.library_type.as_ref() {
+                d.set_library_type(s.clone())
+            }
+            let fli = d.fli_mut();
+            if let Some(s) = e.sample.as_ref() {
+                fli.set_sample(s.clone())
+            }
+            if let Some(s) = e.barcode.as_ref() {
+                fli.set_barcode(s.clone())
+            }
+            if let Some(s) = e.library.as_ref() {
+                fli.set_library(s.clone())
+            }
+        }
+    }
+}
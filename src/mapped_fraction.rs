@@ -0,0 +1,222 @@
+//! Expected fraction of reads mapped to targets given the gc bias of a dataset (--target-gc).
+//!
+//! The gc bias curve of a dataset is the ratio of its per read gc density to that of the
+//! reference, evaluated over GC_BIAS_BINS equal width bins, so that the bias averaged over the
+//! reference distribution is 1.  Using the gc content of each target (read from the target gc
+//! file), the gc bias factor of the panel is the mean of the bias curve at the target gc values
+//! weighted by target size: the relative efficiency with which the targets are sequenced
+//! compared to the genome as a whole.  If the mapped fraction expected for the panel without
+//! gc bias is given (--expected-mapped-fraction), the expected mapped fraction is this value
+//! scaled by the bias factor, and the observed/expected ratio is reported.  A low mapped
+//! fraction explained by the gc bias factor points to gc driven dropout, whereas a low
+//! observed/expected ratio points to other causes such as contamination.
+//!
+//! The target gc file is tab separated with the contig, start, end (as in the target coverage
+//! output) and gc content (as a fraction) of each target.  Lines starting with '#' and a
+//! header line are ignored.  Targets of the kmer file that are not in the target gc file are
+//! not used.
+
+use std::{collections::HashMap, io::BufRead, path::Path};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    gc_density::GcDensity,
+    kmcv::Kmcv,
+    reference::{GcHistKey, GcHistVal},
+};
+
+/// Column names in TSV output
+pub const MAPPED_FRACTION_COLUMNS: [&str; 3] = [
+    "GC-bias-factor",
+    "Expected-mapped-frac",
+    "Mapped-frac-obs/exp",
+];
+
+// Number of bins for the gc bias curve
+const GC_BIAS_BINS: usize = 20;
+
+/// gc content of the panel targets, keyed by contig, start and end
+#[derive(Debug, Default)]
+pub struct TargetGc {
+    gc: HashMap<(String, u32, u32), f64>,
+}
+
+impl TargetGc {
+    pub fn read<P: AsRef<Path>>(p: P) -> anyhow::Result<Self> {
+        let p = p.as_ref();
+        let rdr = CompressIo::new()
+            .path(p)
+            .bufreader()
+            .with_context(|| format!("Could not open target gc file {} for input", p.display()))?;
+        Self::read_target_gc(rdr)
+            .with_context(|| format!("Error reading target gc file {}", p.display()))
+    }
+
+    fn read_target_gc<R: BufRead>(rdr: R) -> anyhow::Result<Self> {
+        let mut gc = HashMap::new();
+        for (ix, line) in rdr.lines().enumerate() {
+            let line = line?;
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<_> = line.split('\t').map(|s| s.trim()).collect();
+            if fields.len() < 4 {
+                return Err(anyhow!("Too few columns at line {}", ix + 1));
+            }
+            let (Ok(start), Ok(end)) = (fields[1].parse::<u32>(), fields[2].parse::<u32>()) else {
+                // Header line
+                if gc.is_empty() {
+                    continue;
+                }
+                return Err(anyhow!("Invalid coordinates at line {}", ix + 1));
+            };
+            let x = fields[3]
+                .parse::<f64>()
+                .ok()
+                .filter(|x| (0.0..=1.0).contains(x))
+                .ok_or_else(|| anyhow!("Invalid gc content at line {}", ix + 1))?;
+            gc.insert((fields[0].to_owned(), start, end), x);
+        }
+        if gc.is_empty() {
+            Err(anyhow!("No targets found"))
+        } else {
+            debug!("Read gc content of {} targets", gc.len());
+            Ok(Self { gc })
+        }
+    }
+
+    /// gc content and size of the targets of kmcv present in the file
+    fn targets(&self, kmcv: &Kmcv) -> Vec<(f64, f64)> {
+        kmcv.targets()
+            .filter_map(|(ctg, t)| {
+                self.gc
+                    .get(&(ctg.to_owned(), t.start(), t.end()))
+                    .map(|x| (*x, t.size() as f64))
+            })
+            .collect()
+    }
+}
+
+/// Observed mapped fraction compared with that expected under the gc bias of a dataset
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct MappedFractionMetrics {
+    bias_factor: f64,
+    expected: Option<f64>,
+    ratio: Option<f64>,
+}
+
+impl MappedFractionMetrics {
+    /// Metrics from the gc counts of the dataset (cts) and the reference, smoothed with a
+    /// prior of `prior` pseudo-reads.  observed is the observed mapped fraction and
+    /// expected_unbiased the mapped fraction expected without gc bias.  Returns None if no
+    /// targets of kmcv have a gc content or the bias curve is not defined at any of them
+    pub fn make(
+        cts: &[(GcHistKey, GcHistVal)],
+        ref_cts: &[(GcHistKey, GcHistVal)],
+        prior: f64,
+        kmcv: &Kmcv,
+        target_gc: &TargetGc,
+        observed: Option<f64>,
+        expected_unbiased: Option<f64>,
+    ) -> Option<Self> {
+        let curve = bias_curve(cts, ref_cts, prior);
+        let bias_factor = bias_factor(&curve, &target_gc.targets(kmcv))?;
+        let expected = expected_unbiased.map(|f| (f * bias_factor).min(1.0));
+        let ratio = observed
+            .zip(expected)
+            .and_then(|(o, e)| (e > 0.0).then_some(o / e));
+        Some(Self {
+            bias_factor,
+            expected,
+            ratio,
+        })
+    }
+
+    /// Relative sequencing efficiency of the targets given the gc bias
+    pub fn bias_factor(&self) -> f64 {
+        self.bias_factor
+    }
+
+    pub fn expected(&self) -> Option<f64> {
+        self.expected
+    }
+
+    /// Observed / expected mapped fraction
+    pub fn ratio(&self) -> Option<f64> {
+        self.ratio
+    }
+}
+
+// Ratio of the dataset and reference gc densities for each bin (None if the reference
+// density is zero)
+fn bias_curve(
+    cts: &[(GcHistKey, GcHistVal)],
+    ref_cts: &[(GcHistKey, GcHistVal)],
+    prior: f64,
+) -> Vec<Option<f64>> {
+    let h = GcDensity::with_prior(cts, prior).histogram(GC_BIAS_BINS);
+    let rh = GcDensity::with_prior(ref_cts, prior).histogram(GC_BIAS_BINS);
+    h.iter()
+        .zip(rh.iter())
+        .map(|((_, y), (_, r))| (*r > 0.0).then(|| y / r))
+        .collect()
+}
+
+// Target size weighted mean of the bias curve at the gc content of the targets, given as
+// (gc, size) pairs
+fn bias_factor(curve: &[Option<f64>], targets: &[(f64, f64)]) -> Option<f64> {
+    let n = curve.len();
+    let (s, w) = targets
+        .iter()
+        .filter_map(|(gc, sz)| {
+            let i = ((gc * n as f64) as usize).min(n - 1);
+            curve[i].map(|b| (b * sz, *sz))
+        })
+        .fold((0.0, 0.0), |(s, w), (x, z)| (s + x, w + z));
+    (w > 0.0).then(|| s / w)
+}
+
+/// Columns of the main TSV output for metrics m (NA if not available)
+pub fn mapped_fraction_columns(m: Option<&MappedFractionMetrics>) -> String {
+    let opt = |x: Option<f64>| {
+        x.map(|x| format!("\t{x:.5}"))
+            .unwrap_or_else(|| "\tNA".to_owned())
+    };
+    match m {
+        Some(m) => format!(
+            "{}{}{}",
+            opt(Some(m.bias_factor)),
+            opt(m.expected),
+            opt(m.ratio)
+        ),
+        None => "\tNA\tNA\tNA".to_owned(),
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn bias_factor_test() {
+        let mut curve = vec![Some(1.0); GC_BIAS_BINS];
+        curve[GC_BIAS_BINS - 1] = Some(0.25);
+        curve[0] = None;
+        // Target at gc 1 uses the last bin, and the target at gc 0 is not used
+        let t = [(0.5, 100.0), (1.0, 300.0), (0.0, 50.0)];
+        assert_eq!(bias_factor(&curve, &t), Some(0.4375));
+        assert_eq!(bias_factor(&curve, &[(0.01, 10.0)]), None);
+    }
+
+    #[test]
+    fn read_target_gc_test() {
+        let s = "#panel\nContig\tStart\tEnd\tgc\nchr1\t100\t199\t0.45\nchr2\t5\t10\t0.6\n";
+        let t = TargetGc::read_target_gc(s.as_bytes()).unwrap();
+        assert_eq!(t.gc.get(&("chr2".to_owned(), 5, 10)), Some(&0.6));
+        assert!(TargetGc::read_target_gc("chr1\t1\t2\t45\n".as_bytes()).is_err());
+    }
+}
@@ -1,70 +1,962 @@
 use anyhow::Context;
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use crossbeam_utils::thread;
 use std::{
-    collections::{hash_map, HashMap},
+    borrow::Cow,
+    collections::{btree_map, BTreeMap, HashMap},
+    fs,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
+use clap::{builder::PossibleValue, ValueEnum};
+use serde::{Deserialize, Serialize};
+
 use crate::{
+    betabin::{mean_gc, smoothed_kl_distance},
+    cache::CountsKey,
     cli::{Config, MergeKey},
-    read::{read_json, DataSet, Fli},
+    consistency::Consistency,
+    merge_expr::path_key,
+    process::{analyze_input, analyze_member, DataResults},
+    read::{DataSet, DataSummary, Fli},
+    reference::{GcHistKey, GcHistVal},
+    source::{is_counted, is_stdin, read_dataset},
+    status::RunStatus,
+    temp_dir::atomic_write,
+    thresholds::QcStatus,
 };
 
-fn get_merge_key(fli: &mut Fli, mut m: MergeKey) -> anyhow::Result<(MergeKey, String)> {
+// Merge key type and key for a dataset from input file p.  If split_read_ends is set, the read
+// end (if known) is added to the key so that datasets from different read ends are merged
+// separately
+fn get_merge_key(
+    fli: &mut Fli,
+    mut m: MergeKey,
+    p: &Path,
+    split_read_ends: bool,
+) -> anyhow::Result<(MergeKey, String)> {
     if matches!(m, MergeKey::Default) {
-        m = fli
-            .find_merge_key()
-            .ok_or(anyhow!("Couldn't determine merge key type for dataset"))?
+        m = fli.find_merge_key().ok_or(anyhow!(
+            "Couldn't determine merge key type for dataset (use --merge-by-path if input files have no FLI information)"
+        ))?
+    }
+    let key = match &m {
+        MergeKey::Path(re) => path_key(re, p).ok_or_else(|| {
+            anyhow!(
+                "Merge key regex {} does not match input file {}",
+                re.as_str(),
+                p.display()
+            )
+        })?,
+        _ => fli
+            .get_key(&m)
+            .ok_or(anyhow!("Couldn't establish merge key not dataset"))?,
+    };
+
+    match fli.read_end() {
+        Some(r) if split_read_ends => Ok((m, format!("{key}_R{r}"))),
+        _ => Ok((m, key)),
     }
-    let key = fli
-        .get_key(m)
-        .ok_or(anyhow!("Couldn't establish merge key not dataset"))?;
+}
+
+/// Order in which merged groups are emitted (and so numbered)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GroupOrder {
+    /// Sorted by merge key
+    #[default]
+    Key,
+    /// Order of the first input file of each group
+    Input,
+    /// Decreasing number of input files, with ties broken by merge key
+    Size,
+}
 
-    Ok((m, key))
+impl ValueEnum for GroupOrder {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Key, Self::Input, Self::Size]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Key => Some(PossibleValue::new("key")),
+            Self::Input => Some(PossibleValue::new("input")),
+            Self::Size => Some(PossibleValue::new("size")),
+        }
+    }
+}
+
+// Path and gc distribution of a member of a merged dataset
+type MemberCounts = (PathBuf, Vec<(GcHistKey, GcHistVal)>);
+
+/// Merged dataset with one of its input files (used to look up manifest overrides) and, if
+/// requested, the gc distributions of the individual members.  With --keep-individual the
+/// members are analyzed before they are merged, and their results are kept to be output
+/// with the group
+pub struct MergedDataSet<'a> {
+    data: Arc<DataSet>,
+    ix: usize,
+    path: Cow<'a, Path>,
+    n_inputs: usize,
+    group: usize,
+    level: usize,
+    members: Arc<Vec<MemberCounts>>,
+    individuals: Vec<(DataSummary, DataResults)>,
+    excluded: Option<Vec<PathBuf>>,
+}
+
+/// Results of an individual member of a merged dataset (--keep-individual), with the number
+/// and merge key of its group
+pub struct Individual {
+    group: usize,
+    parent: String,
+    data: DataSummary,
+    results: DataResults,
+}
+
+impl Individual {
+    pub fn into_parts(self) -> (usize, String, DataSummary, DataResults) {
+        (self.group, self.parent, self.data, self.results)
+    }
+}
+
+/// Sent by the merge threads for analysis: merged datasets, and the already analyzed
+/// individual members
+pub enum MergeItem<'a> {
+    Group(MergedDataSet<'a>),
+    Individual(Box<Individual>),
+}
+
+impl<'a> MergedDataSet<'a> {
+    pub fn data(&self) -> &DataSet {
+        &self.data
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of the group in emission order (from 0), which is stable across runs with
+    /// the same inputs and group order
+    pub fn group_index(&self) -> usize {
+        self.group
+    }
+
+    /// Merge level: 1 for groups of input datasets and 2 for groups of level 1 merged datasets
+    /// (--then-by)
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    // Copy sharing the merged data and member distributions.  Used in watch mode, where
+    // individual members are not kept
+    #[cfg(feature = "watch")]
+    fn shared(&self) -> Self {
+        debug_assert!(self.individuals.is_empty());
+        Self {
+            data: self.data.clone(),
+            ix: self.ix,
+            path: self.path.clone(),
+            n_inputs: self.n_inputs,
+            group: self.group,
+            level: self.level,
+            members: self.members.clone(),
+            individuals: Vec::new(),
+            excluded: self.excluded.clone(),
+        }
+    }
+
+    /// Members excluded for failing QC thresholds (if --exclude-failing is set)
+    pub fn excluded(&self) -> Option<&[PathBuf]> {
+        self.excluded.as_deref()
+    }
+
+    /// KL distance of each member gc distribution from the merged distribution, smoothed with a
+    /// prior of kl_prior pseudo-reads.  Returns None if member distributions were not collected
+    pub fn member_kl(&self, kl_prior: f64) -> Option<MemberKl> {
+        let merged = self.data.gc_counts()?;
+        if self.members.is_empty() {
+            None
+        } else {
+            Some(MemberKl {
+                members: self
+                    .members
+                    .iter()
+                    .map(|(p, cts)| (p.clone(), smoothed_kl_distance(cts, merged, kl_prior)))
+                    .collect(),
+            })
+        }
+    }
+
+    /// Leave-one-out influence of members on the mean gc and (if ref_counts is given) the KL
+    /// distance from the reference of the merged dataset.  Returns the member with the largest
+    /// absolute change in KL distance (or in mean gc if there is no reference).  Returns None
+    /// if member distributions were not collected or there are fewer than two members.  KL
+    /// distances are smoothed with a prior of kl_prior pseudo-reads
+    pub fn influence(
+        &self,
+        ref_counts: Option<&[(GcHistKey, GcHistVal)]>,
+        kl_prior: f64,
+    ) -> Option<Influence> {
+        let merged = self.data.gc_counts()?;
+        if self.members.len() < 2 {
+            return None;
+        }
+        let gc = mean_gc(merged);
+        let kl = ref_counts.map(|r| smoothed_kl_distance(merged, r, kl_prior));
+        let merged_cts: HashMap<GcHistKey, f64> =
+            merged.iter().map(|(k, v)| (*k, v.count())).collect();
+
+        self.members
+            .iter()
+            .filter_map(|(p, cts)| {
+                // Merged counts with member removed
+                let mut h = merged_cts.clone();
+                for (k, v) in cts.iter() {
+                    if let Some(x) = h.get_mut(k) {
+                        *x -= v.count()
+                    }
+                }
+                let loo: Vec<_> = h
+                    .into_iter()
+                    .filter(|(_, x)| *x > 0.5)
+                    .map(|(k, x)| (k, GcHistVal::make(&k, x.round() as u64)))
+                    .collect();
+                if loo.is_empty() {
+                    return None;
+                }
+                let delta_kl = match (ref_counts, kl) {
+                    (Some(r), Some(kl)) => Some(smoothed_kl_distance(&loo, r, kl_prior) - kl),
+                    _ => None,
+                };
+                Some(Influence {
+                    member: Some(p.clone()),
+                    delta_gc: Some(mean_gc(&loo) - gc),
+                    delta_kl,
+                })
+            })
+            .max_by(|a, b| a.size().total_cmp(&b.size()))
+    }
 }
 
-fn merge_dataset(
+/// Change in merged mean gc and KL distance when the member is excluded from the merge.
+/// All fields are None for merged datasets with a single member
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Influence {
+    member: Option<PathBuf>,
+    delta_gc: Option<f64>,
+    delta_kl: Option<f64>,
+}
+
+impl Influence {
+    pub fn member(&self) -> Option<&Path> {
+        self.member.as_deref()
+    }
+
+    pub fn delta_gc(&self) -> Option<f64> {
+        self.delta_gc
+    }
+
+    pub fn delta_kl(&self) -> Option<f64> {
+        self.delta_kl
+    }
+
+    fn size(&self) -> f64 {
+        self.delta_kl.or(self.delta_gc).unwrap_or(0.0).abs()
+    }
+}
+
+/// KL distances between the members of a merged dataset and the merged gc distribution.
+/// Individual members (--keep-individual) have no members
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct MemberKl {
+    members: Vec<(PathBuf, f64)>,
+}
+
+impl MemberKl {
+    pub fn members(&self) -> &[(PathBuf, f64)] {
+        &self.members
+    }
+
+    pub fn max(&self) -> f64 {
+        self.members.iter().map(|(_, x)| *x).fold(0.0, f64::max)
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.members.iter().map(|(_, x)| *x).sum::<f64>() / self.members.len() as f64
+    }
+}
+
+// Check if member fails QC thresholds when analyzed individually
+fn fails_qc(cfg: &Config, p: &Path, d: &mut DataSet) -> anyhow::Result<bool> {
+    Ok(match cfg.thresholds() {
+        Some(t) => {
+            d.mk_gc_counts()?;
+            let res = analyze_member(cfg, p, d)?;
+            t.evaluate(&res) == QcStatus::Fail
+        }
+        None => false,
+    })
+}
+
+/// Input dataset prepared for merging, with the manifest overrides applied, the QC status
+/// checked (if excluding failing members) and the member gc distribution extracted (if required)
+struct Prepared<'a> {
+    data: DataSet,
+    path: Cow<'a, Path>,
+    fails_qc: bool,
+    member: Option<MemberCounts>,
+    individual: Option<DataResults>,
+    ix: usize,
+}
+
+// The work done here is independent of the other datasets, so can be done in parallel
+fn prepare<'a>(
+    cfg: &Config,
     mut d: DataSet,
+    ix: usize,
+    p: Cow<'a, Path>,
+) -> anyhow::Result<Prepared<'a>> {
+    if let Some(man) = cfg.manifest() {
+        man.apply(&p, &mut d)
+    }
+    let fails_qc = cfg.exclude_failing() && fails_qc(cfg, &p, &mut d)?;
+
+    let member = if !fails_qc && (cfg.member_kl().is_some() || cfg.influence()) {
+        d.mk_gc_counts()?;
+        Some((
+            d.path().to_owned(),
+            d.gc_counts().expect("Missing gc counts").to_vec(),
+        ))
+    } else {
+        None
+    };
+    // Individual members are analyzed here, before being merged, so that the dataset does not
+    // need to be kept
+    let individual = if !fails_qc && cfg.keep_individual() {
+        d.mk_gc_counts()?;
+        Some(analyze_input(cfg, &p, &d)?)
+    } else {
+        None
+    };
+    Ok(Prepared {
+        data: d,
+        path: p,
+        fails_qc,
+        member,
+        individual,
+        ix,
+    })
+}
+
+// Merged datasets are stored with the first input file of the group.  Members excluded
+// for failing QC thresholds are recorded in excluded.  The processing parameters of the
+// members are recorded in consistency, and members of inconsistent groups are not merged
+fn add_prepared<'a>(
+    cfg: &Config,
+    pr: Prepared<'a>,
     m: MergeKey,
-    hash: &mut HashMap<String, DataSet>,
+    groups: &mut BTreeMap<String, MergedDataSet<'a>>,
+    excluded: &mut BTreeMap<String, Vec<PathBuf>>,
+    consistency: &mut Consistency,
 ) -> anyhow::Result<MergeKey> {
-    let (m, key) = get_merge_key(d.fli_mut(), m)?;
+    let Prepared {
+        data: mut d,
+        path: p,
+        fails_qc,
+        member,
+        individual,
+        ix,
+    } = pr;
+    let (m, key) = get_merge_key(d.fli_mut(), m, &p, cfg.split_read_ends())?;
+
+    if fails_qc {
+        warn!(
+            "Excluding {} from merged dataset {key} as it fails QC thresholds",
+            d.path().display()
+        );
+        excluded.entry(key).or_default().push(d.path().to_owned());
+        return Ok(m);
+    }
+    if !consistency.add(&key, &d, &p) {
+        return Ok(m);
+    }
 
     let path = PathBuf::from(&key);
-    match hash.entry(key) {
-        hash_map::Entry::Occupied(mut e) => e.get_mut().merge(&d)?,
-        hash_map::Entry::Vacant(e) => {
+    match groups.entry(key) {
+        btree_map::Entry::Occupied(mut e) => {
+            let md = e.get_mut();
+            if let Some(man) = cfg.manifest() {
+                if !man.same_analysis(&md.path, &p) {
+                    return Err(anyhow!(
+                        "Input files {} and {} have different reference or kmcv overrides in manifest but are merged",
+                        md.path.display(),
+                        p.display()
+                    ));
+                }
+            }
+            Arc::make_mut(&mut md.data).merge(&d, cfg.merge_bisulfite_strands())?;
+            md.n_inputs += 1;
+            Arc::make_mut(&mut md.members).extend(member);
+            md.individuals
+                .extend(individual.map(|res| (d.summary(), res)));
+        }
+        btree_map::Entry::Vacant(e) => {
+            let individuals = individual
+                .map(|res| (d.summary(), res))
+                .into_iter()
+                .collect();
             d.set_path(path);
-            e.insert(d);
+            e.insert(MergedDataSet {
+                data: Arc::new(d),
+                ix,
+                path: p,
+                n_inputs: 1,
+                group: 0,
+                level: 1,
+                members: Arc::new(member.into_iter().collect()),
+                individuals,
+                excluded: None,
+            });
         }
     }
 
     Ok(m)
 }
 
-pub fn merge_thread(cfg: &Config, rx: Receiver<&Path>, sd: Sender<DataSet>) -> anyhow::Result<()> {
-    debug!("Merge thread starting up");
+// Merge dataset in the second pass of a two pass merge, when the consistency of the groups
+// has already been checked
+fn merge_dataset<'a>(
+    cfg: &Config,
+    d: DataSet,
+    ix: usize,
+    p: &'a Path,
+    m: MergeKey,
+    groups: &mut BTreeMap<String, MergedDataSet<'a>>,
+    excluded: &mut BTreeMap<String, Vec<PathBuf>>,
+) -> anyhow::Result<MergeKey> {
+    add_prepared(
+        cfg,
+        prepare(cfg, d, ix, p.into())?,
+        m,
+        groups,
+        excluded,
+        &mut Consistency::default(),
+    )
+}
 
-    let mut merge_key = cfg.merge_key().expect("Cannot merge without a key!");
+// Read the datasets (one per read group) from input file p.  Returns an empty vector if the
+// file could not be read and errors are being skipped
+fn read_input(cfg: &Config, p: &Path, status: &RunStatus) -> anyhow::Result<Vec<DataSet>> {
+    match read_counts(cfg, p).with_context(|| format!("Error reading from {}", p.display())) {
+        Ok(v) => Ok(v),
+        Err(e) if cfg.skip_errors() => {
+            warn!("Skipping {}: {e:#}", p.display());
+            status.add_failed_input(p, &e);
+            Ok(Vec::new())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Read the datasets from input file p, using the counts from the cache if p is counted from
+// reads (FASTQ, BAM or CRAM) and was counted in an earlier run
+fn read_counts(cfg: &Config, p: &Path) -> anyhow::Result<Vec<DataSet>> {
+    let Some(cache) = cfg.cache().filter(|_| is_counted(p, cfg.input_format())) else {
+        return read_dataset(p, cfg.input_format(), cfg.aligned_reads());
+    };
+    let key = CountsKey::make(cfg, p)?;
+    if let Some(v) = cache.get_counts(&key, p) {
+        debug!("Using cached counts for {}", p.display());
+        return Ok(v);
+    }
+    let v = read_dataset(p, cfg.input_format(), cfg.aligned_reads())?;
+    cache.put_counts(&key, &v)?;
+    Ok(v)
+}
+
+// Write merged dataset in fastq_gc JSON format to dir.  The file name is taken from the
+// merge key, with characters other than alphanumerics, '-', '_' and '.' replaced by '_'.  If
+// compact is set, N counts are omitted when they are all zero
+fn write_merged(dir: &Path, key: &str, d: &DataSet, compact: bool) -> anyhow::Result<()> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Could not create directory {}", dir.display()))?;
+    let name: String = key
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let p = dir.join(format!("{name}.json"));
+    debug!("Writing merged dataset {key} to {}", p.display());
+    let mut t = d.to_temp_dataset();
+    if compact {
+        t.drop_zero_n()
+    }
+    atomic_write(&p, |tmp| t.write_json(tmp))
+}
+
+/// Second level merge groups (--then-by), built from the level 1 merged datasets as they are
+/// sent for analysis.  The members of a second level group are its level 1 groups
+struct Upper<'a> {
+    key: MergeKey,
+    groups: BTreeMap<String, MergedDataSet<'a>>,
+    excluded: BTreeMap<String, Vec<PathBuf>>,
+}
 
-    let mut hash: HashMap<String, DataSet> = HashMap::new();
+impl<'a> Upper<'a> {
+    fn new(cfg: &Config) -> Option<Self> {
+        cfg.then_by().map(|m| Self {
+            key: m.clone(),
+            groups: BTreeMap::new(),
+            excluded: BTreeMap::new(),
+        })
+    }
 
-    while let Ok(p) = rx.recv() {
-        trace!("Merge thread received file {} for reading", p.display());
+    // Add level 1 merged dataset md with key key1 to its second level group
+    fn add(&mut self, cfg: &Config, key1: &str, md: &MergedDataSet<'a>) -> anyhow::Result<()> {
+        let mut d = DataSet::clone(&md.data);
+        let (m, key) = get_merge_key(
+            d.fli_mut(),
+            self.key.clone(),
+            &md.path,
+            cfg.split_read_ends(),
+        )
+        .with_context(|| format!("Error finding second level merge key for {key1}"))?;
+        self.key = m;
+        if let Some(v) = md.excluded.as_ref() {
+            self.excluded
+                .entry(key.clone())
+                .or_default()
+                .extend_from_slice(v)
+        }
+        let member = (cfg.member_kl().is_some() || cfg.influence()).then(|| {
+            (
+                PathBuf::from(key1),
+                d.gc_counts().expect("Missing gc counts").to_vec(),
+            )
+        });
+        let path = PathBuf::from(&key);
+        match self.groups.entry(key) {
+            btree_map::Entry::Occupied(mut e) => {
+                let ud = e.get_mut();
+                if let Some(man) = cfg.manifest() {
+                    if !man.same_analysis(&ud.path, &md.path) {
+                        return Err(anyhow!(
+                            "Input files {} and {} have different reference or kmcv overrides in manifest but are merged",
+                            ud.path.display(),
+                            md.path.display()
+                        ));
+                    }
+                }
+                Arc::make_mut(&mut ud.data).merge(&d, cfg.merge_bisulfite_strands())?;
+                ud.n_inputs += md.n_inputs;
+                ud.ix = ud.ix.min(md.ix);
+                Arc::make_mut(&mut ud.members).extend(member)
+            }
+            btree_map::Entry::Vacant(e) => {
+                d.set_path(path);
+                e.insert(MergedDataSet {
+                    data: Arc::new(d),
+                    ix: md.ix,
+                    path: md.path.clone(),
+                    n_inputs: md.n_inputs,
+                    group: 0,
+                    level: 2,
+                    members: Arc::new(member.into_iter().collect()),
+                    individuals: Vec::new(),
+                    excluded: None,
+                });
+            }
+        }
+        Ok(())
+    }
 
-        let d = read_json(p).with_context(|| format!("Error reading from {}", p.display()))?;
-        merge_key = merge_dataset(d, merge_key, &mut hash)?;
+    // Send the second level groups for analysis once all level 1 groups have been sent
+    fn send(
+        self,
+        cfg: &Config,
+        next_group: &mut usize,
+        sd: &Sender<MergeItem<'a>>,
+    ) -> anyhow::Result<()> {
+        debug!("Sending {} second level merged datasets", self.groups.len());
+        send_merged(cfg, self.groups, self.excluded, next_group, sd, None)
     }
+}
 
-    debug!("Merge thread finished merging all input files. Sending results to process thread");
+// Make the gc counts of d if they are not already present.  The dataset is only copied if it
+// is shared (i.e., if the counts are missing from a dataset already sent for analysis)
+fn with_gc_counts(d: &mut Arc<DataSet>) -> anyhow::Result<()> {
+    if d.gc_counts().is_none() {
+        Arc::make_mut(d).mk_gc_counts()?
+    }
+    Ok(())
+}
 
-    for (_, mut d) in hash.drain() {
-        d.mk_gc_counts()?;
-        sd.send(d)
-            .with_context(|| "Error sending results to process thread")?
+// Send completed merged datasets for analysis in the order set by --group-order, numbering
+// them from next_group.  Level 1 datasets are also added to the second level groups in upper
+// (if present).  Groups are sent (and merged files written) in an order independent
+// of thread scheduling so that runs are reproducible
+fn send_merged<'a>(
+    cfg: &Config,
+    groups: BTreeMap<String, MergedDataSet<'a>>,
+    mut excluded: BTreeMap<String, Vec<PathBuf>>,
+    next_group: &mut usize,
+    sd: &Sender<MergeItem<'a>>,
+    mut upper: Option<&mut Upper<'a>>,
+) -> anyhow::Result<()> {
+    for (key, v) in excluded.iter() {
+        if !groups.contains_key(key) {
+            warn!(
+                "All {} members of merged dataset {key} were excluded for failing QC thresholds",
+                v.len()
+            )
+        }
+    }
+
+    let mut groups: Vec<_> = groups.into_iter().collect();
+    match cfg.group_order() {
+        GroupOrder::Key => (),
+        GroupOrder::Input => groups.sort_by_key(|(_, md)| md.ix),
+        GroupOrder::Size => groups.sort_by_key(|(_, md)| std::cmp::Reverse(md.n_inputs)),
+    }
+    for (key, mut md) in groups {
+        md.group = *next_group;
+        *next_group += 1;
+        if cfg.exclude_failing() {
+            md.excluded = Some(excluded.remove(&key).unwrap_or_default())
+        }
+        with_gc_counts(&mut md.data)?;
+        if let Some(dir) = cfg.write_merged() {
+            write_merged(dir, &key, &md.data, cfg.compact_merged())?
+        }
+        if let Some(u) = upper.as_deref_mut() {
+            u.add(cfg, &key, &md)?
+        }
+        // Individual members are sent before their group, sharing its group number
+        for (data, results) in std::mem::take(&mut md.individuals) {
+            sd.send(MergeItem::Individual(Box::new(Individual {
+                group: md.group,
+                parent: key.clone(),
+                data,
+                results,
+            })))
+            .map_err(|_| anyhow!("Error sending results to process thread"))?
+        }
+        sd.send(MergeItem::Group(md))
+            .map_err(|_| anyhow!("Error sending results to process thread"))?
+    }
+    Ok(())
+}
+
+type ReadResult<'a> = (usize, anyhow::Result<Vec<Prepared<'a>>>);
+
+// Read and prepare input files, sending the read groups of each file (with the input index)
+// to the merge thread
+fn read_thread<'a>(
+    cfg: &Config,
+    ix: usize,
+    rx: Receiver<(usize, &'a Path)>,
+    sd: Sender<ReadResult<'a>>,
+    status: &RunStatus,
+) {
+    debug!("Merge reader thread {ix} starting up");
+    while let Ok((i, p)) = rx.recv() {
+        trace!(
+            "Reader thread {ix} received file {} for reading",
+            p.display()
+        );
+        let r = read_input(cfg, p, status).and_then(|v| {
+            v.into_iter()
+                .map(|d| prepare(cfg, d, i, p.into()))
+                .collect::<anyhow::Result<Vec<_>>>()
+        });
+        if sd.send((i, r)).is_err() {
+            break;
+        }
+    }
+    debug!("Merge reader thread {ix} closing down");
+}
+
+// Merge all input files in memory, sending the merged datasets once all files have been read.
+// Files are read and prepared in parallel by reader threads, and the prepared datasets are
+// merged in input order so that the results do not depend on the order in which reading
+// completes
+fn merge_all<'a>(
+    cfg: &Config,
+    rx: Receiver<(usize, &'a Path)>,
+    sd: Sender<MergeItem<'a>>,
+    status: &RunStatus,
+) -> anyhow::Result<()> {
+    let nt = cfg.threads();
+    thread::scope(|scope| {
+        let (sd_read, rx_read) = bounded(nt * 2);
+        for ix in 0..nt {
+            let (rx1, sd1) = (rx.clone(), sd_read.clone());
+            scope.spawn(move |_| read_thread(cfg, ix, rx1, sd1, status));
+        }
+        drop(sd_read);
+        drop(rx);
+
+        let mut merge_key = cfg
+            .merge_key()
+            .expect("Cannot merge without a key!")
+            .clone();
+        let mut groups = BTreeMap::new();
+        let mut excluded = BTreeMap::new();
+        let mut consistency = Consistency::default();
+
+        // Datasets that arrive out of order are held until the preceding datasets are merged
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        while let Ok((i, r)) = rx_read.recv() {
+            pending.insert(i, r);
+            while let Some(r) = pending.remove(&next) {
+                next += 1;
+                for pr in r? {
+                    merge_key = add_prepared(
+                        cfg,
+                        pr,
+                        merge_key,
+                        &mut groups,
+                        &mut excluded,
+                        &mut consistency,
+                    )?
+                }
+            }
+        }
+
+        debug!("Merge thread finished merging all input files. Sending results to process thread");
+        finish_merge(cfg, groups, excluded, &consistency, &sd)
+    })
+    .expect("Error in scope generation")
+}
+
+// Check the consistency of the merge groups and send the merged datasets (followed by any
+// second level groups) for analysis
+fn finish_merge<'a>(
+    cfg: &Config,
+    groups: BTreeMap<String, MergedDataSet<'a>>,
+    excluded: BTreeMap<String, Vec<PathBuf>>,
+    consistency: &Consistency,
+    sd: &Sender<MergeItem<'a>>,
+) -> anyhow::Result<()> {
+    consistency.check(cfg)?;
+    let mut upper = Upper::new(cfg);
+    let mut next_group = 0;
+    send_merged(cfg, groups, excluded, &mut next_group, sd, upper.as_mut())?;
+    match upper {
+        Some(u) => u.send(cfg, &mut next_group, sd),
+        None => Ok(()),
+    }
+}
+
+// Merge in two passes.  The first pass reads each input file to determine the merge key of
+// each of its read groups, grouping the read groups by key.  The second pass merges one group
+// at a time, sending each merged dataset as soon as it is complete, so that only one merge
+// group is held in memory.  Each file is read once in the second pass, when its first group is
+// merged, and its read groups belonging to later groups are held until they are merged
+fn merge_by_group<'a>(
+    cfg: &Config,
+    rx: Receiver<(usize, &'a Path)>,
+    sd: Sender<MergeItem<'a>>,
+    status: &RunStatus,
+) -> anyhow::Result<()> {
+    let mut merge_key = cfg
+        .merge_key()
+        .expect("Cannot merge without a key!")
+        .clone();
+
+    // Read groups are identified by the input index and path of their file and their index
+    // within the file
+    let mut groups: BTreeMap<String, Vec<(usize, &Path, usize)>> = BTreeMap::new();
+    let mut consistency = Consistency::default();
+
+    while let Ok((i, p)) = rx.recv() {
+        trace!("Merge thread received file {} for grouping", p.display());
+        if is_stdin(p) {
+            return Err(anyhow!(
+                "Input from stdin can not be used with two pass merging"
+            ));
+        }
+        for (j, mut d) in read_input(cfg, p, status)?.into_iter().enumerate() {
+            if let Some(man) = cfg.manifest() {
+                man.apply(p, &mut d)
+            }
+            let (m, key) = get_merge_key(d.fli_mut(), merge_key.clone(), p, cfg.split_read_ends())?;
+            merge_key = m;
+            consistency.add(&key, &d, p);
+            groups.entry(key).or_default().push((i, p, j))
+        }
+    }
+    consistency.check(cfg)?;
+
+    debug!(
+        "Merge thread found {} merge groups. Starting merge",
+        groups.len()
+    );
+
+    // Groups are merged in the same order as they would be emitted by a single pass merge
+    let mut groups: Vec<_> = groups.into_values().collect();
+    match cfg.group_order() {
+        GroupOrder::Key => (),
+        GroupOrder::Input => groups.sort_by_key(|g| g[0].0),
+        GroupOrder::Size => groups.sort_by_key(|g| std::cmp::Reverse(g.len())),
+    }
+
+    // Second level groups are held in memory until all level 1 groups have been merged
+    let mut upper = Upper::new(cfg);
+    let mut next_group = 0;
+    // Read groups (identified by input index and index within the file) waiting to be merged
+    let mut pending: HashMap<(usize, usize), DataSet> = HashMap::new();
+    for g in groups.iter() {
+        let mut merged = BTreeMap::new();
+        let mut excluded = BTreeMap::new();
+        for &(i, p, j) in g.iter() {
+            let d = match pending.remove(&(i, j)) {
+                Some(d) => Some(d),
+                None => {
+                    let mut d = None;
+                    for (k, dk) in read_input(cfg, p, status)?.into_iter().enumerate() {
+                        if k == j {
+                            d = Some(dk)
+                        } else {
+                            pending.insert((i, k), dk);
+                        }
+                    }
+                    d
+                }
+            };
+            if let Some(d) = d {
+                merge_dataset(cfg, d, i, p, merge_key.clone(), &mut merged, &mut excluded)?;
+            }
+        }
+        send_merged(cfg, merged, excluded, &mut next_group, &sd, upper.as_mut())?
+    }
+    match upper {
+        Some(u) => u.send(cfg, &mut next_group, &sd),
+        None => Ok(()),
+    }
+}
+
+/// Read and merge the input files received on rx (with their index in the input list), sending
+/// the merged datasets to sd
+pub fn merge_thread<'a>(
+    cfg: &Config,
+    rx: Receiver<(usize, &'a Path)>,
+    sd: Sender<MergeItem<'a>>,
+    status: &RunStatus,
+) -> anyhow::Result<()> {
+    debug!("Merge thread starting up");
+
+    if cfg.two_pass_merge() {
+        merge_by_group(cfg, rx, sd, status)?
+    } else {
+        merge_all(cfg, rx, sd, status)?
     }
 
     debug!("Merge thread closing down");
 
     Ok(())
 }
+
+/// Merge groups built up as input files arrive (watch mode), so that the merged datasets can
+/// be sent for analysis repeatedly without re-reading the files already merged
+#[cfg(feature = "watch")]
+pub struct IncrementalMerge<'a> {
+    merge_key: MergeKey,
+    groups: BTreeMap<String, MergedDataSet<'a>>,
+    excluded: BTreeMap<String, Vec<PathBuf>>,
+    consistency: Consistency,
+}
+
+#[cfg(feature = "watch")]
+impl<'a> IncrementalMerge<'a> {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            merge_key: cfg
+                .merge_key()
+                .expect("Cannot merge without a key!")
+                .clone(),
+            groups: BTreeMap::new(),
+            excluded: BTreeMap::new(),
+            consistency: Consistency::default(),
+        }
+    }
+
+    /// Add the datasets from input file p with input index ix
+    pub fn add(
+        &mut self,
+        cfg: &Config,
+        ix: usize,
+        p: Cow<'a, Path>,
+        status: &RunStatus,
+    ) -> anyhow::Result<()> {
+        for d in read_input(cfg, &p, status)? {
+            self.merge_key = add_prepared(
+                cfg,
+                prepare(cfg, d, ix, p.clone())?,
+                self.merge_key.clone(),
+                &mut self.groups,
+                &mut self.excluded,
+                &mut self.consistency,
+            )?
+        }
+        Ok(())
+    }
+
+    /// Send the datasets merged so far for analysis.  The datasets are shared with the merge
+    /// state, which is only copied when a group is changed while its dataset is still held
+    /// for analysis, so the gc counts are made here rather than on the copies sent
+    pub fn send(&mut self, cfg: &Config, sd: &Sender<MergeItem<'a>>) -> anyhow::Result<()> {
+        for md in self.groups.values_mut() {
+            with_gc_counts(&mut md.data)?
+        }
+        let groups = self
+            .groups
+            .iter()
+            .map(|(k, md)| (k.clone(), md.shared()))
+            .collect();
+        finish_merge(cfg, groups, self.excluded.clone(), &self.consistency, sd)
+    }
+}
+
+/// Merge datasets received (with their record number) in stream mode as they arrive, sending
+/// the merged datasets to sd once the stream has ended.  The stream socket p is used as the
+/// input file of the datasets
+pub fn merge_stream<'a>(
+    cfg: &Config,
+    rx: Receiver<(usize, DataSet)>,
+    sd: Sender<MergeItem<'a>>,
+    p: &'a Path,
+) -> anyhow::Result<()> {
+    debug!("Stream merge thread starting up");
+    let mut merge_key = cfg
+        .merge_key()
+        .expect("Cannot merge without a key!")
+        .clone();
+    let mut groups = BTreeMap::new();
+    let mut excluded = BTreeMap::new();
+    let mut consistency = Consistency::default();
+
+    while let Ok((i, d)) = rx.recv() {
+        trace!("Stream merge thread received record {i}");
+        merge_key = add_prepared(
+            cfg,
+            prepare(cfg, d, i, p.into())?,
+            merge_key,
+            &mut groups,
+            &mut excluded,
+            &mut consistency,
+        )?
+    }
+
+    debug!("Stream ended with {} merge groups", groups.len());
+    finish_merge(cfg, groups, excluded, &consistency, &sd)?;
+    debug!("Stream merge thread closing down");
+    Ok(())
+}
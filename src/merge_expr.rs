@@ -0,0 +1,195 @@
+//! Merge key expressions (--merge-by expr:TEMPLATE).
+//!
+//! A template is literal text with FLI fields given in braces, e.g. `{sample}_{library}` or
+//! `{flowcell}:{lane}`, allowing grouping schemes not covered by the fixed merge keys.  The
+//! fields are sample, barcode, library, flowcell, index, lane and read_end (or read-end).
+//! Literal braces are written as `{{` and `}}`.  Datasets where any field used in the
+//! template is not known have no merge key.
+//!
+//! Alternatively the merge key can be taken from the input file paths (--merge-by-path
+//! REGEX), for datasets where fastq_gc was run without FLI metadata.  The key is the first
+//! capture group of the regular expression that participated in the match (or the whole match
+//! if there are no capture groups).
+
+use std::{fmt, path::Path, str::FromStr};
+
+use regex::Regex;
+
+use crate::read::Fli;
+
+/// Fields of a Fli that can be used in merge key expressions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FliField {
+    Sample,
+    Barcode,
+    Library,
+    Flowcell,
+    Index,
+    Lane,
+    ReadEnd,
+}
+
+impl FliField {
+    fn from_name(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "sample" => Some(Self::Sample),
+            "barcode" => Some(Self::Barcode),
+            "library" => Some(Self::Library),
+            "flowcell" => Some(Self::Flowcell),
+            "index" => Some(Self::Index),
+            "lane" => Some(Self::Lane),
+            "read_end" | "read-end" => Some(Self::ReadEnd),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Sample => "sample",
+            Self::Barcode => "barcode",
+            Self::Library => "library",
+            Self::Flowcell => "flowcell",
+            Self::Index => "index",
+            Self::Lane => "lane",
+            Self::ReadEnd => "read_end",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(FliField),
+}
+
+/// Parsed merge key template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeExpr {
+    parts: Vec<Part>,
+}
+
+impl FromStr for MergeExpr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut parts = Vec::new();
+        let mut lit = String::new();
+        let mut it = s.chars().peekable();
+        while let Some(c) = it.next() {
+            match c {
+                '{' if it.peek() == Some(&'{') => {
+                    it.next();
+                    lit.push('{')
+                }
+                '}' if it.peek() == Some(&'}') => {
+                    it.next();
+                    lit.push('}')
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match it.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => {
+                                return Err(anyhow!(
+                                    "Unterminated field in merge key expression {s}"
+                                ))
+                            }
+                        }
+                    }
+                    let f = FliField::from_name(&name).ok_or_else(|| {
+                        anyhow!("Unknown field {{{name}}} in merge key expression {s}")
+                    })?;
+                    if !lit.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut lit)))
+                    }
+                    parts.push(Part::Field(f))
+                }
+                '}' => return Err(anyhow!("Unmatched '}}' in merge key expression {s}")),
+                _ => lit.push(c),
+            }
+        }
+        if !lit.is_empty() {
+            parts.push(Part::Literal(lit))
+        }
+        if !parts.iter().any(|p| matches!(p, Part::Field(_))) {
+            return Err(anyhow!("Merge key expression {s} does not use any fields"));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl MergeExpr {
+    /// Merge key for fli (None if any of the fields used is not known)
+    pub fn eval(&self, fli: &Fli) -> Option<String> {
+        let mut s = String::new();
+        for p in self.parts.iter() {
+            match p {
+                Part::Literal(l) => s.push_str(l),
+                Part::Field(f) => s.push_str(&fli.field(*f)?),
+            }
+        }
+        Some(s)
+    }
+}
+
+/// Merge key extracted from input file path p by re (None if re does not match)
+pub fn path_key(re: &Regex, p: &Path) -> Option<String> {
+    let s = p.to_string_lossy();
+    let c = re.captures(&s)?;
+    let m = c.iter().skip(1).flatten().next().or_else(|| c.get(0))?;
+    Some(m.as_str().to_owned())
+}
+
+impl fmt::Display for MergeExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for p in self.parts.iter() {
+            match p {
+                Part::Literal(l) => write!(f, "{}", l.replace('{', "{{").replace('}', "}}"))?,
+                Part::Field(x) => write!(f, "{{{}}}", x.name())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn merge_expr_test() {
+        let mut fli = Fli::new(
+            Some("S1".to_owned()),
+            Some("L1".to_owned()),
+            Some("FC1".to_owned()),
+            None,
+            Some(2),
+        );
+        let e: MergeExpr = "{sample}_{Library}".parse().unwrap();
+        assert_eq!(e.eval(&fli).as_deref(), Some("S1_L1"));
+        let e: MergeExpr = "{flowcell}:{lane}{{x}}".parse().unwrap();
+        assert_eq!(e.eval(&fli).as_deref(), Some("FC1:2{x}"));
+        assert_eq!(e.to_string(), "{flowcell}:{lane}{{x}}");
+        let e: MergeExpr = "{sample}_R{read_end}".parse().unwrap();
+        assert_eq!(e.eval(&fli), None);
+        fli.set_read_end(1);
+        assert_eq!(e.eval(&fli).as_deref(), Some("S1_R1"));
+        for s in ["{sample", "{tile}", "sample}", "constant"] {
+            assert!(s.parse::<MergeExpr>().is_err())
+        }
+    }
+
+    #[test]
+    fn path_key_test() {
+        let p = Path::new("runs/2019/S12_L003_R1.json");
+        let re = Regex::new(r"([^/]+)_L\d+").unwrap();
+        assert_eq!(path_key(&re, p).as_deref(), Some("S12"));
+        let re = Regex::new(r"L\d+").unwrap();
+        assert_eq!(path_key(&re, p).as_deref(), Some("L003"));
+        let re = Regex::new(r"(x)?_(R\d)").unwrap();
+        assert_eq!(path_key(&re, p).as_deref(), Some("R1"));
+        assert_eq!(path_key(&Regex::new("fastq").unwrap(), p), None);
+    }
+}
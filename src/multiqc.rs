@@ -0,0 +1,215 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    gc_density::GcDensity,
+    process::DataResults,
+    read::{DataSet, DataSummary},
+    reference::{GcHistKey, GcHistVal},
+    status::RunStatus,
+};
+
+/// Number of bins for the gc histogram plot
+const MQC_GC_BINS: usize = 100;
+
+/// Metrics (from the JSON summaries) included in the MultiQC table as (section, key, title)
+const MQC_TABLE_COLUMNS: [(Option<&str>, &str, &str); 8] = [
+    (None, "reads", "Reads"),
+    (None, "gc", "GC"),
+    (None, "ref_gc", "Ref GC"),
+    (None, "kl_distance", "KL distance"),
+    (Some("kmer_coverage"), "mean_coverage", "Mean coverage"),
+    (Some("kmer_coverage"), "median_coverage", "Median coverage"),
+    (
+        Some("kmer_coverage"),
+        "fold_80_base_penalty",
+        "Fold 80 base penalty",
+    ),
+    (Some("kmer_coverage"), "overdispersion", "Overdispersion"),
+];
+
+/// Per dataset plot data for MultiQC output.  This is stored with the results so that it
+/// is available for cached datasets
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MultiqcData {
+    gc_hist: Vec<(f64, f64)>,
+    base_comp: Vec<(usize, [f64; 4])>,
+}
+
+/// Density of gc distribution for plotting as (% gc, density) pairs
+pub fn plot_gc_density(cts: &[(GcHistKey, GcHistVal)]) -> Vec<(f64, f64)> {
+    GcDensity::new(cts)
+        .histogram(MQC_GC_BINS)
+        .into_iter()
+        .map(|(x, y)| (100.0 * x, y))
+        .collect()
+}
+
+impl MultiqcData {
+    pub fn from_dataset(d: &DataSet) -> Self {
+        let gc_hist = plot_gc_density(d.gc_counts().expect("Missing gc counts"));
+
+        // Percentages of A, C, G, T per cycle
+        let trim = d.trim();
+        let base_comp = d
+            .per_pos_cts()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| {
+                let c = c.cts();
+                let s = c[..4].iter().sum::<u64>();
+                if s > 0 {
+                    let s = s as f64 / 100.0;
+                    Some((i + 1 + trim, [0, 1, 3, 2].map(|k| c[k] as f64 / s)))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        Self { gc_hist, base_comp }
+    }
+
+    pub fn gc_hist(&self) -> &[(f64, f64)] {
+        &self.gc_hist
+    }
+
+    /// Percentages of A, C, G, T per cycle
+    pub fn base_comp(&self) -> &[(usize, [f64; 4])] {
+        &self.base_comp
+    }
+}
+
+/// Collects results from all datasets and writes MultiQC custom content files
+#[derive(Default)]
+pub struct Multiqc {
+    table: BTreeMap<String, Map<String, Value>>,
+    gc_hist: BTreeMap<String, BTreeMap<String, f64>>,
+    base_comp: [BTreeMap<String, BTreeMap<usize, f64>>; 4],
+}
+
+impl Multiqc {
+    pub fn add(&mut self, d: &DataSummary, res: &DataResults) {
+        let name = d.path().display().to_string();
+        let mut summary = d.json_summary();
+        summary.extend(res.json_summary());
+
+        let row = MQC_TABLE_COLUMNS
+            .iter()
+            .filter_map(|(sec, key, _)| {
+                let v = match sec {
+                    Some(s) => summary.get(*s).and_then(|x| x.get(key)),
+                    None => summary.get(*key),
+                };
+                v.filter(|v| !v.is_null())
+                    .map(|v| (key.to_string(), v.clone()))
+            })
+            .collect();
+        self.table.insert(name.clone(), row);
+
+        if let Some(m) = res.multiqc() {
+            // JSON object keys must be strings, so the gc values are formatted here
+            self.gc_hist.insert(
+                name.clone(),
+                m.gc_hist
+                    .iter()
+                    .map(|(x, y)| (format!("{x:.1}"), *y))
+                    .collect(),
+            );
+            for (ix, h) in self.base_comp.iter_mut().enumerate() {
+                h.insert(
+                    name.clone(),
+                    m.base_comp.iter().map(|(c, v)| (*c, v[ix])).collect(),
+                );
+            }
+        }
+    }
+
+    /// Write files to dir, recording the output files in status
+    pub fn write(&self, dir: &Path, status: &RunStatus) -> anyhow::Result<()> {
+        let headers: Map<String, Value> = MQC_TABLE_COLUMNS
+            .iter()
+            .map(|(_, key, title)| (key.to_string(), json!({ "title": title })))
+            .collect();
+
+        write_mqc(
+            dir,
+            "gc_collect_mqc.json",
+            json!({
+                "id": "gc_collect_table",
+                "section_name": "gc_collect",
+                "description": "GC content and target coverage metrics from gc_collect",
+                "plot_type": "table",
+                "pconfig": { "id": "gc_collect_table", "namespace": "gc_collect" },
+                "headers": headers,
+                "data": self.table,
+            }),
+            status,
+        )?;
+
+        if !self.gc_hist.is_empty() {
+            write_mqc(
+                dir,
+                "gc_collect_gc_hist_mqc.json",
+                json!({
+                    "id": "gc_collect_gc_hist",
+                    "section_name": "GC distribution",
+                    "description": "Distribution of per read GC content",
+                    "plot_type": "linegraph",
+                    "pconfig": {
+                        "id": "gc_collect_gc_hist_plot",
+                        "xlab": "% GC",
+                        "ylab": "Density",
+                        "xmin": 0,
+                        "xmax": 100,
+                    },
+                    "data": self.gc_hist,
+                }),
+                status,
+            )?;
+
+            write_mqc(
+                dir,
+                "gc_collect_base_comp_mqc.json",
+                json!({
+                    "id": "gc_collect_base_comp",
+                    "section_name": "Per cycle base composition",
+                    "description": "Percentage of each base per sequencing cycle",
+                    "plot_type": "linegraph",
+                    "pconfig": {
+                        "id": "gc_collect_base_comp_plot",
+                        "xlab": "Cycle",
+                        "ylab": "%",
+                        "data_labels": [
+                            { "name": "A", "ylab": "% A" },
+                            { "name": "C", "ylab": "% C" },
+                            { "name": "G", "ylab": "% G" },
+                            { "name": "T", "ylab": "% T" },
+                        ],
+                    },
+                    "data": self.base_comp,
+                }),
+                status,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn write_mqc(dir: &Path, name: &str, v: Value, status: &RunStatus) -> anyhow::Result<()> {
+    let p: PathBuf = dir.join(name);
+    let wrt = CompressIo::new()
+        .path(&p)
+        .bufwriter()
+        .with_context(|| format!("Could not open MultiQC file {} for output", p.display()))?;
+    serde_json::to_writer_pretty(wrt, &v)
+        .with_context(|| format!("Error writing MultiQC file {}", p.display()))?;
+    status.add_output(&p);
+    Ok(())
+}
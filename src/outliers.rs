@@ -0,0 +1,107 @@
+//! Flagging of datasets with metrics far from the rest of the run (--flag-outliers).
+//!
+//! For each metric the median and the median absolute deviation (MAD) are found over all
+//! datasets of the run, and a dataset is flagged for a metric if its robust z-score
+//! (x - median) / (1.4826 * MAD) exceeds the limit in absolute value.  The scaling makes the
+//! MAD a consistent estimator of the standard deviation for normally distributed values.
+
+use crate::{output::IndexedResults, process::DataResults, simple_regression::median};
+
+/// Metrics checked for outliers, named as the columns of the main output
+pub const OUTLIER_METRICS: [&str; 3] = ["gc", "KL-distance", "Median-coverage"];
+
+/// Column of the main output listing the metrics for which a dataset is an outlier
+pub const OUTLIER_COLUMN: &str = "Outlier-flags";
+
+const MAD_SCALE: f64 = 1.4826;
+
+// Metrics need at least this many datasets with values to be checked
+const MIN_DATASETS: usize = 3;
+
+fn metrics(res: &DataResults) -> [Option<f64>; 3] {
+    [
+        Some(res.mean_gc()),
+        res.kl_distance(),
+        res.kmer_coverage().map(|k| k.median().get()),
+    ]
+    .map(|x| x.filter(|x| x.is_finite()))
+}
+
+// Median and scaled MAD of v.  Returns None if there are too few values or if the MAD is zero
+// (when all z-scores would be either zero or infinite)
+fn location_scale(mut v: Vec<f64>) -> Option<(f64, f64)> {
+    if v.len() < MIN_DATASETS {
+        return None;
+    }
+    let m = median(&mut v);
+    let mut dev: Vec<_> = v.iter().map(|x| (x - m).abs()).collect();
+    let mad = MAD_SCALE * median(&mut dev);
+    (mad > 0.0).then_some((m, mad))
+}
+
+pub struct Outliers {
+    limit: f64,
+    // Median and scaled MAD for each metric
+    scales: [Option<(f64, f64)>; 3],
+}
+
+impl Outliers {
+    /// Medians and MADs of the metrics over the datasets of the run
+    pub fn new(results: &[IndexedResults], limit: f64) -> Self {
+        let mut values: [Vec<f64>; 3] = Default::default();
+        for (_, _, res) in results {
+            for (v, x) in values.iter_mut().zip(metrics(res)) {
+                if let Some(x) = x {
+                    v.push(x)
+                }
+            }
+        }
+        let scales = values.map(location_scale);
+        for (name, s) in OUTLIER_METRICS.iter().zip(scales.iter()) {
+            match s {
+                Some((m, mad)) => {
+                    debug!("Outlier detection for {name}: median {m}, scaled MAD {mad}")
+                }
+                None => debug!("Outlier detection for {name} skipped (too few values or zero MAD)"),
+            }
+        }
+        Self { limit, scales }
+    }
+
+    /// Metrics for which res is an outlier
+    pub fn flags(&self, res: &DataResults) -> Vec<&'static str> {
+        OUTLIER_METRICS
+            .iter()
+            .zip(self.scales.iter())
+            .zip(metrics(res))
+            .filter_map(|((name, s), x)| match (s, x) {
+                (Some((m, mad)), Some(x)) if ((x - m) / mad).abs() > self.limit => Some(*name),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Outlier flags as a TSV column (comma separated, or NA if there are no flags)
+    pub fn column(&self, res: &DataResults) -> String {
+        let v = self.flags(res);
+        if v.is_empty() {
+            "\tNA".to_owned()
+        } else {
+            format!("\t{}", v.join(","))
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn location_scale_test() {
+        let (m, mad) = location_scale(vec![0.40, 0.41, 0.42, 0.43, 0.90]).unwrap();
+        assert!((m - 0.42).abs() < 1e-12);
+        assert!((mad - 0.01 * MAD_SCALE).abs() < 1e-12);
+        assert!(location_scale(vec![0.4, 0.4, 0.4, 0.5]).is_none());
+        assert!(location_scale(vec![0.4, 0.5]).is_none());
+    }
+}
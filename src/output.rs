@@ -1,39 +1,724 @@
 use anyhow::Context;
+use clap::{builder::PossibleValue, ValueEnum};
 use compress_io::compress::CompressIo;
-use crossbeam_channel::Receiver;
-use std::io::Write;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::{
+    cell::Cell,
+    cmp::Ordering,
+    fs,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
 
-use crate::{cli::Config, process::DataResults, read::DataSet};
+use crate::{
+    baseline::{BASELINE_METRICS, COHORT_SIZE_COLUMN},
+    cli::Config,
+    contamination::contamination_columns,
+    fingerprint::{write_fingerprints, Fingerprint, SWAP_CHECK_COLUMN},
+    fragments::{fragment_columns, CFDNA_COLUMNS},
+    gof::{gof_columns, GOF_COLUMNS},
+    html_report::HtmlReport,
+    mapped_fraction::{mapped_fraction_columns, MAPPED_FRACTION_COLUMNS},
+    multiqc::Multiqc,
+    outliers::{Outliers, OUTLIER_COLUMN},
+    pairwise::PairwiseMatrix,
+    process::DataResults,
+    qual_bands::{qual_band_columns, QUAL_BAND_COLUMNS},
+    read::DataSummary,
+    read_ends::{read_end_columns, read_end_header},
+    schema::{Projection, FIXED_COLUMNS, KMCV_COLUMNS, REGRESSION_COLUMNS, SKEW_COLUMNS},
+    simple_regression::DriftModel,
+    status::RunStatus,
+    summary::RunSummary,
+    thresholds::QcStatus,
+    utils::tsv_escape,
+};
 
-pub fn output_thread(cfg: &Config, rx: Receiver<(DataSet, DataResults)>) -> anyhow::Result<()> {
-    debug!("Output thread starting up");
+#[derive(Debug, Clone, Copy)]
+pub enum OutputFormat {
+    Tsv,
+    Json,
+}
+
+impl ValueEnum for OutputFormat {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Tsv, Self::Json]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Tsv => Some(PossibleValue::new("tsv")),
+            Self::Json => Some(PossibleValue::new("json")),
+        }
+    }
+}
+
+/// Order of datasets in the main output
+#[derive(Debug, Clone, Copy)]
+pub enum SortKey {
+    Input,
+    Key,
+    Gc,
+    Kl,
+    Coverage,
+}
+
+impl ValueEnum for SortKey {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Input, Self::Key, Self::Gc, Self::Kl, Self::Coverage]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Input => Some(PossibleValue::new("input")),
+            Self::Key => Some(PossibleValue::new("key")),
+            Self::Gc => Some(PossibleValue::new("gc")),
+            Self::Kl => Some(PossibleValue::new("kl")),
+            Self::Coverage => Some(PossibleValue::new("coverage")),
+        }
+    }
+}
+
+/// Dataset summary and analysis results, with the index in the input file list of the (first)
+/// input file of the dataset
+pub type IndexedResults = (usize, DataSummary, DataResults);
+
+/// Capacity of the results channel to the output thread for each process thread.  Process
+/// threads wait when the channel is full, so a slow output sink holds up processing rather
+/// than letting results accumulate in memory
+const RESULTS_QUEUE_PER_THREAD: usize = 4;
+
+/// Bounded channel used to send results from nt process threads to the output thread
+pub fn results_channel(nt: usize) -> (Sender<IndexedResults>, Receiver<IndexedResults>) {
+    bounded(nt.max(1) * RESULTS_QUEUE_PER_THREAD)
+}
+
+// Depth of the results queue as seen by the output thread.  If the queue is often full then
+// the output, not the analysis, is limiting the run
+#[derive(Default)]
+struct QueueMonitor {
+    results: Cell<usize>,
+    max_depth: Cell<usize>,
+    full: Cell<usize>,
+}
+
+impl QueueMonitor {
+    // Record the depth of the queue after a result has been taken from it
+    fn observe(&self, rx: &Receiver<IndexedResults>) {
+        let depth = rx.len();
+        self.results.set(self.results.get() + 1);
+        if depth > self.max_depth.get() {
+            trace!("Results queue depth {depth}");
+            self.max_depth.set(depth)
+        }
+        // The result just taken was received from a full queue
+        if rx.capacity().is_some_and(|c| depth + 1 >= c) {
+            self.full.set(self.full.get() + 1)
+        }
+    }
+
+    fn report(&self, rx: &Receiver<IndexedResults>) {
+        debug!(
+            "Maximum results queue depth {} (capacity {})",
+            self.max_depth.get(),
+            rx.capacity()
+                .map(|c| c.to_string())
+                .unwrap_or_else(|| "unbounded".to_owned())
+        );
+        let full = self.full.get();
+        if full > 0 {
+            info!(
+                "Analysis waited for output: results queue was full for {full} of {} results",
+                self.results.get()
+            )
+        }
+    }
+}
+
+// Sort results by key (in increasing order, with missing values last).  Ties are broken by
+// input order
+fn sort_results(v: &mut [IndexedResults], key: SortKey) {
+    let cmp_opt = |a: Option<f64>, b: Option<f64>| match (a, b) {
+        (Some(x), Some(y)) => x.total_cmp(&y),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    };
+    let median_cov = |r: &DataResults| r.kmer_coverage().map(|k| k.median().get());
+    v.sort_by(|(i, d1, r1), (j, d2, r2)| {
+        match key {
+            SortKey::Input => Ordering::Equal,
+            SortKey::Key => d1.path().cmp(d2.path()),
+            SortKey::Gc => r1.mean_gc().total_cmp(&r2.mean_gc()),
+            SortKey::Kl => cmp_opt(r1.kl_distance(), r2.kl_distance()),
+            SortKey::Coverage => cmp_opt(median_cov(r1), median_cov(r2)),
+        }
+        .then(i.cmp(j))
+    })
+}
+
+// Slope and p-value of the gc content regression
+fn gc_regression_columns(res: &DataResults) -> String {
+    match res.gc_regression() {
+        Some(r) => format!(
+            "\t{:.5e}\t{}",
+            r.slope().estimate(),
+            r.slope()
+                .p()
+                .map(|p| format!("{p:.5}"))
+                .unwrap_or_else(|| "NA".to_owned())
+        ),
+        None => "\tNA\tNA".to_owned(),
+    }
+}
+
+// Evenness score of target coverage
+fn e_score_column(res: &DataResults) -> String {
+    match res.kmer_coverage().and_then(|k| k.e_score()) {
+        Some(e) => format!("\t{e:.5}"),
+        None => "\tNA".to_owned(),
+    }
+}
 
-    let mut wrt = CompressIo::new()
-        .opt_path(cfg.output_file())
-        .bufwriter()
-        .with_context(|| "Could not open output file")?;
+// Non-linear drift model columns for each base (in the order A, C, G, T)
+fn drift_fit_columns(model: DriftModel, res: &DataResults) -> String {
+    let opt = |x: Option<f64>| {
+        x.map(|x| format!("{x:.5}"))
+            .unwrap_or_else(|| "NA".to_owned())
+    };
+    let mut s = String::new();
+    for i in [0, 1, 3, 2] {
+        match res.drift_fits().map(|v| &v[i]) {
+            Some(f) => {
+                // Breakpoints are at observed cycles
+                if model == DriftModel::Segmented {
+                    match f.breakpoint() {
+                        Some(bp) => s.push_str(&format!("\t{bp:.0}")),
+                        None => s.push_str("\tNA"),
+                    }
+                }
+                s.push_str(&format!(
+                    "\t{:.5e}\t{}",
+                    f.effect().estimate(),
+                    opt(f.effect().p())
+                ))
+            }
+            None => {
+                for _ in model.columns('A') {
+                    s.push_str("\tNA")
+                }
+            }
+        }
+    }
+    s
+}
 
-    write!(
-            wrt,
-            "Sample\tBarcode\tLibrary\tFlowcell\tIndex\tLane\tRead-end\tFile\tBisulfite-type\tTrim\tMin-qual\tgc\tref-gc\tKL-distance"
-        )?;
+// Columns of the main TSV output in the current layout.  New column groups must be added
+// after the existing groups (see the schema module)
+fn header_columns(cfg: &Config) -> Vec<String> {
+    let mut v: Vec<String> = FIXED_COLUMNS.iter().map(|s| s.to_string()).collect();
+    let mut add = |cols: &[&str]| v.extend(cols.iter().map(|s| s.to_string()));
 
-    if cfg.kmcv().is_some() {
-        write!(wrt,"\tTotal-reads\tMapped-reads\tTotal-bases\tMapped-bases\tMean-coverage\tMedian-coverage\tMedian/Mean\tDispersion\tFold_80_base_penalty")?
+    if cfg.has_kmcv() {
+        add(&KMCV_COLUMNS)
     }
 
     if cfg.regression() {
-        write!(
-            wrt,
-            "\tb(A)\tlog10 p_b(A)\tb(C)\tlog10 p_b(C)\tb(G)\tlog10 p_b(G)\tb(T)\tlog10 p_b(T)"
-        )?
+        add(&REGRESSION_COLUMNS)
+    };
+
+    if cfg.skew() {
+        add(&SKEW_COLUMNS)
+    }
+
+    for d in cfg.divergences() {
+        add(&[d.column()])
+    }
+
+    if !cfg.ref_panel().is_empty() {
+        for (name, _) in cfg.ref_panel() {
+            add(&[&format!("KL-{}", tsv_escape(name))])
+        }
+        add(&["Best-reference"])
+    }
+
+    if member_kl(cfg) {
+        add(&["Member-KL-max", "Member-KL-mean"])
+    }
+
+    if cfg.influence() && cfg.merge_key().is_some() {
+        add(&[
+            "Influential-member",
+            "Influence-delta-gc",
+            "Influence-delta-KL",
+        ])
+    }
+
+    if cfg.exclude_failing() {
+        add(&["Excluded-members"])
+    }
+
+    if cfg.thresholds().is_some() {
+        add(&["QC-status"])
+    }
+
+    if cfg.confidence_intervals().is_some() {
+        add(&["gc-CI-low", "gc-CI-high", "KL-CI-low", "KL-CI-high"])
+    }
+
+    if cfg.provenance() {
+        add(&["Upstream-version", "Upstream-command"])
+    }
+
+    if cfg.regression() {
+        add(&["b(GC)", "p_b(GC)"])
+    }
+
+    if let Some(m) = cfg.drift_model() {
+        for b in ['A', 'C', 'G', 'T'] {
+            v.extend(m.columns(b))
+        }
+    }
+
+    if cfg.baseline().is_some() {
+        v.extend(BASELINE_METRICS.iter().map(|(c, _)| c.to_string()));
+        v.push(COHORT_SIZE_COLUMN.to_owned())
+    }
+
+    if cfg.flag_outliers().is_some() {
+        v.push(OUTLIER_COLUMN.to_owned())
+    }
+
+    if cfg.fingerprints().is_some() {
+        v.push(SWAP_CHECK_COLUMN.to_owned())
+    }
+
+    if cfg.contamination_ref().is_some() {
+        v.extend(["Contam-fraction", "Contam-p"].map(String::from))
+    }
+
+    if cfg.gof_test().is_some() {
+        v.extend(GOF_COLUMNS.map(String::from))
+    }
+
+    if cfg.qual_bands() {
+        v.extend(QUAL_BAND_COLUMNS.map(String::from))
+    }
+
+    if cfg.read_ends() {
+        v.extend(read_end_header())
+    }
+
+    if cfg.has_kmcv() {
+        v.push("E-score".to_owned())
+    }
+
+    if cfg.target_gc().is_some() {
+        v.extend(MAPPED_FRACTION_COLUMNS.map(String::from))
+    }
+
+    if cfg.cfdna() {
+        v.extend(CFDNA_COLUMNS.map(String::from))
+    }
+
+    if cfg.then_by().is_some() {
+        v.push("Merge-level".to_owned())
+    }
+
+    if cfg.keep_individual() {
+        v.push("Merge-group".to_owned())
+    }
+
+    v.push("Warnings".to_owned());
+
+    v
+}
+
+// QC status of dataset (if thresholds have been set), recording failures in status
+fn qc_status(cfg: &Config, res: &DataResults, status: &RunStatus) -> Option<QcStatus> {
+    let s = cfg.thresholds()?.evaluate(res);
+    if s == QcStatus::Fail {
+        status.add_qc_failure()
+    }
+    Some(s)
+}
+
+// Member KL distances are only calculated for merged datasets
+fn member_kl(cfg: &Config) -> bool {
+    cfg.member_kl().is_some() && cfg.merge_key().is_some()
+}
+
+// Side table of KL distances of members from their merged groups
+struct MemberKlTable<W: Write> {
+    wrt: W,
+}
+
+impl<W: Write> MemberKlTable<W> {
+    fn new(mut wrt: W) -> anyhow::Result<Self> {
+        writeln!(wrt, "Group\tMember\tKL-distance")?;
+        Ok(Self { wrt })
+    }
+
+    fn add(&mut self, data: &DataSummary, res: &DataResults) -> anyhow::Result<()> {
+        if let Some(mk) = res.member_kl() {
+            for (p, kl) in mk.members() {
+                writeln!(
+                    self.wrt,
+                    "{}\t{}\t{:.5}",
+                    data.path().display(),
+                    p.display(),
+                    kl
+                )?
+            }
+        }
+        Ok(())
+    }
+}
+
+// Header line (the first line not starting with '#') of an earlier output file p.  Returns
+// None if p does not exist or is empty
+fn existing_header(p: &Path) -> anyhow::Result<Option<String>> {
+    match fs::File::open(p) {
+        Ok(f) if f.metadata().map(|m| m.len() > 0).unwrap_or(false) => {
+            let h = BufReader::new(f)
+                .lines()
+                .find(|l| l.as_ref().map(|l| !l.starts_with('#')).unwrap_or(true))
+                .transpose()
+                .with_context(|| format!("Error reading output file {}", p.display()))?;
+            Ok(Some(h.unwrap_or_default()))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Check that rows can be appended to the output file, which must have the columns given by
+/// the current options if it already exists.  Returns true if the output file has a header
+pub fn check_appended_output(cfg: &Config) -> anyhow::Result<bool> {
+    let Some(p) = cfg.output_file() else {
+        return Ok(false);
     };
+    let Some(h) = existing_header(p)? else {
+        return Ok(false);
+    };
+    let header = header_columns(cfg);
+    let proj = Projection::new(cfg.schema(), &header)?;
+    if h == proj.apply(&header.join("\t")) {
+        Ok(true)
+    } else {
+        Err(anyhow!(
+            "Columns of existing output file {} do not match the current options",
+            p.display()
+        ))
+    }
+}
+
+// Outputs other than the main output that are built up from the results of each dataset
+struct SideOutputs<'a> {
+    cfg: &'a Config,
+    status: &'a RunStatus,
+    mqc: Option<Multiqc>,
+    html: Option<HtmlReport>,
+    summary: RunSummary,
+    pairwise: Option<PairwiseMatrix>,
+    fingerprints: Vec<(usize, Fingerprint)>,
+    mkl: Option<MemberKlTable<Box<dyn Write>>>,
+}
+
+impl<'a> SideOutputs<'a> {
+    fn new(cfg: &'a Config, status: &'a RunStatus) -> anyhow::Result<Self> {
+        let mkl = match cfg.member_kl().filter(|_| member_kl(cfg)) {
+            Some(p) => {
+                let w = CompressIo::new()
+                    .path(p)
+                    .bufwriter()
+                    .with_context(|| format!("Could not open member KL file {}", p.display()))?;
+                status.add_output(p);
+                Some(MemberKlTable::new(Box::new(w) as Box<dyn Write>)?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            cfg,
+            status,
+            mqc: cfg.multiqc().then(Multiqc::default),
+            html: cfg.html_report().map(HtmlReport::new).transpose()?,
+            summary: RunSummary::default(),
+            pairwise: cfg
+                .pairwise_kl()
+                .map(|_| PairwiseMatrix::new(cfg.pairwise_divergence())),
+            fingerprints: Vec::new(),
+            mkl,
+        })
+    }
+
+    // Add a dataset after its record has been written to the main output
+    fn add(&mut self, ix: usize, data: &DataSummary, res: &DataResults) -> anyhow::Result<()> {
+        if let Some(m) = self.mqc.as_mut() {
+            m.add(data, res)
+        }
+        if let Some(t) = self.mkl.as_mut() {
+            t.add(data, res)?
+        }
+        if let Some(h) = self.html.as_mut() {
+            h.add(data, res, self.status)?
+        }
+        for p in res.aux_files() {
+            self.status.add_output(p)
+        }
+        self.summary.add(data, res, self.cfg.thresholds());
+        if let Some(pw) = self.pairwise.as_mut() {
+            pw.add(ix, data, res)
+        }
+        if let Some(f) = res
+            .fingerprint()
+            .filter(|_| self.cfg.write_fingerprints().is_some())
+        {
+            self.fingerprints.push((ix, f.clone()))
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        let Self {
+            cfg,
+            status,
+            mqc,
+            html,
+            summary,
+            pairwise,
+            mut fingerprints,
+            ..
+        } = self;
+
+        // MultiQC files are written to the same directory as the main output
+        if let Some(m) = mqc {
+            m.write(cfg.output_dir(), status)?
+        }
+
+        if let Some(h) = html {
+            h.write_index(status)?
+        }
+
+        if let (Some(pw), Some(p)) = (pairwise, cfg.pairwise_kl()) {
+            pw.write(p, status)?
+        }
+
+        if let Some(p) = cfg.write_fingerprints() {
+            fingerprints.sort_by_key(|(ix, _)| *ix);
+            let v: Vec<_> = fingerprints.into_iter().map(|(_, f)| f).collect();
+            write_fingerprints(&v, p, status)?
+        }
+
+        summary.log(cfg.thresholds());
+        if let Some(p) = cfg.summary_file() {
+            summary.write_json(p)?;
+            status.add_output(p)
+        }
+        Ok(())
+    }
+}
+
+fn open_append(p: &Path) -> anyhow::Result<BufWriter<Box<dyn Write>>> {
+    let f = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(p)
+        .with_context(|| format!("Could not open output file {} for appending", p.display()))?;
+    Ok(BufWriter::new(Box::new(f)))
+}
+
+pub fn output_thread(
+    cfg: &Config,
+    rx: Receiver<IndexedResults>,
+    status: &RunStatus,
+) -> anyhow::Result<()> {
+    debug!("Output thread starting up");
+
+    // Results are written as they arrive unless sorting or outlier detection (which needs the
+    // results of all datasets) was requested
+    let monitor = QueueMonitor::default();
+    let received = rx.iter().inspect(|_| monitor.observe(&rx));
+    let (results, outliers): (Box<dyn Iterator<Item = IndexedResults>>, _) =
+        if cfg.sort_output().is_some() || cfg.flag_outliers().is_some() {
+            let mut v: Vec<_> = received.collect();
+            if let Some(key) = cfg.sort_output() {
+                sort_results(&mut v, key)
+            }
+            let outliers = cfg.flag_outliers().map(|n| Outliers::new(&v, n));
+            (Box::new(v.into_iter()), outliers)
+        } else {
+            (Box::new(received), None)
+        };
+
+    // When appending (watch mode), rows are added to any earlier output
+    let (mut wrt, has_header) = match cfg.output_file().filter(|_| cfg.append_output()) {
+        Some(p) => {
+            let h = check_appended_output(cfg)?;
+            (open_append(p)?, h)
+        }
+        None => (
+            CompressIo::new()
+                .opt_path(cfg.output_file())
+                .bufwriter()
+                .with_context(|| "Could not open output file")?,
+            false,
+        ),
+    };
+
+    if let Some(p) = cfg.output_file() {
+        status.add_output(p)
+    }
+
+    let mut side = SideOutputs::new(cfg, status)?;
+
+    match cfg.output_format() {
+        OutputFormat::Tsv => {
+            let header = header_columns(cfg);
+            let proj = Projection::new(cfg.schema(), &header)?;
 
-    writeln!(wrt)?;
+            if !has_header {
+                // When writing to stdout with --quiet-stdout, only the header and result rows
+                // are output
+                if let Some(s) = cfg
+                    .schema()
+                    .metadata_line()
+                    .filter(|_| !(cfg.quiet_stdout() && cfg.output_file().is_none()))
+                {
+                    writeln!(wrt, "{s}")?
+                }
+                writeln!(wrt, "{}", proj.apply(&header.join("\t")))?
+            }
 
-    while let Ok((data, res)) = rx.recv() {
-        writeln!(wrt, "{}\t{}", data, res)?
+            for (ix, data, res) in results {
+                let mut row = format!("{data}\t{res}");
+                if let Some(s) = qc_status(cfg, &res, status) {
+                    row.push_str(&format!("\t{s}"))
+                }
+                if let Some(ci) = res.confidence_intervals() {
+                    row.push_str(&format!("\t{ci}"))
+                }
+                if cfg.provenance() {
+                    row.push_str(&format!("\t{}", data.provenance()))
+                }
+                if cfg.regression() {
+                    row.push_str(&gc_regression_columns(&res))
+                }
+                if let Some(m) = cfg.drift_model() {
+                    row.push_str(&drift_fit_columns(m, &res))
+                }
+                if let Some(b) = cfg.baseline() {
+                    row.push_str(&b.columns(&data, &res))
+                }
+                if let Some(o) = outliers.as_ref() {
+                    row.push_str(&o.column(&res))
+                }
+                if let Some(db) = cfg.fingerprints() {
+                    row.push_str(&db.check(&data, res.fingerprint()).column())
+                }
+                if cfg.contamination_ref().is_some() {
+                    row.push_str(&contamination_columns(res.contamination()))
+                }
+                if cfg.gof_test().is_some() {
+                    row.push_str(&gof_columns(res.goodness_of_fit()))
+                }
+                if cfg.qual_bands() {
+                    row.push_str(&qual_band_columns(res.qual_bands()))
+                }
+                if cfg.read_ends() {
+                    row.push_str(&read_end_columns(res.read_ends()))
+                }
+                if cfg.has_kmcv() {
+                    row.push_str(&e_score_column(&res))
+                }
+                if cfg.target_gc().is_some() {
+                    row.push_str(&mapped_fraction_columns(res.mapped_fraction()))
+                }
+                if cfg.cfdna() {
+                    row.push_str(&fragment_columns(res.fragments()))
+                }
+                if cfg.then_by().is_some() {
+                    match res.merge_level() {
+                        Some(l) => row.push_str(&format!("\t{l}")),
+                        None => row.push_str("\tNA"),
+                    }
+                }
+                if cfg.keep_individual() {
+                    row.push_str(&format!(
+                        "\t{}",
+                        tsv_escape(res.merge_group().unwrap_or("NA"))
+                    ))
+                }
+                row.push_str(&format!("\t{}", res.warnings()));
+                writeln!(wrt, "{}", proj.apply(&row))?;
+                if cfg.append_output() {
+                    wrt.flush()?
+                }
+                side.add(ix, &data, &res)?;
+            }
+        }
+        OutputFormat::Json => {
+            // Records are written as they arrive, giving a JSON array of per dataset objects
+            write!(wrt, "[")?;
+            let mut first = true;
+            for (ix, data, res) in results {
+                let mut m = data.json_summary();
+                m.extend(res.json_summary());
+                if let Some(s) = qc_status(cfg, &res, status) {
+                    m.insert("qc_status".to_owned(), s.to_string().into());
+                }
+                if let Some(b) = cfg.baseline() {
+                    m.insert("baseline_percentiles".to_owned(), b.json(&data, &res));
+                }
+                if let Some(o) = outliers.as_ref() {
+                    m.insert("outlier_flags".to_owned(), o.flags(&res).into());
+                }
+                if let Some(db) = cfg.fingerprints() {
+                    m.insert(
+                        "swap_check".to_owned(),
+                        db.check(&data, res.fingerprint()).json(),
+                    );
+                }
+                if cfg.contamination_ref().is_some() {
+                    m.insert(
+                        "contamination".to_owned(),
+                        serde_json::json!(res.contamination()),
+                    );
+                }
+                if cfg.gof_test().is_some() {
+                    m.insert(
+                        "goodness_of_fit".to_owned(),
+                        serde_json::json!(res.goodness_of_fit()),
+                    );
+                }
+                if cfg.qual_bands() {
+                    m.insert(
+                        "quality_bands".to_owned(),
+                        serde_json::json!(res.qual_bands()),
+                    );
+                }
+                if cfg.read_ends() {
+                    m.insert("read_ends".to_owned(), serde_json::json!(res.read_ends()));
+                }
+                if cfg.target_gc().is_some() {
+                    m.insert(
+                        "mapped_fraction_model".to_owned(),
+                        serde_json::json!(res.mapped_fraction()),
+                    );
+                }
+                if cfg.cfdna() {
+                    m.insert("cfdna".to_owned(), serde_json::json!(res.fragments()));
+                }
+                m.insert("schema_version".to_owned(), cfg.schema().version().into());
+                write!(wrt, "{}\n  ", if first { "" } else { "," })?;
+                serde_json::to_writer(&mut wrt, &m).with_context(|| "Error writing JSON output")?;
+                first = false;
+                side.add(ix, &data, &res)?;
+            }
+            writeln!(wrt, "\n]")?;
+        }
     }
+    monitor.report(&rx);
+    side.finish()?;
 
     debug!("Output thread closing down");
     Ok(())
@@ -0,0 +1,82 @@
+//! Matrix of divergences between the gc distributions of all pairs of datasets (or merged
+//! groups) of a run (--pairwise-kl), used to detect lane swaps and index hopping.
+
+use std::{io::Write, path::Path};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+
+use crate::{
+    betabin::{Divergence, TabulatedDensity},
+    process::DataResults,
+    read::DataSummary,
+    status::RunStatus,
+    utils::tsv_path,
+};
+
+pub struct PairwiseMatrix {
+    div: Divergence,
+    // Input index, name and tabulated density of each dataset
+    datasets: Vec<(usize, String, TabulatedDensity)>,
+}
+
+impl PairwiseMatrix {
+    pub fn new(div: Divergence) -> Self {
+        Self {
+            div,
+            datasets: Vec::new(),
+        }
+    }
+
+    /// Add dataset d with results res and input index ix (which gives the order of the datasets
+    /// in the matrix).  Datasets without gc counts are left out of the matrix
+    pub fn add(&mut self, ix: usize, d: &DataSummary, res: &DataResults) {
+        match res.gc_density() {
+            Some(t) => self.datasets.push((ix, tsv_path(d.path()), t.clone())),
+            None => warn!(
+                "{} left out of pairwise matrix as it has no gc counts",
+                d.path().display()
+            ),
+        }
+    }
+
+    /// Write the symmetric matrix as TSV to p, with a header line and the dataset names as the
+    /// first column
+    pub fn write(mut self, p: &Path, status: &RunStatus) -> anyhow::Result<()> {
+        self.datasets.sort_by_key(|(ix, _, _)| *ix);
+        let n = self.datasets.len();
+        debug!(
+            "Calculating pairwise {} for {n} datasets",
+            self.div.column()
+        );
+        let mut m = vec![0.0; n * n];
+        for (i, (_, _, a)) in self.datasets.iter().enumerate() {
+            for (j, (_, _, b)) in self.datasets.iter().enumerate().skip(i + 1) {
+                let x = a.divergence(b, self.div);
+                m[i * n + j] = x;
+                m[j * n + i] = x;
+            }
+        }
+
+        let mut wrt = CompressIo::new()
+            .path(p)
+            .bufwriter()
+            .with_context(|| format!("Could not open pairwise matrix file {}", p.display()))?;
+        write!(wrt, "{}", self.div.column())?;
+        for (_, name, _) in self.datasets.iter() {
+            write!(wrt, "\t{name}")?
+        }
+        writeln!(wrt)?;
+        for (i, (_, name, _)) in self.datasets.iter().enumerate() {
+            write!(wrt, "{name}")?;
+            for x in &m[i * n..(i + 1) * n] {
+                write!(wrt, "\t{x:.5}")?
+            }
+            writeln!(wrt)?
+        }
+        wrt.flush()
+            .with_context(|| format!("Error writing pairwise matrix file {}", p.display()))?;
+        status.add_output(p);
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use std::io::Write;
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+
+use crate::{
+    bam::AlignedReads,
+    cli::PanelCompareConfig,
+    kmcv::Kmcv,
+    kmers::KmerCounts,
+    read::DataSet,
+    source::{read_dataset, InputFormat},
+};
+
+// Collect the kmer counts for a panel from the input datasets (adding counts if more than
+// one input file has counts for the same panel)
+fn panel_counts(data: &[DataSet], kmcv: &Kmcv, name: &str) -> anyhow::Result<KmerCounts> {
+    let mut kc: Option<KmerCounts> = None;
+    for k in data
+        .iter()
+        .filter_map(|d| d.kmer_counts())
+        .filter(|k| k.kmcv_header() == kmcv.header_core())
+    {
+        match kc.as_mut() {
+            Some(x) => x.add(k)?,
+            None => kc = Some(k.clone()),
+        }
+    }
+    kc.ok_or_else(|| anyhow!("No kmer counts found for {name} panel in input dataset"))
+}
+
+// Lin's concordance correlation coefficient and Pearson correlation coefficient
+fn concordance(v: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let n = v.len() as f64;
+    if v.len() < 2 {
+        return None;
+    }
+    let (sx, sy) = v.iter().fold((0.0, 0.0), |(a, b), (x, y)| (a + x, b + y));
+    let (mx, my) = (sx / n, sy / n);
+    let (sxx, syy, sxy) = v.iter().fold((0.0, 0.0, 0.0), |(a, b, c), (x, y)| {
+        let (dx, dy) = (x - mx, y - my);
+        (a + dx * dx, b + dy * dy, c + dx * dy)
+    });
+    if sxx <= 0.0 || syy <= 0.0 {
+        return None;
+    }
+    let ccc = 2.0 * sxy / (sxx + syy + n * (mx - my).powi(2));
+    let r = sxy / (sxx * syy).sqrt();
+    Some((ccc, r))
+}
+
+/// Compare coverage of a dataset between old and new target panels on the targets shared
+/// between the panels (matched by coordinates)
+pub fn panel_compare(cfg: &PanelCompareConfig) -> anyhow::Result<()> {
+    let mut data = Vec::new();
+    for p in cfg.input_files() {
+        data.extend(
+            read_dataset(p, InputFormat::Auto, AlignedReads::All)
+                .with_context(|| format!("Error reading from {}", p.display()))?,
+        )
+    }
+
+    let (old, new) = (cfg.old_kmcv(), cfg.new_kmcv());
+    let coverage = |k: &Kmcv, name| -> anyhow::Result<Vec<f64>> {
+        Ok(panel_counts(&data, k, name)?
+            .target_coverage(k)
+            .iter()
+            .map(|c| c.get())
+            .collect())
+    };
+    let cov_old = coverage(old, "old")?;
+    let cov_new = coverage(new, "new")?;
+
+    let pairs = old.match_targets(new);
+    debug!(
+        "Matched {} targets between old ({}) and new ({}) panels",
+        pairs.len(),
+        old.n_targets(),
+        new.n_targets()
+    );
+
+    // Coverage normalized by mean coverage over shared targets
+    let n = pairs.len() as f64;
+    let m_old = pairs.iter().map(|(i, _)| cov_old[*i]).sum::<f64>() / n;
+    let m_new = pairs.iter().map(|(_, j)| cov_new[*j]).sum::<f64>() / n;
+    let norm: Vec<_> = if m_old > 0.0 && m_new > 0.0 {
+        pairs
+            .iter()
+            .map(|(i, j)| (cov_old[*i] / m_old, cov_new[*j] / m_new))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let within_2fold = norm
+        .iter()
+        .filter(|(x, y)| *x > 0.0 && *y > 0.0 && (x / y).log2().abs() <= 1.0)
+        .count();
+
+    let mut wrt = CompressIo::new()
+        .opt_path(cfg.output_file())
+        .bufwriter()
+        .with_context(|| "Could not open output file")?;
+
+    for (name, k) in [("Old", old), ("New", new)] {
+        writeln!(
+            wrt,
+            "# {name} panel: {} targets on {} contigs spanning {} bases",
+            k.n_targets(),
+            k.n_contigs(),
+            k.total_target_span()
+        )?
+    }
+    writeln!(wrt, "# Shared targets: {}", pairs.len())?;
+    match concordance(&norm) {
+        Some((ccc, r)) => {
+            writeln!(wrt, "# Concordance correlation: {ccc:.5}")?;
+            writeln!(wrt, "# Pearson correlation: {r:.5}")?
+        }
+        None => writeln!(
+            wrt,
+            "# Concordance correlation: NA\n# Pearson correlation: NA"
+        )?,
+    }
+    if !norm.is_empty() {
+        writeln!(
+            wrt,
+            "# Fraction of shared targets within 2 fold: {:.5}",
+            within_2fold as f64 / norm.len() as f64
+        )?
+    }
+
+    writeln!(
+        wrt,
+        "Contig\tOld-start\tOld-end\tNew-start\tNew-end\tOld-coverage\tNew-coverage"
+    )?;
+    for (i, j) in pairs.iter() {
+        let (ctg, t1) = old.get_target_location(*i).expect("Bad target ix");
+        let (_, t2) = new.get_target_location(*j).expect("Bad target ix");
+        writeln!(
+            wrt,
+            "{ctg}\t{}\t{}\t{}\t{}\t{:.3}\t{:.3}",
+            t1.start(),
+            t1.end(),
+            t2.start(),
+            t2.end(),
+            cov_old[*i],
+            cov_new[*j]
+        )?
+    }
+
+    Ok(())
+}
@@ -1,24 +1,346 @@
-use std::{fmt, io::Write, path::Path};
+use std::{
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
-use compress_io::compress::CompressIo;
 use crossbeam_channel::{Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 
 use crate::{
+    aux_file::AuxFile,
     betabin::*,
+    cache::CacheKey,
     cli::Config,
+    composition::CompositionMetrics,
+    contamination::Contamination,
+    fingerprint::Fingerprint,
+    fragments::FragmentMetrics,
+    gof::GoodnessOfFit,
+    kmcv::Kmcv,
     kmers::KmerCoverage,
-    read::{read_json, BisulfiteType, DataSet},
+    mapped_fraction::MappedFractionMetrics,
+    merge::{Influence, MemberKl, MergeItem},
+    multiqc::{plot_gc_density, MultiqcData},
+    output::IndexedResults,
+    qual_bands::{hq_fraction, output_qual_bands, QualBandMetrics},
+    read::{dataset_path, read_group_path, BisulfiteType, Counts, DataSet},
+    read_ends::ReadEndMetrics,
+    reference::{GcHistKey, GcHistVal, ReferenceProvider},
     simple_regression::*,
+    source::{is_stdin, read_dataset},
+    stats::Z_95,
+    status::RunStatus,
+    utils::{tsv_escape, tsv_path},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct DataResults {
     mean_gc: f64,
     ref_mean_gc: Option<f64>,
     kl_distance: Option<f64>,
+    divergences: Vec<(Divergence, Option<f64>)>,
+    reference_kl: Option<Vec<(String, Option<f64>)>>,
+    confidence_intervals: Option<ConfidenceIntervals>,
+    composition: CompositionMetrics,
+    fragments: Option<FragmentMetrics>,
     regression: Option<Vec<SimpleRegression>>,
+    gc_regression: Option<SimpleRegression>,
+    drift_fits: Option<Vec<NonLinearFit>>,
+    skew_regression: Option<Vec<Option<SimpleRegression>>>,
     kmer_coverage: Option<KmerCoverage>,
+    multiqc: Option<MultiqcData>,
+    ref_gc_hist: Option<Vec<(f64, f64)>>,
+    member_kl: Option<MemberKl>,
+    influence: Option<Influence>,
+    excluded: Option<Vec<PathBuf>>,
+    #[serde(default)]
+    fingerprint: Option<Fingerprint>,
+    #[serde(default)]
+    contamination: Option<Contamination>,
+    #[serde(default)]
+    goodness_of_fit: Option<GoodnessOfFit>,
+    #[serde(default)]
+    qual_bands: Option<QualBandMetrics>,
+    #[serde(default)]
+    read_ends: Option<Vec<ReadEndMetrics>>,
+    #[serde(default)]
+    mapped_fraction: Option<MappedFractionMetrics>,
+    #[serde(default)]
+    merge_level: Option<usize>,
+    #[serde(default)]
+    merge_group: Option<String>,
+    #[serde(default)]
+    gc_density: Option<TabulatedDensity>,
+    #[serde(default)]
+    warnings: Warnings,
+    aux_files: Vec<PathBuf>,
+}
+
+/// Datasets with fewer reads than this are flagged with a warning
+const LOW_READ_COUNT: u64 = 10_000;
+
+/// Caveats found during the analysis of a dataset (degraded modes, reference mismatches, low
+/// data).  Warnings are logged as they are added, and are also reported with the results of
+/// the dataset
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Warnings(Vec<String>);
+
+impl Warnings {
+    fn add<S: Into<String>>(&mut self, d: &DataSet, msg: S) {
+        let msg = msg.into();
+        warn!("{}: {msg}", d.path().display());
+        self.0.push(msg)
+    }
+
+    pub fn messages(&self) -> &[String] {
+        &self.0
+    }
+}
+
+/// Warnings as a semicolon separated list (NA if there are no warnings)
+impl fmt::Display for Warnings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "NA")
+        } else {
+            write!(f, "{}", tsv_escape(&self.0.join("; ")))
+        }
+    }
+}
+
+impl DataResults {
+    pub fn mean_gc(&self) -> f64 {
+        self.mean_gc
+    }
+
+    pub fn ref_mean_gc(&self) -> Option<f64> {
+        self.ref_mean_gc
+    }
+
+    pub fn kl_distance(&self) -> Option<f64> {
+        self.kl_distance
+    }
+
+    /// Additional divergence measures from the reference (NA if there is no reference)
+    pub fn divergences(&self) -> &[(Divergence, Option<f64>)] {
+        &self.divergences
+    }
+
+    /// KL distances from each reference of the reference panel (if more than one reference
+    /// was given)
+    pub fn reference_kl(&self) -> Option<&[(String, Option<f64>)]> {
+        self.reference_kl.as_deref()
+    }
+
+    /// Name of the reference with the smallest KL distance from the dataset
+    pub fn best_reference(&self) -> Option<&str> {
+        self.reference_kl
+            .as_ref()?
+            .iter()
+            .filter_map(|(s, kl)| kl.map(|x| (s, x)))
+            .min_by(|(_, x), (_, y)| x.total_cmp(y))
+            .map(|(s, _)| s.as_str())
+    }
+
+    /// 95% confidence intervals for the mean gc and KL distance (if requested)
+    pub fn confidence_intervals(&self) -> Option<&ConfidenceIntervals> {
+        self.confidence_intervals.as_ref()
+    }
+
+    pub fn composition(&self) -> &CompositionMetrics {
+        &self.composition
+    }
+
+    pub fn fragments(&self) -> Option<&FragmentMetrics> {
+        self.fragments.as_ref()
+    }
+
+    pub fn regression(&self) -> Option<&[SimpleRegression]> {
+        self.regression.as_deref()
+    }
+
+    /// Regression of gc content against cycle (with --regression)
+    pub fn gc_regression(&self) -> Option<&SimpleRegression> {
+        self.gc_regression.as_ref()
+    }
+
+    /// Non-linear fits of base fractions against cycle (with --drift-model), in the order
+    /// A, C, T, G
+    pub fn drift_fits(&self) -> Option<&[NonLinearFit]> {
+        self.drift_fits.as_deref()
+    }
+
+    pub fn skew_regression(&self) -> Option<&[Option<SimpleRegression>]> {
+        self.skew_regression.as_deref()
+    }
+
+    pub fn kmer_coverage(&self) -> Option<&KmerCoverage> {
+        self.kmer_coverage.as_ref()
+    }
+
+    /// Auxiliary output files written during analysis of the dataset
+    pub fn aux_files(&self) -> &[PathBuf] {
+        &self.aux_files
+    }
+
+    pub fn multiqc(&self) -> Option<&MultiqcData> {
+        self.multiqc.as_ref()
+    }
+
+    /// Tabulated gc density (for the pairwise matrix)
+    pub fn gc_density(&self) -> Option<&TabulatedDensity> {
+        self.gc_density.as_ref()
+    }
+
+    /// Density of the reference gc distribution (for the HTML report)
+    pub fn ref_gc_hist(&self) -> Option<&[(f64, f64)]> {
+        self.ref_gc_hist.as_deref()
+    }
+
+    /// Most influential member of a merged dataset
+    pub fn influence(&self) -> Option<&Influence> {
+        self.influence.as_ref()
+    }
+
+    /// Merge level of a merged dataset with a second level merge (--then-by)
+    pub fn merge_level(&self) -> Option<usize> {
+        self.merge_level
+    }
+
+    /// Merged group of an individual member (--keep-individual)
+    pub fn merge_group(&self) -> Option<&str> {
+        self.merge_group.as_deref()
+    }
+
+    /// Members excluded from a merged dataset for failing QC thresholds
+    pub fn excluded(&self) -> Option<&[PathBuf]> {
+        self.excluded.as_deref()
+    }
+
+    /// Caveats found during the analysis of the dataset
+    pub fn warnings(&self) -> &Warnings {
+        &self.warnings
+    }
+
+    /// KL distances of members from the merged dataset (merged datasets only)
+    pub fn member_kl(&self) -> Option<&MemberKl> {
+        self.member_kl.as_ref()
+    }
+
+    /// Fingerprint for the sample swap screen (if requested and the dataset has a sample name)
+    pub fn fingerprint(&self) -> Option<&Fingerprint> {
+        self.fingerprint.as_ref()
+    }
+
+    /// Contaminant fraction estimated with the contamination reference (if given)
+    pub fn contamination(&self) -> Option<&Contamination> {
+        self.contamination.as_ref()
+    }
+
+    /// Goodness of fit test against the reference (if requested and a reference is available)
+    pub fn goodness_of_fit(&self) -> Option<&GoodnessOfFit> {
+        self.goodness_of_fit.as_ref()
+    }
+
+    /// Quality band metrics (if requested and quality band counts are available)
+    pub fn qual_bands(&self) -> Option<&QualBandMetrics> {
+        self.qual_bands.as_ref()
+    }
+
+    /// gc metrics for each read end (if requested)
+    pub fn read_ends(&self) -> Option<&[ReadEndMetrics]> {
+        self.read_ends.as_deref()
+    }
+
+    /// Observed and expected mapped fraction given the gc bias (if requested)
+    pub fn mapped_fraction(&self) -> Option<&MappedFractionMetrics> {
+        self.mapped_fraction.as_ref()
+    }
+
+    /// Analysis results as a JSON object (for JSON output)
+    pub fn json_summary(&self) -> Map<String, Value> {
+        let slope =
+            |r: &SimpleRegression| json!({"slope": r.slope().estimate(), "p": r.slope().p()});
+        let mut m = Map::new();
+        m.insert("gc".to_owned(), json!(self.mean_gc));
+        m.insert("ref_gc".to_owned(), json!(self.ref_mean_gc));
+        m.insert("kl_distance".to_owned(), json!(self.kl_distance));
+        for (d, x) in self.divergences.iter() {
+            m.insert(d.key().to_owned(), json!(x));
+        }
+        if let Some(v) = self.reference_kl.as_ref() {
+            let kl: Map<_, _> = v.iter().map(|(s, x)| (s.clone(), json!(x))).collect();
+            m.insert("reference_kl".to_owned(), Value::Object(kl));
+            m.insert("best_reference".to_owned(), json!(self.best_reference()));
+        }
+        if let Some(ci) = self.confidence_intervals.as_ref() {
+            m.insert("gc_ci".to_owned(), json!(ci.mean_gc()));
+            m.insert("kl_distance_ci".to_owned(), json!(ci.kl_distance()));
+        }
+        m.insert("composition".to_owned(), json!(self.composition));
+        if let Some(kc) = self.kmer_coverage.as_ref() {
+            m.insert("kmer_coverage".to_owned(), kc.json_summary());
+        }
+        if let Some(v) = self.regression.as_ref() {
+            m.insert(
+                "base_regression".to_owned(),
+                json!({"A": slope(&v[0]), "C": slope(&v[1]), "G": slope(&v[3]), "T": slope(&v[2])}),
+            );
+        }
+        if let Some(r) = self.gc_regression.as_ref() {
+            m.insert("gc_regression".to_owned(), slope(r));
+        }
+        if let Some(v) = self.drift_fits.as_ref() {
+            let fit = |f: &NonLinearFit| match f.breakpoint() {
+                Some(bp) => {
+                    json!({"breakpoint": bp, "slope_change": f.effect().estimate(), "p": f.effect().p()})
+                }
+                None => json!({"curvature": f.effect().estimate(), "p": f.effect().p()}),
+            };
+            m.insert(
+                "base_drift".to_owned(),
+                json!({"A": fit(&v[0]), "C": fit(&v[1]), "G": fit(&v[3]), "T": fit(&v[2])}),
+            );
+        }
+        if let Some(mk) = self
+            .member_kl
+            .as_ref()
+            .filter(|mk| !mk.members().is_empty())
+        {
+            let members: Vec<_> = mk
+                .members()
+                .iter()
+                .map(|(p, kl)| json!({"file": p, "kl_distance": kl}))
+                .collect();
+            m.insert(
+                "member_kl".to_owned(),
+                json!({"max": mk.max(), "mean": mk.mean(), "members": members}),
+            );
+        }
+        if let Some(v) = self.excluded.as_ref() {
+            m.insert("excluded_members".to_owned(), json!(v));
+        }
+        if let Some(l) = self.merge_level {
+            m.insert("merge_level".to_owned(), json!(l));
+        }
+        if let Some(g) = self.merge_group.as_ref() {
+            m.insert("merge_group".to_owned(), json!(g));
+        }
+        m.insert("warnings".to_owned(), json!(self.warnings.messages()));
+        if let Some(inf) = self.influence.as_ref() {
+            m.insert("influence".to_owned(), json!(inf));
+        }
+        if let Some(v) = self.skew_regression.as_ref() {
+            m.insert(
+                "skew_regression".to_owned(),
+                json!({"AT": v[0].as_ref().map(slope), "GC": v[1].as_ref().map(slope)}),
+            );
+        }
+        m
+    }
 }
 
 impl fmt::Display for DataResults {
@@ -34,6 +356,8 @@ impl fmt::Display for DataResults {
         write!(f, "{}", self.mean_gc)?;
         output_opt_f64(self.ref_mean_gc, f)?;
         output_opt_f64(self.kl_distance, f)?;
+        output_opt_f64(self.composition.cpg_obs_exp(), f)?;
+        output_opt_f64(self.composition.dinuc_bias(), f)?;
 
         if let Some(kc) = self.kmer_coverage.as_ref() {
             write!(f, "\t{kc}")?
@@ -51,75 +375,252 @@ impl fmt::Display for DataResults {
             }
         }
 
+        if let Some(v) = self.skew_regression.as_ref() {
+            for r in v.iter() {
+                match r {
+                    Some(r) => {
+                        write!(f, "\t{:.5e}", r.slope().estimate())?;
+                        output_opt_f64(r.slope().p(), f)?
+                    }
+                    None => write!(f, "\tNA\tNA")?,
+                }
+            }
+        }
+
+        for (_, x) in self.divergences.iter() {
+            output_opt_f64(*x, f)?
+        }
+
+        if let Some(v) = self.reference_kl.as_ref() {
+            for (_, x) in v.iter() {
+                output_opt_f64(*x, f)?
+            }
+            write!(f, "\t{}", tsv_escape(self.best_reference().unwrap_or("NA")))?
+        }
+
+        if let Some(mk) = self.member_kl.as_ref() {
+            if mk.members().is_empty() {
+                write!(f, "\tNA\tNA")?
+            } else {
+                write!(f, "\t{:.5}\t{:.5}", mk.max(), mk.mean())?
+            }
+        }
+
+        if let Some(inf) = self.influence.as_ref() {
+            match inf.member() {
+                Some(p) => write!(f, "\t{}", tsv_path(p))?,
+                None => write!(f, "\tNA")?,
+            }
+            output_opt_f64(inf.delta_gc(), f)?;
+            output_opt_f64(inf.delta_kl(), f)?
+        }
+
+        if let Some(v) = self.excluded.as_ref() {
+            if v.is_empty() {
+                write!(f, "\tNA")?
+            } else {
+                let s: Vec<_> = v.iter().map(|p| tsv_path(p)).collect();
+                write!(f, "\t{}", s.join(","))?
+            }
+        }
+
         Ok(())
     }
 }
 
+// Reference gc distribution for dataset d (selected by read length and bisulfite type)
+fn reference_counts<'a>(
+    r: &'a dyn ReferenceProvider,
+    d: &DataSet,
+) -> Option<&'a [(GcHistKey, GcHistVal)]> {
+    let (rl, counts) = r.get_closest_reference(d.max_read_len() as u32);
+    trace!(
+        "Using reference length {rl} for actual length {}",
+        d.max_read_len()
+    );
+
+    match d.bisulfite() {
+        BisulfiteType::None => Some(counts.regular()),
+        _ => counts.bisulfite(),
+    }
+}
+
+// Results from the comparison of a dataset with the reference
+struct RefComparison {
+    kl_distance: Option<f64>,
+    divergences: Vec<(Divergence, Option<f64>)>,
+    reference_kl: Option<Vec<(String, Option<f64>)>>,
+    confidence_intervals: Option<ConfidenceIntervals>,
+    ref_mean_gc: Option<f64>,
+    hist_path: Option<Vec<PathBuf>>,
+    ref_gc_hist: Option<Vec<(f64, f64)>>,
+    goodness_of_fit: Option<GoodnessOfFit>,
+}
+
 fn compare_to_reference(
     cfg: &Config,
+    ref_dist: Option<&dyn ReferenceProvider>,
     path: &Path,
     d: &DataSet,
-) -> anyhow::Result<(Option<f64>, Option<f64>)> {
-    let (r, kl, gc) = match cfg.ref_dist() {
+    aux: bool,
+    w: &mut Warnings,
+) -> anyhow::Result<RefComparison> {
+    let (r, kl, gc) = match ref_dist {
         Some(r) => {
-            let (rl, counts) = r.get_closest_reference(d.max_read_len() as u32);
-            trace!(
-                "Using reference length {rl} for actual length {}",
-                d.max_read_len()
+            let ref_counts = reference_counts(r, d);
+            let (rl, len) = (
+                r.get_closest_reference(d.max_read_len() as u32).0,
+                d.max_read_len(),
             );
-
-            let ref_counts = match d.bisulfite() {
-                BisulfiteType::None => Some(counts.regular()),
-                _ => counts.bisulfite(),
-            };
-
+            if rl.abs_diff(len as u32) as usize * 10 > len {
+                w.add(
+                    d,
+                    format!("Reference for read length {rl} used for read length {len}"),
+                )
+            }
+            if ref_counts.is_none() {
+                w.add(
+                    d,
+                    format!(
+                        "No reference distribution for bisulfite type {}",
+                        d.bisulfite()
+                    ),
+                )
+            }
             (
                 ref_counts,
-                ref_counts.map(|ref_counts| kl_distance(d.gc_counts().unwrap(), ref_counts)),
+                ref_counts.map(|ref_counts| {
+                    smoothed_kl_distance(d.gc_counts().unwrap(), ref_counts, cfg.kl_prior())
+                }),
                 ref_counts.map(mean_gc),
             )
         }
         None => (None, None, None),
     };
 
-    output_gc_hist(path, d.gc_counts().unwrap(), r)
-        .with_context(|| "Error writing gc distribution file")?;
-    Ok((kl, gc))
+    let divergences = cfg
+        .divergences()
+        .iter()
+        .map(|div| {
+            let x = r.map(|r| divergence(*div, d.gc_counts().unwrap(), r, cfg.kl_prior()));
+            (*div, x)
+        })
+        .collect();
+
+    // KL distance from each reference in the panel (None if a reference has no distribution
+    // for this type of dataset)
+    let panel = cfg.ref_panel();
+    let reference_kl = (!panel.is_empty()).then(|| {
+        panel
+            .iter()
+            .map(|(name, r)| {
+                let kl = reference_counts(r.as_ref(), d).map(|ref_counts| {
+                    smoothed_kl_distance(d.gc_counts().unwrap(), ref_counts, cfg.kl_prior())
+                });
+                (name.clone(), kl)
+            })
+            .collect()
+    });
+
+    let confidence_intervals = cfg.confidence_intervals().map(|reps| {
+        ConfidenceIntervals::make(
+            d.gc_counts().unwrap(),
+            r,
+            cfg.kl_prior(),
+            reps,
+            cfg.seed().unwrap_or(0),
+        )
+    });
+
+    let hist_path = if aux {
+        Some(
+            output_gc_hist(path, d.gc_counts().unwrap(), r, cfg.bgzip_aux())
+                .with_context(|| "Error writing gc distribution file")?,
+        )
+    } else {
+        None
+    };
+
+    let ref_gc_hist = r
+        .filter(|_| cfg.html_report().is_some())
+        .map(plot_gc_density);
+
+    let goodness_of_fit = cfg
+        .gof_test()
+        .and_then(|t| r.and_then(|r| GoodnessOfFit::test(d.gc_counts().unwrap(), r, t)));
+
+    Ok(RefComparison {
+        kl_distance: kl,
+        divergences,
+        reference_kl,
+        confidence_intervals,
+        ref_mean_gc: gc,
+        hist_path,
+        ref_gc_hist,
+        goodness_of_fit,
+    })
 }
 
-fn base_content_regressions(d: &DataSet) -> Option<Vec<SimpleRegression>> {
-    let ct = d.per_pos_cts();
+// Observations of a per cycle statistic against scaled cycle (in [0, 1)) for the last 2/3 of
+// the read.  Also returns the first cycle used (0 based) and the scale factor
+fn drift_obs<T, F>(ct: &[T], f: F) -> (Vec<(f64, f64)>, usize, f64)
+where
+    F: Fn(&T) -> Option<f64>,
+{
     let l = ct.len();
     let x0 = l / 3;
-    if l - x0 < 3 {
+    let scale = (l - x0) as f64;
+    let obs: Vec<_> = ct[x0..]
+        .iter()
+        .map(f)
+        .enumerate()
+        .filter_map(|(x, y)| y.map(|y| ((x as f64) / scale, y)))
+        .collect();
+    (obs, x0, scale)
+}
+
+// Regression of a per cycle statistic against (scaled) cycle using the last 2/3 of the read.
+// If robust is set, Theil-Sen regression is used instead of least squares
+fn drift_regression<T, F>(ct: &[T], f: F, robust: bool) -> anyhow::Result<SimpleRegression>
+where
+    F: Fn(&T) -> Option<f64>,
+{
+    let (obs, _, _) = drift_obs(ct, f);
+    if robust {
+        theil_sen_regression(&obs)
+    } else {
+        simple_regression(&obs)
+    }
+}
+
+fn base_content_regressions(
+    d: &DataSet,
+    robust: bool,
+    w: &mut Warnings,
+) -> Option<Vec<SimpleRegression>> {
+    let ct = d.per_pos_cts();
+    let l = ct.len();
+    if l - l / 3 < 3 {
+        w.add(d, "Reads too short for base composition regression");
         return None;
     }
-    let scale = (l - x0) as f64;
-    let mut obs = Vec::with_capacity(l - x0);
     let mut res = Vec::with_capacity(4);
     for ix in 0..4 {
-        obs.clear();
-        for (x, y) in ct[x0..]
-            .iter()
-            .map(|c| {
+        let reg = match drift_regression(
+            ct,
+            |c| {
                 let s = c.cts()[..4].iter().sum::<u64>();
                 if s > 0 {
                     Some(c.cts()[ix] as f64 / s as f64)
                 } else {
                     None
                 }
-            })
-            .enumerate()
-        {
-            if let Some(y) = y {
-                // eprintln!("Add: {ix}\t{x}\t{y}\t{scale}");
-                obs.push(((x as f64) / scale, y))
-            }
-        }
-        let reg = match simple_regression(&obs) {
+            },
+            robust,
+        ) {
             Ok(r) => r,
             Err(e) => {
-                warn!("Could not perform regression: {:?}", e);
+                w.add(d, format!("Could not perform regression: {e}"));
                 return None;
             }
         };
@@ -128,105 +629,641 @@ fn base_content_regressions(d: &DataSet) -> Option<Vec<SimpleRegression>> {
     Some(res)
 }
 
-fn output_per_cycle_bases(d: &DataSet, p: &Path) -> anyhow::Result<()> {
-    let mut path = p.to_path_buf();
-    path.set_extension("base_dist.tsv");
-    let mut wrt = CompressIo::new()
-        .path(&path)
-        .bufwriter()
-        .with_context(|| "Could not open output file")?;
+// Regression of gc content against cycle
+fn gc_content_regression(d: &DataSet, robust: bool, w: &mut Warnings) -> Option<SimpleRegression> {
+    gc_regression_for(d, d.per_pos_cts(), robust, w)
+}
+
+// Regression of gc content against cycle for per cycle counts ct of dataset d
+fn gc_regression_for(
+    d: &DataSet,
+    ct: &[Counts],
+    robust: bool,
+    w: &mut Warnings,
+) -> Option<SimpleRegression> {
+    if ct.len() - ct.len() / 3 < 3 {
+        return None;
+    }
+    match drift_regression(ct, |c| c.gc_fraction(), robust) {
+        Ok(r) => Some(r),
+        Err(e) => {
+            w.add(d, format!("Could not perform gc regression: {e}"));
+            None
+        }
+    }
+}
 
-    writeln!(wrt, "Cycle\tA\tC\tG\tT")?;
+// Quality band metrics, with the regression of the high quality fraction against cycle.  If
+// aux is set, the per cycle composition of each band is also written, and the path returned
+fn qual_band_metrics(
+    cfg: &Config,
+    d: &DataSet,
+    path: &Path,
+    aux: bool,
+    w: &mut Warnings,
+) -> anyhow::Result<(Option<QualBandMetrics>, Option<Vec<PathBuf>>)> {
+    if !cfg.qual_bands() {
+        return Ok((None, None));
+    }
+    let Some(qb) = d.qual_bands() else {
+        w.add(d, "No quality band counts in input");
+        return Ok((None, None));
+    };
+    let ct = qb.per_pos();
+    let reg = if ct.len() - ct.len() / 3 < 3 {
+        None
+    } else {
+        match drift_regression(ct, |v| hq_fraction(v), cfg.robust_regression()) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                w.add(d, format!("Could not perform quality band regression: {e}"));
+                None
+            }
+        }
+    };
+    let qb_path = if aux {
+        Some(
+            output_qual_bands(path, qb, d.trim(), cfg.bgzip_aux())
+                .with_context(|| "Error writing quality band composition")?,
+        )
+    } else {
+        None
+    };
+    Ok((Some(QualBandMetrics::make(qb, reg)), qb_path))
+}
+
+// Non-linear fits of the base fractions against cycle over the same part of the read as the
+// linear regressions.  Breakpoints are reported as (1 based) cycle numbers
+fn base_content_drift_fits(
+    d: &DataSet,
+    model: DriftModel,
+    w: &mut Warnings,
+) -> Option<Vec<NonLinearFit>> {
+    let ct = d.per_pos_cts();
+    let mut res = Vec::with_capacity(4);
+    for ix in 0..4 {
+        let (obs, x0, scale) = drift_obs(ct, |c| {
+            let s = c.cts()[..4].iter().sum::<u64>();
+            (s > 0).then(|| c.cts()[ix] as f64 / s as f64)
+        });
+        match model.fit(&obs) {
+            Ok(mut fit) => {
+                fit.map_breakpoint(|x| (x0 + 1) as f64 + x * scale);
+                res.push(fit)
+            }
+            Err(e) => {
+                w.add(d, format!("Could not fit {model:?} drift model: {e}"));
+                return None;
+            }
+        }
+    }
+    Some(res)
+}
+
+// Regressions of AT and GC skew against cycle
+fn skew_regressions(d: &DataSet, robust: bool, w: &mut Warnings) -> Vec<Option<SimpleRegression>> {
+    let ct = d.per_pos_cts();
+    (0..2)
+        .map(|ix| match drift_regression(ct, |c| c.skews()[ix], robust) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                w.add(d, format!("Could not perform skew regression: {e}"));
+                None
+            }
+        })
+        .collect()
+}
+
+fn output_opt_f64<W: Write>(wrt: &mut W, x: Option<f64>) -> std::io::Result<()> {
+    match x {
+        Some(x) => write!(wrt, "\t{:.5}", x),
+        None => write!(wrt, "\tNA"),
+    }
+}
+
+// Wilson score interval for a binomial proportion with k successes from n trials
+fn wilson_interval(k: u64, n: u64, z: f64) -> (f64, f64) {
+    let n = n as f64;
+    let p = k as f64 / n;
+    let z2 = z * z / n;
+    let centre = (p + 0.5 * z2) / (1.0 + z2);
+    let half = z * (p * (1.0 - p) / n + 0.25 * z2 / n).sqrt() / (1.0 + z2);
+    ((centre - half).max(0.0), (centre + half).min(1.0))
+}
+
+// Per cycle counts for cycles with at least one base call.  If there are more than max_rows
+// such cycles, runs of consecutive cycles are binned (summing their counts) to give at most
+// max_rows rows, each labelled with the first cycle of the bin
+fn per_cycle_rows(d: &DataSet, max_rows: Option<usize>) -> anyhow::Result<Vec<(usize, Counts)>> {
     let trim = d.trim();
-    let cts = d.per_pos_cts();
-    for (i, ct) in cts.iter().enumerate() {
-        let s = ct.cts()[..4].iter().sum::<u64>();
-        if s > 0 {
-            let s = s as f64;
-            write!(wrt, "{}", i + 1 + trim)?;
+    let rows: Vec<_> = d
+        .per_pos_cts()
+        .iter()
+        .enumerate()
+        .filter(|(_, ct)| ct.cts()[..4].iter().sum::<u64>() > 0)
+        .map(|(i, ct)| (i + 1 + trim, *ct))
+        .collect();
+    match max_rows {
+        Some(n) if rows.len() > n => {
+            let k = rows.len().div_ceil(n);
+            rows.chunks(k)
+                .map(|v| {
+                    let mut ct = Counts::default();
+                    for (_, c) in v.iter() {
+                        ct.add(c)?
+                    }
+                    Ok((v[0].0, ct))
+                })
+                .collect()
+        }
+        _ => Ok(rows),
+    }
+}
+
+fn output_per_cycle_bases(cfg: &Config, d: &DataSet, p: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let (skew, ci) = (cfg.skew(), cfg.base_dist_ci());
+    let aux = AuxFile::new(p, "base_dist.tsv", cfg.bgzip_aux());
+    let mut wrt = aux
+        .writer()
+        .with_context(|| format!("Could not open output file {}", aux.path().display()))?;
+
+    write!(wrt, "Cycle\tA\tC\tG\tT")?;
+    if skew {
+        write!(wrt, "\tAT-skew\tGC-skew")?
+    }
+    if ci {
+        for b in ["A", "C", "G", "T"] {
+            write!(wrt, "\t{b}-lower\t{b}-upper")?
+        }
+    }
+    writeln!(wrt, "\tGC")?;
+    for (cycle, ct) in per_cycle_rows(d, cfg.base_dist_max_rows())? {
+        let n = ct.cts()[..4].iter().sum::<u64>();
+        let s = n as f64;
+        write!(wrt, "{cycle}")?;
+        for k in [0, 1, 3, 2] {
+            let y = (ct.cts()[k] as f64) / s;
+            write!(wrt, "\t{:.5}", y)?;
+        }
+        if skew {
+            for x in ct.skews() {
+                output_opt_f64(&mut wrt, x)?
+            }
+        }
+        if ci {
             for k in [0, 1, 3, 2] {
-                let y = (ct.cts()[k] as f64) / s;
-                write!(wrt, "\t{:.5}", y)?;
+                let (lo, hi) = wilson_interval(ct.cts()[k], n, Z_95);
+                write!(wrt, "\t{lo:.5}\t{hi:.5}")?;
             }
-            writeln!(wrt)?
         }
+        output_opt_f64(&mut wrt, ct.gc_fraction())?;
+        writeln!(wrt)?
     }
-    Ok(())
+    aux.finish(wrt)
+}
+
+/// Compute the analysis results for a dataset.  Auxiliary output files are written if
+/// requested in cfg
+pub fn analyze_dataset(cfg: &Config, d: &DataSet) -> anyhow::Result<DataResults> {
+    analyze(cfg, d, cfg.ref_dist(), cfg.kmcv(), cfg.aux_output())
+}
+
+// Call f with the reference for dataset d read from input file p.  With --self-reference this
+// is the pooled reference from the run excluding d, otherwise the reference for p
+fn with_reference<T>(
+    cfg: &Config,
+    p: &Path,
+    d: &DataSet,
+    f: impl FnOnce(Option<&dyn ReferenceProvider>) -> T,
+) -> T {
+    match cfg.reference_pool() {
+        Some(pool) => {
+            let r = pool.leave_one_out(d);
+            f(r.as_ref().map(|r| r as &dyn ReferenceProvider))
+        }
+        None => f(cfg.ref_dist_for(p)),
+    }
+}
+
+// Analyze dataset read from input file p, using any manifest overrides for p
+pub(crate) fn analyze_input(cfg: &Config, p: &Path, d: &DataSet) -> anyhow::Result<DataResults> {
+    with_reference(cfg, p, d, |r| {
+        analyze(cfg, d, r, cfg.kmcv_for(p), cfg.aux_output())
+    })
+}
+
+/// Analyze an individual member of a merged dataset (read from input file p) for QC
+/// checks.  No auxiliary output files are written
+pub fn analyze_member(cfg: &Config, p: &Path, d: &DataSet) -> anyhow::Result<DataResults> {
+    with_reference(cfg, p, d, |r| analyze(cfg, d, r, cfg.kmcv_for(p), false))
 }
 
-fn analyze_dataset(cfg: &Config, d: &DataSet) -> anyhow::Result<DataResults> {
-    let path = d.path();
-    output_per_cycle_bases(d, path).with_context(|| "Error writing per cycle base distribution")?;
+fn analyze(
+    cfg: &Config,
+    d: &DataSet,
+    ref_dist: Option<&dyn ReferenceProvider>,
+    kmcv: Option<&Kmcv>,
+    aux: bool,
+) -> anyhow::Result<DataResults> {
+    // Base path for auxiliary output files
+    let path = &cfg.aux_path(d.path());
+    let base_path = if aux {
+        Some(
+            output_per_cycle_bases(cfg, d, path)
+                .with_context(|| "Error writing per cycle base distribution")?,
+        )
+    } else {
+        None
+    };
+    let mut warnings = Warnings::default();
+    if d.n_reads() < LOW_READ_COUNT {
+        warnings.add(d, format!("Low read count ({})", d.n_reads()))
+    }
     let mean_gc = mean_gc(d.gc_counts().unwrap());
-    let (kl_distance, ref_mean_gc) = compare_to_reference(cfg, path, d)?;
+    let RefComparison {
+        kl_distance,
+        divergences,
+        reference_kl,
+        confidence_intervals,
+        ref_mean_gc,
+        hist_path,
+        ref_gc_hist,
+        goodness_of_fit,
+    } = compare_to_reference(cfg, ref_dist, path, d, aux, &mut warnings)?;
     
-    let regression = if cfg.regression() {
-        base_content_regressions(d)
+    let (regression, gc_regression) = if cfg.regression() {
+        let robust = cfg.robust_regression();
+        (
+            base_content_regressions(d, robust, &mut warnings),
+            gc_content_regression(d, robust, &mut warnings),
+        )
     } else {
-        None
+        (None, None)
     };
 
-    let kmer_coverage = if let Some(kc) = d.kmer_counts() {
-        kc.kmer_coverage(cfg)
+    let drift_fits = cfg
+        .drift_model()
+        .and_then(|m| base_content_drift_fits(d, m, &mut warnings));
+
+    let composition = d
+        .composition()
+        .map(CompositionMetrics::from_composition)
+        .unwrap_or_default();
+
+    let fragments = cfg
+        .cfdna()
+        .then(|| FragmentMetrics::from_dataset(d))
+        .flatten();
+
+    let skew_regression = if cfg.skew() {
+        Some(skew_regressions(d, cfg.robust_regression(), &mut warnings))
     } else {
         None
     };
 
+    let kmer_coverage = match (d.kmer_counts(), kmcv) {
+        (Some(kc), Some(k)) => Some(kc.kmer_coverage(k)),
+        (Some(_), None) => {
+            warnings.add(
+                d,
+                "Kmer coverage not calculated without an input kmer file (use -k option)",
+            );
+            None
+        }
+        _ => None,
+    };
+
+    let mapped_fraction = match (cfg.target_gc(), kmcv, kmer_coverage.as_ref()) {
+        (Some(t), Some(k), Some(kc)) => match ref_dist.and_then(|r| reference_counts(r, d)) {
+            Some(rc) => {
+                let m = MappedFractionMetrics::make(
+                    d.gc_counts().unwrap(),
+                    rc,
+                    cfg.kl_prior(),
+                    k,
+                    t,
+                    kc.mapped_fraction(),
+                    cfg.expected_mapped_fraction(),
+                );
+                if m.is_none() {
+                    warnings.add(
+                        d,
+                        "No targets of kmer file with gc content in target gc file",
+                    )
+                }
+                m
+            }
+            None => {
+                warnings.add(d, "gc bias factor not calculated without a reference");
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let contamination = cfg.contamination_ref().and_then(|c| {
+        let primary = ref_dist.and_then(|r| reference_counts(r, d))?;
+        match reference_counts(c, d) {
+            Some(cc) => Contamination::estimate(d.gc_counts().unwrap(), primary, cc),
+            None => {
+                warnings.add(
+                    d,
+                    format!(
+                        "No contamination reference distribution for bisulfite type {}",
+                        d.bisulfite()
+                    ),
+                );
+                None
+            }
+        }
+    });
+
+    let (qual_bands, qual_bands_path) = qual_band_metrics(cfg, d, path, aux, &mut warnings)?;
+
+    let read_ends = cfg.read_ends().then(|| {
+        d.read_end_cts()
+            .iter()
+            .map(|(r, ct)| {
+                let reg = gc_regression_for(d, ct, cfg.robust_regression(), &mut warnings);
+                ReadEndMetrics::make(*r, ct, reg)
+            })
+            .collect()
+    });
+
+    let target_cov_path = match (d.kmer_counts(), kmcv) {
+        (Some(kc), Some(k)) if aux && cfg.target_coverage() => Some(
+            kc.output_target_coverage(k, path, cfg.bgzip_aux())
+                .with_context(|| "Error writing per target coverage file")?,
+        ),
+        _ => None,
+    };
+
     Ok(DataResults {
         mean_gc,
         kl_distance,
+        divergences,
+        reference_kl,
+        confidence_intervals,
+        composition,
+        fragments,
         ref_mean_gc,
         regression,
+        gc_regression,
+        drift_fits,
+        skew_regression,
         kmer_coverage,
+        // Plot data is shared between MultiQC output and the HTML report
+        multiqc: (cfg.multiqc() || cfg.html_report().is_some())
+            .then(|| MultiqcData::from_dataset(d)),
+        ref_gc_hist,
+        member_kl: None,
+        influence: None,
+        excluded: None,
+        fingerprint: cfg
+            .fingerprinting()
+            .then(|| Fingerprint::make(d, kmcv, cfg.kl_prior()))
+            .flatten(),
+        contamination,
+        goodness_of_fit,
+        qual_bands,
+        read_ends,
+        mapped_fraction,
+        merge_level: None,
+        merge_group: None,
+        // The gc counts are not kept for output, so the densities for the pairwise matrix are
+        // tabulated here
+        gc_density: cfg
+            .pairwise_kl()
+            .and(d.gc_counts())
+            .and_then(|cts| TabulatedDensity::new(cts, cfg.kl_prior())),
+        warnings,
+        aux_files: base_path
+            .into_iter()
+            .chain(hist_path)
+            .chain(target_cov_path)
+            .chain(qual_bands_path)
+            .flatten()
+            .collect(),
     })
 }
-fn process_file(cfg: &Config, p: &Path) -> anyhow::Result<(DataSet, DataResults)> {
+/// Read datasets (one per read group) from input file p, applying any manifest overrides
+/// for p
+pub(crate) fn read_input(cfg: &Config, p: &Path) -> anyhow::Result<Vec<DataSet>> {
     trace!("Reading from {}", p.display());
-    let mut d = read_json(p).with_context(|| format!("Error reading from {}", p.display()))?;
-    d.mk_gc_counts()?;
-    let dres = analyze_dataset(cfg, &d)?;
-    Ok((d, dres))
+    let mut v = read_dataset(p, cfg.input_format(), cfg.aligned_reads())
+        .with_context(|| format!("Error reading from {}", p.display()))?;
+    if v.len() > 1 {
+        debug!("Read {} read groups from {}", v.len(), p.display())
+    }
+    for d in v.iter_mut() {
+        if let Some(m) = cfg.manifest() {
+            m.apply(p, d)
+        }
+        d.mk_gc_counts()?;
+    }
+    Ok(v)
 }
 
-pub fn process_thread(
+// Adapt cached results v to input file p.  The same file contents could be present under a
+// different name, and the auxiliary files may have been written elsewhere (e.g., with another
+// --outdir) or removed, in which case None is returned so that the file is analyzed again
+fn adapt_cached(
+    cfg: &Config,
+    p: &Path,
+    mut v: Vec<(DataSet, DataResults)>,
+) -> Option<Vec<(DataSet, DataResults)>> {
+    let multi = v.len() > 1;
+    for (i, (d, res)) in v.iter_mut().enumerate() {
+        let path = dataset_path(p);
+        d.set_path(if multi {
+            read_group_path(&path, i)
+        } else {
+            path
+        });
+        if let Some(m) = cfg.manifest() {
+            m.apply(p, d)
+        }
+        // Auxiliary files are named by replacing the extension of the base path
+        let base = format!("{}.", cfg.aux_path(d.path()).with_extension("").display());
+        if !res
+            .aux_files
+            .iter()
+            .all(|f| f.to_string_lossy().starts_with(&base) && f.exists())
+        {
+            debug!(
+                "Auxiliary files for cached results of {} not found",
+                p.display()
+            );
+            return None;
+        }
+    }
+    Some(v)
+}
+
+// Read and analyze input file p, returning the results for each read group
+pub(crate) fn process_file(cfg: &Config, p: &Path) -> anyhow::Result<Vec<(DataSet, DataResults)>> {
+    // Input from stdin can not be cached as we can not hash the contents in advance
+    let cache_key = match cfg.cache().filter(|_| !is_stdin(p)) {
+        Some(cache) => {
+            let key = CacheKey::make(cfg, p)?;
+            if let Some(v) = cache.get(&key, |v| adapt_cached(cfg, p, v)) {
+                debug!("Using cached results for {}", p.display());
+                return Ok(v);
+            }
+            Some(key)
+        }
+        None => None,
+    };
+    let v = read_input(cfg, p)?
+        .into_iter()
+        .map(|d| analyze_input(cfg, p, &d).map(|dres| (d, dres)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if let (Some(cache), Some(key)) = (cfg.cache(), cache_key) {
+        cache.put(&key, &v)?
+    }
+    Ok(v)
+}
+
+/// Analyze datasets v not read from an input file (e.g., sent to the server), where p is the
+/// path given to the datasets.  Results are not cached
+#[cfg(feature = "server")]
+pub(crate) fn analyze_datasets(
+    cfg: &Config,
+    p: &Path,
+    v: Vec<DataSet>,
+) -> anyhow::Result<Vec<(DataSet, DataResults)>> {
+    v.into_iter()
+        .map(|mut d| {
+            d.mk_gc_counts()?;
+            analyze_input(cfg, p, &d).map(|dres| (d, dres))
+        })
+        .collect()
+}
+
+/// Process input files received (with their index in the input file list) on rx, sending the
+/// results to the output thread
+pub fn process_thread<P: AsRef<Path>>(
     cfg: &Config,
     ix: usize,
-    rx: Receiver<&Path>,
-    sd: Sender<(DataSet, DataResults)>,
+    rx: Receiver<(usize, P)>,
+    sd: Sender<IndexedResults>,
+    status: &RunStatus,
 ) -> anyhow::Result<()> {
     debug!("Process thread {ix} starting up");
-    while let Ok(p) = rx.recv() {
+    while let Ok((i, p)) = rx.recv() {
+        let p = p.as_ref();
         trace!(
             "Process thread {ix} received file {} for processing",
             p.display()
         );
-        let (data, dres) = process_file(cfg, p)?;
+        let v = match process_file(cfg, p) {
+            Ok(x) => x,
+            Err(e) if cfg.skip_errors() => {
+                warn!("Skipping {}: {e:#}", p.display());
+                status.add_failed_input(p, &e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
         trace!(
             "Process thread {ix} finished processing file {}",
             p.display()
         );
-        sd.send((data, dres))
-            .with_context(|| "Error sending results to output thread")?
+        // Read groups from the same file share the input index, and are sent in file order
+        for (data, dres) in v {
+            sd.send((i, data.summary(), dres))
+                .with_context(|| "Error sending results to output thread")?
+        }
     }
     debug!("Process thread {ix} closing down");
     Ok(())
 }
 
+/// Analyze datasets received (with their record number) in stream mode, sending the results
+/// to the output thread
+pub fn stream_thread(
+    cfg: &Config,
+    ix: usize,
+    rx: Receiver<(usize, DataSet)>,
+    sd: Sender<IndexedResults>,
+    status: &RunStatus,
+) -> anyhow::Result<()> {
+    debug!("Stream process thread {ix} starting up");
+    while let Ok((i, mut d)) = rx.recv() {
+        trace!("Stream process thread {ix} received record {i}");
+        let p = d.path().to_owned();
+        let dres = match d.mk_gc_counts().and_then(|_| analyze_input(cfg, &p, &d)) {
+            Ok(x) => x,
+            Err(e) if cfg.skip_errors() => {
+                warn!("Skipping {}: {e:#}", p.display());
+                status.add_failed_input(&p, &e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        sd.send((i, d.summary(), dres))
+            .with_context(|| "Error sending results to output thread")?
+    }
+    debug!("Stream process thread {ix} closing down");
+    Ok(())
+}
+
+/// Analyze merged datasets.  Each dataset is sent with one of its input files, used to look
+/// up any manifest overrides for the dataset
 pub fn analyze_thread(
     cfg: &Config,
     ix: usize,
-    rx: Receiver<DataSet>,
-    sd: Sender<(DataSet, DataResults)>,
+    rx: Receiver<MergeItem>,
+    sd: Sender<IndexedResults>,
 ) -> anyhow::Result<()> {
     debug!("Analyze thread {ix} starting up");
-    while let Ok(d) = rx.recv() {
+    while let Ok(item) = rx.recv() {
+        let md = match item {
+            MergeItem::Group(md) => md,
+            // Individual members were analyzed before merging, and are output with NA values
+            // for the metrics of merged datasets
+            MergeItem::Individual(ind) => {
+                let (i, parent, d, mut dres) = ind.into_parts();
+                if cfg.member_kl().is_some() {
+                    dres.member_kl = Some(MemberKl::default())
+                }
+                dres.excluded = cfg.exclude_failing().then(Vec::new);
+                if cfg.then_by().is_some() {
+                    dres.merge_level = Some(0)
+                }
+                dres.merge_group = Some(parent);
+                if cfg.influence() {
+                    dres.influence = Some(Influence::default())
+                }
+                sd.send((i, d, dres))
+                    .with_context(|| "Error sending results to output thread")?;
+                continue;
+            }
+        };
         trace!("Analyze thread {ix} received dataset for processing",);
-        let dres = analyze_dataset(cfg, &d)?;
+        let mut dres = analyze_input(cfg, md.path(), md.data())?;
+        if cfg.member_kl().is_some() {
+            dres.member_kl = md.member_kl(cfg.kl_prior())
+        }
+        dres.excluded = md.excluded().map(|v| v.to_vec());
+        if cfg.then_by().is_some() {
+            dres.merge_level = Some(md.level())
+        }
+        if cfg.influence() {
+            dres.influence = with_reference(cfg, md.path(), md.data(), |r| {
+                let ref_counts = r.and_then(|r| reference_counts(r, md.data()));
+                // Groups with a single member are output with NA values
+                Some(md.influence(ref_counts, cfg.kl_prior()).unwrap_or_default())
+            })
+        }
+        // Results are indexed by group so that output in input order follows the group order
+        let i = md.group_index();
+        let d = md.data().summary();
         trace!(
             "Analyze thread {ix} finished processing file {}",
             d.path().display()
         );
-        sd.send((d, dres))
+        sd.send((i, d, dres))
             .with_context(|| "Error sending results to output thread")?
     }
     debug!("Analyze thread {ix} closing down");
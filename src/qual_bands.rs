@@ -0,0 +1,191 @@
+//! Per cycle base counts stratified by base quality band (--qual-bands).
+//!
+//! Upstream programs may store, for each cycle, separate base counts for each of a set of
+//! quality bands (e.g. <Q20, Q20-Q30 and >=Q30).  Base miscalls are concentrated in the low
+//! quality bands, where they are masked in the aggregate composition, so the composition of
+//! each band is written to a per cycle file.  The fraction of bases in the highest band
+//! (HQ-frac) is reported, with its regression against cycle over the same part of the read
+//! as the base composition regressions.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{aux_file::AuxFile, read::Counts, simple_regression::SimpleRegression};
+
+/// Column names in TSV output
+pub const QUAL_BAND_COLUMNS: [&str; 3] = ["HQ-frac", "HQ-frac-slope", "HQ-frac-p"];
+
+/// Base counts by quality band for each cycle.  Band i (for i > 0) holds bases with quality
+/// of at least bounds[i - 1] and below bounds[i] (if present)
+#[derive(Clone, Debug)]
+pub struct QualBands {
+    bounds: Vec<u8>,
+    per_pos: Vec<Vec<Counts>>,
+}
+
+impl QualBands {
+    /// Make from the band bounds and the counts for each band at each cycle (which should
+    /// have already been checked for consistency)
+    pub(crate) fn new(bounds: Vec<u8>, per_pos: Vec<Vec<Counts>>) -> Self {
+        Self { bounds, per_pos }
+    }
+
+    pub fn bounds(&self) -> &[u8] {
+        &self.bounds
+    }
+
+    /// Counts for each band at each cycle
+    pub fn per_pos(&self) -> &[Vec<Counts>] {
+        &self.per_pos
+    }
+
+    /// Band labels (e.g., <Q20, Q20-Q30, >=Q30)
+    pub fn labels(&self) -> Vec<String> {
+        let n = self.bounds.len();
+        (0..=n)
+            .map(|i| match i {
+                0 => format!("<Q{}", self.bounds[0]),
+                _ if i == n => format!(">=Q{}", self.bounds[i - 1]),
+                _ => format!("Q{}-Q{}", self.bounds[i - 1], self.bounds[i]),
+            })
+            .collect()
+    }
+
+    /// Add counts from other.  Returns false (leaving self unchanged) if the bands differ
+    pub fn add(&mut self, other: &Self) -> anyhow::Result<bool> {
+        if self.bounds != other.bounds {
+            return Ok(false);
+        }
+        let nb = self.bounds.len() + 1;
+        if self.per_pos.len() < other.per_pos.len() {
+            self.per_pos
+                .resize_with(other.per_pos.len(), || vec![Counts::default(); nb])
+        }
+        for (v1, v2) in self.per_pos.iter_mut().zip(other.per_pos.iter()) {
+            for (c1, c2) in v1.iter_mut().zip(v2.iter()) {
+                c1.add(c2)?
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Fraction of the bases at a cycle in the highest quality band
+pub fn hq_fraction(bands: &[Counts]) -> Option<f64> {
+    let t: u64 = bands.iter().map(|c| c.total()).sum();
+    (t > 0).then(|| bands.last().map(|c| c.total()).unwrap_or(0) as f64 / t as f64)
+}
+
+/// Summary of the quality band counts of a dataset
+#[derive(Debug, Deserialize, Serialize)]
+pub struct QualBandMetrics {
+    hq_fraction: Option<f64>,
+    hq_regression: Option<SimpleRegression>,
+}
+
+impl QualBandMetrics {
+    pub fn make(qb: &QualBands, hq_regression: Option<SimpleRegression>) -> Self {
+        let (hq, t) = qb.per_pos.iter().fold((0, 0), |(hq, t), v| {
+            (
+                hq + v.last().map(|c| c.total()).unwrap_or(0),
+                t + v.iter().map(|c| c.total()).sum::<u64>(),
+            )
+        });
+        Self {
+            hq_fraction: (t > 0).then(|| hq as f64 / t as f64),
+            hq_regression,
+        }
+    }
+
+    /// Overall fraction of bases in the highest quality band
+    pub fn hq_fraction(&self) -> Option<f64> {
+        self.hq_fraction
+    }
+
+    /// Regression of the fraction of bases in the highest quality band against cycle
+    pub fn hq_regression(&self) -> Option<&SimpleRegression> {
+        self.hq_regression.as_ref()
+    }
+}
+
+/// Columns of the main TSV output for quality band metrics m (NA if not available)
+pub fn qual_band_columns(m: Option<&QualBandMetrics>) -> String {
+    let Some(m) = m else {
+        return "\tNA\tNA\tNA".to_owned();
+    };
+    let mut s = match m.hq_fraction {
+        Some(x) => format!("\t{x:.5}"),
+        None => "\tNA".to_owned(),
+    };
+    match m.hq_regression.as_ref() {
+        Some(r) => {
+            s.push_str(&format!("\t{:.5e}", r.slope().estimate()));
+            match r.slope().p() {
+                Some(p) => s.push_str(&format!("\t{p:.5}")),
+                None => s.push_str("\tNA"),
+            }
+        }
+        None => s.push_str("\tNA\tNA"),
+    }
+    s
+}
+
+/// Write the base composition of each quality band at each cycle to <p>.qual_bands.tsv,
+/// returning the paths of the output files.  trim is the number of cycles trimmed from the
+/// start of the reads
+pub fn output_qual_bands(
+    p: &Path,
+    qb: &QualBands,
+    trim: usize,
+    bgzip: bool,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let aux = AuxFile::new(p, "qual_bands.tsv", bgzip);
+    let mut wrt = aux
+        .writer()
+        .with_context(|| format!("Could not open output file {}", aux.path().display()))?;
+
+    write!(wrt, "Cycle")?;
+    for l in qb.labels() {
+        write!(wrt, "\t{l}:frac\t{l}:A\t{l}:C\t{l}:G\t{l}:T")?
+    }
+    writeln!(wrt)?;
+    for (i, v) in qb.per_pos.iter().enumerate() {
+        let t: u64 = v.iter().map(|c| c.total()).sum();
+        if t == 0 {
+            continue;
+        }
+        write!(wrt, "{}", i + 1 + trim)?;
+        for c in v.iter() {
+            write!(wrt, "\t{:.5}", c.total() as f64 / t as f64)?;
+            // Base fractions out of the ACGT bases in the band
+            let s = c.cts()[..4].iter().sum::<u64>();
+            for k in [0, 1, 3, 2] {
+                if s > 0 {
+                    write!(wrt, "\t{:.5}", c.cts()[k] as f64 / s as f64)?
+                } else {
+                    write!(wrt, "\tNA")?
+                }
+            }
+        }
+        writeln!(wrt)?
+    }
+    aux.finish(wrt)
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn labels_test() {
+        let qb = QualBands::new(vec![20, 30], Vec::new());
+        assert_eq!(qb.labels(), vec!["<Q20", "Q20-Q30", ">=Q30"]);
+        let qb = QualBands::new(vec![20], Vec::new());
+        assert_eq!(qb.labels(), vec!["<Q20", ">=Q20"]);
+    }
+}
@@ -7,16 +7,20 @@ use std::{
 
 use anyhow::Context;
 use compress_io::compress::CompressIo;
-use serde::Deserialize;
-use serde_json::from_reader;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, json, Map, Value};
 
 use crate::{
     cli::MergeKey,
+    composition::Composition,
     kmers::KmerCounts,
+    merge_expr::FliField,
+    qual_bands::QualBands,
     reference::{GcHistKey, GcHistVal},
+    utils::{tsv_escape, tsv_path, RunDate},
 };
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
 pub enum BisulfiteType {
     None = 0,
     Forward,
@@ -24,6 +28,18 @@ pub enum BisulfiteType {
     NonStranded,
 }
 
+impl BisulfiteType {
+    /// Bisulfite type of the composite formed by merging datasets of types self and other
+    /// across strands.  Returns None if only one of the types is bisulfite converted
+    pub fn strand_composite(&self, other: &Self) -> Option<Self> {
+        match (self, other) {
+            (a, b) if a == b => Some(*a),
+            (Self::None, _) | (_, Self::None) => None,
+            _ => Some(Self::NonStranded),
+        }
+    }
+}
+
 impl fmt::Display for BisulfiteType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -39,7 +55,7 @@ impl fmt::Display for BisulfiteType {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Fli {
     sample: Option<String>,
     barcode: Option<String>,
@@ -51,13 +67,44 @@ pub struct Fli {
 }
 
 impl Fli {
-    pub fn get_key(&self, key: MergeKey) -> Option<String> {
+    pub fn new(
+        sample: Option<String>,
+        library: Option<String>,
+        flowcell: Option<String>,
+        index: Option<String>,
+        lane: Option<u8>,
+    ) -> Self {
+        Self {
+            sample,
+            library,
+            flowcell,
+            index,
+            lane,
+            ..Default::default()
+        }
+    }
+
+    pub fn get_key(&self, key: &MergeKey) -> Option<String> {
         match key {
             MergeKey::Sample => self.sample.as_ref().map(|x| x.to_owned()),
             MergeKey::Barcode => self.barcode.as_ref().map(|x| x.to_owned()),
             MergeKey::Library => self.library.as_ref().map(|x| x.to_owned()),
             MergeKey::Fli => self.fli(),
-            MergeKey::Default => None,
+            MergeKey::Expr(e) => e.eval(self),
+            MergeKey::Default | MergeKey::Path(_) => None,
+        }
+    }
+
+    /// Value of field f (if known) as used in merge key expressions
+    pub fn field(&self, f: FliField) -> Option<String> {
+        match f {
+            FliField::Sample => self.sample.clone(),
+            FliField::Barcode => self.barcode.clone(),
+            FliField::Library => self.library.clone(),
+            FliField::Flowcell => self.flowcell.clone(),
+            FliField::Index => self.index.clone(),
+            FliField::Lane => self.lane.map(|x| x.to_string()),
+            FliField::ReadEnd => self.read_end.map(|x| x.to_string()),
         }
     }
 
@@ -85,6 +132,30 @@ impl Fli {
         }
     }
 
+    pub fn set_sample(&mut self, sample: String) {
+        self.sample = Some(sample)
+    }
+
+    pub fn set_barcode(&mut self, barcode: String) {
+        self.barcode = Some(barcode)
+    }
+
+    pub fn set_library(&mut self, library: String) {
+        self.library = Some(library)
+    }
+
+    pub fn set_index(&mut self, index: String) {
+        self.index = Some(index)
+    }
+
+    pub fn set_read_end(&mut self, read_end: u8) {
+        self.read_end = Some(read_end)
+    }
+
+    pub fn read_end(&self) -> Option<u8> {
+        self.read_end
+    }
+
     fn find_common(&mut self, other: &Self) {
         if self.sample != other.sample {
             self.sample = None
@@ -120,27 +191,84 @@ impl fmt::Display for Fli {
             }
         };
 
-        write!(f, "{}", self.sample.as_deref().unwrap_or("NA"))?;
-        write!(f, "\t{}", self.barcode.as_deref().unwrap_or("NA"))?;
-        write!(f, "\t{}", self.library.as_deref().unwrap_or("NA"))?;
-        write!(f, "\t{}", self.flowcell.as_deref().unwrap_or("NA"))?;
-        write!(f, "\t{}", self.index.as_deref().unwrap_or("NA"))?;
+        let output_opt_str =
+            |x: &Option<String>| tsv_escape(x.as_deref().unwrap_or("NA")).into_owned();
+
+        write!(f, "{}", output_opt_str(&self.sample))?;
+        write!(f, "\t{}", output_opt_str(&self.barcode))?;
+        write!(f, "\t{}", output_opt_str(&self.library))?;
+        write!(f, "\t{}", output_opt_str(&self.flowcell))?;
+        write!(f, "\t{}", output_opt_str(&self.index))?;
         output_opt_u8(self.lane, f)?;
         output_opt_u8(self.read_end, f)
     }
 }
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(non_snake_case)]
-struct TempCounts {
+pub struct TempCounts {
     A: u64,
     C: u64,
     G: u64,
     T: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
     N: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     Other: Option<u64>,
 }
 
-#[derive(Copy, Clone, Debug, Default)]
+/// Per position counts stratified by base quality.  bounds are the lower quality bounds of
+/// all but the first band, and each position has counts for bounds.len() + 1 bands
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TempQualBands {
+    bounds: Vec<u8>,
+    per_pos_cts: BTreeMap<u32, Vec<TempCounts>>,
+}
+
+impl TempQualBands {
+    fn check(&self, per_pos_cts: &BTreeMap<u32, TempCounts>, v: &mut Vec<String>) {
+        if self.bounds.is_empty() || self.bounds.windows(2).any(|w| w[0] >= w[1]) {
+            v.push(format!(
+                "qual_band_cts bounds {:?} not non-empty and strictly increasing",
+                self.bounds
+            ))
+        }
+        if !self.per_pos_cts.keys().eq(per_pos_cts.keys()) {
+            v.push("qual_band_cts positions do not match per_pos_cts".to_owned())
+        }
+        if let Some((k, c)) = self
+            .per_pos_cts
+            .iter()
+            .find(|(_, c)| c.len() != self.bounds.len() + 1)
+        {
+            v.push(format!(
+                "qual_band_cts has {} bands at position {k} (expected {})",
+                c.len(),
+                self.bounds.len() + 1
+            ))
+        }
+    }
+}
+
+impl TempCounts {
+    fn from_counts(c: &Counts) -> Self {
+        let c = c.cts();
+        Self::new([c[0], c[1], c[3], c[2], c[4]])
+    }
+
+    /// Make from counts in the order A, C, G, T, N
+    pub fn new(cts: [u64; 5]) -> Self {
+        Self {
+            A: cts[0],
+            C: cts[1],
+            G: cts[2],
+            T: cts[3],
+            N: Some(cts[4]),
+            Other: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Counts([u64; 5]);
 
 impl Counts {
@@ -154,15 +282,79 @@ impl Counts {
         &self.0
     }
 
-    pub fn add(&mut self, other: &Self) {
+    pub fn total(&self) -> u64 {
+        self.0.iter().sum()
+    }
+
+    /// AT skew (A - T) / (A + T) and GC skew (G - C) / (G + C)
+    pub fn skews(&self) -> [Option<f64>; 2] {
+        let skew = |a: u64, b: u64| {
+            if a + b > 0 {
+                Some((a as f64 - b as f64) / ((a + b) as f64))
+            } else {
+                None
+            }
+        };
+        [skew(self.0[0], self.0[2]), skew(self.0[3], self.0[1])]
+    }
+
+    /// Fraction of G + C out of all ACGT bases
+    pub fn gc_fraction(&self) -> Option<f64> {
+        let s = self.0[..4].iter().sum::<u64>();
+        if s > 0 {
+            Some((self.0[1] + self.0[3]) as f64 / s as f64)
+        } else {
+            None
+        }
+    }
+
+    /// Fraction of A, C, G, T and N (in that order) out of all bases
+    pub fn fractions(&self) -> Option<[f64; 5]> {
+        let t = self.total();
+        if t > 0 {
+            let t = t as f64;
+            let mut f = [0.0; 5];
+            for (x, k) in f.iter_mut().zip([0, 1, 3, 2, 4]) {
+                *x = self.0[k] as f64 / t
+            }
+            Some(f)
+        } else {
+            None
+        }
+    }
+
+    pub fn add(&mut self, other: &Self) -> anyhow::Result<()> {
         for i in 0..5 {
-            self.0[i] += other.0[i];
+            self.0[i] = checked_add(self.0[i], other.0[i])?;
         }
+        Ok(())
+    }
+}
+
+/// Error for a dataset whose contents are inconsistent (see [`TempDataSet::check`]), listing
+/// the problems found.  Like other read errors, this is skipped with --skip-errors
+#[derive(Debug)]
+pub struct InvalidDataSet {
+    path: PathBuf,
+    problems: Vec<String>,
+}
+
+impl fmt::Display for InvalidDataSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid dataset in {}: {}",
+            self.path.display(),
+            self.problems.join("; ")
+        )
     }
 }
 
-#[derive(Deserialize)]
-struct TempDataSet {
+impl std::error::Error for InvalidDataSet {}
+
+/// Dataset as stored in the fastq_gc JSON files
+#[derive(Deserialize, Serialize)]
+pub struct TempDataSet {
     trim: usize,
     min_qual: u8,
     max_read_length: usize,
@@ -171,10 +363,188 @@ struct TempDataSet {
     cts: TempCounts,
     per_pos_cts: BTreeMap<u32, TempCounts>,
     gc_hash: HashMap<String, u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     kmer_counts: Option<KmerCounts>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    run_date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    library_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dinuc_counts: Option<HashMap<String, u64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trinuc_counts: Option<HashMap<String, u64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    command_line: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    program_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    qual_band_cts: Option<TempQualBands>,
+}
+
+impl TempDataSet {
+    /// Make dataset with no trimming or bisulfite conversion from per position counts
+    /// (positions starting from 1), gc_hash and optional kmer counts
+    pub fn new(
+        min_qual: u8,
+        fli: Fli,
+        per_pos_cts: Vec<[u64; 5]>,
+        gc_hash: HashMap<String, u64>,
+        kmer_counts: Option<KmerCounts>,
+    ) -> Self {
+        let mut cts = [0; 5];
+        for c in per_pos_cts.iter() {
+            for (x, y) in cts.iter_mut().zip(c.iter()) {
+                *x += *y
+            }
+        }
+        Self {
+            trim: 0,
+            min_qual,
+            max_read_length: per_pos_cts.len(),
+            bisulfite: BisulfiteType::None,
+            fli,
+            cts: TempCounts::new(cts),
+            per_pos_cts: per_pos_cts
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (i as u32 + 1, TempCounts::new(*c)))
+                .collect(),
+            gc_hash,
+            kmer_counts,
+            run_date: None,
+            library_type: None,
+            dinuc_counts: None,
+            trinuc_counts: None,
+            command_line: None,
+            program_version: None,
+            qual_band_cts: None,
+        }
+    }
+
+    /// Problems that would prevent the dataset from being analyzed: per position counts
+    /// not covering positions trim + 1 to max_read_length contiguously, malformed gc_hash keys
+    /// or kmer counts inconsistent with their header.  Only the first offending gc_hash key
+    /// is reported
+    pub fn check(&self) -> Vec<String> {
+        let mut v = Vec::new();
+        if self.max_read_length < self.trim {
+            v.push(format!(
+                "max_read_length ({}) is less than trim ({})",
+                self.max_read_length, self.trim
+            ))
+        } else if self.max_read_length - self.trim != self.per_pos_cts.len() {
+            v.push(format!(
+                "per_pos_cts has {} positions but max_read_length - trim is {}",
+                self.per_pos_cts.len(),
+                self.max_read_length - self.trim
+            ))
+        }
+        if let Some((ix, k)) = self
+            .per_pos_cts
+            .keys()
+            .enumerate()
+            .find(|(ix, k)| **k as usize != ix + 1 + self.trim)
+        {
+            v.push(format!(
+                "per_pos_cts keys not contiguous: expected position {}, found {k}",
+                ix + 1 + self.trim
+            ))
+        }
+        let mut bad_keys = self
+            .gc_hash
+            .keys()
+            .filter(|k| k.parse::<GcHistKey>().is_err());
+        if let Some(k) = bad_keys.next() {
+            v.push(format!(
+                "gc_hash key '{k}' not of the form AT:GC ({} invalid keys)",
+                1 + bad_keys.count()
+            ))
+        }
+        if let Some(k) = self.gc_hash.keys().find(|k| {
+            k.parse::<GcHistKey>()
+                .map(|x| {
+                    let (a, b) = x.counts();
+                    a + b > self.max_read_length as f64
+                })
+                .unwrap_or(false)
+        }) {
+            v.push(format!(
+                "gc_hash key '{k}' exceeds max_read_length ({})",
+                self.max_read_length
+            ))
+        }
+        if let Some(kc) = self.kmer_counts.as_ref() {
+            v.extend(kc.check())
+        }
+        if let Some(qb) = self.qual_band_cts.as_ref() {
+            qb.check(&self.per_pos_cts, &mut v)
+        }
+        v
+    }
+
+    /// Remove the N counts if they are zero for all positions.  This makes the JSON output
+    /// noticeably smaller for datasets with many positions; missing N counts are read as zero
+    pub fn drop_zero_n(&mut self) {
+        let zero = |c: &TempCounts| c.N.unwrap_or(0) == 0;
+        if zero(&self.cts) && self.per_pos_cts.values().all(zero) {
+            for c in std::iter::once(&mut self.cts).chain(self.per_pos_cts.values_mut()) {
+                c.N = None
+            }
+        }
+    }
+
+    pub fn write_json<P: AsRef<Path>>(&self, p: P) -> anyhow::Result<()> {
+        let p = p.as_ref();
+        let wrt = CompressIo::new()
+            .path(p)
+            .bufwriter()
+            .with_context(|| format!("Could not open {} for output", p.display()))?;
+        serde_json::to_writer(wrt, self)
+            .with_context(|| format!("Error writing JSON file {}", p.display()))
+    }
 }
 
-#[derive(Clone)]
+/// Command line and version of the upstream program that generated a dataset (if recorded
+/// in the input JSON)
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Provenance {
+    command_line: Option<String>,
+    program_version: Option<String>,
+}
+
+impl Provenance {
+    pub fn command_line(&self) -> Option<&str> {
+        self.command_line.as_deref()
+    }
+
+    pub fn program_version(&self) -> Option<&str> {
+        self.program_version.as_deref()
+    }
+
+    /// Merged datasets keep the provenance only if it is the same for all members
+    fn merge(&mut self, other: &Self) {
+        if self.command_line != other.command_line {
+            self.command_line = None
+        }
+        if self.program_version != other.program_version {
+            self.program_version = None
+        }
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}",
+            tsv_escape(self.program_version.as_deref().unwrap_or("NA")),
+            tsv_escape(self.command_line.as_deref().unwrap_or("NA"))
+        )
+    }
+}
+
+// Only the metadata is serialized (for the results cache); the count data is skipped
+#[derive(Clone, Deserialize, Serialize)]
 pub struct DataSet {
     path: PathBuf,
     trim: usize,
@@ -182,29 +552,162 @@ pub struct DataSet {
     max_read_length: usize,
     bisulfite: BisulfiteType,
     fli: Fli,
+    date: Option<RunDate>,
+    library_type: Option<String>,
+    n_reads: u64,
     cts: Counts,
+    composition: Option<Composition>,
+    #[serde(default)]
+    provenance: Provenance,
+    #[serde(skip)]
     per_pos_cts: Vec<Counts>,
+    #[serde(skip)]
     gc_hash: HashMap<String, u64>,
+    #[serde(skip)]
     gc_counts: Option<Vec<(GcHistKey, GcHistVal)>>,
+    #[serde(skip)]
     kmer_counts: Option<KmerCounts>,
+    #[serde(skip)]
+    qual_bands: Option<QualBands>,
+    // Per position counts for each read end (if known)
+    #[serde(skip)]
+    read_end_cts: BTreeMap<u8, Vec<Counts>>,
+}
+
+/// Description and overall base counts of a dataset, without the per position and gc counts.
+/// This is what the output thread receives, so the counts of a dataset are freed as soon as
+/// it has been analyzed rather than being held while results wait to be output
+#[derive(Clone, Debug)]
+pub struct DataSummary {
+    path: PathBuf,
+    trim: usize,
+    min_qual: u8,
+    max_read_length: usize,
+    bisulfite: BisulfiteType,
+    fli: Fli,
+    date: Option<RunDate>,
+    library_type: Option<String>,
+    n_reads: u64,
+    cts: Counts,
+    provenance: Provenance,
 }
 
-impl fmt::Display for DataSet {
+impl fmt::Display for DataSummary {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{}\t{}\t{}\t{}\t{}",
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
             self.fli,
-            self.path.display(),
+            tsv_path(&self.path),
             self.bisulfite,
             self.trim,
-            self.min_qual
+            self.min_qual,
+            self.date.map(|d| d.to_string()).as_deref().unwrap_or("NA"),
+            self.n_reads,
+            self.n_bases()
         )?;
+        match self.cts.fractions() {
+            Some(fr) => {
+                for x in fr {
+                    write!(f, "\t{:.5}", x)?
+                }
+            }
+            None => write!(f, "\tNA\tNA\tNA\tNA\tNA")?,
+        }
+        for x in self.cts.skews() {
+            match x {
+                Some(x) => write!(f, "\t{:.5}", x)?,
+                None => write!(f, "\tNA")?,
+            }
+        }
         Ok(())
     }
 }
 
+impl DataSummary {
+    /// Dataset description and base composition as a JSON object (for JSON output)
+    pub fn json_summary(&self) -> Map<String, Value> {
+        let mut m = match serde_json::to_value(&self.fli) {
+            Ok(Value::Object(m)) => m,
+            _ => Map::new(),
+        };
+        let fractions = self
+            .cts
+            .fractions()
+            .map(|fr| json!({"A": fr[0], "C": fr[1], "G": fr[2], "T": fr[3], "N": fr[4]}));
+        let [at_skew, gc_skew] = self.cts.skews();
+        m.insert("file".to_owned(), json!(self.path));
+        m.insert(
+            "bisulfite_type".to_owned(),
+            json!(self.bisulfite.to_string()),
+        );
+        m.insert("trim".to_owned(), json!(self.trim));
+        m.insert("min_qual".to_owned(), json!(self.min_qual));
+        m.insert("date".to_owned(), json!(self.date));
+        m.insert("reads".to_owned(), json!(self.n_reads));
+        m.insert("bases".to_owned(), json!(self.n_bases()));
+        m.insert("base_fractions".to_owned(), json!(fractions));
+        m.insert("at_skew".to_owned(), json!(at_skew));
+        m.insert("gc_skew".to_owned(), json!(gc_skew));
+        if self.provenance != Provenance::default() {
+            m.insert("provenance".to_owned(), json!(self.provenance));
+        }
+        m
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn bisulfite(&self) -> &BisulfiteType {
+        &self.bisulfite
+    }
+    pub fn max_read_len(&self) -> usize {
+        self.max_read_length
+    }
+    pub fn trim(&self) -> usize {
+        self.trim
+    }
+    pub fn min_qual(&self) -> u8 {
+        self.min_qual
+    }
+
+    pub fn n_reads(&self) -> u64 {
+        self.n_reads
+    }
+
+    /// Total number of bases (including Ns)
+    pub fn n_bases(&self) -> u64 {
+        self.cts.total()
+    }
+
+    pub fn library_type(&self) -> Option<&str> {
+        self.library_type.as_deref()
+    }
+
+    /// Upstream command line and program version
+    pub fn provenance(&self) -> &Provenance {
+        &self.provenance
+    }
+}
+
 impl DataSet {
+    /// Summary of the dataset for output
+    pub fn summary(&self) -> DataSummary {
+        DataSummary {
+            path: self.path.clone(),
+            trim: self.trim,
+            min_qual: self.min_qual,
+            max_read_length: self.max_read_length,
+            bisulfite: self.bisulfite,
+            fli: self.fli.clone(),
+            date: self.date,
+            library_type: self.library_type.clone(),
+            n_reads: self.n_reads,
+            cts: self.cts,
+            provenance: self.provenance.clone(),
+        }
+    }
+
     pub fn gc_counts(&self) -> Option<&[(GcHistKey, GcHistVal)]> {
         self.gc_counts.as_deref()
     }
@@ -217,15 +720,67 @@ impl DataSet {
     pub fn trim(&self) -> usize {
         self.trim
     }
+    pub fn min_qual(&self) -> u8 {
+        self.min_qual
+    }
 
     pub fn per_pos_cts(&self) -> &[Counts] {
         &self.per_pos_cts
     }
 
+    pub fn n_reads(&self) -> u64 {
+        self.n_reads
+    }
+
+    /// Total number of bases (including Ns)
+    pub fn n_bases(&self) -> u64 {
+        self.cts.total()
+    }
+
     pub fn kmer_counts(&self) -> Option<&KmerCounts> {
         self.kmer_counts.as_ref()
     }
 
+    pub fn library_type(&self) -> Option<&str> {
+        self.library_type.as_deref()
+    }
+
+    /// Dataset is tagged as coming from a cfDNA library
+    pub fn is_cfdna(&self) -> bool {
+        self.library_type
+            .as_deref()
+            .map(|s| s.eq_ignore_ascii_case("cfdna"))
+            .unwrap_or(false)
+    }
+
+    pub fn set_library_type(&mut self, library_type: String) {
+        self.library_type = Some(library_type)
+    }
+
+    /// Upstream command line and program version
+    pub fn provenance(&self) -> &Provenance {
+        &self.provenance
+    }
+
+    pub fn composition(&self) -> Option<&Composition> {
+        self.composition.as_ref()
+    }
+
+    /// Per position counts for each read end (from the FLI of the dataset or its merged
+    /// components)
+    pub fn read_end_cts(&self) -> &BTreeMap<u8, Vec<Counts>> {
+        &self.read_end_cts
+    }
+
+    /// Per position counts by base quality band (if provided upstream)
+    pub fn qual_bands(&self) -> Option<&QualBands> {
+        self.qual_bands.as_ref()
+    }
+
+    pub fn sample(&self) -> Option<&str> {
+        self.fli.sample.as_deref()
+    }
+
     pub fn fli_mut(&mut self) -> &mut Fli {
         &mut self.fli
     }
@@ -241,7 +796,9 @@ impl DataSet {
     pub fn mk_gc_counts(&mut self) -> anyhow::Result<()> {
         let mut gc_counts = Vec::with_capacity(self.gc_hash.len());
         for (k, v) in self.gc_hash.iter() {
-            let key = GcHistKey::from_str(k)?;
+            let key = k
+                .parse::<GcHistKey>()
+                .with_context(|| format!("Invalid gc_hash key '{k}'"))?;
             let val = GcHistVal::make(&key, *v);
             gc_counts.push((key, val));
         }
@@ -249,20 +806,15 @@ impl DataSet {
         Ok(())
     }
 
-    fn add_gc_hash(&mut self, other: &Self) {
-        let gc_hash = &mut self.gc_hash;
-
-        for (k, v) in other.gc_hash.iter() {
-            match gc_hash.get_mut(k) {
-                Some(x) => *x += *v,
-                None => {
-                    gc_hash.insert(k.clone(), *v);
-                }
+    pub(crate) fn from_temp_dataset(t: TempDataSet, p: &Path) -> anyhow::Result<Self> {
+        let problems = t.check();
+        if !problems.is_empty() {
+            return Err(InvalidDataSet {
+                path: p.to_owned(),
+                problems,
             }
+            .into());
         }
-    }
-
-    fn from_temp_dataset(t: TempDataSet, p: &Path) -> anyhow::Result<Self> {
         let TempDataSet {
             trim,
             min_qual,
@@ -273,23 +825,56 @@ impl DataSet {
             per_pos_cts: tmp_ppc,
             gc_hash,
             kmer_counts,
+            run_date,
+            library_type,
+            dinuc_counts,
+            trinuc_counts,
+            command_line,
+            program_version,
+            qual_band_cts,
         } = t;
 
+        let composition = Composition::from_counts(dinuc_counts.as_ref(), trinuc_counts.as_ref())
+            .with_context(|| {
+            format!("Error reading composition counts from {}", p.display())
+        })?;
+
         let cts = Counts::from_temp_counts(&tmp_cts);
-        let l = tmp_ppc.len();
-        assert!(max_read_length >= trim && max_read_length - trim == l);
-        let mut per_pos_cts = Vec::with_capacity(l);
-        for (ix, (k, v)) in tmp_ppc.iter().enumerate() {
-            assert_eq!(*k as usize, ix + 1 + trim);
-            per_pos_cts.push(Counts::from_temp_counts(v))
-        }
+        let per_pos_cts: Vec<_> = tmp_ppc.values().map(Counts::from_temp_counts).collect();
 
-        let s = OsStr::new("gz");
-        let path = if p.extension() == Some(s) {
-            PathBuf::from(p.file_stem().unwrap())
-        } else {
-            p.to_owned()
-        };
+        let qual_bands = qual_band_cts.map(|q| {
+            let per_pos = q
+                .per_pos_cts
+                .values()
+                .map(|v| v.iter().map(Counts::from_temp_counts).collect())
+                .collect();
+            QualBands::new(q.bounds, per_pos)
+        });
+
+        let read_end_cts = fli
+            .read_end
+            .map(|r| (r, per_pos_cts.clone()))
+            .into_iter()
+            .collect();
+
+        let path = dataset_path(p);
+
+        // Number of reads is taken as the total count at the first (untrimmed) position
+        let n_reads = per_pos_cts.first().map(|c: &Counts| c.total()).unwrap_or(0);
+
+        // Use run date from JSON if present, otherwise fall back to the file modification time
+        let date = run_date
+            .and_then(|s| {
+                s.parse::<RunDate>()
+                    .map_err(|e| warn!("{e} in {} (using file modification time)", p.display()))
+                    .ok()
+            })
+            .or_else(|| {
+                p.metadata()
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .map(RunDate::from_system_time)
+            });
 
         Ok(Self {
             path,
@@ -298,55 +883,520 @@ impl DataSet {
             max_read_length,
             bisulfite,
             fli,
+            date,
+            library_type,
+            n_reads,
             cts,
+            composition,
+            provenance: Provenance {
+                command_line,
+                program_version,
+            },
             per_pos_cts,
             gc_hash,
             gc_counts: None,
             kmer_counts,
+            qual_bands,
+            read_end_cts,
         })
     }
 
+    /// Dataset in fastq_gc JSON format (used to save merged datasets)
+    pub fn to_temp_dataset(&self) -> TempDataSet {
+        let (dinuc_counts, trinuc_counts) = match self.composition.as_ref().map(|c| c.to_counts()) {
+            Some((d, t)) => (Some(d), t),
+            None => (None, None),
+        };
+        // Merging can extend per_pos_cts past the trimmed read length
+        let per_pos_cts = self
+            .per_pos_cts
+            .iter()
+            .take(self.max_read_length - self.trim)
+            .enumerate()
+            .map(|(ix, c)| ((ix + 1 + self.trim) as u32, TempCounts::from_counts(c)))
+            .collect();
+        let qual_band_cts = self.qual_bands.as_ref().map(|q| TempQualBands {
+            bounds: q.bounds().to_vec(),
+            per_pos_cts: q
+                .per_pos()
+                .iter()
+                .take(self.max_read_length - self.trim)
+                .enumerate()
+                .map(|(ix, v)| {
+                    (
+                        (ix + 1 + self.trim) as u32,
+                        v.iter().map(TempCounts::from_counts).collect(),
+                    )
+                })
+                .collect(),
+        });
+
+        TempDataSet {
+            trim: self.trim,
+            min_qual: self.min_qual,
+            max_read_length: self.max_read_length,
+            bisulfite: self.bisulfite,
+            fli: self.fli.clone(),
+            cts: TempCounts::from_counts(&self.cts),
+            per_pos_cts,
+            gc_hash: self.gc_hash.clone(),
+            kmer_counts: self.kmer_counts.clone(),
+            run_date: self.date.map(|d| d.to_string()),
+            library_type: self.library_type.clone(),
+            dinuc_counts,
+            trinuc_counts,
+            command_line: self.provenance.command_line.clone(),
+            program_version: self.provenance.program_version.clone(),
+            qual_band_cts,
+        }
+    }
+
     fn check_constants(&self, other: &Self) -> bool {
         self.trim == other.trim
             && self.min_qual == other.min_qual
-            && self.bisulfite == other.bisulfite
             && ((self.kmer_counts.is_some() && other.kmer_counts.is_some())
                 || (self.kmer_counts.is_none() && other.kmer_counts.is_none()))
     }
 
-    fn add_counts(&mut self, other: &Self) {
-        self.cts.add(&other.cts);
+    fn add_counts(&mut self, other: &Self) -> anyhow::Result<()> {
+        self.n_reads = checked_add(self.n_reads, other.n_reads)?;
+        self.cts.add(&other.cts)?;
         self.per_pos_cts
             .resize_with(self.max_read_length, Default::default);
         for (c1, c2) in self.per_pos_cts.iter_mut().zip(other.per_pos_cts().iter()) {
-            c1.add(c2)
+            c1.add(c2)?
         }
+        Ok(())
     }
-    pub fn merge(&mut self, other: &Self) -> anyhow::Result<()> {
+    /// Merge other into self.  If merge_strands is set, datasets with different bisulfite
+    /// types are merged into a NonStranded composite.  The counts are checked for overflow as
+    /// they are added, so after an overflow error self is partially merged and should be
+    /// discarded
+    pub fn merge(&mut self, other: &Self, merge_strands: bool) -> anyhow::Result<()> {
+        let bisulfite = match self.bisulfite.strand_composite(&other.bisulfite) {
+            Some(b) if b == self.bisulfite || merge_strands => b,
+            Some(_) => {
+                return Err(anyhow!(
+                    "Cannot merge datasets with bisulfite types {} and {} (use --merge-bisulfite-strands)",
+                    self.bisulfite,
+                    other.bisulfite
+                ))
+            }
+            None => {
+                return Err(anyhow!(
+                    "Cannot merge bisulfite converted and unconverted datasets"
+                ))
+            }
+        };
         if !self.check_constants(other) {
             Err(anyhow!(
                 "Cannot merge datasets generated with different parameters"
             ))
         } else {
+            // Counts are in read orientation, with Forward reads showing C to T and Reverse
+            // reads G to A conversion.  A NonStranded library is a mixture of reads of both
+            // types, so the counts of the strands are added as they are
+            if bisulfite != self.bisulfite {
+                debug!(
+                    "Merging {} ({}) into {} ({}) as {bisulfite}",
+                    other.path.display(),
+                    other.bisulfite,
+                    self.path.display(),
+                    self.bisulfite
+                );
+                self.bisulfite = bisulfite
+            }
             self.max_read_length = self.max_read_length.max(other.max_read_length);
             self.fli.find_common(&other.fli);
-            self.add_counts(other);
-            self.add_gc_hash(other);
+            // Merged datasets take the most recent date of their components
+            if other.date > self.date {
+                self.date = other.date
+            }
+            if self.library_type != other.library_type {
+                self.library_type = None
+            }
+            self.provenance.merge(&other.provenance);
+            self.add_counts(other)?;
+            for (r, v) in other.read_end_cts.iter() {
+                let ct = self.read_end_cts.entry(*r).or_default();
+                if ct.len() < v.len() {
+                    ct.resize_with(v.len(), Default::default)
+                }
+                for (c1, c2) in ct.iter_mut().zip(v.iter()) {
+                    c1.add(c2)?
+                }
+            }
+            merge_gc_hash(&mut self.gc_hash, &other.gc_hash)?;
+            // The gc counts (if made) no longer match the merged gc_hash
+            self.gc_counts = None;
+            // Composition counts are only kept if available for all merged datasets
+            match (self.composition.as_mut(), other.composition()) {
+                (Some(c1), Some(c2)) => c1.add(c2),
+                (Some(_), None) => {
+                    warn!("Composition counts not available for all merged datasets");
+                    self.composition = None
+                }
+                _ => (),
+            }
             if let Some(kc) = self.kmer_counts.as_mut() {
                 kc.add(other.kmer_counts().as_ref().unwrap())?
             }
+            // As are quality band counts, which must also have the same bands
+            if let Some(q1) = self.qual_bands.as_mut() {
+                let ok = match other.qual_bands() {
+                    Some(q2) => q1.add(q2)?,
+                    None => false,
+                };
+                if !ok {
+                    warn!("Quality band counts not available with the same bands for all merged datasets");
+                    self.qual_bands = None
+                }
+            }
             Ok(())
         }
     }
 }
 
-pub fn read_json<P: AsRef<Path>>(p: P) -> anyhow::Result<DataSet> {
+// Addition of counts when merging, returning an error on overflow rather than wrapping
+fn checked_add(a: u64, b: u64) -> anyhow::Result<u64> {
+    a.checked_add(b)
+        .ok_or_else(|| anyhow!("Overflow when merging counts"))
+}
+
+// Add the counts in h2 to h1.  The merged map has at least as many keys as the larger input,
+// so capacity for this is reserved up front.  On overflow an error is returned, and h1 is left
+// partially updated
+fn merge_gc_hash(h1: &mut HashMap<String, u64>, h2: &HashMap<String, u64>) -> anyhow::Result<()> {
+    h1.reserve(h2.len().saturating_sub(h1.len()));
+    for (k, v) in h2.iter() {
+        match h1.get_mut(k) {
+            Some(x) => *x = checked_add(*x, *v)?,
+            None => {
+                h1.insert(k.clone(), *v);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Path used for a dataset read from input file p (with any .gz extension removed)
+pub fn dataset_path(p: &Path) -> PathBuf {
+    let s = OsStr::new("gz");
+    if p.extension() == Some(s) {
+        PathBuf::from(p.file_stem().unwrap())
+    } else {
+        p.to_owned()
+    }
+}
+
+/// Layout of a fastq_gc JSON file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonLayout {
+    /// Layout of older fastq_gc versions, which may use the old field names, store the per
+    /// position counts as an array or keyed from 0, and lack the trim, total counts or kmer
+    /// counts
+    Legacy,
+    Current,
+}
+
+// Fields renamed in fastq_gc, as (old name, current name)
+const RENAMED_FIELDS: [(&str, &str); 4] = [
+    ("max_read_len", "max_read_length"),
+    ("counts", "cts"),
+    ("pos_cts", "per_pos_cts"),
+    ("gc_counts", "gc_hash"),
+];
+
+// Fields required in the current layout
+const REQUIRED_FIELDS: [&str; 8] = [
+    "trim",
+    "min_qual",
+    "max_read_length",
+    "bisulfite",
+    "fli",
+    "cts",
+    "per_pos_cts",
+    "gc_hash",
+];
+
+const BASES: [&str; 5] = ["A", "C", "G", "T", "N"];
+
+/// Layout of the fastq_gc JSON object m
+pub fn json_layout(m: &Map<String, Value>) -> JsonLayout {
+    let legacy = RENAMED_FIELDS.iter().any(|(old, _)| m.contains_key(*old))
+        || ["trim", "cts"].iter().any(|k| !m.contains_key(*k))
+        || match m.get("per_pos_cts") {
+            Some(Value::Array(_)) => true,
+            Some(Value::Object(c)) => c.contains_key("0"),
+            _ => false,
+        };
+    if legacy {
+        JsonLayout::Legacy
+    } else {
+        JsonLayout::Current
+    }
+}
+
+// Convert per position counts from a legacy layout to a map keyed by position (from trim + 1).
+// Counts may be given as an array, or as a map keyed from 0
+fn normalize_per_pos_cts(v: Value, trim: u64) -> anyhow::Result<Value> {
+    let cts: Vec<Value> = match v {
+        Value::Array(a) => a,
+        Value::Object(m) if m.contains_key("0") => {
+            let mut c = m
+                .into_iter()
+                .map(|(k, v)| {
+                    k.parse::<u64>()
+                        .map(|i| (i, v))
+                        .map_err(|_| anyhow!("Invalid position {k} in per_pos_cts"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            c.sort_unstable_by_key(|(i, _)| *i);
+            c.into_iter().map(|(_, v)| v).collect()
+        }
+        v => return Ok(v),
+    };
+    Ok(Value::Object(
+        cts.into_iter()
+            .enumerate()
+            .map(|(i, v)| ((trim + 1 + i as u64).to_string(), v))
+            .collect(),
+    ))
+}
+
+// Total counts summed over the per position counts
+fn sum_per_pos_cts(ppc: &Value) -> Value {
+    let mut tot = Map::new();
+    if let Value::Object(m) = ppc {
+        for c in m.values() {
+            for b in BASES {
+                if let Some(x) = c.get(b).and_then(|x| x.as_u64()) {
+                    let t = tot.entry(b).or_insert(json!(0u64));
+                    *t = json!(t.as_u64().unwrap_or(0) + x)
+                }
+            }
+        }
+    }
+    Value::Object(tot)
+}
+
+/// Convert a fastq_gc JSON object in a legacy layout to the current layout.  Objects
+/// already in the current layout are unchanged
+pub fn normalize_json(mut m: Map<String, Value>) -> anyhow::Result<Map<String, Value>> {
+    if json_layout(&m) == JsonLayout::Current {
+        return Ok(m);
+    }
+    for (old, new) in RENAMED_FIELDS {
+        if let Some(v) = m.remove(old) {
+            m.entry(new).or_insert(v);
+        }
+    }
+    let trim = m.get("trim").and_then(|x| x.as_u64()).unwrap_or(0);
+    m.entry("trim").or_insert(json!(0));
+    m.entry("bisulfite").or_insert(json!("None"));
+    if let Some(v) = m.remove("per_pos_cts") {
+        let ppc = normalize_per_pos_cts(v, trim)?;
+        if let Value::Object(c) = &ppc {
+            m.entry("max_read_length")
+                .or_insert(json!(trim + c.len() as u64));
+        }
+        if !m.contains_key("cts") {
+            m.insert("cts".to_owned(), sum_per_pos_cts(&ppc));
+        }
+        m.insert("per_pos_cts".to_owned(), ppc);
+    }
+    let missing: Vec<_> = REQUIRED_FIELDS
+        .iter()
+        .filter(|k| !m.contains_key(**k))
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "Not a recognized fastq_gc JSON layout (missing {})",
+            missing.join(", ")
+        ));
+    }
+    Ok(m)
+}
+
+// Parse one fastq_gc JSON object in any known layout
+fn parse_json_object(m: Map<String, Value>) -> anyhow::Result<(JsonLayout, TempDataSet)> {
+    let layout = json_layout(&m);
+    if layout == JsonLayout::Legacy {
+        debug!("Converting JSON from legacy fastq_gc layout")
+    }
+    let m = normalize_json(m)?;
+    let t = serde_json::from_value(Value::Object(m))
+        .with_context(|| format!("Invalid fastq_gc JSON ({layout:?} layout)"))?;
+    Ok((layout, t))
+}
+
+/// Parse a fastq_gc JSON file in any known layout, returning the layout found and the
+/// dataset converted to the current layout for each read group.  A file with multiple read
+/// groups (FLIs) holds an array of fastq_gc JSON objects, one per read group
+pub fn parse_json<R: std::io::Read>(rdr: R) -> anyhow::Result<Vec<(JsonLayout, TempDataSet)>> {
+    match from_reader(rdr)? {
+        Value::Object(m) => Ok(vec![parse_json_object(m)?]),
+        Value::Array(v) if v.is_empty() => Err(anyhow!("Empty array of read groups")),
+        Value::Array(v) => {
+            v.into_iter()
+                .enumerate()
+                .map(|(i, x)| match x {
+                    Value::Object(m) => parse_json_object(m)
+                        .with_context(|| format!("Error in read group {}", i + 1)),
+                    _ => Err(anyhow!("Read group {} is not a JSON object", i + 1)),
+                })
+                .collect()
+        }
+        _ => Err(anyhow!("Not a JSON object or array")),
+    }
+}
+
+/// Path used for read group ix (from 0) of a dataset with path p.  The read group number
+/// (from 1) is inserted before the extension, so for example read group 0 of sample.json
+/// becomes sample.rg1.json, giving each read group its own auxiliary files
+pub fn read_group_path(p: &Path, ix: usize) -> PathBuf {
+    let name = match (p.file_stem(), p.extension()) {
+        (Some(stem), Some(ext)) => format!(
+            "{}.rg{}.{}",
+            stem.to_string_lossy(),
+            ix + 1,
+            ext.to_string_lossy()
+        ),
+        _ => format!(
+            "{}.rg{}",
+            p.file_name().unwrap_or_default().to_string_lossy(),
+            ix + 1
+        ),
+    };
+    p.with_file_name(name)
+}
+
+// Convert the read groups parsed from input file p to datasets.  Datasets from files with
+// multiple read groups are given separate paths
+fn datasets_from_groups(
+    v: Vec<(JsonLayout, TempDataSet)>,
+    p: &Path,
+) -> anyhow::Result<Vec<DataSet>> {
+    let multi = v.len() > 1;
+    v.into_iter()
+        .enumerate()
+        .map(|(i, (_, tmp))| {
+            let mut d = DataSet::from_temp_dataset(tmp, p)?;
+            if multi {
+                d.set_path(read_group_path(d.path(), i))
+            }
+            Ok(d)
+        })
+        .collect()
+}
+
+/// Read all read groups from fastq_gc JSON file p, returning one dataset per read group
+pub fn read_json_groups<P: AsRef<Path>>(p: P) -> anyhow::Result<Vec<DataSet>> {
     let p = p.as_ref();
 
     let rdr = CompressIo::new()
         .path(p)
         .bufreader()
         .with_context(|| format!("Could not open {} for input", p.display()))?;
-    let tmp: TempDataSet = from_reader(rdr).with_context(|| "Error parsing JSON file")?;
-    DataSet::from_temp_dataset(tmp, p)
+    let v = parse_json(rdr).with_context(|| "Error parsing JSON file")?;
+    datasets_from_groups(v, p)
+}
+
+/// Read dataset from fastq_gc JSON file p.  Fails if the file has multiple read groups (use
+/// [`read_json_groups`] for these)
+pub fn read_json<P: AsRef<Path>>(p: P) -> anyhow::Result<DataSet> {
+    let p = p.as_ref();
+    let mut v = read_json_groups(p)?;
+    if v.len() > 1 {
+        Err(anyhow!(
+            "{} has {} read groups where one was expected",
+            p.display(),
+            v.len()
+        ))
+    } else {
+        Ok(v.pop().unwrap())
+    }
+}
+
+/// Read JSON datasets (one per read group) from rdr, giving the datasets the path p (with
+/// the read group inserted if there are multiple read groups)
+pub fn read_json_reader<R: std::io::Read>(rdr: R, p: &Path) -> anyhow::Result<Vec<DataSet>> {
+    let v = parse_json(rdr).with_context(|| "Error parsing JSON")?;
+    datasets_from_groups(v, p)
+}
+
+/// Read JSON datasets (one per read group) from stdin.  The dataset is given the path 'stdin'
+/// (or stdin.rg1, stdin.rg2 etc. with multiple read groups)
+pub fn read_json_stdin() -> anyhow::Result<Vec<DataSet>> {
+    let rdr = CompressIo::new()
+        .bufreader()
+        .with_context(|| "Could not open stdin for input")?;
+    let v = parse_json(rdr).with_context(|| "Error parsing JSON from stdin")?;
+    datasets_from_groups(v, Path::new("stdin"))
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn merge_gc_hash_test() {
+        let h: HashMap<String, u64> = (0..100).map(|i| (format!("{i}:1"), i)).collect();
+        let h2: HashMap<String, u64> = (50..150).map(|i| (format!("{i}:1"), 1)).collect();
+        let mut a = h.clone();
+        merge_gc_hash(&mut a, &h2).unwrap();
+        assert_eq!((a.len(), a["60:1"], a["120:1"]), (150, 61, 1));
+
+        let mut c = HashMap::from([("1:1".to_owned(), u64::MAX)]);
+        let d = HashMap::from([("1:1".to_owned(), 1), ("2:1".to_owned(), 1)]);
+        assert!(merge_gc_hash(&mut c, &d).is_err());
+    }
+
+    #[test]
+    fn legacy_layout_test() {
+        let m = json!({
+            "min_qual": 20,
+            "max_read_len": 2,
+            "fli": {"sample": "S1", "barcode": null, "library": null, "flowcell": null,
+                "index": null, "lane": null, "read_end": null},
+            "pos_cts": [{"A": 1, "C": 2, "G": 3, "T": 4, "N": 0}, {"A": 4, "C": 3, "G": 2, "T": 1, "N": 1}],
+            "gc_counts": {"2:1": 5},
+        });
+        let Value::Object(m) = m else { panic!() };
+        assert_eq!(json_layout(&m), JsonLayout::Legacy);
+        let m = normalize_json(m).unwrap();
+        assert_eq!(json_layout(&m), JsonLayout::Current);
+        assert_eq!(m["cts"], json!({"A": 5, "C": 5, "G": 5, "T": 5, "N": 1}));
+        assert_eq!(m["per_pos_cts"]["2"]["N"], json!(1));
+        let t: TempDataSet = serde_json::from_value(Value::Object(m)).unwrap();
+        assert!(t.kmer_counts.is_none() && t.trim == 0 && t.max_read_length == 2);
+
+        let Value::Object(m) = json!({"min_qual": 20}) else {
+            panic!()
+        };
+        assert!(normalize_json(m).is_err());
+    }
+
+    #[test]
+    fn read_group_path_test() {
+        assert_eq!(
+            read_group_path(Path::new("dir/s1.json"), 0),
+            Path::new("dir/s1.rg1.json")
+        );
+        assert_eq!(
+            read_group_path(Path::new("stdin"), 1),
+            Path::new("stdin.rg2")
+        );
+    }
+
+    #[test]
+    fn strand_composite_test() {
+        use BisulfiteType::*;
+        assert_eq!(Forward.strand_composite(&Reverse), Some(NonStranded));
+        assert_eq!(Reverse.strand_composite(&NonStranded), Some(NonStranded));
+        assert_eq!(Forward.strand_composite(&Forward), Some(Forward));
+        assert_eq!(None.strand_composite(&None), Some(None));
+        assert_eq!(None.strand_composite(&Reverse), Option::None);
+    }
 }
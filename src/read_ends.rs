@@ -0,0 +1,105 @@
+//! Per read end (R1/R2) gc metrics (--read-ends).
+//!
+//! Datasets keep separate per cycle counts for each read end recorded in their FLI, and these
+//! are summed by read end when datasets are merged, so the metrics of each end remain
+//! available after R1 and R2 are merged into one dataset.  For each of R1 and R2 the gc
+//! content and the regression of the per cycle gc content against cycle (over the same part
+//! of the read as the other regressions) are reported, so that quality decay specific to
+//! read 2 is not hidden by merging.  Per read end counts are not kept in merged datasets
+//! written with --write-merged.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{read::Counts, simple_regression::SimpleRegression};
+
+/// Read ends with output columns
+pub const READ_ENDS: [u8; 2] = [1, 2];
+
+/// gc metrics for one read end
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReadEndMetrics {
+    read_end: u8,
+    gc: Option<f64>,
+    gc_regression: Option<SimpleRegression>,
+}
+
+impl ReadEndMetrics {
+    /// Metrics for read end from its per cycle counts ct and the gc regression
+    pub fn make(read_end: u8, ct: &[Counts], gc_regression: Option<SimpleRegression>) -> Self {
+        // Counts are in the order A, C, T, G, N
+        let (gc, s) = ct.iter().fold((0, 0), |(gc, s), c| {
+            let c = c.cts();
+            (gc + c[1] + c[3], s + c[..4].iter().sum::<u64>())
+        });
+        Self {
+            read_end,
+            gc: (s > 0).then(|| gc as f64 / s as f64),
+            gc_regression,
+        }
+    }
+
+    pub fn read_end(&self) -> u8 {
+        self.read_end
+    }
+
+    /// gc content of the read end (from the base composition)
+    pub fn gc(&self) -> Option<f64> {
+        self.gc
+    }
+
+    pub fn gc_regression(&self) -> Option<&SimpleRegression> {
+        self.gc_regression.as_ref()
+    }
+}
+
+/// Column names in TSV output
+pub fn read_end_header() -> Vec<String> {
+    READ_ENDS
+        .iter()
+        .flat_map(|r| ["gc", "gc-slope", "gc-p"].map(|c| format!("R{r}-{c}")))
+        .collect()
+}
+
+/// Columns of the main TSV output for read end metrics v (NA for read ends not present)
+pub fn read_end_columns(v: Option<&[ReadEndMetrics]>) -> String {
+    let mut s = String::new();
+    for r in READ_ENDS {
+        match v.and_then(|v| v.iter().find(|m| m.read_end == r)) {
+            Some(m) => {
+                match m.gc {
+                    Some(x) => s.push_str(&format!("\t{x:.5}")),
+                    None => s.push_str("\tNA"),
+                }
+                match m.gc_regression.as_ref() {
+                    Some(reg) => s.push_str(&format!(
+                        "\t{:.5e}\t{}",
+                        reg.slope().estimate(),
+                        reg.slope()
+                            .p()
+                            .map(|p| format!("{p:.5}"))
+                            .unwrap_or_else(|| "NA".to_owned())
+                    )),
+                    None => s.push_str("\tNA\tNA"),
+                }
+            }
+            None => s.push_str("\tNA\tNA\tNA"),
+        }
+    }
+    s
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn read_end_columns_test() {
+        assert_eq!(
+            read_end_header()[..3],
+            ["R1-gc", "R1-gc-slope", "R1-gc-p"].map(String::from)
+        );
+        let v = [ReadEndMetrics::make(2, &[], None)];
+        assert_eq!(read_end_columns(Some(&v)), "\tNA\tNA\tNA\tNA\tNA\tNA");
+        assert_eq!(read_end_columns(None).matches('\t').count(), 6);
+    }
+}
@@ -1,4 +1,10 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use serde::Deserialize;
 
@@ -19,17 +25,23 @@ struct RawRef {
     read_lengths: Vec<u32>,
     read_length_specific_counts: HashMap<u32, RSCounts>,
 }
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Ord, PartialOrd)]
+/// Key of a gc histogram: the number of AT and GC bases in a read
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Ord, PartialOrd)]
 pub struct GcHistKey(u32, u32);
 
 impl GcHistKey {
+    pub fn new(at: u32, gc: u32) -> Self {
+        Self(at, gc)
+    }
     pub fn counts(&self) -> (f64, f64) {
         (self.0 as f64, self.1 as f64)
     }
 }
 
-impl GcHistKey {
-    pub fn from_str(s: &str) -> anyhow::Result<Self> {
+impl FromStr for GcHistKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
         if let Some((s1, s2)) = s.split_once(':') {
             let c1 = s1.parse::<u32>()?;
             let c2 = s2.parse::<u32>()?;
@@ -40,6 +52,8 @@ impl GcHistKey {
     }
 }
 
+/// Value of a gc histogram: the count for a key along with the (log) beta function term used
+/// by the divergence calculations
 #[derive(Debug, Copy, Clone)]
 pub struct GcHistVal {
     count: f64,
@@ -63,6 +77,7 @@ impl GcHistVal {
     }
 }
 
+/// Reference gc histograms for one read length
 pub struct Counts {
     regular: Vec<(GcHistKey, GcHistVal)>,
     bisulfite: Option<Vec<(GcHistKey, GcHistVal)>>,
@@ -76,7 +91,7 @@ impl Counts {
         } = rs;
 
         let make = |k: &String, v| -> anyhow::Result<_> {
-            let key = GcHistKey::from_str(k)?;
+            let key = k.parse::<GcHistKey>()?;
             let val = GcHistVal::make(&key, v);
             Ok((key, val))
         };
@@ -100,6 +115,14 @@ impl Counts {
         Ok(Self { regular, bisulfite })
     }
 
+    /// Counts from gc histograms for regular and (optionally) bisulfite converted reads
+    pub fn from_hists(
+        regular: Vec<(GcHistKey, GcHistVal)>,
+        bisulfite: Option<Vec<(GcHistKey, GcHistVal)>>,
+    ) -> Self {
+        Self { regular, bisulfite }
+    }
+
     pub fn regular(&self) -> &[(GcHistKey, GcHistVal)] {
         &self.regular
     }
@@ -107,6 +130,163 @@ impl Counts {
         self.bisulfite.as_deref()
     }
 }
+/// Source of reference gc distributions used by compare_to_reference
+pub trait ReferenceProvider: Send + Sync {
+    /// Reference counts for the available read length closest to rl.  Returns the read length
+    /// of the selected reference along with the counts
+    fn get_closest_reference(&self, rl: u32) -> (u32, &Counts);
+}
+
+/// Source of reference distributions, selected from the reference path
+#[derive(Debug)]
+pub enum ReferenceSource {
+    /// JSON file (e.g., from analyze_ref_gc or build-ref).  A binary copy is cached next to
+    /// the JSON file and used while it is newer than the JSON file
+    Json(PathBuf),
+    /// Binary reference file (written by a previous read of a JSON file)
+    Binary(PathBuf),
+    /// Reference FASTA file, optionally with a BED file of target regions (FASTA,BED).  Not
+    /// currently supported
+    FastaBed(PathBuf, Option<PathBuf>),
+    /// Remote URL.  Not currently supported
+    Remote(String),
+}
+
+/// Extension of binary reference files
+const BINARY_EXT: &str = "gcref";
+
+/// Magic number at the start of binary reference files (including the format version)
+const BINARY_MAGIC: &[u8; 8] = b"GCREF\x00\x00\x01";
+
+const FASTA_EXTS: [&str; 4] = [".fa", ".fasta", ".fna", ".fas"];
+
+fn is_fasta(p: &Path) -> bool {
+    let s = p.to_string_lossy();
+    let s = [".gz", ".bz2", ".xz", ".zst"]
+        .iter()
+        .find_map(|c| s.strip_suffix(c))
+        .unwrap_or(&s);
+    FASTA_EXTS.iter().any(|e| s.ends_with(e))
+}
+
+// Path of the binary cache of a JSON reference file
+fn binary_cache_path(p: &Path) -> PathBuf {
+    let mut s = p.as_os_str().to_owned();
+    s.push(".");
+    s.push(BINARY_EXT);
+    PathBuf::from(s)
+}
+
+// True if the binary cache exists and was modified after the JSON file
+fn cache_is_current(json: &Path, cache: &Path) -> bool {
+    let mtime = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+    matches!((mtime(json), mtime(cache)), (Some(t1), Some(t2)) if t2 >= t1)
+}
+
+impl ReferenceSource {
+    /// Select the source from the reference path: URLs are remote, paths ending in .gcref are
+    /// binary, FASTA files (or FASTA,BED pairs) are FastaBed and anything else is JSON
+    pub fn from_path(p: &Path) -> Self {
+        let s = p.to_string_lossy();
+        if s.contains("://") {
+            Self::Remote(s.into_owned())
+        } else if p.extension().is_some_and(|e| e == BINARY_EXT) {
+            Self::Binary(p.to_owned())
+        } else {
+            match s.split_once(',') {
+                Some((f, b)) if is_fasta(Path::new(f)) => {
+                    Self::FastaBed(PathBuf::from(f), Some(PathBuf::from(b)))
+                }
+                _ if is_fasta(p) => Self::FastaBed(p.to_owned(), None),
+                _ => Self::Json(p.to_owned()),
+            }
+        }
+    }
+
+    /// Read the reference distributions
+    pub fn load(&self) -> anyhow::Result<RefDist> {
+        match self {
+            Self::Json(p) => {
+                let cache = binary_cache_path(p);
+                if cache_is_current(p, &cache) {
+                    match RefDist::from_binary_file(&cache) {
+                        Ok(r) => return Ok(r),
+                        Err(e) => warn!("Ignoring binary reference cache: {e:#}"),
+                    }
+                }
+                let r = RefDist::from_json_file(p).with_context(|| {
+                    format!(
+                        "Error reading reference distributions from JSON file {}",
+                        p.display()
+                    )
+                })?;
+                // The cache is optional, so failure to write it (e.g., a read only directory)
+                // is not an error
+                if let Err(e) = r.write_binary_file(&cache) {
+                    debug!("Binary reference cache not written: {e:#}")
+                }
+                Ok(r)
+            }
+            Self::Binary(p) => RefDist::from_binary_file(p),
+            Self::FastaBed(f, _) => Err(anyhow!(
+                "Reference distributions from FASTA (+BED) file {} are not supported: generate a reference JSON file with build-ref",
+                f.display()
+            )),
+            Self::Remote(url) => Err(anyhow!(
+                "Remote reference {url} not supported: download the reference file first"
+            )),
+        }
+    }
+}
+
+fn read_u32<R: Read>(rdr: &mut R) -> std::io::Result<u32> {
+    let mut b = [0; 4];
+    rdr.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_f64<R: Read>(rdr: &mut R) -> std::io::Result<f64> {
+    let mut b = [0; 8];
+    rdr.read_exact(&mut b)?;
+    Ok(f64::from_le_bytes(b))
+}
+
+// Histogram as the number of entries followed by (at, gc, count) for each entry
+fn write_hist<W: Write>(w: &mut W, h: &[(GcHistKey, GcHistVal)]) -> std::io::Result<()> {
+    w.write_all(&(h.len() as u32).to_le_bytes())?;
+    for (k, v) in h {
+        w.write_all(&k.0.to_le_bytes())?;
+        w.write_all(&k.1.to_le_bytes())?;
+        w.write_all(&v.count.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_hist<R: Read>(rdr: &mut R) -> std::io::Result<Vec<(GcHistKey, GcHistVal)>> {
+    let n = read_u32(rdr)? as usize;
+    let mut h = Vec::with_capacity(n);
+    for _ in 0..n {
+        let k = GcHistKey(read_u32(rdr)?, read_u32(rdr)?);
+        let count = read_f64(rdr)?;
+        let (a, b) = k.counts();
+        h.push((
+            k,
+            GcHistVal {
+                count,
+                beta_a_b: lbeta(a + 1.0, b + 1.0),
+            },
+        ))
+    }
+    Ok(h)
+}
+
+/// Read lengths for which synthetic reference distributions are generated (--expected-gc)
+const EXPECTED_GC_READ_LENGTHS: [u32; 9] = [36, 50, 75, 100, 125, 150, 200, 250, 300];
+
+/// Number of pseudo-reads in each synthetic reference distribution
+const EXPECTED_GC_READS: f64 = 1.0e8;
+
+/// Reference distributions read from a JSON file
 pub struct RefDist {
     read_lengths: Vec<u32>,
     read_length_specific_counts: HashMap<u32, Counts>,
@@ -128,6 +308,41 @@ impl RefDist {
         })
     }
 
+    /// Reference distributions from counts by read length.  Returns None if no counts are given
+    pub(crate) fn from_counts(counts: HashMap<u32, Counts>) -> Option<Self> {
+        let mut read_lengths: Vec<_> = counts.keys().copied().collect();
+        read_lengths.sort_unstable();
+        (!read_lengths.is_empty()).then_some(Self {
+            read_lengths,
+            read_length_specific_counts: counts,
+        })
+    }
+
+    /// Synthetic reference distributions for a genome whose local gc proportion follows a beta
+    /// distribution with the given mean and concentration (alpha + beta), so that the number
+    /// of GC bases in a read of length n is beta-binomial.  Distributions are generated for a
+    /// fixed set of read lengths, with no bisulfite distributions
+    pub fn expected_gc(mean: f64, concentration: f64) -> Self {
+        let (alpha, beta) = (mean * concentration, (1.0 - mean) * concentration);
+        let counts = EXPECTED_GC_READ_LENGTHS
+            .iter()
+            .map(|&n| {
+                let hist = (0..=n)
+                    .filter_map(|b| {
+                        let (x, y) = (b as f64, (n - b) as f64);
+                        let ln_choose = -lbeta(x + 1.0, y + 1.0) - ((n + 1) as f64).ln();
+                        let lp = ln_choose + lbeta(x + alpha, y + beta) - lbeta(alpha, beta);
+                        let c = (EXPECTED_GC_READS * lp.exp()).round() as u64;
+                        let k = GcHistKey(n - b, b);
+                        (c > 0).then(|| (k, GcHistVal::make(&k, c)))
+                    })
+                    .collect();
+                (n, Counts::from_hists(hist, None))
+            })
+            .collect();
+        Self::from_counts(counts).expect("No read lengths for synthetic reference")
+    }
+
     pub fn from_json_file<P: AsRef<Path>>(p: P) -> anyhow::Result<Self> {
         let p = p.as_ref();
         let rdr = CompressIo::new()
@@ -143,7 +358,83 @@ impl RefDist {
         Self::from_raw(raw)
     }
 
-    pub fn get_closest_reference(&self, rl: u32) -> (u32, &Counts) {
+    /// Read reference distributions from a binary file written by write_binary_file
+    pub fn from_binary_file<P: AsRef<Path>>(p: P) -> anyhow::Result<Self> {
+        let p = p.as_ref();
+        let mut rdr = BufReader::new(
+            File::open(p).with_context(|| format!("Could not open {} for input", p.display()))?,
+        );
+        let mut read = || -> std::io::Result<Option<HashMap<u32, Counts>>> {
+            let mut magic = [0; 8];
+            rdr.read_exact(&mut magic)?;
+            if &magic != BINARY_MAGIC {
+                return Ok(None);
+            }
+            let n = read_u32(&mut rdr)? as usize;
+            let mut counts = HashMap::with_capacity(n);
+            for _ in 0..n {
+                let rl = read_u32(&mut rdr)?;
+                let regular = read_hist(&mut rdr)?;
+                let mut flag = [0];
+                rdr.read_exact(&mut flag)?;
+                let bisulfite = if flag[0] != 0 {
+                    Some(read_hist(&mut rdr)?)
+                } else {
+                    None
+                };
+                counts.insert(rl, Counts { regular, bisulfite });
+            }
+            Ok(Some(counts))
+        };
+        let counts = read()
+            .with_context(|| format!("Error reading binary reference file {}", p.display()))?
+            .ok_or_else(|| anyhow!("{} is not a binary reference file", p.display()))?;
+        let r = Self::from_counts(counts)
+            .ok_or_else(|| anyhow!("No reference distributions in {}", p.display()))?;
+        info!("Reference distributions read from {}", p.display());
+        Ok(r)
+    }
+
+    /// Write the reference distributions to a binary file, which is faster to read than the
+    /// JSON file.  The file is written to a temporary file and then renamed, so a partially
+    /// written file is never seen by readers
+    pub fn write_binary_file<P: AsRef<Path>>(&self, p: P) -> anyhow::Result<()> {
+        let p = p.as_ref();
+        let mut tmp = p.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut w = BufWriter::new(
+            File::create(&tmp)
+                .with_context(|| format!("Could not open {} for output", tmp.display()))?,
+        );
+        let mut write = || -> std::io::Result<()> {
+            w.write_all(BINARY_MAGIC)?;
+            w.write_all(&(self.read_lengths.len() as u32).to_le_bytes())?;
+            for rl in self.read_lengths.iter() {
+                let c = &self.read_length_specific_counts[rl];
+                w.write_all(&rl.to_le_bytes())?;
+                write_hist(&mut w, &c.regular)?;
+                match &c.bisulfite {
+                    Some(b) => {
+                        w.write_all(&[1])?;
+                        write_hist(&mut w, b)?
+                    }
+                    None => w.write_all(&[0])?,
+                }
+            }
+            w.flush()
+        };
+        if let Err(e) = write().and_then(|_| fs::rename(&tmp, p)) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e).with_context(|| format!("Error writing {}", p.display()));
+        }
+        debug!("Binary reference distributions written to {}", p.display());
+        Ok(())
+    }
+}
+
+impl ReferenceProvider for RefDist {
+    fn get_closest_reference(&self, rl: u32) -> (u32, &Counts) {
         let rlens = &self.read_lengths;
         let closest_ix = rlens[1..].iter().enumerate().fold(0, |k, (i, l)| {
             if rl.abs_diff(*l) < rl.abs_diff(rlens[k]) {
@@ -156,3 +447,49 @@ impl RefDist {
         (rl1, &self.read_length_specific_counts[&rl1])
     }
 }
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn expected_gc_test() {
+        let r = RefDist::expected_gc(0.41, 50.0);
+        let (rl, counts) = r.get_closest_reference(98);
+        assert_eq!(rl, 100);
+        assert!(counts.bisulfite().is_none());
+        let gc = crate::betabin::mean_gc(counts.regular());
+        assert!((gc - 0.41).abs() < 1e-4);
+    }
+
+    #[test]
+    fn reference_source_test() {
+        let src = |s: &str| ReferenceSource::from_path(Path::new(s));
+        assert!(matches!(src("ref.json.gz"), ReferenceSource::Json(_)));
+        assert!(matches!(src("ref.json.gcref"), ReferenceSource::Binary(_)));
+        assert!(matches!(
+            src("hg38.fa.gz,targets.bed"),
+            ReferenceSource::FastaBed(_, Some(_))
+        ));
+        assert!(matches!(
+            src("https://host/ref.json"),
+            ReferenceSource::Remote(_)
+        ));
+        assert!(src("hg38.fasta").load().is_err());
+
+        // Binary round trip
+        let r = RefDist::expected_gc(0.41, 50.0);
+        let p = std::env::temp_dir().join(format!("gc_collect_test_{}.gcref", std::process::id()));
+        r.write_binary_file(&p).unwrap();
+        let r1 = src(p.to_str().unwrap()).load().unwrap();
+        let _ = fs::remove_file(&p);
+        assert_eq!(r.read_lengths, r1.read_lengths);
+        let (c, c1) = (
+            r.get_closest_reference(150).1,
+            r1.get_closest_reference(150).1,
+        );
+        assert_eq!(c.regular().len(), c1.regular().len());
+        assert_eq!(c.regular()[3].0, c1.regular()[3].0);
+        assert_eq!(c.regular()[3].1.count(), c1.regular()[3].1.count());
+    }
+}
@@ -0,0 +1,604 @@
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::{builder::PossibleValue, ValueEnum};
+use compress_io::compress::CompressIo;
+use serde::Serialize;
+use serde_json::Value;
+use tera::Tera;
+
+use crate::{
+    cli::{ReportConfig, RescoreConfig},
+    fli_duplicates::{find_fli_duplicates, FliDuplicate},
+    rescore::{gc_hist_path, rescore_table},
+    results_table::{first_char, ResultsTable},
+    utils::base64_encode,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ReportFormat {
+    Markdown,
+    Html,
+    #[cfg(feature = "xlsx")]
+    Xlsx,
+}
+
+impl ValueEnum for ReportFormat {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Self::Markdown,
+            Self::Html,
+            #[cfg(feature = "xlsx")]
+            Self::Xlsx,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Markdown => Some(PossibleValue::new("markdown")),
+            Self::Html => Some(PossibleValue::new("html")),
+            #[cfg(feature = "xlsx")]
+            Self::Xlsx => Some(PossibleValue::new("xlsx")),
+        }
+    }
+}
+
+/// Columns (if present) from the results tables that are shown in the report
+const REPORT_COLUMNS: [&str; 10] = [
+    "Sample",
+    "Barcode",
+    "Library",
+    "File",
+    "Bisulfite-type",
+    "gc",
+    "ref-gc",
+    "KL-distance",
+    "Median-coverage",
+    "Fold_80_base_penalty",
+];
+
+/// Columns taken from JSON results, with the location of the values in the JSON records
+const JSON_COLUMNS: [(&str, &str); 16] = [
+    ("Sample", "/sample"),
+    ("Barcode", "/barcode"),
+    ("Library", "/library"),
+    ("File", "/file"),
+    ("Bisulfite-type", "/bisulfite_type"),
+    ("gc", "/gc"),
+    ("ref-gc", "/ref_gc"),
+    ("KL-distance", "/kl_distance"),
+    ("Median-coverage", "/kmer_coverage/median_coverage"),
+    (
+        "Fold_80_base_penalty",
+        "/kmer_coverage/fold_80_base_penalty",
+    ),
+    ("Flowcell", "/flowcell"),
+    ("Lane", "/lane"),
+    ("Index", "/index"),
+    ("Read-end", "/read_end"),
+    ("frac-C", "/base_fractions/C"),
+    ("frac-G", "/base_fractions/G"),
+];
+
+/// Columns written as text rather than numbers in xlsx output
+#[cfg(feature = "xlsx")]
+const TEXT_COLUMNS: [&str; 9] = [
+    "Sample",
+    "Barcode",
+    "Library",
+    "File",
+    "Bisulfite-type",
+    "Flowcell",
+    "Index",
+    "Key",
+    "Files",
+];
+
+/// Columns for which summary statistics are calculated
+const SUMMARY_COLUMNS: [&str; 4] = [
+    "gc",
+    "KL-distance",
+    "Median-coverage",
+    "Fold_80_base_penalty",
+];
+
+#[derive(Serialize)]
+struct Summary {
+    name: &'static str,
+    n: usize,
+    min: f64,
+    median: f64,
+    max: f64,
+}
+
+impl Summary {
+    fn make(name: &'static str, mut v: Vec<f64>) -> Option<Self> {
+        if v.is_empty() {
+            None
+        } else {
+            v.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+            let n = v.len();
+            Some(Self {
+                name,
+                n,
+                min: v[0],
+                median: v[n >> 1],
+                max: v[n - 1],
+            })
+        }
+    }
+}
+
+struct Report<'a> {
+    sources: &'a [PathBuf],
+    columns: Vec<&'static str>,
+    rows: Vec<Vec<&'a str>>,
+    summary: Vec<Summary>,
+    fli_duplicates: Vec<FliDuplicate>,
+}
+
+impl<'a> Report<'a> {
+    fn make(sources: &'a [PathBuf], tables: &'a [ResultsTable], fli_tol: f64) -> Self {
+        // Only report columns present in at least one table
+        let columns: Vec<_> = REPORT_COLUMNS
+            .iter()
+            .copied()
+            .filter(|c| tables.iter().any(|t| t.column(c).is_some()))
+            .collect();
+
+        let mut rows = Vec::new();
+        for t in tables {
+            let ix: Vec<_> = columns.iter().map(|c| t.column(c)).collect();
+            for r in t.rows() {
+                rows.push(
+                    ix.iter()
+                        .map(|i| i.map(|i| r[i].as_str()).unwrap_or("NA"))
+                        .collect(),
+                )
+            }
+        }
+
+        let summary = SUMMARY_COLUMNS
+            .iter()
+            .filter_map(|c| {
+                let v = tables
+                    .iter()
+                    .filter_map(|t| t.column(c).map(|i| (t, i)))
+                    .flat_map(|(t, i)| {
+                        t.rows()
+                            .iter()
+                            .filter_map(move |r| r[i].parse::<f64>().ok())
+                    })
+                    .filter(|x| x.is_finite())
+                    .collect();
+                Summary::make(c, v)
+            })
+            .collect();
+
+        Self {
+            sources,
+            columns,
+            rows,
+            summary,
+            fli_duplicates: find_fli_duplicates(tables, fli_tol),
+        }
+    }
+
+    fn write_markdown<W: Write>(&self, wrt: &mut W) -> anyhow::Result<()> {
+        let esc = |s: &str| s.replace('|', "\\|");
+
+        writeln!(wrt, "# gc_collect report\n")?;
+        writeln!(wrt, "Generated from:\n")?;
+        for p in self.sources {
+            writeln!(wrt, "- `{}`", p.display())?
+        }
+        writeln!(wrt, "\nNumber of datasets: {}\n", self.rows.len())?;
+
+        if !self.summary.is_empty() {
+            writeln!(wrt, "## Summary\n")?;
+            writeln!(wrt, "| Metric | N | Min | Median | Max |")?;
+            writeln!(wrt, "|---|---:|---:|---:|---:|")?;
+            for s in self.summary.iter() {
+                writeln!(
+                    wrt,
+                    "| {} | {} | {:.5} | {:.5} | {:.5} |",
+                    s.name, s.n, s.min, s.median, s.max
+                )?
+            }
+            writeln!(wrt)?
+        }
+
+        if !self.fli_duplicates.is_empty() {
+            writeln!(
+                wrt,
+                "## Flowcell/lane/index keys with differing gc profiles\n"
+            )?;
+            writeln!(wrt, "| Key | N | Max difference | Files |")?;
+            writeln!(wrt, "|---|---:|---:|---|")?;
+            for d in self.fli_duplicates.iter() {
+                writeln!(
+                    wrt,
+                    "| {} | {} | {:.5} | {} |",
+                    esc(&d.key()),
+                    d.n(),
+                    d.max_difference(),
+                    esc(&d.files().join(", "))
+                )?
+            }
+            writeln!(wrt)?
+        }
+
+        writeln!(wrt, "## Datasets\n")?;
+        writeln!(wrt, "| {} |", self.columns.join(" | "))?;
+        writeln!(wrt, "|{}", "---|".repeat(self.columns.len()))?;
+        for r in self.rows.iter() {
+            let v: Vec<_> = r.iter().map(|s| esc(s)).collect();
+            writeln!(wrt, "| {} |", v.join(" | "))?
+        }
+        Ok(())
+    }
+
+    fn write_html<W: Write>(&self, wrt: &mut W) -> anyhow::Result<()> {
+        writeln!(
+            wrt,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>gc_collect report</title>"
+        )?;
+        writeln!(wrt, "<style>table {{ border-collapse: collapse; }} td, th {{ border: 1px solid #999; padding: 2px 6px; }}</style>\n</head>\n<body>")?;
+        writeln!(
+            wrt,
+            "<h1>gc_collect report</h1>\n<p>Generated from:</p>\n<ul>"
+        )?;
+        for p in self.sources {
+            writeln!(
+                wrt,
+                "<li><code>{}</code></li>",
+                html_escape(&p.display().to_string())
+            )?
+        }
+        writeln!(wrt, "</ul>\n<p>Number of datasets: {}</p>", self.rows.len())?;
+
+        if !self.summary.is_empty() {
+            writeln!(wrt, "<h2>Summary</h2>\n<table>")?;
+            writeln!(
+                wrt,
+                "<tr><th>Metric</th><th>N</th><th>Min</th><th>Median</th><th>Max</th></tr>"
+            )?;
+            for s in self.summary.iter() {
+                writeln!(
+                    wrt,
+                    "<tr><td>{}</td><td>{}</td><td>{:.5}</td><td>{:.5}</td><td>{:.5}</td></tr>",
+                    s.name, s.n, s.min, s.median, s.max
+                )?
+            }
+            writeln!(wrt, "</table>")?
+        }
+
+        if !self.fli_duplicates.is_empty() {
+            writeln!(
+                wrt,
+                "<h2>Flowcell/lane/index keys with differing gc profiles</h2>\n<table>"
+            )?;
+            writeln!(
+                wrt,
+                "<tr><th>Key</th><th>N</th><th>Max difference</th><th>Files</th></tr>"
+            )?;
+            for d in self.fli_duplicates.iter() {
+                writeln!(
+                    wrt,
+                    "<tr><td>{}</td><td>{}</td><td>{:.5}</td><td>{}</td></tr>",
+                    html_escape(&d.key()),
+                    d.n(),
+                    d.max_difference(),
+                    html_escape(&d.files().join(", "))
+                )?
+            }
+            writeln!(wrt, "</table>")?
+        }
+
+        writeln!(wrt, "<h2>Datasets</h2>\n<table>\n<tr>")?;
+        for c in self.columns.iter() {
+            write!(wrt, "<th>{c}</th>")?
+        }
+        writeln!(wrt, "</tr>")?;
+        for r in self.rows.iter() {
+            write!(wrt, "<tr>")?;
+            for s in r.iter() {
+                write!(wrt, "<td>{}</td>", html_escape(s))?
+            }
+            writeln!(wrt, "</tr>")?
+        }
+        writeln!(wrt, "</table>\n</body>\n</html>")?;
+        Ok(())
+    }
+}
+
+impl<'a> Report<'a> {
+    /// Render report using a user supplied template directory.
+    ///
+    /// The directory must contain `report.html` or `report.md` (depending on the report format)
+    /// as a Tera template; other templates in the directory are available for
+    /// inheritance/includes.  The following variables are available to the templates:
+    /// `title`, `sources`, `n_datasets`, `summary` (list of objects with fields `name`, `n`, `min`,
+    /// `median` and `max`), `fli_duplicates` (list of objects with fields `flowcell`, `lane`,
+    /// `index`, `read_end`, `n`, `max_difference` and `files`), `columns`, `rows` and, if an
+    /// image file `logo.{png,svg,jpg}`
+    /// is present in the directory, `logo` as a data URI that can be used in an
+    /// `<img>` element (as `{{ logo | safe }}` to avoid escaping).
+    fn write_template<W: Write>(
+        &self,
+        dir: &Path,
+        fmt: ReportFormat,
+        wrt: &mut W,
+    ) -> anyhow::Result<()> {
+        let name = match fmt {
+            ReportFormat::Markdown => "report.md",
+            ReportFormat::Html => "report.html",
+            #[cfg(feature = "xlsx")]
+            ReportFormat::Xlsx => {
+                return Err(anyhow!("Templates can not be used for xlsx reports"))
+            }
+        };
+        let pattern = dir.join("**").join("*");
+        let pattern = pattern
+            .to_str()
+            .ok_or_else(|| anyhow!("Template directory path is not valid UTF-8"))?;
+        let tera = Tera::new(pattern)
+            .with_context(|| format!("Error reading report templates from {}", dir.display()))?;
+        if !tera.get_template_names().any(|s| s == name) {
+            return Err(anyhow!(
+                "Template {name} not found in template directory {}",
+                dir.display()
+            ));
+        }
+
+        let sources: Vec<_> = self
+            .sources
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+
+        let mut ctx = tera::Context::new();
+        ctx.insert("title", "gc_collect report");
+        ctx.insert("sources", &sources);
+        ctx.insert("n_datasets", &self.rows.len());
+        ctx.insert("summary", &self.summary);
+        ctx.insert("fli_duplicates", &self.fli_duplicates);
+        ctx.insert("columns", &self.columns);
+        ctx.insert("rows", &self.rows);
+        if let Some(logo) = find_logo(dir)? {
+            ctx.insert("logo", &logo)
+        }
+
+        tera.render_to(name, &ctx, wrt)
+            .with_context(|| format!("Error rendering template {name}"))
+    }
+}
+
+fn find_logo(dir: &Path) -> anyhow::Result<Option<String>> {
+    for (ext, mime) in [
+        ("png", "image/png"),
+        ("svg", "image/svg+xml"),
+        ("jpg", "image/jpeg"),
+    ] {
+        let p = dir.join(format!("logo.{ext}"));
+        if p.is_file() {
+            let buf = std::fs::read(&p)
+                .with_context(|| format!("Error reading logo file {}", p.display()))?;
+            return Ok(Some(format!("data:{mime};base64,{}", base64_encode(&buf))));
+        }
+    }
+    Ok(None)
+}
+
+pub fn html_escape(s: &str) -> String {
+    let mut t = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => t.push_str("&amp;"),
+            '<' => t.push_str("&lt;"),
+            '>' => t.push_str("&gt;"),
+            '"' => t.push_str("&quot;"),
+            _ => t.push(c),
+        }
+    }
+    t
+}
+
+// Results table from the JSON results in p (an array of records as written with -F json, or a
+// single record).  Only columns with a value in at least one record are kept
+fn read_json_table(p: &Path) -> anyhow::Result<ResultsTable> {
+    let rdr = CompressIo::new()
+        .path(p)
+        .bufreader()
+        .with_context(|| format!("Could not open {} for input", p.display()))?;
+    let v: Value = serde_json::from_reader(rdr)
+        .with_context(|| format!("Error parsing JSON from {}", p.display()))?;
+    let recs = match v {
+        Value::Array(v) => v,
+        v @ Value::Object(_) => vec![v],
+        _ => return Err(anyhow!("No JSON results found in {}", p.display())),
+    };
+    let columns: Vec<_> = JSON_COLUMNS
+        .iter()
+        .filter(|(_, ptr)| {
+            recs.iter()
+                .any(|r| r.pointer(ptr).is_some_and(|x| !x.is_null()))
+        })
+        .collect();
+    let rows = recs
+        .iter()
+        .map(|r| {
+            columns
+                .iter()
+                .map(|(_, ptr)| match r.pointer(ptr) {
+                    Some(Value::String(s)) => s.to_owned(),
+                    None | Some(Value::Null) => "NA".to_owned(),
+                    Some(x) => x.to_string(),
+                })
+                .collect()
+        })
+        .collect();
+    let header = columns.iter().map(|(c, _)| c.to_string()).collect();
+    Ok(ResultsTable::new(header, rows))
+}
+
+// Read the report inputs, which can be results tables (TSV or JSON) or auxiliary files.  The
+// summary metrics for the auxiliary files are recalculated as for the rescore subcommand, with
+// a base_dist file standing for the gc_hist file written alongside it
+fn read_tables(inputs: &[PathBuf]) -> anyhow::Result<Vec<ResultsTable>> {
+    let mut tables = Vec::new();
+    let mut aux = Vec::new();
+    for p in inputs {
+        let name = p
+            .file_name()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default();
+        if name.contains("gc_hist.tsv") {
+            aux.push(p.to_owned())
+        } else if let Some(h) = gc_hist_path(p) {
+            aux.push(h)
+        } else if matches!(first_char(p)?, Some(b'[' | b'{')) {
+            tables.push(read_json_table(p)?)
+        } else {
+            tables.push(
+                ResultsTable::read(p)
+                    .with_context(|| format!("Error reading results table {}", p.display()))?,
+            )
+        }
+    }
+    let mut seen = HashSet::new();
+    aux.retain(|p| seen.insert(p.clone()));
+    if !aux.is_empty() {
+        debug!("Recalculating metrics from {} gc_hist file(s)", aux.len());
+        tables.push(rescore_table(&RescoreConfig::for_aux_files(aux))?)
+    }
+    Ok(tables)
+}
+
+// Add sheet with the given name, columns and rows to workbook wb.  Values that parse as
+// numbers are written as numbers unless they are in TEXT_COLUMNS
+#[cfg(feature = "xlsx")]
+fn xlsx_sheet(
+    wb: &mut rust_xlsxwriter::Workbook,
+    name: &str,
+    columns: &[&str],
+    rows: &[Vec<String>],
+) -> anyhow::Result<()> {
+    let bold = rust_xlsxwriter::Format::new().set_bold();
+    let ws = wb.add_worksheet();
+    ws.set_name(name)?;
+    for (j, c) in columns.iter().enumerate() {
+        ws.write_string_with_format(0, j as u16, *c, &bold)?;
+    }
+    let text: Vec<_> = columns.iter().map(|c| TEXT_COLUMNS.contains(c)).collect();
+    for (i, r) in rows.iter().enumerate() {
+        let i = i as u32 + 1;
+        for (j, s) in r.iter().enumerate() {
+            match s.parse::<f64>() {
+                Ok(x) if x.is_finite() && !text[j] => ws.write_number(i, j as u16, x)?,
+                _ => ws.write_string(i, j as u16, s)?,
+            };
+        }
+    }
+    ws.set_freeze_panes(1, 0)?;
+    ws.autofit();
+    Ok(())
+}
+
+#[cfg(feature = "xlsx")]
+impl Report<'_> {
+    /// Write report as an xlsx workbook to p, with the datasets, summary statistics, any
+    /// flowcell/lane/index keys with differing gc profiles and the input files on separate
+    /// sheets
+    fn write_xlsx(&self, p: &Path) -> anyhow::Result<()> {
+        let mut wb = rust_xlsxwriter::Workbook::new();
+        let rows: Vec<Vec<_>> = self
+            .rows
+            .iter()
+            .map(|r| r.iter().map(|s| s.to_string()).collect())
+            .collect();
+        xlsx_sheet(&mut wb, "Datasets", &self.columns, &rows)?;
+        if !self.summary.is_empty() {
+            let rows: Vec<_> = self
+                .summary
+                .iter()
+                .map(|s| {
+                    vec![
+                        s.name.to_owned(),
+                        s.n.to_string(),
+                        s.min.to_string(),
+                        s.median.to_string(),
+                        s.max.to_string(),
+                    ]
+                })
+                .collect();
+            xlsx_sheet(
+                &mut wb,
+                "Summary",
+                &["Metric", "N", "Min", "Median", "Max"],
+                &rows,
+            )?
+        }
+        if !self.fli_duplicates.is_empty() {
+            let rows: Vec<_> = self
+                .fli_duplicates
+                .iter()
+                .map(|d| {
+                    vec![
+                        d.key(),
+                        d.n().to_string(),
+                        d.max_difference().to_string(),
+                        d.files().join(", "),
+                    ]
+                })
+                .collect();
+            xlsx_sheet(
+                &mut wb,
+                "FLI duplicates",
+                &["Key", "N", "Max difference", "Files"],
+                &rows,
+            )?
+        }
+        let rows: Vec<_> = self
+            .sources
+            .iter()
+            .map(|p| vec![p.display().to_string()])
+            .collect();
+        xlsx_sheet(&mut wb, "Sources", &["File"], &rows)?;
+        wb.save(p)
+            .with_context(|| format!("Error writing report to {}", p.display()))
+    }
+}
+
+fn open_output(p: Option<&Path>) -> anyhow::Result<impl Write> {
+    CompressIo::new()
+        .opt_path(p)
+        .bufwriter()
+        .with_context(|| "Could not open report output file")
+}
+
+/// Regenerate a report from previously generated results tables and auxiliary files
+pub fn report(cfg: &ReportConfig) -> anyhow::Result<()> {
+    debug!(
+        "Generating report from {} input file(s)",
+        cfg.input_files().len()
+    );
+    let tables = read_tables(cfg.input_files())?;
+    let rep = Report::make(cfg.input_files(), &tables, cfg.fli_gc_tolerance());
+
+    let wrt = || open_output(cfg.output_file());
+    match (cfg.template_dir(), cfg.format()) {
+        (Some(dir), fmt) => rep.write_template(dir, fmt, &mut wrt()?),
+        (None, ReportFormat::Markdown) => rep.write_markdown(&mut wrt()?),
+        (None, ReportFormat::Html) => rep.write_html(&mut wrt()?),
+        #[cfg(feature = "xlsx")]
+        (None, ReportFormat::Xlsx) => rep.write_xlsx(
+            cfg.output_file()
+                .ok_or_else(|| anyhow!("An output file (-o) is needed for an xlsx report"))?,
+        ),
+    }
+}
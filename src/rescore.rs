@@ -0,0 +1,324 @@
+//! Recalculation of summary metrics from the gc_hist (and base_dist) auxiliary files written by
+//! a previous run (rescore subcommand), so that datasets can be compared against a new
+//! reference or with new divergence measures after the original JSON files are gone.
+//!
+//! The gc densities in the gc_hist files are already smoothed and discretized into
+//! GC_HIST_BINS bins, so the divergences here are calculated on the binned densities rather
+//! than by quadrature over the beta mixtures as in the main pipeline.  The values are therefore
+//! close to, but not identical with, those from the original run.  The reference is either the
+//! Reference column of the gc_hist file or, if a new reference is given, the reference
+//! distribution for the read length of the dataset.  The read length is taken from the number of
+//! cycles in the matching base_dist file unless given on the command line.
+//!
+//! The gc and ref-gc columns are as in the main output, with gc found from the mean base
+//! composition over cycles in the base_dist file and ref-gc only available with a new
+//! reference.  The hist-gc and ref-hist-gc columns give the means of the smoothed densities,
+//! which are pulled slightly towards 0.5 by the smoothing.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+
+use crate::{
+    betabin::{mean_gc, Divergence},
+    cli::RescoreConfig,
+    gc_density::{GcDensity, GC_HIST_BINS},
+    reference::ReferenceProvider,
+    results_table::ResultsTable,
+    utils::tsv_path,
+};
+
+/// Base composition columns taken from the base_dist files
+const BASE_COLUMNS: [&str; 4] = ["A", "C", "G", "T"];
+
+// Path of the base_dist file written alongside gc_hist file p (if p looks like a gc_hist file)
+fn base_dist_path(p: &Path) -> Option<PathBuf> {
+    let name = p.file_name()?.to_str()?;
+    let ix = name.rfind("gc_hist.tsv")?;
+    Some(p.with_file_name(format!(
+        "{}base_dist.tsv{}",
+        &name[..ix],
+        &name[ix + "gc_hist.tsv".len()..]
+    )))
+}
+
+/// Path of the gc_hist file written alongside base_dist file p (if p looks like a base_dist
+/// file)
+pub(crate) fn gc_hist_path(p: &Path) -> Option<PathBuf> {
+    let name = p.file_name()?.to_str()?;
+    let ix = name.rfind("base_dist.tsv")?;
+    Some(p.with_file_name(format!(
+        "{}gc_hist.tsv{}",
+        &name[..ix],
+        &name[ix + "base_dist.tsv".len()..]
+    )))
+}
+
+// Parse column c of table t as f64 values
+fn parse_column(t: &ResultsTable, c: &str, p: &Path) -> anyhow::Result<Option<Vec<f64>>> {
+    let Some(ix) = t.column(c) else {
+        return Ok(None);
+    };
+    t.rows()
+        .iter()
+        .map(|r| {
+            r[ix]
+                .parse::<f64>()
+                .with_context(|| format!("{}: bad value {} in column {c}", p.display(), r[ix]))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map(Some)
+}
+
+/// Binned gc densities of a dataset and (optionally) its reference
+struct GcHist {
+    sample: Vec<f64>,
+    reference: Option<Vec<f64>>,
+}
+
+impl GcHist {
+    fn read(p: &Path) -> anyhow::Result<Self> {
+        let t = ResultsTable::read(p)?;
+        if t.rows().len() != GC_HIST_BINS {
+            return Err(anyhow!(
+                "{} has {} gc bins (expected {GC_HIST_BINS})",
+                p.display(),
+                t.rows().len()
+            ));
+        }
+        let sample = parse_column(&t, "Sample", p)?
+            .ok_or_else(|| anyhow!("No Sample column in {}", p.display()))?;
+        let reference = parse_column(&t, "Reference", p)?;
+        Ok(Self { sample, reference })
+    }
+}
+
+// Normalize binned densities so that they integrate to 1.  Returns None if the total is zero
+fn normalize(v: &[f64]) -> Option<Vec<f64>> {
+    let w = 1.0 / v.len() as f64;
+    let t: f64 = v.iter().map(|x| x.max(0.0) * w).sum();
+    (t > 0.0).then(|| v.iter().map(|x| x.max(0.0) / t).collect())
+}
+
+// Mean of a normalized binned density
+fn binned_mean(p: &[f64]) -> f64 {
+    let w = 1.0 / p.len() as f64;
+    p.iter()
+        .enumerate()
+        .map(|(i, x)| w * w * (0.5 + i as f64) * x)
+        .sum()
+}
+
+fn kl_term(p: f64, q: f64) -> f64 {
+    if p > 0.0 {
+        p * (p / q).ln()
+    } else {
+        0.0
+    }
+}
+
+// Divergence of binned density p from q (both normalized, with the same number of bins)
+fn binned_divergence(div: Divergence, p: &[f64], q: &[f64]) -> f64 {
+    let w = 1.0 / p.len() as f64;
+    let wasserstein = || {
+        p.iter()
+            .zip(q)
+            .fold((0.0, 0.0), |(s, d), (x, y)| {
+                let d = d + w * (x - y);
+                (s + w * d.abs(), d)
+            })
+            .0
+    };
+    let pairs = p.iter().zip(q);
+    match div {
+        Divergence::Kl => pairs.map(|(x, y)| w * kl_term(*x, *y)).sum(),
+        Divergence::Js => pairs
+            .map(|(x, y)| {
+                let m = 0.5 * (x + y);
+                0.5 * w * (kl_term(*x, m) + kl_term(*y, m))
+            })
+            .sum(),
+        Divergence::Wasserstein => wasserstein(),
+        Divergence::Hellinger => {
+            let bc: f64 = pairs.map(|(x, y)| w * (x * y).sqrt()).sum();
+            (1.0 - bc).max(0.0).sqrt()
+        }
+        Divergence::Emd => 100.0 * wasserstein(),
+    }
+}
+
+// Number of cycles and mean base composition over cycles from base_dist file p
+fn base_summary(p: &Path) -> anyhow::Result<(u32, Vec<f64>)> {
+    let t = ResultsTable::read(p)?;
+    let cycles = parse_column(&t, "Cycle", p)?
+        .ok_or_else(|| anyhow!("No Cycle column in {}", p.display()))?
+        .into_iter()
+        .fold(0.0, f64::max) as u32;
+    let n = t.rows().len().max(1) as f64;
+    let mut fracs = Vec::with_capacity(BASE_COLUMNS.len());
+    for c in BASE_COLUMNS {
+        let v =
+            parse_column(&t, c, p)?.ok_or_else(|| anyhow!("No {c} column in {}", p.display()))?;
+        fracs.push(v.iter().sum::<f64>() / n)
+    }
+    Ok((cycles, fracs))
+}
+
+fn opt_col(x: Option<f64>) -> String {
+    match x {
+        Some(x) => format!("\t{x:.6}"),
+        None => "\tNA".to_owned(),
+    }
+}
+
+// Output line for gc_hist file p
+fn rescore_file(cfg: &RescoreConfig, p: &Path) -> anyhow::Result<String> {
+    let hist = GcHist::read(p)?;
+    let base = match base_dist_path(p).filter(|b| b.exists()) {
+        Some(b) => Some(base_summary(&b)?),
+        None => {
+            debug!("No base_dist file found for {}", p.display());
+            None
+        }
+    };
+
+    let (reference, ref_gc) = match cfg.reference() {
+        Some(r) => {
+            let rl = cfg
+                .read_length()
+                .or(base.as_ref().map(|(c, _)| *c))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Read length for {} unknown: no base_dist file found (use --read-length)",
+                        p.display()
+                    )
+                })?;
+            let (rl1, counts) = r.get_closest_reference(rl);
+            trace!("Using reference length {rl1} for {}", p.display());
+            let cts = if cfg.bisulfite() {
+                counts.bisulfite().ok_or_else(|| {
+                    anyhow!("Reference has no bisulfite distribution for read length {rl1}")
+                })?
+            } else {
+                counts.regular()
+            };
+            let h: Vec<_> = GcDensity::new(cts)
+                .histogram(GC_HIST_BINS)
+                .into_iter()
+                .map(|(_, y)| y)
+                .collect();
+            (Some(h), Some(mean_gc(cts)))
+        }
+        None => (hist.reference, None),
+    };
+
+    let sample = normalize(&hist.sample)
+        .ok_or_else(|| anyhow!("Empty gc distribution in {}", p.display()))?;
+    let reference = reference.as_deref().and_then(normalize);
+    let divergence = |d| reference.as_ref().map(|q| binned_divergence(d, &sample, q));
+
+    // gc from the base composition (C + G) as in the main output
+    let gc = base.as_ref().map(|(_, f)| f[1] + f[2]);
+    let mut s = format!(
+        "{}{}{}\t{:.6}{}",
+        tsv_path(p),
+        opt_col(gc),
+        opt_col(ref_gc),
+        binned_mean(&sample),
+        opt_col(reference.as_deref().map(binned_mean))
+    );
+    s.push_str(&opt_col(divergence(Divergence::Kl)));
+    for d in cfg.divergences() {
+        s.push_str(&opt_col(divergence(*d)))
+    }
+    match base {
+        Some((cycles, fracs)) => {
+            s.push_str(&format!("\t{cycles}"));
+            for x in fracs {
+                s.push_str(&format!("\t{x:.5}"))
+            }
+        }
+        None => s.push_str(&"\tNA".repeat(BASE_COLUMNS.len() + 1)),
+    }
+    Ok(s)
+}
+
+fn header(cfg: &RescoreConfig) -> Vec<String> {
+    let mut v: Vec<String> = [
+        "File",
+        "gc",
+        "ref-gc",
+        "hist-gc",
+        "ref-hist-gc",
+        "KL-distance",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    v.extend(cfg.divergences().iter().map(|d| d.column().to_string()));
+    v.push("Cycles".to_owned());
+    v.extend(BASE_COLUMNS.iter().map(|c| format!("frac-{c}")));
+    v
+}
+
+fn rescore_input(cfg: &RescoreConfig, p: &Path) -> anyhow::Result<String> {
+    rescore_file(cfg, p).with_context(|| format!("Error rescoring {}", p.display()))
+}
+
+/// Recalculated summary metrics from gc_hist files as a results table (one row per input file)
+pub(crate) fn rescore_table(cfg: &RescoreConfig) -> anyhow::Result<ResultsTable> {
+    let rows = cfg
+        .input_files()
+        .iter()
+        .map(|p| rescore_input(cfg, p).map(|s| s.split('\t').map(|x| x.to_owned()).collect()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(ResultsTable::new(header(cfg), rows))
+}
+
+/// Recalculate summary metrics from gc_hist files, writing one line per input file
+pub fn rescore(cfg: &RescoreConfig) -> anyhow::Result<()> {
+    let mut wrt = CompressIo::new()
+        .opt_path(cfg.output_file())
+        .bufwriter()
+        .with_context(|| "Could not open output file")?;
+
+    writeln!(wrt, "{}", header(cfg).join("\t"))?;
+    for p in cfg.input_files() {
+        writeln!(wrt, "{}", rescore_input(cfg, p)?)?
+    }
+    wrt.flush().with_context(|| "Error writing output file")?;
+    Ok(())
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn base_dist_path_test() {
+        assert_eq!(
+            base_dist_path(Path::new("out/S1.gc_hist.tsv.gz")),
+            Some(PathBuf::from("out/S1.base_dist.tsv.gz"))
+        );
+        assert_eq!(base_dist_path(Path::new("S1.tsv")), None);
+        assert_eq!(
+            gc_hist_path(Path::new("out/S1.base_dist.tsv")),
+            Some(PathBuf::from("out/S1.gc_hist.tsv"))
+        );
+    }
+
+    #[test]
+    fn binned_divergence_test() {
+        let p = normalize(&[1.0, 1.0, 0.0, 0.0]).unwrap();
+        let q = normalize(&[0.0, 0.0, 1.0, 1.0]).unwrap();
+        assert!((binned_mean(&p) - 0.25).abs() < 1e-12);
+        assert!((binned_divergence(Divergence::Wasserstein, &p, &q) - 0.5).abs() < 1e-12);
+        assert!((binned_divergence(Divergence::Hellinger, &p, &q) - 1.0).abs() < 1e-12);
+        assert!((binned_divergence(Divergence::Js, &p, &q) - std::f64::consts::LN_2).abs() < 1e-12);
+        assert_eq!(binned_divergence(Divergence::Kl, &p, &p), 0.0);
+    }
+}
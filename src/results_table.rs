@@ -0,0 +1,88 @@
+use std::{io::BufRead, path::Path};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+
+/// Results table as previously written by the output thread
+///
+/// Lines starting with '#' are treated as comments and skipped.  The first
+/// non-comment line is taken as the header.
+pub struct ResultsTable {
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl ResultsTable {
+    pub fn new(header: Vec<String>, rows: Vec<Vec<String>>) -> Self {
+        Self { header, rows }
+    }
+
+    pub fn read<P: AsRef<Path>>(p: P) -> anyhow::Result<Self> {
+        let p = p.as_ref();
+        let rdr = CompressIo::new()
+            .path(p)
+            .bufreader()
+            .with_context(|| format!("Could not open {} for input", p.display()))?;
+
+        let mut header: Option<Vec<String>> = None;
+        let mut rows = Vec::new();
+        for (ix, l) in rdr.lines().enumerate() {
+            let l = l.with_context(|| format!("Error reading from {}", p.display()))?;
+            if l.starts_with('#') || l.is_empty() {
+                continue;
+            }
+            let fields: Vec<String> = l.split('\t').map(|s| s.to_owned()).collect();
+            match header.as_ref() {
+                Some(h) => {
+                    if fields.len() != h.len() {
+                        return Err(anyhow!(
+                            "{}:{} Expected {} columns, found {}",
+                            p.display(),
+                            ix + 1,
+                            h.len(),
+                            fields.len()
+                        ));
+                    }
+                    rows.push(fields)
+                }
+                None => header = Some(fields),
+            }
+        }
+        let header = header.ok_or_else(|| anyhow!("No header found in {}", p.display()))?;
+        Ok(Self { header, rows })
+    }
+
+    pub fn header(&self) -> &[String] {
+        &self.header
+    }
+
+    pub fn rows(&self) -> &[Vec<String>] {
+        &self.rows
+    }
+
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.header.iter().position(|s| s == name)
+    }
+}
+
+/// First non-whitespace character of file p (None if the file is empty), used to distinguish
+/// JSON from TSV results
+pub(crate) fn first_char(p: &Path) -> anyhow::Result<Option<u8>> {
+    let mut rdr = CompressIo::new()
+        .path(p)
+        .bufreader()
+        .with_context(|| format!("Could not open {} for input", p.display()))?;
+    loop {
+        let buf = rdr
+            .fill_buf()
+            .with_context(|| format!("Error reading from {}", p.display()))?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        if let Some(c) = buf.iter().find(|c| !c.is_ascii_whitespace()) {
+            return Ok(Some(*c));
+        }
+        let n = buf.len();
+        rdr.consume(n)
+    }
+}
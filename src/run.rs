@@ -0,0 +1,457 @@
+//! Pipelines for the main analysis modes, run from the command line by the gc_collect binary
+
+use std::path::PathBuf;
+#[cfg(feature = "watch")]
+use std::time::Instant;
+
+use crossbeam_channel::bounded;
+#[cfg(feature = "watch")]
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError};
+use crossbeam_utils::thread::{self, ScopedJoinHandle};
+
+use crate::{
+    build_ref,
+    cli::{self, Config, Task},
+    concat, contrast,
+    merge::{merge_stream, merge_thread},
+    output::{output_thread, results_channel},
+    panel_compare,
+    process::{analyze_thread, process_thread, stream_thread},
+    report, rescore,
+    self_reference::ReferencePool,
+    selftest, simulate,
+    status::RunStatus,
+    validate,
+};
+
+#[cfg(unix)]
+use crate::stream;
+#[cfg(feature = "watch")]
+use crate::{cli::WatchConfig, merge::IncrementalMerge, output::check_appended_output, watch};
+
+/// File (in the output directory) listing inputs skipped with --skip-errors
+const FAILED_INPUTS_FILE: &str = "failed_inputs.tsv";
+
+/// Exit code when the run completed but some input files were skipped
+const EXIT_FAILED_INPUTS: i32 = 2;
+
+fn check_join(j: ScopedJoinHandle<anyhow::Result<()>>, s: &str, status: &RunStatus) -> bool {
+    if let Err(e) = j
+        .join()
+        .unwrap_or_else(|_| panic!("Error joining {s} thread"))
+    {
+        error!("{:?}", e);
+        status.add_error(&e);
+        true
+    } else {
+        false
+    }
+}
+fn merge_pipeline(cfg: &Config, files: &[PathBuf], status: &RunStatus) -> bool {
+    let nt = cfg.threads();
+    trace!("Running merge pipeline with {nt} threads");
+
+    let mut error = false;
+
+    thread::scope(|scope| {
+        // Channel used to send files to read and merge thread
+        let (sd, rx) = bounded(2);
+
+        // Channel to send merged datasets for analysis
+        let (sd_data, rx_data) = bounded(nt * 2);
+
+        // Channel used to send results to output thread
+        let (sd_res, rc_res) = results_channel(nt);
+
+        // Start output thread
+        let output_task = scope.spawn(move |_| output_thread(cfg, rc_res, status));
+
+        // Add merge thread
+        let merge_task = scope.spawn(move |_| merge_thread(cfg, rx, sd_data, status));
+
+        let mut process_tasks = Vec::with_capacity(nt);
+        for ix in 0..nt {
+            let rx1 = rx_data.clone();
+            let sd_res1 = sd_res.clone();
+            process_tasks.push(scope.spawn(move |_| analyze_thread(cfg, ix, rx1, sd_res1)));
+        }
+
+        drop(rx_data);
+        drop(sd_res);
+
+        for (ix, p) in files.iter().enumerate() {
+            sd.send((ix, p.as_path()))
+                .expect("Error sending input file to merge thread")
+        }
+        drop(sd);
+        // Wait for merge thread
+        error = check_join(merge_task, "merge thread", status);
+        // ... and process threads
+        for jh in process_tasks.drain(..) {
+            error |= check_join(jh, "process thread", status)
+        }
+        // ...and output thread
+        error |= check_join(output_task, "output thread", status)
+    })
+    .expect("Error in scope generation");
+
+    error
+}
+
+fn std_pipeline(cfg: &Config, status: &RunStatus) -> bool {
+    let nt = cfg.threads();
+    trace!("Running standard pipeline with {nt} threads");
+    let mut error = false;
+
+    thread::scope(|scope| {
+        // Channel used to send files to process threads
+        let (sd, rx) = bounded(nt * 2);
+
+        // Channel used to send results to output thread
+        let (sd_res, rc_res) = results_channel(nt);
+
+        // Start output thread
+        let output_task = scope.spawn(move |_| output_thread(cfg, rc_res, status));
+
+        let mut process_tasks = Vec::with_capacity(nt);
+        for ix in 0..nt {
+            let rx1 = rx.clone();
+            let sd_res1 = sd_res.clone();
+            process_tasks.push(scope.spawn(move |_| process_thread(cfg, ix, rx1, sd_res1, status)));
+        }
+
+        drop(rx);
+        drop(sd_res);
+
+        for (ix, p) in cfg.input_files().iter().enumerate() {
+            sd.send((ix, p.as_path()))
+                .expect("Error sending input file to process threads")
+        }
+        drop(sd);
+        // Wait for process threads
+        for jh in process_tasks.drain(..) {
+            error |= check_join(jh, "process thread", status)
+        }
+        // ...and output thread
+        error |= check_join(output_task, "output thread", status)
+    })
+    .expect("Error in scope generation");
+
+    error
+}
+
+// Datasets received on the stream socket are analyzed as they arrive or, if merging, merged as
+// they arrive and analyzed when the stream ends
+#[cfg(unix)]
+fn stream_pipeline(cfg: &Config, status: &RunStatus) -> bool {
+    let nt = cfg.threads();
+    trace!("Running stream pipeline with {nt} threads");
+    let mut error = false;
+
+    thread::scope(|scope| {
+        // Channel used to send received datasets to the process or merge threads
+        let (sd, rx) = bounded(nt * 2);
+
+        // Channel used to send results to output thread
+        let (sd_res, rc_res) = results_channel(nt);
+
+        // Start output thread
+        let output_task = scope.spawn(move |_| output_thread(cfg, rc_res, status));
+
+        let mut tasks = Vec::with_capacity(nt + 1);
+        if let Some(p) = cfg.stream_socket().filter(|_| cfg.merge_key().is_some()) {
+            // Channel to send merged datasets for analysis
+            let (sd_data, rx_data) = bounded(nt * 2);
+            let rx1 = rx.clone();
+            tasks.push((
+                "merge thread",
+                scope.spawn(move |_| merge_stream(cfg, rx1, sd_data, p)),
+            ));
+            for ix in 0..nt {
+                let (rx1, sd_res1) = (rx_data.clone(), sd_res.clone());
+                tasks.push((
+                    "process thread",
+                    scope.spawn(move |_| analyze_thread(cfg, ix, rx1, sd_res1)),
+                ));
+            }
+        } else {
+            for ix in 0..nt {
+                let (rx1, sd_res1) = (rx.clone(), sd_res.clone());
+                tasks.push((
+                    "process thread",
+                    scope.spawn(move |_| stream_thread(cfg, ix, rx1, sd_res1, status)),
+                ));
+            }
+        }
+
+        drop(rx);
+        drop(sd_res);
+
+        // Datasets are received in this thread until the stream ends
+        if let Err(e) = stream::receive(cfg, sd, status) {
+            error!("{:?}", e);
+            status.add_error(&e);
+            error = true
+        }
+        // Wait for merge and process threads
+        for (s, jh) in tasks.drain(..) {
+            error |= check_join(jh, s, status)
+        }
+        // ...and output thread
+        error |= check_join(output_task, "output thread", status)
+    })
+    .expect("Error in scope generation");
+
+    error
+}
+
+// Analyze the datasets merged so far in watch mode, writing the merged output
+#[cfg(feature = "watch")]
+fn write_merged_output(cfg: &Config, state: &mut IncrementalMerge, status: &RunStatus) -> bool {
+    let nt = cfg.threads();
+    let mut error = false;
+
+    thread::scope(|scope| {
+        // Channel to send merged datasets for analysis
+        let (sd_data, rx_data) = bounded(nt * 2);
+
+        // Channel used to send results to output thread
+        let (sd_res, rc_res) = results_channel(nt);
+
+        // Start output thread
+        let output_task = scope.spawn(move |_| output_thread(cfg, rc_res, status));
+
+        let mut process_tasks = Vec::with_capacity(nt);
+        for ix in 0..nt {
+            let rx1 = rx_data.clone();
+            let sd_res1 = sd_res.clone();
+            process_tasks.push(scope.spawn(move |_| analyze_thread(cfg, ix, rx1, sd_res1)));
+        }
+
+        drop(rx_data);
+        drop(sd_res);
+
+        if let Err(e) = state.send(cfg, &sd_data) {
+            error!("{:?}", e);
+            error = true
+        }
+        drop(sd_data);
+        // Wait for process threads
+        for jh in process_tasks.drain(..) {
+            error |= check_join(jh, "process thread", status)
+        }
+        // ...and output thread
+        error |= check_join(output_task, "output thread", status)
+    })
+    .expect("Error in scope generation");
+
+    error
+}
+
+// Merge input files as they arrive in watch mode, writing the merged output at most once per
+// merge interval when new files have been merged.  Errors are reported, but watching continues.
+// The run status of the merged output is kept separate from that of the individual datasets so
+// that failures are not counted again each time the merged output is written
+#[cfg(feature = "watch")]
+fn watch_merge_thread(
+    cfg: &Config,
+    wcfg: &WatchConfig,
+    rx: Receiver<(usize, PathBuf)>,
+) -> anyhow::Result<()> {
+    let status = RunStatus::default();
+    let mut state = IncrementalMerge::new(cfg);
+    let (mut last_merge, mut pending) = (Instant::now(), false);
+    loop {
+        match rx.recv_timeout(wcfg.merge_interval()) {
+            Ok((ix, p)) => {
+                // The merge state keeps its own copies of the input file paths
+                match state.add(cfg, ix, p.clone().into(), &status) {
+                    Ok(_) => pending = true,
+                    Err(e) => warn!("Error merging {}: {e:#}", p.display()),
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if pending && last_merge.elapsed() >= wcfg.merge_interval() {
+            debug!("Writing merged output");
+            if write_merged_output(cfg, &mut state, &status) {
+                warn!("Error writing merged output")
+            }
+            (last_merge, pending) = (Instant::now(), false)
+        }
+    }
+    Ok(())
+}
+
+// Input files are analyzed as they appear in the watched directory, and are also merged (if
+// merged output is requested) in a separate thread.  The cache index and status files are
+// written at each checkpoint
+#[cfg(feature = "watch")]
+fn watch_pipeline(cfg: &Config, wcfg: &WatchConfig, status: &RunStatus) -> bool {
+    let nt = cfg.threads();
+    trace!("Running watch pipeline with {nt} threads");
+    // Checked here as otherwise this would only be noticed when the first file arrives
+    if let Err(e) = check_appended_output(cfg) {
+        error!("{:?}", e);
+        status.add_error(&e);
+        return true;
+    }
+    let mut error = false;
+
+    thread::scope(|scope| {
+        // Channel used to send files to process threads
+        let (sd, rx) = bounded(nt * 2);
+
+        // Channel used to send results to output thread
+        let (sd_res, rc_res) = results_channel(nt);
+
+        // Start output thread
+        let output_task = scope.spawn(move |_| output_thread(cfg, rc_res, status));
+
+        let mut tasks = Vec::with_capacity(nt + 1);
+        for ix in 0..nt {
+            let rx1 = rx.clone();
+            let sd_res1 = sd_res.clone();
+            tasks.push((
+                "process thread",
+                scope.spawn(move |_| process_thread(cfg, ix, rx1, sd_res1, status)),
+            ));
+        }
+
+        drop(rx);
+        drop(sd_res);
+
+        // Files are sent unbounded to the merge thread so that the watcher does not wait while
+        // the merged output is written
+        let mut sds = vec![sd];
+        if let Some(mcfg) = wcfg.merged() {
+            let (sd_merge, rx_merge) = unbounded();
+            sds.push(sd_merge);
+            tasks.push((
+                "merge thread",
+                scope.spawn(move |_| watch_merge_thread(mcfg, wcfg, rx_merge)),
+            ));
+        }
+
+        let checkpoint = || {
+            if let Err(e) = cfg
+                .cache()
+                .map_or(Ok(()), |c| c.write_index())
+                .and_then(|_| write_run_status(cfg, status, false).map(|_| ()))
+            {
+                warn!("Error writing checkpoint: {e:#}")
+            }
+        };
+
+        // Files are watched for in this thread until an error occurs
+        if let Err(e) = watch::watch(wcfg, sds, checkpoint) {
+            error!("{:?}", e);
+            status.add_error(&e);
+            error = true
+        }
+        // Wait for process and merge threads
+        for (s, jh) in tasks.drain(..) {
+            error |= check_join(jh, s, status)
+        }
+        // ...and output thread
+        error |= check_join(output_task, "output thread", status)
+    })
+    .expect("Error in scope generation");
+
+    error
+}
+
+// Write the list of failed inputs and the status file, returning the exit status for the run
+fn write_run_status(cfg: &Config, status: &RunStatus, error: bool) -> anyhow::Result<i32> {
+    let qc_failures = status.qc_failures();
+    let failed_inputs = status.failed_inputs();
+    if failed_inputs > 0 {
+        status.write_failed_inputs(cfg.output_dir().join(FAILED_INPUTS_FILE))?
+    }
+
+    let exit_status = if error || qc_failures > 0 {
+        1
+    } else if failed_inputs > 0 {
+        EXIT_FAILED_INPUTS
+    } else {
+        0
+    };
+    if let Some(p) = cfg.status_file() {
+        status.write_json(p, exit_status)?
+    }
+    Ok(exit_status)
+}
+
+/// Run gc_collect with the command line arguments of the process.  Returns the exit status
+/// for a run that completed: 0, or 2 if some input files could not be processed and were
+/// skipped (with --skip-errors).  The process is not exited, so this is left to the caller
+pub fn run() -> anyhow::Result<i32> {
+    let done = |r: anyhow::Result<()>| r.map(|_| 0);
+    let mut cfg = match cli::handle_cli()? {
+        Task::Collect(cfg) => cfg,
+        Task::Report(rcfg) => return done(report::report(&rcfg)),
+        Task::SelfTest(st) => return done(selftest::selftest(&st, std_pipeline)),
+        Task::Simulate(scfg) => return done(simulate::simulate(&scfg)),
+        Task::PanelCompare(pcfg) => return done(panel_compare::panel_compare(&pcfg)),
+        Task::BuildRef(bcfg) => return done(build_ref::build_ref(&bcfg)),
+        Task::Concat(ccfg) => return done(concat::concat(&ccfg)),
+        Task::Validate(vcfg) => return done(validate::validate(&vcfg)),
+        Task::Rescore(rcfg) => return done(rescore::rescore(&rcfg)),
+        #[cfg(feature = "server")]
+        Task::Serve(cfg, scfg) => return done(crate::server::serve(&cfg, &scfg)),
+        #[cfg(feature = "watch")]
+        Task::Watch(cfg, wcfg) => {
+            let status = RunStatus::default();
+            let error = watch_pipeline(&cfg, &wcfg, &status);
+            if let Some(c) = cfg.cache() {
+                c.finish()?
+            }
+            write_run_status(&cfg, &status, error)?;
+            return if error {
+                Err(anyhow!("Error occurred during processing"))
+            } else {
+                Ok(0)
+            };
+        }
+        Task::PrintConfig(s) => {
+            print!("{s}");
+            return Ok(0);
+        }
+    };
+
+    if let Some(lists) = cfg.contrast() {
+        return done(contrast::contrast(&cfg, lists));
+    }
+
+    // With a self reference, a first pass over the inputs builds the pooled reference
+    if cfg.self_reference() {
+        let pool = ReferencePool::from_inputs(&cfg)?;
+        cfg.set_reference_pool(pool)
+    }
+
+    let status = RunStatus::default();
+    let error = match cfg.stream_socket() {
+        #[cfg(unix)]
+        Some(_) => stream_pipeline(&cfg, &status),
+        _ if cfg.merge_key().is_none() => std_pipeline(&cfg, &status),
+        _ => merge_pipeline(&cfg, cfg.input_files(), &status),
+    };
+    if let Some(c) = cfg.cache() {
+        c.finish()?
+    }
+
+    write_run_status(&cfg, &status, error)?;
+    let qc_failures = status.qc_failures();
+    let failed_inputs = status.failed_inputs();
+    if error {
+        Err(anyhow!("Error occurred during processing"))
+    } else if qc_failures > 0 {
+        Err(anyhow!("{qc_failures} dataset(s) failed QC thresholds"))
+    } else if failed_inputs > 0 {
+        warn!("{failed_inputs} input file(s) could not be processed and were skipped (see {FAILED_INPUTS_FILE})");
+        Ok(EXIT_FAILED_INPUTS)
+    } else {
+        Ok(0)
+    }
+}
@@ -0,0 +1,254 @@
+//! Versioned layout of the main TSV output.
+//!
+//! Compatibility policy: within a schema version, columns are only ever appended (new optional
+//! column groups go after the existing groups) and are never removed or renamed.  Changes that
+//! can not follow this policy require a new schema version, with the previous layouts remaining
+//! available through `--schema`.  Older layouts are generated by selecting their columns by name
+//! from the current output, with renamed columns resolved through [`COLUMN_ALIASES`].
+
+use std::{borrow::Cow, fmt};
+
+use clap::{builder::PossibleValue, ValueEnum};
+
+/// Layout version of the main output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Schema {
+    V1,
+    #[default]
+    V2,
+}
+
+impl ValueEnum for Schema {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::V1, Self::V2]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::V1 => Some(PossibleValue::new("v1")),
+            Self::V2 => Some(PossibleValue::new("v2")),
+        }
+    }
+}
+
+impl fmt::Display for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.version())
+    }
+}
+
+impl Schema {
+    /// Schema of the current output layout
+    pub const CURRENT: Self = Self::V2;
+
+    pub fn version(&self) -> u32 {
+        match self {
+            Self::V1 => 1,
+            Self::V2 => 2,
+        }
+    }
+
+    /// Metadata line written before the TSV header.  Not written for v1, which predates the
+    /// metadata line
+    pub fn metadata_line(&self) -> Option<String> {
+        match self {
+            Self::V1 => None,
+            _ => Some(format!(
+                "#schema_version={}\tgc_collect_version={}",
+                self.version(),
+                env!("CARGO_PKG_VERSION")
+            )),
+        }
+    }
+}
+
+/// Columns always present in the current layout
+pub(crate) const FIXED_COLUMNS: [&str; 26] = [
+    "Sample",
+    "Barcode",
+    "Library",
+    "Flowcell",
+    "Index",
+    "Lane",
+    "Read-end",
+    "File",
+    "Bisulfite-type",
+    "Trim",
+    "Min-qual",
+    "Date",
+    "Reads",
+    "Bases",
+    "frac-A",
+    "frac-C",
+    "frac-G",
+    "frac-T",
+    "frac-N",
+    "AT-skew",
+    "GC-skew",
+    "gc",
+    "ref-gc",
+    "KL-distance",
+    "CpG-o/e",
+    "Dinuc-bias",
+];
+
+/// Kmer coverage columns (with a kmer file)
+pub(crate) const KMCV_COLUMNS: [&str; 11] = [
+    "Total-reads",
+    "Mapped-reads",
+    "Total-bases",
+    "Mapped-bases",
+    "Mean-coverage",
+    "Median-coverage",
+    "Median/Mean",
+    "Dispersion",
+    "Fold_80_base_penalty",
+    "Overdispersion",
+    "Poisson-p",
+];
+
+/// Base composition regression columns (with --regression)
+pub(crate) const REGRESSION_COLUMNS: [&str; 8] = [
+    "b(A)",
+    "log10 p_b(A)",
+    "b(C)",
+    "log10 p_b(C)",
+    "b(G)",
+    "log10 p_b(G)",
+    "b(T)",
+    "log10 p_b(T)",
+];
+
+/// Skew regression columns (with --skew)
+pub(crate) const SKEW_COLUMNS: [&str; 4] =
+    ["b(AT-skew)", "p_b(AT-skew)", "b(GC-skew)", "p_b(GC-skew)"];
+
+/// Columns of the v1 layout in output order.  The kmer coverage and regression columns are
+/// only present if the corresponding options are set
+const V1_COLUMNS: [&str; 31] = [
+    "Sample",
+    "Barcode",
+    "Library",
+    "Flowcell",
+    "Index",
+    "Lane",
+    "Read-end",
+    "File",
+    "Bisulfite-type",
+    "Trim",
+    "Min-qual",
+    "gc",
+    "ref-gc",
+    "KL-distance",
+    "Total-reads",
+    "Mapped-reads",
+    "Total-bases",
+    "Mapped-bases",
+    "Mean-coverage",
+    "Median-coverage",
+    "Median/Mean",
+    "Dispersion",
+    "Fold_80_base_penalty",
+    "b(A)",
+    "log10 p_b(A)",
+    "b(C)",
+    "log10 p_b(C)",
+    "b(G)",
+    "log10 p_b(G)",
+    "b(T)",
+    "log10 p_b(T)",
+];
+
+/// Columns renamed since an earlier schema, as (old name, current name).  Any rename of a
+/// column must be added here so that older layouts can still be generated
+pub(crate) const COLUMN_ALIASES: [(&str, &str); 0] = [];
+
+// Current name of a column from an older layout
+fn current_name(c: &str) -> &str {
+    COLUMN_ALIASES
+        .iter()
+        .find(|(old, _)| *old == c)
+        .map(|(_, new)| *new)
+        .unwrap_or(c)
+}
+
+// Column is defined in the current layout
+fn is_known_column(c: &str) -> bool {
+    FIXED_COLUMNS
+        .iter()
+        .chain(KMCV_COLUMNS.iter())
+        .chain(REGRESSION_COLUMNS.iter())
+        .chain(SKEW_COLUMNS.iter())
+        .any(|s| *s == c)
+}
+
+/// Selection of the columns of the requested schema from rows of the current layout
+pub struct Projection {
+    columns: Option<Vec<usize>>,
+}
+
+impl Projection {
+    /// Projection from the current layout with columns header to schema.  Columns of schema
+    /// that are absent from header because the options generating them were not set are
+    /// omitted (as they would have been in the older layout).  Returns an error if a column of
+    /// schema is not defined in the current layout
+    pub fn new(schema: Schema, header: &[String]) -> anyhow::Result<Self> {
+        let old_columns: &[&str] = match schema {
+            Schema::V1 => &V1_COLUMNS,
+            Schema::V2 => return Ok(Self { columns: None }),
+        };
+        let mut columns = Vec::with_capacity(old_columns.len());
+        for c in old_columns {
+            let name = current_name(c);
+            if !is_known_column(name) {
+                return Err(anyhow!(
+                    "Column {c} of schema {schema} has no equivalent in current layout"
+                ));
+            }
+            if let Some(i) = header.iter().position(|s| s == name) {
+                columns.push(i)
+            }
+        }
+        Ok(Self {
+            columns: Some(columns),
+        })
+    }
+
+    /// Select columns from a tab separated row of the current layout
+    pub fn apply<'a>(&self, row: &'a str) -> Cow<'a, str> {
+        match self.columns.as_ref() {
+            None => Cow::Borrowed(row),
+            Some(v) => {
+                let fields: Vec<_> = row.split('\t').collect();
+                let sel: Vec<_> = v.iter().map(|i| fields[*i]).collect();
+                Cow::Owned(sel.join("\t"))
+            }
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn schema_test() {
+        // The leading columns of the current schema are frozen: new columns must be appended
+        assert_eq!(FIXED_COLUMNS.join("\t"), "Sample\tBarcode\tLibrary\tFlowcell\tIndex\tLane\tRead-end\tFile\tBisulfite-type\tTrim\tMin-qual\tDate\tReads\tBases\tfrac-A\tfrac-C\tfrac-G\tfrac-T\tfrac-N\tAT-skew\tGC-skew\tgc\tref-gc\tKL-distance\tCpG-o/e\tDinuc-bias");
+
+        // All v1 columns can be generated from a full current layout
+        let header: Vec<String> = FIXED_COLUMNS
+            .iter()
+            .chain(KMCV_COLUMNS.iter())
+            .chain(REGRESSION_COLUMNS.iter())
+            .map(|s| s.to_string())
+            .collect();
+        let p = Projection::new(Schema::V1, &header).unwrap();
+        assert_eq!(p.apply(&header.join("\t")), V1_COLUMNS.join("\t"));
+
+        // Optional columns absent from the current output are omitted
+        let header: Vec<String> = FIXED_COLUMNS.iter().map(|s| s.to_string()).collect();
+        let p = Projection::new(Schema::V1, &header).unwrap();
+        assert_eq!(p.apply(&header.join("\t")), V1_COLUMNS[..14].join("\t"));
+    }
+}
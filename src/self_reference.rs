@@ -0,0 +1,140 @@
+//! Empirical reference distributions pooled from the datasets of a run (--self-reference).
+//!
+//! The input files are read in a first pass and their gc distributions summed by read length
+//! and bisulfite type.  Each dataset is then compared against the pool with its own counts
+//! removed (leave-one-out), so that a dataset does not contribute to its own reference.
+
+use std::{collections::HashMap, path::Path};
+
+use crossbeam_channel::bounded;
+use crossbeam_utils::thread;
+
+use crate::{
+    cli::Config,
+    process::read_input,
+    read::{BisulfiteType, DataSet},
+    reference::{Counts, GcHistKey, GcHistVal, RefDist},
+};
+
+#[derive(Default)]
+pub struct ReferencePool {
+    // Pooled gc counts by read length and bisulfite status
+    pools: HashMap<(u32, bool), HashMap<GcHistKey, u64>>,
+    n_datasets: usize,
+}
+
+// Read length and bisulfite status used to pool dataset d
+fn pool_key(d: &DataSet) -> (u32, bool) {
+    (
+        d.max_read_len() as u32,
+        !matches!(d.bisulfite(), BisulfiteType::None),
+    )
+}
+
+impl ReferencePool {
+    fn add(&mut self, d: &DataSet) {
+        let h = self.pools.entry(pool_key(d)).or_default();
+        for (k, v) in d.gc_counts().expect("gc counts not generated") {
+            *h.entry(*k).or_insert(0) += v.count() as u64
+        }
+        self.n_datasets += 1
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (key, h) in other.pools {
+            let pool = self.pools.entry(key).or_default();
+            for (k, v) in h {
+                *pool.entry(k).or_insert(0) += v
+            }
+        }
+        self.n_datasets += other.n_datasets
+    }
+
+    pub fn n_datasets(&self) -> usize {
+        self.n_datasets
+    }
+
+    /// Pool all input files of the run, reading the files in parallel.  With --skip-errors,
+    /// files that can not be read are left out of the pool (the error is reported when the
+    /// file is processed)
+    pub fn from_inputs(cfg: &Config) -> anyhow::Result<Self> {
+        let nt = cfg.threads();
+        let pools = thread::scope(|scope| {
+            let (sd, rx) = bounded::<&Path>(nt * 2);
+            let tasks: Vec<_> = (0..nt)
+                .map(|_| {
+                    let rx = rx.clone();
+                    scope.spawn(move |_| -> anyhow::Result<Self> {
+                        let mut pool = Self::default();
+                        while let Ok(p) = rx.recv() {
+                            match read_input(cfg, p) {
+                                Ok(v) => v.iter().for_each(|d| pool.add(d)),
+                                Err(_) if cfg.skip_errors() => (),
+                                Err(e) => return Err(e),
+                            }
+                        }
+                        Ok(pool)
+                    })
+                })
+                .collect();
+            drop(rx);
+            for p in cfg.input_files() {
+                // Fails only if all readers have exited with an error
+                if sd.send(p.as_path()).is_err() {
+                    break;
+                }
+            }
+            drop(sd);
+            tasks
+                .into_iter()
+                .map(|t| t.join().expect("Error joining pool thread"))
+                .collect::<anyhow::Result<Vec<_>>>()
+        })
+        .expect("Error in scope generation")?;
+
+        let mut pool = Self::default();
+        for p in pools {
+            pool.merge(p)
+        }
+        info!("Pooled reference built from {} datasets", pool.n_datasets);
+        Ok(pool)
+    }
+
+    /// Reference distributions for dataset d from the pool with the counts of d removed.
+    /// Only distributions for the bisulfite status of d are included.  Returns None if no
+    /// other datasets with the same bisulfite status are in the pool
+    pub fn leave_one_out(&self, d: &DataSet) -> Option<RefDist> {
+        let (rl, bisulfite) = pool_key(d);
+        let own: HashMap<_, _> = d
+            .gc_counts()
+            .map(|v| v.iter().map(|(k, v)| (*k, v.count() as u64)).collect())
+            .unwrap_or_default();
+        let counts = self
+            .pools
+            .iter()
+            .filter(|((_, b), _)| *b == bisulfite)
+            .filter_map(|((l, _), h)| {
+                let mut hist: Vec<_> = h
+                    .iter()
+                    .filter_map(|(k, c)| {
+                        let c = if *l == rl {
+                            c.saturating_sub(own.get(k).copied().unwrap_or(0))
+                        } else {
+                            *c
+                        };
+                        (c > 0).then(|| (*k, GcHistVal::make(k, c)))
+                    })
+                    .collect();
+                hist.sort_unstable_by_key(|(k, _)| *k);
+                if hist.is_empty() {
+                    None
+                } else if bisulfite {
+                    Some((*l, Counts::from_hists(Vec::new(), Some(hist))))
+                } else {
+                    Some((*l, Counts::from_hists(hist, None)))
+                }
+            })
+            .collect();
+        RefDist::from_counts(counts)
+    }
+}
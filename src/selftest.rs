@@ -0,0 +1,203 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use libm::lgamma;
+use serde_json::{json, Value};
+
+use crate::{
+    cli::{Config, ConfigBuilder, SelfTestConfig},
+    reference::RefDist,
+    results_table::ResultsTable,
+    status::RunStatus,
+    temp_dir::TempDir,
+};
+
+const READ_LEN: u32 = 100;
+const N_READS: u64 = 100_000;
+
+/// Synthetic datasets: name and GC proportion.  The first dataset
+/// has the same distribution as the reference
+const DATASETS: [(&str, f64); 3] = [
+    ("selftest_1", 0.41),
+    ("selftest_2", 0.45),
+    ("selftest_3", 0.55),
+];
+const REF_GC: f64 = 0.41;
+
+// Expected number of reads with k GC bases (k = 0..=READ_LEN) under a binomial model
+fn binomial_counts(p: f64) -> Vec<u64> {
+    let n = READ_LEN as f64;
+    (0..=READ_LEN)
+        .map(|k| {
+            let k = k as f64;
+            let lp = lgamma(n + 1.0) - lgamma(k + 1.0) - lgamma(n - k + 1.0)
+                + k * p.ln()
+                + (n - k) * (1.0 - p).ln();
+            (N_READS as f64 * lp.exp()).round() as u64
+        })
+        .collect()
+}
+
+fn gc_hash(p: f64) -> (Value, f64) {
+    let cts = binomial_counts(p);
+    let mut h = serde_json::Map::new();
+    let (mut at, mut gc) = (0.0, 0.0);
+    for (k, c) in cts.iter().enumerate().filter(|(_, c)| **c > 0) {
+        let k = k as u32;
+        h.insert(format!("{}:{}", READ_LEN - k, k), json!(c));
+        at += ((READ_LEN - k) as u64 * c) as f64;
+        gc += (k as u64 * c) as f64;
+    }
+    (Value::Object(h), gc / (at + gc))
+}
+
+fn base_counts(p: f64, n: u64) -> Value {
+    let gc = (n as f64 * p * 0.5).round() as u64;
+    let at = (n as f64 * (1.0 - p) * 0.5).round() as u64;
+    json!({"A": at, "C": gc, "G": gc, "T": at, "N": 0})
+}
+
+// Write synthetic dataset, returning the expected mean GC
+fn write_dataset(path: &Path, sample: &str, p: f64) -> anyhow::Result<f64> {
+    let (gc_hash, mean_gc) = gc_hash(p);
+    let per_pos_cts: serde_json::Map<_, _> = (1..=READ_LEN)
+        .map(|i| (format!("{i}"), base_counts(p, N_READS)))
+        .collect();
+    let d = json!({
+        "trim": 0,
+        "min_qual": 20,
+        "max_read_length": READ_LEN,
+        "bisulfite": "None",
+        "fli": {
+            "sample": sample,
+            "barcode": null,
+            "library": format!("{sample}_lib"),
+            "flowcell": "FC_SELFTEST",
+            "index": "ACGTACGT",
+            "lane": 1,
+            "read_end": 1
+        },
+        "cts": base_counts(p, N_READS * READ_LEN as u64),
+        "per_pos_cts": per_pos_cts,
+        "gc_hash": gc_hash,
+    });
+    write_json(path, &d)?;
+    Ok(mean_gc)
+}
+
+fn write_reference(path: &Path) -> anyhow::Result<()> {
+    let (counts, _) = gc_hash(REF_GC);
+    let d = json!({
+        "read_lengths": [READ_LEN],
+        "read_length_specific_counts": { format!("{READ_LEN}"): { "counts": counts } }
+    });
+    write_json(path, &d)
+}
+
+fn write_json(path: &Path, v: &Value) -> anyhow::Result<()> {
+    let wrt =
+        fs::File::create(path).with_context(|| format!("Could not create {}", path.display()))?;
+    serde_json::to_writer(wrt, v).with_context(|| format!("Error writing {}", path.display()))
+}
+
+fn check_results(path: &Path, expected: &[(PathBuf, f64)]) -> anyhow::Result<()> {
+    let tab = ResultsTable::read(path)?;
+    let get_col = |s: &str| {
+        tab.column(s)
+            .ok_or_else(|| anyhow!("Column {s} missing from output"))
+    };
+    let (file_ix, gc_ix, kl_ix) = (get_col("File")?, get_col("gc")?, get_col("KL-distance")?);
+    if tab.rows().len() != expected.len() {
+        return Err(anyhow!(
+            "Expected {} rows in output, found {}",
+            expected.len(),
+            tab.rows().len()
+        ));
+    }
+    for r in tab.rows() {
+        let (p, exp_gc) = expected
+            .iter()
+            .find(|(p, _)| p.file_stem() == Path::new(&r[file_ix]).file_stem())
+            .ok_or_else(|| anyhow!("Unexpected file {} in output", r[file_ix]))?;
+        let gc: f64 = r[gc_ix].parse()?;
+        if (gc - exp_gc).abs() > 1.0e-5 {
+            return Err(anyhow!(
+                "{}: mean GC {gc} differs from expected value {exp_gc}",
+                p.display()
+            ));
+        }
+        let kl: f64 = r[kl_ix].parse()?;
+        let matched = (exp_gc - REF_GC).abs() < 0.005;
+        if (matched && kl > 1.0e-4) || (!matched && kl < 1.0e-2) {
+            return Err(anyhow!(
+                "{}: KL distance {kl} inconsistent with expected GC difference",
+                p.display()
+            ));
+        }
+        let base_dist = p.with_extension("base_dist.tsv");
+        let n = fs::read_to_string(&base_dist)
+            .with_context(|| format!("Could not read {}", base_dist.display()))?
+            .lines()
+            .count();
+        if n != READ_LEN as usize + 1 {
+            return Err(anyhow!(
+                "{}: expected {} lines, found {n}",
+                base_dist.display(),
+                READ_LEN + 1
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Generate synthetic inputs and a reference, run the standard pipeline and check
+/// the results against the expected values
+pub fn selftest<F>(st: &SelfTestConfig, pipeline: F) -> anyhow::Result<()>
+where
+    F: Fn(&Config, &RunStatus) -> bool,
+{
+    // Without --dir, the test data are written to a temporary directory removed on exit
+    let tmp = TempDir::new(st.tmpdir());
+    let dir = match st.dir() {
+        Some(d) => d.to_owned(),
+        None => tmp.path()?.to_owned(),
+    };
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("Could not create directory {}", dir.display()))?;
+    info!("Writing self test data to {}", dir.display());
+
+    let res = (|| {
+        let ref_path = dir.join("selftest_ref.json");
+        write_reference(&ref_path)?;
+        let mut expected = Vec::with_capacity(DATASETS.len());
+        for (name, p) in DATASETS {
+            let path = dir.join(format!("{name}.json"));
+            let mean_gc = write_dataset(&path, name, p)?;
+            expected.push((path, mean_gc))
+        }
+
+        let output = dir.join("selftest_results.tsv");
+        let ref_dist = RefDist::from_json_file(&ref_path)?;
+        let cfg = ConfigBuilder::new()
+            .input_files(expected.iter().map(|(p, _)| p))
+            .output_file(Some(&output))
+            .ref_dist(Some(ref_dist))
+            .threads(1)
+            .build()?;
+        if pipeline(&cfg, &RunStatus::default()) {
+            return Err(anyhow!("Error running pipeline"));
+        }
+        check_results(&output, &expected)
+    })();
+
+    match res {
+        Ok(_) => {
+            info!("Self test passed");
+            Ok(())
+        }
+        Err(e) => Err(e.context("Self test failed")),
+    }
+}
@@ -0,0 +1,254 @@
+//! Service mode (serve subcommand, built with the server feature).
+//!
+//! A small HTTP API so that datasets can be analyzed on demand (e.g., from a LIMS) without
+//! starting gc_collect for each file.  The analysis options are taken from the command line as
+//! for a normal run, and requests are analyzed by a pool of worker threads (--threads).
+//!
+//! Endpoints:
+//!
+//! - POST /analyze: the body is either a JSON object `{"path": "<file>"}` giving an input file
+//!   readable by the server (FASTQ, BAM and CRAM files are accepted as for normal input), or
+//!   the JSON output of fastq_gc for a dataset.  The response is a JSON array with one object
+//!   per read group, as for the JSON output format.  Results for input files are cached if a
+//!   cache directory is set
+//! - GET /summary: summary of the datasets analyzed since the server was started, with the
+//!   number of requests and failed requests
+//! - GET /health: returns `{"status": "ok"}`
+//!
+//! Input files are read with the permissions of the server, so by default the server only
+//! listens on the loopback interface.  Request bodies larger than 1 GiB are rejected (413), as
+//! are analysis requests when all workers are busy and the request queue is full (503).  The
+//! cache file index (if a cache directory is set) is written after each analysis request.
+
+use std::{
+    io::Read,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use crossbeam_channel::{bounded, Receiver, TrySendError};
+use crossbeam_utils::thread;
+use serde_json::{json, Map, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    cli::{Config, ServeConfig},
+    process::{analyze_datasets, process_file, DataResults},
+    read::{read_json_reader, DataSummary},
+    summary::RunSummary,
+};
+
+// Largest request body accepted
+const MAX_BODY: usize = 1 << 30;
+
+/// Dataset submitted for analysis
+#[derive(Debug, PartialEq)]
+enum Submission {
+    /// Input file readable by the server
+    Path(PathBuf),
+    /// fastq_gc JSON output sent in the request body
+    Json,
+}
+
+impl Submission {
+    fn from_body(body: &[u8]) -> anyhow::Result<Self> {
+        let v: Value =
+            serde_json::from_slice(body).map_err(|e| anyhow!("Request body is not JSON: {e}"))?;
+        match v.as_object() {
+            Some(m) if m.len() == 1 && m.contains_key("path") => match m["path"].as_str() {
+                Some(p) => Ok(Self::Path(PathBuf::from(p))),
+                None => Err(anyhow!("Path in request is not a string")),
+            },
+            _ => Ok(Self::Json),
+        }
+    }
+}
+
+// Request counts and summary of the datasets analyzed
+#[derive(Default)]
+struct ServerState {
+    requests: AtomicUsize,
+    failed: AtomicUsize,
+    summary: Mutex<RunSummary>,
+}
+
+impl ServerState {
+    fn json(&self) -> Value {
+        json!({
+            "requests": self.requests.load(Ordering::Relaxed),
+            "failed_requests": self.failed.load(Ordering::Relaxed),
+            "summary": self.summary.lock().unwrap().json(),
+        })
+    }
+}
+
+fn respond(req: Request, status: u16, v: &Value) {
+    let header =
+        Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).expect("Invalid header");
+    let resp = Response::from_string(v.to_string())
+        .with_status_code(status)
+        .with_header(header);
+    if let Err(e) = req.respond(resp) {
+        warn!("Error sending response: {e}")
+    }
+}
+
+fn error_json(e: &anyhow::Error) -> Value {
+    json!({ "error": format!("{e:#}") })
+}
+
+// Result record for a dataset, as for the JSON output format
+fn record(cfg: &Config, d: &DataSummary, res: &DataResults) -> Value {
+    let mut m: Map<String, Value> = d.json_summary();
+    m.extend(res.json_summary());
+    if let Some(t) = cfg.thresholds() {
+        m.insert("qc_status".to_owned(), t.evaluate(res).to_string().into());
+    }
+    Value::Object(m)
+}
+
+// Read the body of request req, failing if it is larger than MAX_BODY
+fn read_body(req: &mut Request) -> Result<Vec<u8>, (u16, anyhow::Error)> {
+    let too_large = || (413, anyhow!("Request body larger than {MAX_BODY} bytes"));
+    if req.body_length().is_some_and(|l| l > MAX_BODY) {
+        return Err(too_large());
+    }
+    // One byte more than the limit is read so that larger bodies (e.g., chunked bodies without
+    // a length) are detected
+    let mut body = Vec::new();
+    req.as_reader()
+        .take(MAX_BODY as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| (400, anyhow!("Error reading request body: {e}")))?;
+    if body.len() > MAX_BODY {
+        Err(too_large())
+    } else {
+        Ok(body)
+    }
+}
+
+// Analyze the dataset submitted in request body.  Request bodies are given the path
+// request<n>.json (for auxiliary output files)
+fn analyze_request(
+    cfg: &Config,
+    n: usize,
+    body: &[u8],
+    state: &ServerState,
+) -> Result<Value, (u16, anyhow::Error)> {
+    let v = match Submission::from_body(body).map_err(|e| (400, e))? {
+        Submission::Path(p) => process_file(cfg, &p),
+        Submission::Json => {
+            let p = PathBuf::from(format!("request{n}.json"));
+            read_json_reader(body, &p).and_then(|v| analyze_datasets(cfg, &p, v))
+        }
+    }
+    .map_err(|e| (422, e))?;
+    let mut summary = state.summary.lock().unwrap();
+    let recs = v
+        .iter()
+        .map(|(d, res)| {
+            let d = d.summary();
+            summary.add(&d, res, cfg.thresholds());
+            record(cfg, &d, res)
+        })
+        .collect();
+    Ok(Value::Array(recs))
+}
+
+fn worker_thread(cfg: &Config, ix: usize, rx: Receiver<(usize, Request)>, state: &ServerState) {
+    debug!("Server worker thread {ix} starting up");
+    while let Ok((n, mut req)) = rx.recv() {
+        trace!("Server worker thread {ix} received request {n}");
+        let res = read_body(&mut req).and_then(|body| analyze_request(cfg, n, &body, state));
+        // Written here as the server only stops when the process is terminated
+        if let Some(Err(e)) = cfg.cache().map(|c| c.write_index()) {
+            warn!("Error writing cache index: {e:#}")
+        }
+        match res {
+            Ok(v) => respond(req, 200, &v),
+            Err((status, e)) => {
+                warn!("Request {n} failed: {e:#}");
+                state.failed.fetch_add(1, Ordering::Relaxed);
+                respond(req, status, &error_json(&e))
+            }
+        }
+    }
+    debug!("Server worker thread {ix} closing down");
+}
+
+/// Run the server until the process is terminated
+pub fn serve(cfg: &Config, scfg: &ServeConfig) -> anyhow::Result<()> {
+    let server = Server::http(scfg.listen())
+        .map_err(|e| anyhow!("Could not listen on {}: {e}", scfg.listen()))?;
+    info!("Listening on {}", scfg.listen());
+    if !cfg.input_files().is_empty() {
+        warn!("Input files ignored in service mode")
+    }
+    let state = ServerState::default();
+    let nt = cfg.threads();
+    thread::scope(|scope| {
+        // Requests wait here for a free worker when all workers are busy.  Requests are
+        // rejected when the queue is full so that the other endpoints remain responsive
+        let (sd, rx) = bounded(nt * 2);
+        for ix in 0..nt {
+            let (rx1, st) = (rx.clone(), &state);
+            scope.spawn(move |_| worker_thread(cfg, ix, rx1, st));
+        }
+        drop(rx);
+
+        for req in server.incoming_requests() {
+            let path = req.url().split('?').next().unwrap_or("").to_owned();
+            trace!("Received {} request for {path}", req.method());
+            match (req.method(), path.as_str()) {
+                (Method::Post, "/analyze") => {
+                    let n = state.requests.fetch_add(1, Ordering::Relaxed) + 1;
+                    match sd.try_send((n, req)) {
+                        Ok(_) => (),
+                        Err(TrySendError::Full((_, req))) => {
+                            warn!("Request {n} rejected: all workers busy");
+                            state.failed.fetch_add(1, Ordering::Relaxed);
+                            respond(
+                                req,
+                                503,
+                                &error_json(&anyhow!("Server busy, try again later")),
+                            )
+                        }
+                        Err(TrySendError::Disconnected(_)) => {
+                            panic!("Error sending request to worker threads")
+                        }
+                    }
+                }
+                (Method::Get, "/summary") => respond(req, 200, &state.json()),
+                (Method::Get, "/health") => respond(req, 200, &json!({"status": "ok"})),
+                _ => respond(req, 404, &error_json(&anyhow!("Unknown endpoint {path}"))),
+            }
+        }
+    })
+    .expect("Error in scope generation");
+    match cfg.cache() {
+        Some(c) => c.finish(),
+        None => Ok(()),
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn submission_test() {
+        assert_eq!(
+            Submission::from_body(br#"{"path": "/data/s1.json"}"#).unwrap(),
+            Submission::Path(PathBuf::from("/data/s1.json"))
+        );
+        assert_eq!(
+            Submission::from_body(br#"{"fli": "FC1_1_ACGT", "path": "x"}"#).unwrap(),
+            Submission::Json
+        );
+        assert!(Submission::from_body(br#"{"path": 1}"#).is_err());
+        assert!(Submission::from_body(b"not json").is_err());
+    }
+}
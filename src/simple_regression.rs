@@ -1,7 +1,10 @@
 /// Simple (one predictor) linear regression
-use stat_functions::students_t::StudentsT;
+use clap::{builder::PossibleValue, ValueEnum};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Copy, Clone)]
+use crate::stats::{students_t_cdf, Z_95};
+
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
 pub struct Coefficient {
     estimate: f64,
     standard_error: f64,
@@ -25,12 +28,11 @@ impl Coefficient {
         // 2-sided t-test
         t.map(|t| {
             let v = self.df as f64;
-            let s = StudentsT::new(v).expect("Invalid df");
-            2.0 * s.pt(-t.abs())
+            2.0 * students_t_cdf(-t.abs(), v)
         })
     }
 }
-#[derive(Debug)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(unused)]
 pub struct SimpleRegression {
     intercept: Coefficient,
@@ -121,6 +123,241 @@ pub fn simple_regression(obs: &[(f64, f64)]) -> anyhow::Result<SimpleRegression>
     }
 }
 
+// Median of a non-empty slice (which is sorted in place)
+pub(crate) fn median(v: &mut [f64]) -> f64 {
+    v.sort_unstable_by(|a, b| a.total_cmp(b));
+    let n = v.len();
+    if n & 1 == 1 {
+        v[n >> 1]
+    } else {
+        0.5 * (v[(n >> 1) - 1] + v[n >> 1])
+    }
+}
+
+/// Theil-Sen regression: the slope is the median of the slopes between all pairs of
+/// observations with distinct x, and the intercept is the median of y - slope * x.  Unlike
+/// least squares this is not affected by a minority of outlying observations.
+///
+/// The standard error of the slope is derived from the width of the (distribution free)
+/// 95% confidence interval from the null distribution of Kendall's S, so that the p-value of
+/// the slope approximates that of the Mann-Kendall trend test.  No standard error is given for
+/// the intercept
+pub fn theil_sen_regression(obs: &[(f64, f64)]) -> anyhow::Result<SimpleRegression> {
+    let n = obs.len();
+    if n < 3 {
+        return Err(anyhow!(
+            "Cannot obtain meaningful regression estimates with <3 observations"
+        ));
+    }
+    let mut slopes = Vec::with_capacity(n * (n - 1) / 2);
+    for (i, (x1, y1)) in obs.iter().enumerate() {
+        for (x2, y2) in obs[i + 1..].iter() {
+            if x1 != x2 {
+                slopes.push((y2 - y1) / (x2 - x1))
+            }
+        }
+    }
+    if slopes.is_empty() {
+        return Err(anyhow!("Numerical error during regression calculations"));
+    }
+    let b1 = median(&mut slopes);
+    let mut r: Vec<_> = obs.iter().map(|(x, y)| y - b1 * x).collect();
+    let b0 = median(&mut r);
+
+    // Ranks of the confidence limits in the sorted slopes
+    let m = slopes.len() as f64;
+    let nf = n as f64;
+    let c = Z_95 * (nf * (nf - 1.0) * (2.0 * nf + 5.0) / 18.0).sqrt();
+    let lo = ((0.5 * (m - c)).floor().max(0.0) as usize).min(slopes.len() - 1);
+    let hi = ((0.5 * (m + c)).ceil() as usize).min(slopes.len() - 1);
+    let se = (slopes[hi] - slopes[lo]) / (2.0 * Z_95);
+
+    let residual_ss = obs
+        .iter()
+        .map(|(x, y)| (y - b0 - x * b1).powi(2))
+        .sum::<f64>();
+    let df = n - 2;
+    Ok(SimpleRegression {
+        intercept: Coefficient {
+            estimate: b0,
+            standard_error: 0.0,
+            df,
+        },
+        slope: Coefficient {
+            estimate: b1,
+            standard_error: se,
+            df,
+        },
+        residual_ss,
+        residual_df: df,
+    })
+}
+
+/// Non-linear models for drift along the read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DriftModel {
+    /// Second order polynomial
+    Quadratic,
+    /// Two linear segments joined at a breakpoint
+    Segmented,
+}
+
+impl ValueEnum for DriftModel {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Quadratic, Self::Segmented]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Quadratic => Some(PossibleValue::new("quadratic")),
+            Self::Segmented => Some(PossibleValue::new("segmented")),
+        }
+    }
+}
+
+impl DriftModel {
+    /// TSV column names for base b
+    pub fn columns(&self, b: char) -> Vec<String> {
+        match self {
+            Self::Quadratic => vec![format!("c({b})"), format!("p_c({b})")],
+            Self::Segmented => vec![format!("bp({b})"), format!("db({b})"), format!("p_db({b})")],
+        }
+    }
+
+    pub fn fit(&self, obs: &[(f64, f64)]) -> anyhow::Result<NonLinearFit> {
+        match self {
+            Self::Quadratic => quadratic_regression(obs),
+            Self::Segmented => segmented_regression(obs),
+        }
+    }
+}
+
+/// Fit of a non-linear drift model.  The effect is the coefficient of x^2 (the curvature) for
+/// the quadratic model, and the change in slope at the breakpoint for the segmented model
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NonLinearFit {
+    effect: Coefficient,
+    breakpoint: Option<f64>,
+    residual_ss: f64,
+}
+
+impl NonLinearFit {
+    pub fn effect(&self) -> &Coefficient {
+        &self.effect
+    }
+    /// Breakpoint (segmented model only)
+    pub fn breakpoint(&self) -> Option<f64> {
+        self.breakpoint
+    }
+    /// Transform the breakpoint to a different x scale
+    pub fn map_breakpoint<F: Fn(f64) -> f64>(&mut self, f: F) {
+        self.breakpoint = self.breakpoint.map(f)
+    }
+}
+
+// Least squares fit of y on the three predictors in each row.  Returns the coefficients, the
+// diagonal of (X'X)^-1 and the residual sum of squares, or None if X'X is singular
+fn least_squares_3(rows: &[([f64; 3], f64)]) -> Option<([f64; 3], [f64; 3], f64)> {
+    let mut xx = [[0.0; 3]; 3];
+    let mut xy = [0.0; 3];
+    for (x, y) in rows {
+        for i in 0..3 {
+            xy[i] += x[i] * y;
+            for j in 0..3 {
+                xx[i][j] += x[i] * x[j]
+            }
+        }
+    }
+    // Inverse of X'X from the adjugate
+    let cof = |i: usize, j: usize| {
+        let (i1, i2) = ((i + 1) % 3, (i + 2) % 3);
+        let (j1, j2) = ((j + 1) % 3, (j + 2) % 3);
+        xx[i1][j1] * xx[i2][j2] - xx[i1][j2] * xx[i2][j1]
+    };
+    let det = (0..3).map(|j| xx[0][j] * cof(0, j)).sum::<f64>();
+    let scale = xx.iter().flatten().fold(0.0f64, |m, x| m.max(x.abs()));
+    if det.abs() <= 1.0e-12 * scale.powi(3) {
+        return None;
+    }
+    let mut inv = [[0.0; 3]; 3];
+    for (i, row) in inv.iter_mut().enumerate() {
+        for (j, x) in row.iter_mut().enumerate() {
+            *x = cof(j, i) / det
+        }
+    }
+    let b: Vec<_> = (0..3)
+        .map(|i| (0..3).map(|j| inv[i][j] * xy[j]).sum::<f64>())
+        .collect();
+    let b = [b[0], b[1], b[2]];
+    let rss = rows
+        .iter()
+        .map(|(x, y)| (y - x.iter().zip(b.iter()).map(|(a, c)| a * c).sum::<f64>()).powi(2))
+        .sum::<f64>();
+    Some((b, [inv[0][0], inv[1][1], inv[2][2]], rss))
+}
+
+// Fit of y = b0 + b1 * x + b2 * g(x), reporting b2
+fn fit_third_term<G: Fn(f64) -> f64>(
+    obs: &[(f64, f64)],
+    g: G,
+    breakpoint: Option<f64>,
+) -> Option<NonLinearFit> {
+    let rows: Vec<_> = obs.iter().map(|(x, y)| ([1.0, *x, g(*x)], *y)).collect();
+    let (b, d, residual_ss) = least_squares_3(&rows)?;
+    let df = obs.len() - 3;
+    let res_var = residual_ss / df as f64;
+    Some(NonLinearFit {
+        effect: Coefficient {
+            estimate: b[2],
+            standard_error: (d[2] * res_var).sqrt(),
+            df,
+        },
+        breakpoint,
+        residual_ss,
+    })
+}
+
+/// Least squares fit of y = b0 + b1 * x + b2 * x^2, reporting the curvature b2
+pub fn quadratic_regression(obs: &[(f64, f64)]) -> anyhow::Result<NonLinearFit> {
+    if obs.len() < 4 {
+        return Err(anyhow!(
+            "Cannot obtain meaningful quadratic regression estimates with <4 observations"
+        ));
+    }
+    fit_third_term(obs, |x| x * x, None)
+        .ok_or_else(|| anyhow!("Numerical error during regression calculations"))
+}
+
+/// Continuous two segment linear fit y = b0 + b1 * x + b2 * max(x - c, 0), with the
+/// breakpoint c chosen from the observed x values (leaving at least three observations in
+/// each segment) to minimize the residual sum of squares.  Reports the change in slope b2 at
+/// the breakpoint.  The p-value of b2 does not take the selection of the breakpoint into
+/// account, so overstates the evidence for a change in slope
+pub fn segmented_regression(obs: &[(f64, f64)]) -> anyhow::Result<NonLinearFit> {
+    if obs.len() < 6 {
+        return Err(anyhow!(
+            "Cannot obtain meaningful segmented regression estimates with <6 observations"
+        ));
+    }
+    let mut xs: Vec<_> = obs.iter().map(|(x, _)| *x).collect();
+    xs.sort_unstable_by(|a, b| a.total_cmp(b));
+    let n = xs.len();
+    let mut best: Option<NonLinearFit> = None;
+    for c in xs[2..n - 3].iter() {
+        if let Some(fit) = fit_third_term(obs, |x| (x - c).max(0.0), Some(*c)) {
+            if best
+                .as_ref()
+                .map(|b| fit.residual_ss < b.residual_ss)
+                .unwrap_or(true)
+            {
+                best = Some(fit)
+            }
+        }
+    }
+    best.ok_or_else(|| anyhow!("Numerical error during regression calculations"))
+}
+
 mod test {
     #[allow(unused_imports)]
     use super::*;
@@ -135,4 +372,46 @@ mod test {
         }
         assert!((reg.slope().p().unwrap() - 0.0140732510).abs() < 1.0e-8);
     }
+
+    #[test]
+    fn theil_sen_test() {
+        // Points on y = 2x + 1 with a trailing outlier
+        let mut obs: Vec<_> = (0..20).map(|i| (i as f64, 2.0 * i as f64 + 1.0)).collect();
+        obs.push((20.0, 100.0));
+        let reg = theil_sen_regression(&obs).expect("Error in regression");
+        assert!((reg.slope().estimate() - 2.0).abs() < 1.0e-12);
+        assert!((reg.intercept.estimate() - 1.0).abs() < 1.0e-12);
+        let ols = simple_regression(&obs).expect("Error in regression");
+        assert!(ols.slope().estimate() > 2.5);
+
+        // No trend
+        let obs = [(1.0, 4.0), (2.0, 6.0), (3.0, 3.0), (4.0, 5.0), (5.0, 4.5)];
+        let reg = theil_sen_regression(&obs).expect("Error in regression");
+        assert!(reg.slope().p().unwrap() > 0.2);
+    }
+
+    #[test]
+    fn drift_model_test() {
+        // Exact quadratic
+        let obs: Vec<_> = (0..10)
+            .map(|i| {
+                let x = i as f64 / 10.0;
+                (x, 1.0 + 0.5 * x - 2.0 * x * x)
+            })
+            .collect();
+        let fit = quadratic_regression(&obs).expect("Error in regression");
+        assert!((fit.effect().estimate() + 2.0).abs() < 1.0e-8);
+
+        // Flat then rising from x = 12
+        let obs: Vec<_> = (0..20)
+            .map(|i| {
+                let x = i as f64;
+                (x, 3.0 + 0.1 * (x - 12.0).max(0.0) + 0.001 * (i % 3) as f64)
+            })
+            .collect();
+        let fit = segmented_regression(&obs).expect("Error in regression");
+        assert_eq!(fit.breakpoint(), Some(12.0));
+        assert!((fit.effect().estimate() - 0.1).abs() < 1.0e-3);
+        assert!(fit.effect().p().unwrap() < 1.0e-6);
+    }
 }
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Beta, Binomial, Distribution};
+
+use crate::{
+    cli::SimulateConfig,
+    kmers::{BaseCount, KmerCounts, ReadCount},
+    read::{Fli, TempDataSet},
+};
+
+/// Simulate per read GC counts.  Each read has a GC probability drawn from a Beta
+/// distribution with the requested mean and intra-class correlation (overdispersion) rho, and
+/// the number of GC bases is then Binomial(read_len, p).  With rho = 0 this reduces to a
+/// simple Binomial.
+fn simulate_gc<R: Rng>(
+    cfg: &SimulateConfig,
+    rng: &mut R,
+) -> anyhow::Result<(HashMap<String, u64>, f64)> {
+    let l = cfg.read_length();
+    let mean = cfg.gc_mean();
+    let rho = cfg.gc_overdispersion();
+    let beta = if rho > 0.0 {
+        let conc = (1.0 - rho) / rho;
+        Some(Beta::new(mean * conc, (1.0 - mean) * conc)?)
+    } else {
+        None
+    };
+
+    let mut cts = vec![0u64; l as usize + 1];
+    for _ in 0..cfg.depth() {
+        let p = beta.as_ref().map(|b| b.sample(rng)).unwrap_or(mean);
+        let k = Binomial::new(l as u64, p)?.sample(rng);
+        cts[k as usize] += 1;
+    }
+
+    let gc = cts
+        .iter()
+        .enumerate()
+        .map(|(k, c)| (k as u64) * c)
+        .sum::<u64>();
+    let gc_hash = cts
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| **c > 0)
+        .map(|(k, c)| (format!("{}:{}", l as usize - k, k), *c))
+        .collect();
+    Ok((gc_hash, gc as f64 / (cfg.depth() * l as u64) as f64))
+}
+
+/// Per position base counts with GC proportion p (positions are exchangeable)
+fn simulate_per_pos<R: Rng>(
+    cfg: &SimulateConfig,
+    p: f64,
+    rng: &mut R,
+) -> anyhow::Result<Vec<[u64; 5]>> {
+    let n = cfg.depth();
+    let gc_dist = Binomial::new(n, p)?;
+    let mut v = Vec::with_capacity(cfg.read_length() as usize);
+    for _ in 0..cfg.read_length() {
+        let gc = gc_dist.sample(rng);
+        let c = Binomial::new(gc, 0.5)?.sample(rng);
+        let a = Binomial::new(n - gc, 0.5)?.sample(rng);
+        v.push([a, c, gc - c, n - gc - a, 0])
+    }
+    Ok(v)
+}
+
+/// Per target kmer counts.  The number of mapped reads is Binomial(depth, mapped fraction), and
+/// these are split between the targets in proportion to target size (multinomially), so the
+/// mapped totals are the sums of the per target counts
+fn simulate_kmer_counts<R: Rng>(
+    cfg: &SimulateConfig,
+    rng: &mut R,
+) -> anyhow::Result<Option<KmerCounts>> {
+    let kmcv = match cfg.kmcv() {
+        Some(k) => k,
+        None => return Ok(None),
+    };
+    let l = cfg.read_length() as u64;
+    let sizes: Vec<_> = kmcv.targets().map(|(_, t)| t.size() as f64).collect();
+    let mut counts = Vec::with_capacity(sizes.len());
+    let mut mapped_reads = 0;
+    if !sizes.is_empty() {
+        let mut left = Binomial::new(cfg.depth(), cfg.mapped_fraction())?.sample(rng);
+        let mut size_left = sizes.iter().sum::<f64>();
+        // Multinomial sample as a sequence of Binomials conditional on the reads left
+        for s in sizes {
+            let reads = if size_left > 0.0 && left > 0 {
+                Binomial::new(left, (s / size_left).min(1.0))?.sample(rng)
+            } else {
+                0
+            };
+            (left, size_left, mapped_reads) = (left - reads, size_left - s, mapped_reads + reads);
+            counts.push((ReadCount::new(reads), BaseCount::new(reads * l)))
+        }
+    }
+    Ok(Some(KmerCounts::new(
+        kmcv.header_core().clone(),
+        ReadCount::new(cfg.depth()),
+        ReadCount::new(mapped_reads),
+        BaseCount::new(cfg.depth() * l),
+        BaseCount::new(mapped_reads * l),
+        counts,
+    )))
+}
+
+/// Generate synthetic fastq_gc datasets for benchmarking and test fixtures
+pub fn simulate(cfg: &SimulateConfig) -> anyhow::Result<()> {
+    let mut rng = match cfg.seed() {
+        Some(s) => StdRng::seed_from_u64(s),
+        None => StdRng::from_entropy(),
+    };
+    let dir = cfg.outdir();
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Could not create output directory {}", dir.display()))?;
+
+    for ix in 1..=cfg.n_datasets() {
+        let name = format!("{}_{ix}", cfg.prefix());
+        debug!("Simulating dataset {name}");
+        let (gc_hash, p) = simulate_gc(cfg, &mut rng)?;
+        let per_pos_cts = simulate_per_pos(cfg, p, &mut rng)?;
+        let kmer_counts = simulate_kmer_counts(cfg, &mut rng)?;
+        let fli = Fli::new(
+            Some(name.clone()),
+            Some(format!("{name}_lib")),
+            Some("SIMFC".to_owned()),
+            Some("ACGTACGT".to_owned()),
+            Some(1),
+        );
+        let d = TempDataSet::new(20, fli, per_pos_cts, gc_hash, kmer_counts);
+        let path = dir.join(format!("{name}.json"));
+        d.write_json(&path)?;
+        info!("Wrote simulated dataset to {}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use std::path::Path;
+
+use clap::{builder::PossibleValue, ValueEnum};
+
+use crate::{
+    bam::{read_bam, read_cram, AlignedReads},
+    fastq::read_fastq,
+    read::{read_json_groups, read_json_stdin, DataSet},
+};
+
+/// Input format selection.  With Auto, the format is chosen from the input file name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Auto,
+    Json,
+    Fastq,
+    Bam,
+}
+
+impl ValueEnum for InputFormat {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Auto, Self::Json, Self::Fastq, Self::Bam]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Auto => Some(PossibleValue::new("auto")),
+            Self::Json => Some(PossibleValue::new("json")),
+            Self::Fastq => Some(PossibleValue::new("fastq")),
+            Self::Bam => Some(PossibleValue::new("bam").alias("cram")),
+        }
+    }
+}
+
+/// Source of datasets for the pipelines.  New input formats are added by implementing
+/// this trait and adding the implementation to SOURCES
+pub trait DatasetSource: Sync {
+    /// Input format handled by this source
+    fn format(&self) -> InputFormat;
+
+    /// Returns true if this source can handle input p (judging from the name)
+    fn accepts(&self, p: &Path) -> bool;
+
+    /// Read the datasets from input p, one per read group.  reads selects the reads used
+    /// from aligned inputs
+    fn read(&self, p: &Path, reads: AlignedReads) -> anyhow::Result<Vec<DataSet>>;
+}
+
+/// Input file name used to indicate reading from stdin
+pub const STDIN_NAME: &str = "-";
+
+pub fn is_stdin(p: &Path) -> bool {
+    p.as_os_str() == STDIN_NAME
+}
+
+/// fastq_gc JSON files (optionally compressed)
+struct JsonFileSource;
+
+impl DatasetSource for JsonFileSource {
+    fn format(&self) -> InputFormat {
+        InputFormat::Json
+    }
+
+    fn accepts(&self, p: &Path) -> bool {
+        let s = p.to_string_lossy();
+        !is_stdin(p) && [".json", ".json.gz"].iter().any(|x| s.ends_with(x))
+    }
+
+    fn read(&self, p: &Path, _: AlignedReads) -> anyhow::Result<Vec<DataSet>> {
+        read_json_groups(p)
+    }
+}
+
+/// fastq_gc JSON streamed on stdin
+struct JsonStdinSource;
+
+impl DatasetSource for JsonStdinSource {
+    fn format(&self) -> InputFormat {
+        InputFormat::Json
+    }
+
+    fn accepts(&self, p: &Path) -> bool {
+        is_stdin(p)
+    }
+
+    fn read(&self, _: &Path, _: AlignedReads) -> anyhow::Result<Vec<DataSet>> {
+        read_json_stdin()
+    }
+}
+
+/// FASTQ files (optionally compressed), counted directly
+struct FastqSource;
+
+impl DatasetSource for FastqSource {
+    fn format(&self) -> InputFormat {
+        InputFormat::Fastq
+    }
+
+    fn accepts(&self, p: &Path) -> bool {
+        let s = p.to_string_lossy();
+        !is_stdin(p)
+            && [".fastq", ".fq", ".fastq.gz", ".fq.gz"]
+                .iter()
+                .any(|x| s.ends_with(x))
+    }
+
+    fn read(&self, p: &Path, _: AlignedReads) -> anyhow::Result<Vec<DataSet>> {
+        read_fastq(p)
+    }
+}
+
+/// CRAM files, decoded by samtools
+struct CramSource;
+
+impl DatasetSource for CramSource {
+    fn format(&self) -> InputFormat {
+        InputFormat::Bam
+    }
+
+    fn accepts(&self, p: &Path) -> bool {
+        !is_stdin(p) && p.to_string_lossy().ends_with(".cram")
+    }
+
+    fn read(&self, p: &Path, reads: AlignedReads) -> anyhow::Result<Vec<DataSet>> {
+        read_cram(p, reads)
+    }
+}
+
+/// BAM files
+struct BamSource;
+
+impl DatasetSource for BamSource {
+    fn format(&self) -> InputFormat {
+        InputFormat::Bam
+    }
+
+    fn accepts(&self, p: &Path) -> bool {
+        !is_stdin(p) && p.to_string_lossy().ends_with(".bam")
+    }
+
+    fn read(&self, p: &Path, reads: AlignedReads) -> anyhow::Result<Vec<DataSet>> {
+        read_bam(p, reads)
+    }
+}
+
+// For each format the generic (file) source should come last as it is used as the fallback
+// when no source accepts the input name
+const SOURCES: [&dyn DatasetSource; 5] = [
+    &JsonStdinSource,
+    &JsonFileSource,
+    &FastqSource,
+    &CramSource,
+    &BamSource,
+];
+
+/// Find source for input p.  With automatic format detection, inputs with unrecognized
+/// names are assumed to be JSON files
+fn find_source(p: &Path, fmt: InputFormat) -> &'static dyn DatasetSource {
+    let candidates = || {
+        SOURCES
+            .iter()
+            .copied()
+            .filter(move |s| fmt == InputFormat::Auto || s.format() == fmt)
+    };
+    candidates()
+        .find(|s| s.accepts(p))
+        .or_else(|| match fmt {
+            InputFormat::Auto => None,
+            _ => candidates().next_back(),
+        })
+        .unwrap_or(&JsonFileSource)
+}
+
+/// Read datasets (one per read group) from input p using the source appropriate for the
+/// input format.  reads selects the reads used from aligned inputs
+pub fn read_dataset(
+    p: &Path,
+    fmt: InputFormat,
+    reads: AlignedReads,
+) -> anyhow::Result<Vec<DataSet>> {
+    find_source(p, fmt).read(p, reads)
+}
+
+/// True if the datasets of input p are counted from reads (FASTQ, BAM or CRAM) rather than
+/// read from fastq_gc JSON
+pub fn is_counted(p: &Path, fmt: InputFormat) -> bool {
+    !is_stdin(p) && find_source(p, fmt).format() != InputFormat::Json
+}
+
+/// True if p is named as a fastq_gc JSON file (optionally compressed)
+#[cfg(feature = "watch")]
+pub fn is_json_file(p: &Path) -> bool {
+    JsonFileSource.accepts(p)
+}
@@ -0,0 +1,306 @@
+//! Cumulative distribution functions used for hypothesis tests.
+//!
+//! The distributions are provided by a [`StatBackend`], selected at compile time.  By default
+//! the Student's t distribution comes from the `stat_functions` crate, with the F and
+//! chi-square distributions computed from the incomplete beta and gamma functions.  Building
+//! with the `statrs` feature uses the `statrs` crate for all distributions instead.
+
+use libm::lgamma;
+use stat_functions::students_t::StudentsT;
+
+use crate::betabin::lbeta;
+
+/// Maximum number of iterations for series and continued fraction evaluations
+const MAX_ITER: usize = 300;
+
+const TINY: f64 = 1.0e-300;
+
+/// Normal quantile for 95% confidence intervals
+pub const Z_95: f64 = 1.959963984540054;
+
+/// Source of cumulative distribution functions.  Arguments are assumed to be valid
+/// (positive degrees of freedom); the functions panic otherwise
+pub trait StatBackend {
+    /// Lower tail probability of Student's t distribution with df degrees of freedom
+    fn students_t_cdf(t: f64, df: f64) -> f64;
+
+    /// Lower tail probability of the F distribution with df1 and df2 degrees of freedom
+    fn f_cdf(x: f64, df1: f64, df2: f64) -> f64;
+
+    /// Lower tail probability of the chi-square distribution with df degrees of freedom
+    fn chi_squared_cdf(x: f64, df: f64) -> f64;
+
+    /// Upper tail probability of the chi-square distribution with df degrees of freedom
+    /// (calculated directly so that small probabilities are accurate)
+    fn chi_squared_sf(x: f64, df: f64) -> f64;
+}
+
+/// Backend using the `stat_functions` crate and the internal special functions
+#[cfg_attr(feature = "statrs", allow(dead_code))]
+pub struct StatFunctions;
+
+impl StatBackend for StatFunctions {
+    fn students_t_cdf(t: f64, df: f64) -> f64 {
+        StudentsT::new(df).expect("Invalid df").pt(t)
+    }
+
+    fn f_cdf(x: f64, df1: f64, df2: f64) -> f64 {
+        assert!(df1 > 0.0 && df2 > 0.0, "Invalid df");
+        if x <= 0.0 {
+            0.0
+        } else {
+            let (a, b) = (0.5 * df1, 0.5 * df2);
+            reg_inc_beta(df1 * x / (df1 * x + df2), a, b, lbeta(a, b))
+        }
+    }
+
+    fn chi_squared_cdf(x: f64, df: f64) -> f64 {
+        assert!(df > 0.0, "Invalid df");
+        reg_lower_gamma(0.5 * df, 0.5 * x)
+    }
+
+    fn chi_squared_sf(x: f64, df: f64) -> f64 {
+        assert!(df > 0.0, "Invalid df");
+        reg_upper_gamma(0.5 * df, 0.5 * x)
+    }
+}
+
+/// Backend using the `statrs` crate
+#[cfg(feature = "statrs")]
+pub struct Statrs;
+
+#[cfg(feature = "statrs")]
+impl StatBackend for Statrs {
+    fn students_t_cdf(t: f64, df: f64) -> f64 {
+        use statrs::distribution::ContinuousCDF;
+        statrs::distribution::StudentsT::new(0.0, 1.0, df)
+            .expect("Invalid df")
+            .cdf(t)
+    }
+
+    fn f_cdf(x: f64, df1: f64, df2: f64) -> f64 {
+        use statrs::distribution::ContinuousCDF;
+        if x <= 0.0 {
+            0.0
+        } else {
+            statrs::distribution::FisherSnedecor::new(df1, df2)
+                .expect("Invalid df")
+                .cdf(x)
+        }
+    }
+
+    fn chi_squared_cdf(x: f64, df: f64) -> f64 {
+        use statrs::distribution::ContinuousCDF;
+        if x <= 0.0 {
+            0.0
+        } else {
+            statrs::distribution::ChiSquared::new(df)
+                .expect("Invalid df")
+                .cdf(x)
+        }
+    }
+
+    fn chi_squared_sf(x: f64, df: f64) -> f64 {
+        use statrs::distribution::ContinuousCDF;
+        if x <= 0.0 {
+            1.0
+        } else {
+            statrs::distribution::ChiSquared::new(df)
+                .expect("Invalid df")
+                .sf(x)
+        }
+    }
+}
+
+#[cfg(not(feature = "statrs"))]
+pub type DefaultBackend = StatFunctions;
+
+#[cfg(feature = "statrs")]
+pub type DefaultBackend = Statrs;
+
+/// Lower tail probability of Student's t distribution using the default backend
+pub fn students_t_cdf(t: f64, df: f64) -> f64 {
+    DefaultBackend::students_t_cdf(t, df)
+}
+
+/// Lower tail probability of the F distribution using the default backend
+#[allow(dead_code)]
+pub fn f_cdf(x: f64, df1: f64, df2: f64) -> f64 {
+    DefaultBackend::f_cdf(x, df1, df2)
+}
+
+/// Lower tail probability of the chi-square distribution using the default backend
+pub fn chi_squared_cdf(x: f64, df: f64) -> f64 {
+    DefaultBackend::chi_squared_cdf(x, df)
+}
+
+/// Upper tail probability of the chi-square distribution using the default backend
+pub fn chi_squared_sf(x: f64, df: f64) -> f64 {
+    DefaultBackend::chi_squared_sf(x, df)
+}
+
+// Continued fraction for the incomplete beta function (modified Lentz's method)
+fn beta_cf(x: f64, a: f64, b: f64) -> f64 {
+    let (qab, qap, qam) = (a + b, a + 1.0, a - 1.0);
+    let mut c = 1.0;
+    let mut d = 1.0 - qab * x / qap;
+    if d.abs() < TINY {
+        d = TINY
+    }
+    d = 1.0 / d;
+    let mut h = d;
+    for m in 1..=MAX_ITER {
+        let m = m as f64;
+        let m2 = 2.0 * m;
+        let aa = m * (b - m) * x / ((qam + m2) * (a + m2));
+        for (i, aa) in [aa, -(a + m) * (qab + m) * x / ((a + m2) * (qap + m2))]
+            .into_iter()
+            .enumerate()
+        {
+            d = 1.0 + aa * d;
+            if d.abs() < TINY {
+                d = TINY
+            }
+            c = 1.0 + aa / c;
+            if c.abs() < TINY {
+                c = TINY
+            }
+            d = 1.0 / d;
+            let del = d * c;
+            h *= del;
+            if i == 1 && (del - 1.0).abs() < f64::EPSILON {
+                return h;
+            }
+        }
+    }
+    h
+}
+
+// y * ln(x), taking 0 * ln(0) as 0
+pub(crate) fn xlogy(y: f64, lnx: f64) -> f64 {
+    if y == 0.0 {
+        0.0
+    } else {
+        y * lnx
+    }
+}
+
+/// Regularized incomplete beta function I_x(a, b), where lbeta_ab = ln B(a, b)
+pub(crate) fn reg_inc_beta(x: f64, a: f64, b: f64, lbeta_ab: f64) -> f64 {
+    if x <= 0.0 {
+        0.0
+    } else if x >= 1.0 {
+        1.0
+    } else {
+        let front = (xlogy(a, x.ln()) + xlogy(b, (1.0 - x).ln()) - lbeta_ab).exp();
+        if x < (a + 1.0) / (a + b + 2.0) {
+            front * beta_cf(x, a, b) / a
+        } else {
+            1.0 - front * beta_cf(1.0 - x, b, a) / b
+        }
+    }
+}
+
+/// Regularized lower incomplete gamma function P(a, x)
+#[cfg_attr(feature = "statrs", allow(dead_code))]
+pub(crate) fn reg_lower_gamma(a: f64, x: f64) -> f64 {
+    reg_gamma(a, x).0
+}
+
+/// Regularized upper incomplete gamma function Q(a, x) = 1 - P(a, x)
+#[cfg_attr(feature = "statrs", allow(dead_code))]
+pub(crate) fn reg_upper_gamma(a: f64, x: f64) -> f64 {
+    reg_gamma(a, x).1
+}
+
+// Regularized lower and upper incomplete gamma functions (P(a, x), Q(a, x)), using the series
+// expansion for P with x < a + 1 and the continued fraction for Q otherwise, so that the
+// smaller of the two is calculated directly without loss of precision
+#[cfg_attr(feature = "statrs", allow(dead_code))]
+fn reg_gamma(a: f64, x: f64) -> (f64, f64) {
+    if x <= 0.0 {
+        return (0.0, 1.0);
+    }
+    let front = (a * x.ln() - x - lgamma(a)).exp();
+    if x < a + 1.0 {
+        let mut ap = a;
+        let mut del = 1.0 / a;
+        let mut sum = del;
+        for _ in 0..MAX_ITER {
+            ap += 1.0;
+            del *= x / ap;
+            sum += del;
+            if del.abs() < sum.abs() * f64::EPSILON {
+                break;
+            }
+        }
+        let p = sum * front;
+        (p, 1.0 - p)
+    } else {
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / TINY;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..=MAX_ITER {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < TINY {
+                d = TINY
+            }
+            c = b + an / c;
+            if c.abs() < TINY {
+                c = TINY
+            }
+            d = 1.0 / d;
+            let del = d * c;
+            h *= del;
+            if (del - 1.0).abs() < f64::EPSILON {
+                break;
+            }
+        }
+        let q = front * h;
+        (1.0 - q, q)
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn chi_squared_test() {
+        // Two degrees of freedom is exponential with mean 2
+        for x in [0.1f64, 1.0, 3.0, 10.0] {
+            let p = 1.0 - (-0.5 * x).exp();
+            assert!((StatFunctions::chi_squared_cdf(x, 2.0) - p).abs() < 1.0e-12);
+        }
+        assert!((chi_squared_cdf(3.841458820694124, 1.0) - 0.95).abs() < 1.0e-10);
+        assert!((chi_squared_cdf(18.307038053275146, 10.0) - 0.95).abs() < 1.0e-10);
+        assert_eq!(chi_squared_cdf(0.0, 3.0), 0.0);
+        // Small upper tail probabilities are not lost to rounding
+        let q = chi_squared_sf(100.0, 2.0) / (-50.0f64).exp();
+        assert!((q - 1.0).abs() < 1.0e-10);
+    }
+
+    #[test]
+    fn f_test() {
+        // Median of F(d, d) is 1
+        assert!((f_cdf(1.0, 7.0, 7.0) - 0.5).abs() < 1.0e-12);
+        assert!((f_cdf(3.885293834652391, 2.0, 12.0) - 0.95).abs() < 1.0e-10);
+        // F(1, d) is the square of t(d)
+        let t = 1.7;
+        let p = 2.0 * students_t_cdf(t, 9.0) - 1.0;
+        assert!((f_cdf(t * t, 1.0, 9.0) - p).abs() < 1.0e-10);
+    }
+
+    #[test]
+    fn students_t_test() {
+        assert!((students_t_cdf(0.0, 5.0) - 0.5).abs() < 1.0e-12);
+        assert!((students_t_cdf(2.2281388519649385, 10.0) - 0.975).abs() < 1.0e-10);
+        // t(1) is Cauchy
+        let x = 2.5f64;
+        let p = 0.5 + x.atan() / std::f64::consts::PI;
+        assert!((students_t_cdf(x, 1.0) - p).abs() < 1.0e-10);
+    }
+}
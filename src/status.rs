@@ -0,0 +1,118 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+use serde::Serialize;
+
+use crate::temp_dir::atomic_write;
+
+/// Input file that was skipped (with --skip-errors) as it could not be processed
+#[derive(Serialize)]
+struct FailedInput {
+    path: PathBuf,
+    reason: String,
+}
+
+#[derive(Default, Serialize)]
+struct StatusInner {
+    errors: Vec<String>,
+    #[serde(skip)]
+    qc_failures: usize,
+    failed_inputs: Vec<FailedInput>,
+    outputs: Vec<PathBuf>,
+}
+
+/// Collects information on the run (errors, QC failures, skipped inputs, output files) from the
+/// different threads so that it can be reported in a machine-readable status file
+#[derive(Default)]
+pub struct RunStatus {
+    inner: Mutex<StatusInner>,
+}
+
+#[derive(Serialize)]
+struct StatusFile<'a> {
+    exit_status: i32,
+    error_count: usize,
+    qc_failure_count: usize,
+    failed_input_count: usize,
+    #[serde(flatten)]
+    inner: &'a StatusInner,
+}
+
+impl RunStatus {
+    pub fn add_error(&self, e: &anyhow::Error) {
+        self.inner.lock().unwrap().errors.push(format!("{e:#}"))
+    }
+
+    pub fn add_qc_failure(&self) {
+        self.inner.lock().unwrap().qc_failures += 1
+    }
+
+    pub fn qc_failures(&self) -> usize {
+        self.inner.lock().unwrap().qc_failures
+    }
+
+    /// Record input file p as skipped because of error e
+    pub fn add_failed_input<P: AsRef<Path>>(&self, p: P, e: &anyhow::Error) {
+        self.inner.lock().unwrap().failed_inputs.push(FailedInput {
+            path: p.as_ref().to_owned(),
+            reason: format!("{e:#}"),
+        })
+    }
+
+    pub fn failed_inputs(&self) -> usize {
+        self.inner.lock().unwrap().failed_inputs.len()
+    }
+
+    /// Write TSV file with the skipped input files and the reasons for failure
+    pub fn write_failed_inputs<P: AsRef<Path>>(&self, p: P) -> anyhow::Result<()> {
+        let p = p.as_ref();
+        let mut inner = self.inner.lock().unwrap();
+        let mut wrt = CompressIo::new().path(p).bufwriter().with_context(|| {
+            format!(
+                "Could not open failed inputs file {} for output",
+                p.display()
+            )
+        })?;
+        writeln!(wrt, "Path\tReason")?;
+        for f in inner.failed_inputs.iter() {
+            // Tabs and newlines in the error chain would break the TSV format
+            let reason = f.reason.replace(['\t', '\n'], " ");
+            writeln!(wrt, "{}\t{reason}", f.path.display())?
+        }
+        inner.outputs.push(p.to_owned());
+        Ok(())
+    }
+
+    pub fn add_output<P: AsRef<Path>>(&self, p: P) {
+        self.inner
+            .lock()
+            .unwrap()
+            .outputs
+            .push(p.as_ref().to_owned())
+    }
+
+    pub fn write_json<P: AsRef<Path>>(&self, p: P, exit_status: i32) -> anyhow::Result<()> {
+        let p = p.as_ref();
+        let inner = self.inner.lock().unwrap();
+        let status = StatusFile {
+            exit_status,
+            error_count: inner.errors.len(),
+            qc_failure_count: inner.qc_failures,
+            failed_input_count: inner.failed_inputs.len(),
+            inner: &inner,
+        };
+        // Monitoring tools polling the status file should never see a partial file
+        atomic_write(p, |tmp| {
+            let wrt = CompressIo::new().path(tmp).bufwriter().with_context(|| {
+                format!("Could not open status file {} for output", tmp.display())
+            })?;
+            serde_json::to_writer_pretty(wrt, &status)
+                .with_context(|| format!("Error writing status file {}", tmp.display()))
+        })
+    }
+}
@@ -0,0 +1,204 @@
+//! Stream mode (--stream-socket): datasets are pushed by fastq_gc over a Unix domain socket
+//! rather than read from JSON files, and are analyzed (or merged) as they arrive.
+//!
+//! Any number of connections may be open at once.  On each connection the sender writes
+//! frames consisting of a 4 byte big-endian length followed by that many bytes of fastq_gc
+//! JSON (one record, possibly with several read groups).  Every frame is answered with a
+//! frame holding a JSON object: `{"record": n, "datasets": k}` if the record was accepted, or
+//! `{"record": n, "error": "<message>"}` if not, so the sender must read the replies.  A zero
+//! length frame ends the run: no new connections are accepted, and once all open connections
+//! have closed the remaining results (e.g., merged datasets) are output and gc_collect exits.
+//!
+//! Records are numbered from 1 in order of arrival, and their datasets are given the paths
+//! stream<n>.json.  Invalid records end the run with an error unless --skip-errors is set, in
+//! which case they are listed in failed_inputs.tsv.  Results are not cached.
+
+use std::{
+    fs,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::Context;
+use crossbeam_channel::Sender;
+use crossbeam_utils::thread;
+use serde_json::{json, Value};
+
+use crate::{
+    cli::Config,
+    read::{read_json_reader, DataSet},
+    status::RunStatus,
+};
+
+// Largest frame accepted
+const MAX_FRAME: usize = 1 << 30;
+
+// Interval between checks for new connections or the end of the run
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct StreamState {
+    records: AtomicUsize,
+    end: AtomicBool,
+}
+
+// Read frame from rdr.  Returns None if the connection was closed at a frame boundary
+fn read_frame<R: Read>(rdr: &mut R) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    let mut k = 0;
+    while k < len.len() {
+        match rdr.read(&mut len[k..]) {
+            Ok(0) if k == 0 => return Ok(None),
+            Ok(0) => return Err(anyhow!("Connection closed in frame header")),
+            Ok(n) => k += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let n = u32::from_be_bytes(len) as usize;
+    if n > MAX_FRAME {
+        return Err(anyhow!("Frame of {n} bytes exceeds the maximum size"));
+    }
+    let mut buf = vec![0; n];
+    rdr.read_exact(&mut buf)
+        .with_context(|| "Connection closed in frame")?;
+    Ok(Some(buf))
+}
+
+fn write_frame<W: Write>(wrt: &mut W, v: &Value) -> io::Result<()> {
+    let s = v.to_string();
+    wrt.write_all(&(s.len() as u32).to_be_bytes())?;
+    wrt.write_all(s.as_bytes())?;
+    wrt.flush()
+}
+
+// Replies are not essential to the run, so a sender that has gone away is only noted
+fn reply(s: &mut UnixStream, conn: usize, v: &Value) {
+    if let Err(e) = write_frame(s, v) {
+        debug!("Could not send reply on stream connection {conn}: {e}")
+    }
+}
+
+// Read records from connection s, sending their datasets (with the record number) to sd
+fn connection_thread(
+    cfg: &Config,
+    conn: usize,
+    mut s: UnixStream,
+    sd: Sender<(usize, DataSet)>,
+    state: &StreamState,
+    status: &RunStatus,
+) -> anyhow::Result<()> {
+    debug!("Stream connection {conn} opened");
+    let mut rdr = BufReader::new(s.try_clone()?);
+    while let Some(frame) =
+        read_frame(&mut rdr).with_context(|| format!("Error reading stream connection {conn}"))?
+    {
+        if frame.is_empty() {
+            info!("End of run requested on stream connection {conn}");
+            state.end.store(true, Ordering::Relaxed);
+            reply(&mut s, conn, &json!({"end": true}));
+            break;
+        }
+        let n = state.records.fetch_add(1, Ordering::Relaxed) + 1;
+        let p = PathBuf::from(format!("stream{n}.json"));
+        match read_json_reader(&frame[..], &p) {
+            Ok(v) => {
+                trace!("Received record {n} with {} datasets", v.len());
+                reply(&mut s, conn, &json!({"record": n, "datasets": v.len()}));
+                for d in v {
+                    sd.send((n, d))
+                        .map_err(|_| anyhow!("Error sending dataset to analysis threads"))?
+                }
+            }
+            Err(e) => {
+                reply(
+                    &mut s,
+                    conn,
+                    &json!({"record": n, "error": format!("{e:#}")}),
+                );
+                if cfg.skip_errors() {
+                    warn!("Skipping record {n}: {e:#}");
+                    status.add_failed_input(&p, &e)
+                } else {
+                    state.end.store(true, Ordering::Relaxed);
+                    return Err(
+                        e.context(format!("Invalid record {n} on stream connection {conn}"))
+                    );
+                }
+            }
+        }
+    }
+    debug!("Stream connection {conn} closed");
+    Ok(())
+}
+
+/// Receive datasets on the stream socket until the end of the run, sending them (with their
+/// record number) to sd.  The socket file is removed afterwards
+pub fn receive(
+    cfg: &Config,
+    sd: Sender<(usize, DataSet)>,
+    status: &RunStatus,
+) -> anyhow::Result<()> {
+    let p = cfg.stream_socket().expect("Missing stream socket");
+    let listener = UnixListener::bind(p)
+        .with_context(|| format!("Could not listen on stream socket {}", p.display()))?;
+    info!("Listening for datasets on {}", p.display());
+    let state = StreamState::default();
+
+    let res = thread::scope(|scope| {
+        // The listener is polled so that the end of the run is noticed without a further
+        // connection
+        listener.set_nonblocking(true)?;
+        let mut tasks = Vec::new();
+        while !state.end.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((s, _)) => {
+                    s.set_nonblocking(false)?;
+                    let (conn, sd1, st) = (tasks.len() + 1, sd.clone(), &state);
+                    tasks.push(
+                        scope.spawn(move |_| connection_thread(cfg, conn, s, sd1, st, status)),
+                    )
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(POLL_INTERVAL),
+                Err(e) => return Err(anyhow!("Error accepting stream connection: {e}")),
+            }
+        }
+        debug!("No longer accepting stream connections");
+        tasks
+            .into_iter()
+            .try_for_each(|t| t.join().expect("Error joining stream connection thread"))
+    })
+    .expect("Error in scope generation");
+
+    if let Err(e) = fs::remove_file(p) {
+        warn!("Could not remove stream socket {}: {e}", p.display())
+    }
+    info!(
+        "Stream closed after {} records",
+        state.records.load(Ordering::Relaxed)
+    );
+    res
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn frame_test() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &json!({"record": 1})).unwrap();
+        write_frame(&mut buf, &json!({})).unwrap();
+        assert_eq!(&buf[..4], &[0, 0, 0, 12]);
+        let mut rdr = &buf[..];
+        assert_eq!(read_frame(&mut rdr).unwrap().unwrap(), br#"{"record":1}"#);
+        assert_eq!(read_frame(&mut rdr).unwrap().unwrap(), b"{}");
+        assert!(read_frame(&mut rdr).unwrap().is_none());
+        // Truncated frames
+        assert!(read_frame(&mut &buf[..2]).is_err());
+        assert!(read_frame(&mut &buf[..10]).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Summary of a whole run, accumulated by the output thread as results are written.
+
+use std::{collections::BTreeMap, path::Path};
+
+use serde_json::{json, Value};
+
+use crate::{
+    process::DataResults,
+    read::DataSummary,
+    temp_dir::atomic_write,
+    thresholds::{QcStatus, Thresholds},
+};
+
+#[derive(Default)]
+pub struct RunSummary {
+    n_datasets: usize,
+    mean_gc: Vec<f64>,
+    // Largest KL distance with the path of its dataset
+    worst_kl: Option<(f64, String)>,
+    // Datasets with kmer coverage, and those exceeding the warn and fail dispersion thresholds
+    n_coverage: usize,
+    dispersion_warn: usize,
+    dispersion_fail: usize,
+    bisulfite: BTreeMap<String, usize>,
+    trim: BTreeMap<usize, usize>,
+    min_qual: BTreeMap<u8, usize>,
+}
+
+// Quantile q of sorted v by linear interpolation
+fn quantile(v: &[f64], q: f64) -> f64 {
+    let x = q * (v.len() - 1) as f64;
+    let (i, f) = (x.floor() as usize, x.fract());
+    if i + 1 < v.len() {
+        v[i] * (1.0 - f) + v[i + 1] * f
+    } else {
+        v[i]
+    }
+}
+
+// Values with their counts as "value (count)"
+fn value_counts<T: std::fmt::Display>(m: &BTreeMap<T, usize>) -> String {
+    m.iter()
+        .map(|(k, n)| format!("{k} ({n})"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl RunSummary {
+    pub fn add(&mut self, d: &DataSummary, res: &DataResults, thresholds: Option<&Thresholds>) {
+        self.n_datasets += 1;
+        if res.mean_gc().is_finite() {
+            self.mean_gc.push(res.mean_gc())
+        }
+        if let Some(kl) = res.kl_distance() {
+            if self.worst_kl.as_ref().map(|(x, _)| kl > *x).unwrap_or(true) {
+                self.worst_kl = Some((kl, d.path().display().to_string()))
+            }
+        }
+        if let Some(kc) = res.kmer_coverage() {
+            self.n_coverage += 1;
+            match thresholds.and_then(|t| t.dispersion_status(kc.dispersion())) {
+                Some(QcStatus::Fail) => self.dispersion_fail += 1,
+                Some(QcStatus::Warn) => self.dispersion_warn += 1,
+                _ => (),
+            }
+        }
+        *self.bisulfite.entry(d.bisulfite().to_string()).or_insert(0) += 1;
+        *self.trim.entry(d.trim()).or_insert(0) += 1;
+        *self.min_qual.entry(d.min_qual()).or_insert(0) += 1
+    }
+
+    // Min, lower quartile, median, upper quartile and max of the mean gc
+    fn gc_distribution(&self) -> Option<[f64; 5]> {
+        if self.mean_gc.is_empty() {
+            return None;
+        }
+        let mut v = self.mean_gc.clone();
+        v.sort_unstable_by(|a, b| a.total_cmp(b));
+        Some([0.0, 0.25, 0.5, 0.75, 1.0].map(|q| quantile(&v, q)))
+    }
+
+    /// Log the summary (at info level)
+    pub fn log(&self, thresholds: Option<&Thresholds>) {
+        info!("Run summary: {} dataset(s)", self.n_datasets);
+        if let Some(g) = self.gc_distribution() {
+            info!(
+                "  Mean gc: min {:.4}, quartiles {:.4} {:.4} {:.4}, max {:.4}",
+                g[0], g[1], g[2], g[3], g[4]
+            )
+        }
+        if let Some((kl, p)) = self.worst_kl.as_ref() {
+            info!("  Largest KL distance: {kl:.5} ({p})")
+        }
+        if self.n_coverage > 0 && thresholds.is_some() {
+            info!(
+                "  Coverage dispersion: {} of {} dataset(s) exceed the warn threshold, {} exceed the fail threshold",
+                self.dispersion_warn, self.n_coverage, self.dispersion_fail
+            )
+        }
+        let bs: Vec<_> = self
+            .bisulfite
+            .iter()
+            .map(|(k, n)| format!("{k} {n}"))
+            .collect();
+        if !bs.is_empty() {
+            info!("  Bisulfite types: {}", bs.join(", "))
+        }
+        // Processing parameters are only logged if they vary
+        if self.trim.len() > 1 {
+            info!("  Trim values: {}", value_counts(&self.trim))
+        }
+        if self.min_qual.len() > 1 {
+            info!("  Min-qual values: {}", value_counts(&self.min_qual))
+        }
+    }
+
+    pub fn json(&self) -> Value {
+        let gc = self.gc_distribution().map(|g| {
+            json!({"min": g[0], "lower_quartile": g[1], "median": g[2], "upper_quartile": g[3], "max": g[4]})
+        });
+        json!({
+            "n_datasets": self.n_datasets,
+            "mean_gc": gc,
+            "worst_kl_distance": self.worst_kl.as_ref().map(|(kl, p)| json!({"kl_distance": kl, "dataset": p})),
+            "coverage_dispersion": {
+                "n_datasets": self.n_coverage,
+                "exceeding_warn": self.dispersion_warn,
+                "exceeding_fail": self.dispersion_fail,
+            },
+            "bisulfite_types": self.bisulfite,
+            "trim": self.trim,
+            "min_qual": self.min_qual,
+        })
+    }
+
+    /// Write the summary as JSON to p
+    pub fn write_json(&self, p: &Path) -> anyhow::Result<()> {
+        atomic_write(p, |tmp| {
+            std::fs::write(tmp, format!("{:#}\n", self.json()))
+                .map_err(|e| anyhow!("Error writing summary file {}: {e}", tmp.display()))
+        })
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn quantile_test() {
+        let v = [0.1, 0.2, 0.4, 0.8];
+        assert_eq!(quantile(&v, 0.0), 0.1);
+        assert!((quantile(&v, 0.5) - 0.3).abs() < 1e-12);
+        assert_eq!(quantile(&v, 1.0), 0.8);
+        assert_eq!(quantile(&[0.5], 0.25), 0.5);
+    }
+}
@@ -0,0 +1,229 @@
+//! Management of temporary files.
+//!
+//! Scratch files for a run are kept in a run directory (`gc_collect_<pid>`) created on first
+//! use under the temporary directory (from --tmpdir, otherwise TMPDIR or the system default).
+//! The run directory is removed when the [`TempDir`] is dropped.  Run directories left behind
+//! by processes that are no longer running (i.e., after a crash) are removed when a new run
+//! directory is created.
+//!
+//! Files that should only appear once complete are written with [`atomic_write`] to a
+//! temporary file in the destination directory (so that the final rename does not cross file
+//! systems) which is renamed into place.  Stale temporary files from crashed processes can be
+//! removed with [`remove_stale_files`].
+
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+
+const RUN_DIR_PREFIX: &str = "gc_collect_";
+const TEMP_FILE_PREFIX: &str = ".gc_collect_tmp.";
+
+/// Age after which temporary files are considered stale if it can not be determined whether
+/// the process that created them is still running
+const STALE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// The process with id pid is running.  Only determined on Linux (from /proc); elsewhere the
+// process is assumed to be running
+fn process_running(pid: u32) -> Option<bool> {
+    if cfg!(target_os = "linux") {
+        Some(Path::new("/proc").join(pid.to_string()).exists())
+    } else {
+        None
+    }
+}
+
+// Temporary file or directory p created by process pid is stale
+fn is_stale(p: &Path, pid: u32) -> bool {
+    if pid == std::process::id() {
+        return false;
+    }
+    match process_running(pid) {
+        Some(running) => !running,
+        None => fs::metadata(p)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| SystemTime::now().duration_since(t).ok())
+            .map(|age| age > STALE_AGE)
+            .unwrap_or(false),
+    }
+}
+
+/// Per run directory for scratch files
+pub struct TempDir {
+    base: PathBuf,
+    dir: OnceLock<PathBuf>,
+    counter: AtomicUsize,
+}
+
+impl TempDir {
+    /// Temporary directory under base, or under the system temporary directory (which
+    /// respects TMPDIR) if base is None.  No directory is created until it is needed
+    pub fn new<P: AsRef<Path>>(base: Option<P>) -> Self {
+        Self {
+            base: base
+                .map(|p| p.as_ref().to_owned())
+                .unwrap_or_else(std::env::temp_dir),
+            dir: OnceLock::new(),
+            counter: AtomicUsize::new(0),
+        }
+    }
+
+    /// Run directory, created on the first call (when stale run directories are also removed)
+    pub fn path(&self) -> anyhow::Result<&Path> {
+        if let Some(d) = self.dir.get() {
+            return Ok(d);
+        }
+        fs::create_dir_all(&self.base).with_context(|| {
+            format!(
+                "Could not create temporary directory {}",
+                self.base.display()
+            )
+        })?;
+        remove_stale_run_dirs(&self.base);
+        let d = self
+            .base
+            .join(format!("{RUN_DIR_PREFIX}{}", std::process::id()));
+        fs::create_dir_all(&d)
+            .with_context(|| format!("Could not create temporary directory {}", d.display()))?;
+        debug!("Using temporary directory {}", d.display());
+        Ok(self.dir.get_or_init(|| d))
+    }
+
+    /// Path for a new scratch file with the given suffix in the run directory.  The file is
+    /// not created
+    pub fn file(&self, suffix: &str) -> anyhow::Result<PathBuf> {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        Ok(self.path()?.join(format!("tmp{n}{suffix}")))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Some(d) = self.dir.get() {
+            if let Err(e) = fs::remove_dir_all(d) {
+                warn!("Could not remove temporary directory {}: {e}", d.display())
+            }
+        }
+    }
+}
+
+// Remove run directories in base from processes that are no longer running
+fn remove_stale_run_dirs(base: &Path) {
+    let Ok(rd) = fs::read_dir(base) else {
+        return;
+    };
+    for e in rd.flatten() {
+        let name = e.file_name();
+        let Some(pid) = name
+            .to_str()
+            .and_then(|s| s.strip_prefix(RUN_DIR_PREFIX))
+            .and_then(|s| s.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let p = e.path();
+        if p.is_dir() && is_stale(&p, pid) {
+            info!("Removing stale temporary directory {}", p.display());
+            if let Err(e) = fs::remove_dir_all(&p) {
+                warn!("Could not remove {}: {e}", p.display())
+            }
+        }
+    }
+}
+
+// Process id of the creator of a temporary file from atomic_write
+fn temp_file_pid(name: &str) -> Option<u32> {
+    name.strip_prefix(TEMP_FILE_PREFIX)?
+        .split_once('.')?
+        .0
+        .parse()
+        .ok()
+}
+
+/// Remove temporary files left in dir by atomic writes from processes that are no longer
+/// running.  Returns the number of files removed
+pub fn remove_stale_files(dir: &Path) -> usize {
+    let Ok(rd) = fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut n = 0;
+    for e in rd.flatten() {
+        let p = e.path();
+        let stale = e
+            .file_name()
+            .to_str()
+            .and_then(temp_file_pid)
+            .map(|pid| is_stale(&p, pid))
+            .unwrap_or(false);
+        if stale && fs::remove_file(&p).is_ok() {
+            n += 1
+        }
+    }
+    if n > 0 {
+        info!("Removed {n} stale temporary files from {}", dir.display())
+    }
+    n
+}
+
+/// Write the file p by calling f with the path of a temporary file in the same directory,
+/// which is then renamed to p.  The temporary file keeps the name (and so the extension) of p
+/// after a hidden prefix.  If f fails, the temporary file is removed and p is unchanged
+pub fn atomic_write<F>(p: &Path, f: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&Path) -> anyhow::Result<()>,
+{
+    let name = p
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid output file name {}", p.display()))?;
+    let mut tmp_name = OsString::from(format!("{TEMP_FILE_PREFIX}{}.", std::process::id()));
+    tmp_name.push(name);
+    let tmp = p.with_file_name(tmp_name);
+    debug!("Writing {} via {}", p.display(), tmp.display());
+    if let Err(e) = f(&tmp) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    fs::rename(&tmp, p).with_context(|| format!("Error renaming temporary file to {}", p.display()))
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn temp_dir_test() {
+        let base = std::env::temp_dir().join(format!("gc_collect_test_{}", std::process::id()));
+        let t = TempDir::new(Some(&base));
+        let f = t.file(".txt").unwrap();
+        assert!(f.starts_with(base.join(format!("{RUN_DIR_PREFIX}{}", std::process::id()))));
+        let run_dir = t.path().unwrap().to_owned();
+        assert!(run_dir.is_dir());
+
+        let out = base.join("out.txt");
+        atomic_write(&out, |p| {
+            assert_eq!(
+                temp_file_pid(&p.file_name().unwrap().to_string_lossy()),
+                Some(std::process::id())
+            );
+            Ok(fs::write(p, "x")?)
+        })
+        .unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "x");
+        assert!(atomic_write(&out, |_| Err(anyhow!("failed"))).is_err());
+        assert_eq!(fs::read_to_string(&out).unwrap(), "x");
+        assert_eq!(remove_stale_files(&base), 0);
+
+        drop(t);
+        assert!(!run_dir.exists());
+        let _ = fs::remove_dir_all(&base);
+    }
+}
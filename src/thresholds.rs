@@ -0,0 +1,153 @@
+use std::{fmt, io::Read, path::Path};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::process::DataResults;
+
+/// QC acceptance criteria.  Criteria that are not set, or that can not be evaluated for a
+/// dataset (i.e., slopes without --regression, coverage without kmer counts), are ignored
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Criteria {
+    max_kl_distance: Option<f64>,
+    max_abs_slope: Option<f64>,
+    min_median_coverage: Option<f64>,
+    max_dispersion: Option<f64>,
+}
+
+impl Criteria {
+    // Returns true if the results meet all criteria
+    fn check(&self, res: &DataResults) -> bool {
+        let max = |lim: Option<f64>, x: Option<f64>| match (lim, x) {
+            (Some(l), Some(x)) => x <= l,
+            _ => true,
+        };
+        let kc = res.kmer_coverage();
+        let max_slope = res.regression().map(|v| {
+            v.iter()
+                .map(|r| r.slope().estimate().abs())
+                .fold(0.0, f64::max)
+        });
+        max(self.max_kl_distance, res.kl_distance())
+            && max(self.max_abs_slope, max_slope)
+            && max(self.max_dispersion, kc.map(|k| k.dispersion()))
+            && match (self.min_median_coverage, kc) {
+                (Some(l), Some(k)) => k.median().get() >= l,
+                _ => true,
+            }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QcStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for QcStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Pass => "PASS",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// QC thresholds with separate criteria for failure and warnings.
+///
+/// Read from JSON (`{"fail": {"max_kl_distance": 0.1}, "warn": {...}}`) or, for files with a
+/// .toml extension, from a TOML file with `[fail]` and `[warn]` tables of `key = value` lines.
+/// Criteria given outside of a table are taken as failure criteria.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Thresholds {
+    #[serde(default)]
+    fail: Criteria,
+    #[serde(default)]
+    warn: Criteria,
+}
+
+// Parse the subset of TOML used for threshold files (tables containing numeric values)
+fn parse_toml(s: &str) -> anyhow::Result<Value> {
+    let mut root = Map::new();
+    let mut table: Option<String> = None;
+    for (ix, line) in s.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(t) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let t = t.trim().to_owned();
+            root.entry(t.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+            table = Some(t);
+            continue;
+        }
+        let (k, v) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected key = value at line {}", ix + 1))?;
+        let x = v
+            .trim()
+            .parse::<f64>()
+            .with_context(|| format!("Expected numeric value at line {}", ix + 1))?;
+        root.entry(table.as_deref().unwrap_or("fail"))
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("Value and table with same name at line {}", ix + 1))?
+            .insert(k.trim().to_owned(), Value::from(x));
+    }
+    Ok(Value::Object(root))
+}
+
+impl Thresholds {
+    pub fn from_file<P: AsRef<Path>>(p: P) -> anyhow::Result<Self> {
+        let p = p.as_ref();
+        let mut rdr = CompressIo::new()
+            .path(p)
+            .bufreader()
+            .with_context(|| format!("Could not open thresholds file {} for input", p.display()))?;
+        let mut s = String::new();
+        rdr.read_to_string(&mut s)
+            .with_context(|| format!("Error reading thresholds file {}", p.display()))?;
+
+        let v = if p.extension().map(|e| e == "toml").unwrap_or(false) {
+            parse_toml(&s)?
+        } else {
+            serde_json::from_str(&s)
+                .with_context(|| format!("Error parsing JSON from {}", p.display()))?
+        };
+        serde_json::from_value(v)
+            .with_context(|| format!("Error parsing thresholds file {}", p.display()))
+    }
+
+    pub fn evaluate(&self, res: &DataResults) -> QcStatus {
+        if !self.fail.check(res) {
+            QcStatus::Fail
+        } else if !self.warn.check(res) {
+            QcStatus::Warn
+        } else {
+            QcStatus::Pass
+        }
+    }
+
+    /// Status from the dispersion criteria alone for a kmer coverage dispersion of d.  Returns
+    /// None if no dispersion criteria are set
+    pub fn dispersion_status(&self, d: f64) -> Option<QcStatus> {
+        let (fail, warn) = (self.fail.max_dispersion, self.warn.max_dispersion);
+        if fail.is_none() && warn.is_none() {
+            None
+        } else if fail.map(|l| d > l).unwrap_or(false) {
+            Some(QcStatus::Fail)
+        } else if warn.map(|l| d > l).unwrap_or(false) {
+            Some(QcStatus::Warn)
+        } else {
+            Some(QcStatus::Pass)
+        }
+    }
+}
@@ -1,6 +1,7 @@
-use std::fmt;
+use std::{borrow::Cow, fmt, path::Path, time::SystemTime};
 
 use clap::{builder::PossibleValue, ArgMatches, ValueEnum};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 /// LogLevel
 ///
@@ -86,3 +87,239 @@ pub fn init_log(m: &ArgMatches) {
         .init()
         .unwrap();
 }
+
+/// Escape a free text value (sample name, contig name, file path etc.) for TSV output.
+///
+/// Backslash, tab, newline and carriage return are written as `\\`, `\t`, `\n` and `\r`
+/// respectively so that each value occupies exactly one field; all other characters
+/// (including spaces) are written unchanged
+pub fn tsv_escape(s: &str) -> Cow<'_, str> {
+    if !s.contains(['\\', '\t', '\n', '\r']) {
+        return Cow::Borrowed(s);
+    }
+    let mut t = String::with_capacity(s.len() + 4);
+    for c in s.chars() {
+        match c {
+            '\\' => t.push_str("\\\\"),
+            '\t' => t.push_str("\\t"),
+            '\n' => t.push_str("\\n"),
+            '\r' => t.push_str("\\r"),
+            _ => t.push(c),
+        }
+    }
+    Cow::Owned(t)
+}
+
+/// Path escaped for TSV output.  Non UTF-8 sequences are replaced by U+FFFD
+pub fn tsv_path(p: &Path) -> String {
+    tsv_escape(&p.to_string_lossy()).into_owned()
+}
+
+/// Base64 encoding (standard alphabet with padding)
+pub fn base64_encode(buf: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut s = String::with_capacity(4 * buf.len().div_ceil(3));
+    for chunk in buf.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let x = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | (b[2] as u32);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(ALPHABET[((x >> (18 - 6 * i)) & 0x3f) as usize] as char)
+            } else {
+                s.push('=')
+            }
+        }
+    }
+    s
+}
+
+// Civil date (year, month, day) from days since the epoch (H. Hinnant's algorithm)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + (m <= 2) as i64, m as u32, d as u32)
+}
+
+// Days since the epoch from civil date (inverse of civil_from_days)
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = y - (m <= 2) as i64;
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Run date of a dataset, with or without a time of day (UTC).  Dates are parsed from the
+/// formats found in LIMS exports and run folders so that dates given in different formats
+/// compare correctly, and are output in ISO-8601 format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RunDate {
+    secs: i64,
+    has_time: bool,
+}
+
+impl RunDate {
+    pub fn from_system_time(t: SystemTime) -> Self {
+        let secs = t
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        Self {
+            secs,
+            has_time: true,
+        }
+    }
+
+    // Date from the date part of a string: YYYY-MM-DD, YYYY/MM/DD, M/D/YYYY, YYYYMMDD or
+    // YYMMDD (as in Illumina run folder names)
+    fn parse_date(s: &str) -> Option<i64> {
+        let num = |x: &str| x.parse::<u32>().ok();
+        let (y, m, d) = if s.contains(['-', '/']) {
+            let v: Vec<_> = s.split(['-', '/']).collect();
+            if v.len() != 3 {
+                return None;
+            }
+            if v[0].len() == 4 {
+                (num(v[0])?, num(v[1])?, num(v[2])?)
+            } else if v[2].len() == 4 && s.contains('/') {
+                (num(v[2])?, num(v[0])?, num(v[1])?)
+            } else {
+                return None;
+            }
+        } else if s.len() == 8 && s.bytes().all(|c| c.is_ascii_digit()) {
+            (num(&s[..4])?, num(&s[4..6])?, num(&s[6..])?)
+        } else if s.len() == 6 && s.bytes().all(|c| c.is_ascii_digit()) {
+            (2000 + num(&s[..2])?, num(&s[2..4])?, num(&s[4..])?)
+        } else {
+            return None;
+        };
+        let days = days_from_civil(y as i64, m, d);
+        // Rejects out of range months and days
+        (civil_from_days(days) == (y as i64, m, d)).then_some(days)
+    }
+
+    // Seconds from the time part of a string: HH:MM[:SS[.fff]] followed by Z, an offset
+    // (+HH:MM or -HH:MM) or nothing (taken as UTC)
+    fn parse_time(s: &str) -> Option<i64> {
+        let (t, offset) = if let Some(t) = s.strip_suffix('Z') {
+            (t, 0)
+        } else if let Some(i) = s.rfind(['+', '-']) {
+            let (h, m) = s[i + 1..].split_once(':').unwrap_or((&s[i + 1..], "0"));
+            let off = h.parse::<i64>().ok()? * 3600 + m.parse::<i64>().ok()? * 60;
+            (&s[..i], if &s[i..i + 1] == "-" { -off } else { off })
+        } else {
+            (s, 0)
+        };
+        let v: Vec<_> = t.split(':').collect();
+        if !(2..=3).contains(&v.len()) {
+            return None;
+        }
+        let h = v[0].parse::<i64>().ok().filter(|x| *x < 24)?;
+        let m = v[1].parse::<i64>().ok().filter(|x| *x < 60)?;
+        let sec = match v.get(2) {
+            Some(x) => x.parse::<f64>().ok().filter(|x| *x < 61.0)? as i64,
+            None => 0,
+        };
+        Some(h * 3600 + m * 60 + sec - offset)
+    }
+}
+
+impl std::str::FromStr for RunDate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (d, t) = match s.split_once(['T', ' ']) {
+            Some((d, t)) => (d, Some(t.trim())),
+            None => (s, None),
+        };
+        let err = || anyhow!("Unrecognized date '{s}'");
+        let days = Self::parse_date(d).ok_or_else(err)?;
+        let secs = match t {
+            Some(t) => Some(Self::parse_time(t).ok_or_else(err)?),
+            None => None,
+        };
+        Ok(Self {
+            secs: days * 86400 + secs.unwrap_or(0),
+            has_time: secs.is_some(),
+        })
+    }
+}
+
+impl fmt::Display for RunDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (days, rem) = (self.secs.div_euclid(86400), self.secs.rem_euclid(86400));
+        let (y, m, d) = civil_from_days(days);
+        write!(f, "{y:04}-{m:02}-{d:02}")?;
+        if self.has_time {
+            write!(
+                f,
+                "T{:02}:{:02}:{:02}Z",
+                rem / 3600,
+                (rem % 3600) / 60,
+                rem % 60
+            )?
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for RunDate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for RunDate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn tsv_escape_test() {
+        assert!(matches!(tsv_escape("chr1 AC:CM000663.2"), Cow::Borrowed(_)));
+        assert_eq!(tsv_escape("a\tb\\c\r\n"), "a\\tb\\\\c\\r\\n");
+    }
+
+    #[test]
+    fn run_date_test() {
+        let d = |s: &str| s.parse::<RunDate>().unwrap();
+        assert_eq!(d("9/1/2023").to_string(), "2023-09-01");
+        assert_eq!(d("230901"), d("2023-09-01"));
+        assert_eq!(d("20230901"), d("2023/09/01"));
+        assert!(d("9/1/2023") < d("2023-10-01"));
+        assert_eq!(
+            d("2023-09-01T23:30:00-01:00").to_string(),
+            "2023-09-02T00:30:00Z"
+        );
+        assert_eq!(d("2023-09-01 10:15").to_string(), "2023-09-01T10:15:00Z");
+        assert!("2023-02-30".parse::<RunDate>().is_err());
+        assert!("yesterday".parse::<RunDate>().is_err());
+        assert_eq!(
+            RunDate::from_system_time(
+                SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(86399)
+            )
+            .to_string(),
+            "1970-01-01T23:59:59Z"
+        );
+    }
+}
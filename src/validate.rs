@@ -0,0 +1,88 @@
+use std::{io::Write, path::Path};
+
+use anyhow::Context;
+use compress_io::compress::CompressIo;
+
+use crate::{
+    cli::ValidateConfig,
+    read::{parse_json, read_group_path, JsonLayout},
+    source::is_stdin,
+    utils::{tsv_escape, tsv_path},
+};
+
+// Layout and problems found for each read group of one input file.  A single entry with no
+// layout is returned if the file could not be parsed
+fn check_file(p: &Path) -> Vec<(Option<JsonLayout>, Vec<String>)> {
+    let rdr = if is_stdin(p) {
+        CompressIo::new().bufreader()
+    } else {
+        CompressIo::new().path(p).bufreader()
+    };
+    let res = rdr
+        .with_context(|| format!("Could not open {} for input", p.display()))
+        .and_then(parse_json);
+    match res {
+        Ok(v) => v
+            .into_iter()
+            .map(|(layout, t)| (Some(layout), t.check()))
+            .collect(),
+        Err(e) => vec![(None, vec![format!("{e:#}")])],
+    }
+}
+
+/// Check fastq_gc JSON files without analyzing them, writing a TSV report with the layout,
+/// status and any problems found for each file (or for each read group of files with multiple
+/// read groups).  Returns an error if any file is invalid
+pub fn validate(cfg: &ValidateConfig) -> anyhow::Result<()> {
+    let mut wrt = CompressIo::new()
+        .opt_path(cfg.output_file())
+        .bufwriter()
+        .with_context(|| "Could not open output file")?;
+    writeln!(wrt, "File\tLayout\tStatus\tProblems")?;
+
+    let mut n_invalid = 0;
+    for p in cfg.input_files() {
+        let groups = check_file(p);
+        let multi = groups.len() > 1;
+        let mut valid = true;
+        for (i, (layout, problems)) in groups.into_iter().enumerate() {
+            let name = if multi {
+                read_group_path(p, i)
+            } else {
+                p.to_owned()
+            };
+            let layout = match layout {
+                Some(JsonLayout::Current) => "current",
+                Some(JsonLayout::Legacy) => "legacy",
+                None => "NA",
+            };
+            let status = if problems.is_empty() {
+                "OK"
+            } else {
+                valid = false;
+                warn!("{}: {}", name.display(), problems.join("; "));
+                "INVALID"
+            };
+            let problems = if problems.is_empty() {
+                "NA".to_owned()
+            } else {
+                tsv_escape(&problems.join("; ")).into_owned()
+            };
+            writeln!(wrt, "{}\t{layout}\t{status}\t{problems}", tsv_path(&name))?
+        }
+        if !valid {
+            n_invalid += 1
+        }
+    }
+    wrt.flush()?;
+
+    if n_invalid > 0 {
+        Err(anyhow!(
+            "{n_invalid} of {} input file(s) failed validation",
+            cfg.input_files().len()
+        ))
+    } else {
+        info!("All {} input file(s) are valid", cfg.input_files().len());
+        Ok(())
+    }
+}
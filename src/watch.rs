@@ -0,0 +1,185 @@
+//! Watch mode (watch subcommand, built with the watch feature).
+//!
+//! A directory is watched for new fastq_gc JSON files (named *.json or *.json.gz, ignoring
+//! hidden files), which are analyzed as they appear using the analysis options given before
+//! the subcommand, so that dashboards can follow a sequencing run in near real time.  Result
+//! rows are appended to the output file (the header is only written if the file is new or
+//! empty) and flushed as they are written.  A file is analyzed once it has been unchanged for
+//! SETTLE_TIME, so files still being written are not read, and each file is analyzed once.
+//! Invalid files are skipped with a warning.  With --existing the files already in the
+//! directory are also analyzed, so rows for these are appended again when watching is
+//! restarted with the same output file.
+//!
+//! Datasets are always output individually.  If a merge key is given then with
+//! --merged-output the merged results of all datasets seen so far are written to a separate
+//! file, which is rewritten every --merge-interval seconds if new datasets have arrived.  Each
+//! input file is merged once as it arrives, and the merged output is written outside of the
+//! watcher loop.
+//!
+//! Watching continues until gc_collect is terminated, so outputs written at the end of a
+//! normal run (e.g., MultiQC files and the run summary) are not produced.  The cache file
+//! index, the status file and the list of failed inputs are instead written every
+//! CHECKPOINT_INTERVAL (and when watching stops after an error).
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use crossbeam_channel::Sender;
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::{cli::WatchConfig, source::is_json_file};
+
+// Time a file must be unchanged before it is analyzed
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+// Interval between checks for settled files
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Interval between checkpoints (writing of the cache index and status files)
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+fn is_candidate(p: &Path) -> bool {
+    is_json_file(p)
+        && p.file_name()
+            .is_some_and(|s| !s.to_string_lossy().starts_with('.'))
+}
+
+// Files waiting to settle (with the time of the last change), the files sent for analysis and
+// the number of files sent
+#[derive(Default)]
+struct Arrivals {
+    pending: HashMap<PathBuf, Instant>,
+    seen: HashSet<PathBuf>,
+    n_sent: usize,
+}
+
+impl Arrivals {
+    fn changed(&mut self, p: PathBuf, t: Instant) {
+        if is_candidate(&p) && !self.seen.contains(&p) {
+            self.pending.insert(p, t);
+        }
+    }
+
+    fn removed(&mut self, p: &Path) {
+        self.pending.remove(p);
+    }
+
+    // Files unchanged for SETTLE_TIME at time now (in name order), with their arrival index.
+    // Files that have gone are dropped
+    fn settled(&mut self, now: Instant) -> Vec<(usize, PathBuf)> {
+        let mut v: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, t)| now.saturating_duration_since(**t) >= SETTLE_TIME)
+            .map(|(p, _)| p.clone())
+            .collect();
+        v.sort_unstable();
+        v.into_iter()
+            .filter_map(|p| {
+                self.pending.remove(&p);
+                p.is_file().then(|| {
+                    self.seen.insert(p.clone());
+                    self.n_sent += 1;
+                    (self.n_sent - 1, p)
+                })
+            })
+            .collect()
+    }
+}
+
+/// Watch the directory for new input files until an error occurs, sending them (with their
+/// arrival index) to each of sds.  checkpoint is called every CHECKPOINT_INTERVAL
+pub fn watch<F: FnMut()>(
+    wcfg: &WatchConfig,
+    sds: Vec<Sender<(usize, PathBuf)>>,
+    mut checkpoint: F,
+) -> anyhow::Result<()> {
+    let dir = wcfg.dir();
+    let (tx, rx) = channel();
+    let mut watcher =
+        notify::recommended_watcher(tx).with_context(|| "Could not start file watcher")?;
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Could not watch directory {}", dir.display()))?;
+    info!("Watching {} for new input files", dir.display());
+
+    let mut arrivals = Arrivals::default();
+    // Listed after the watcher has started so that no files are missed
+    if wcfg.existing() {
+        let now = Instant::now();
+        for e in fs::read_dir(dir)
+            .with_context(|| format!("Could not read directory {}", dir.display()))?
+        {
+            arrivals.changed(e?.path(), now)
+        }
+    }
+
+    let mut last_checkpoint = Instant::now();
+    loop {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(ev)) => match ev.kind {
+                EventKind::Create(_) | EventKind::Modify(_) => {
+                    let now = Instant::now();
+                    for p in ev.paths {
+                        arrivals.changed(p, now)
+                    }
+                }
+                EventKind::Remove(_) => ev.paths.iter().for_each(|p| arrivals.removed(p)),
+                _ => (),
+            },
+            Ok(Err(e)) => warn!("Error watching {}: {e}", dir.display()),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow!("Watcher for {} stopped", dir.display()))
+            }
+        }
+        for (ix, p) in arrivals.settled(Instant::now()) {
+            debug!("New input file {}", p.display());
+            for sd in sds.iter() {
+                sd.send((ix, p.clone()))
+                    .map_err(|_| anyhow!("Error sending input file for analysis"))?
+            }
+        }
+        if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+            checkpoint();
+            last_checkpoint = Instant::now()
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn arrivals_test() {
+        let dir = std::env::temp_dir().join(format!("gc_collect_watch_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.json"), dir.join("b.json.gz"));
+        fs::write(&a, "{}").unwrap();
+        fs::write(&b, "{}").unwrap();
+        let t = Instant::now();
+        let mut arr = Arrivals::default();
+        arr.changed(b.clone(), t);
+        arr.changed(a.clone(), t);
+        arr.changed(dir.join(".c.json"), t);
+        arr.changed(dir.join("d.txt"), t);
+        // Missing file
+        arr.changed(dir.join("e.json"), t);
+        assert!(arr.settled(t + SETTLE_TIME / 2).is_empty());
+        // A change restarts the wait
+        arr.changed(b.clone(), t + SETTLE_TIME / 2);
+        assert_eq!(arr.settled(t + SETTLE_TIME), vec![(0, a.clone())]);
+        assert_eq!(arr.settled(t + SETTLE_TIME * 2), vec![(1, b)]);
+        // Files are only analyzed once
+        arr.changed(a, t + SETTLE_TIME * 2);
+        assert!(arr.pending.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}